@@ -0,0 +1,63 @@
+// A preload library filling the glibc heap from its constructor, before mosalloc takes over brk,
+// and checking at exit that the blocks still in use are intact, e.g.
+// `LD_PRELOAD=target/debug/examples/libearly_heap.so run_mosalloc --hook-type preload
+// --config pool.csv --heap copy /bin/true`
+
+use std::ptr::{addr_of, addr_of_mut};
+
+use nix::libc;
+
+const BLOCKS: usize = 4096;
+
+static mut EARLY: [(usize, usize); BLOCKS] = [(0, 0); BLOCKS];
+
+fn pattern(i: usize) -> u8 {
+    (i % 251) as u8
+}
+
+extern "C" fn early_heap_init() {
+    unsafe {
+        // small, varying sizes, all of them below the mmap threshold
+        for (i, block) in (*addr_of_mut!(EARLY)).iter_mut().enumerate() {
+            let len = 16 + (i * 37) % 2048;
+            let p = libc::malloc(len) as *mut u8;
+            assert!(!p.is_null(), "early_heap: malloc failed");
+            p.write_bytes(pattern(i), len);
+            *block = (p as usize, len);
+        }
+
+        // leave holes behind for the bins
+        for &(p, _) in (*addr_of!(EARLY)).iter().step_by(3) {
+            libc::free(p as *mut libc::c_void);
+        }
+    }
+
+    println!("early_heap: {} blocks allocated", BLOCKS);
+}
+
+extern "C" fn early_heap_fini() {
+    unsafe {
+        let early = &*addr_of!(EARLY);
+
+        let corrupted = early
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i % 3 != 0)
+            .filter(|&(i, &(p, len))| {
+                std::slice::from_raw_parts(p as *const u8, len)
+                    .iter()
+                    .any(|&x| x != pattern(i))
+            })
+            .count();
+
+        println!("early_heap: {} blocks corrupted", corrupted);
+    }
+}
+
+#[used]
+#[link_section = ".init_array"]
+static EARLY_HEAP_INIT: extern "C" fn() = early_heap_init;
+
+#[used]
+#[link_section = ".fini_array"]
+static EARLY_HEAP_FINI: extern "C" fn() = early_heap_fini;
@@ -0,0 +1,56 @@
+// Registers an anonymous buffer as an io_uring fixed buffer through syscall() and unregisters it,
+// mosalloc keeps the buffer's pages in place meanwhile and reports the registration in its stats,
+// e.g.
+// `run_mosalloc --config pool.csv target/debug/examples/io_uring`
+
+use nix::libc;
+
+const IORING_REGISTER_BUFFERS: libc::c_long = 0;
+const IORING_UNREGISTER_BUFFERS: libc::c_long = 1;
+
+const LEN: usize = 4 << 20;
+
+fn main() {
+    unsafe {
+        // struct io_uring_params, all defaults
+        let mut params = [0u8; 120];
+        let fd = libc::syscall(libc::SYS_io_uring_setup, 8, params.as_mut_ptr());
+        if fd < 0 {
+            println!("io_uring: unavailable, nothing to check");
+            return;
+        }
+
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+        let buf = libc::mmap(std::ptr::null_mut(), LEN, prot, flags, -1, 0);
+        assert_ne!(buf, libc::MAP_FAILED, "io_uring: mmap failed");
+        (buf as *mut u8).write_bytes(0xaa, LEN);
+
+        let iov = libc::iovec {
+            iov_base: buf,
+            iov_len: LEN,
+        };
+        let ret = libc::syscall(
+            libc::SYS_io_uring_register,
+            fd,
+            IORING_REGISTER_BUFFERS,
+            &iov as *const libc::iovec,
+            1,
+        );
+        assert_eq!(ret, 0, "io_uring: buffer registration failed");
+        println!("io_uring: 0x{:x} registered", buf as usize);
+
+        let ret = libc::syscall(
+            libc::SYS_io_uring_register,
+            fd,
+            IORING_UNREGISTER_BUFFERS,
+            std::ptr::null::<libc::c_void>(),
+            0,
+        );
+        assert_eq!(ret, 0, "io_uring: buffer unregistration failed");
+
+        assert_eq!(libc::munmap(buf, LEN), 0);
+        libc::close(fd as i32);
+        println!("io_uring: fixed buffer ok");
+    }
+}
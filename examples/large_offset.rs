@@ -0,0 +1,77 @@
+// Maps a sparse file past the 4GB offset, run it under run_mosalloc to check the file region
+// handling of large offsets, e.g. `run_mosalloc --config pool.csv target/debug/examples/large_offset`
+
+use std::env;
+use std::fs::OpenOptions;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::ptr::null_mut;
+
+use nix::errno::Errno;
+use nix::libc;
+
+const GB: u64 = 1 << 30;
+const PAGE: usize = 4096;
+
+fn main() {
+    let path = env::temp_dir().join(format!("mosalloc-large-offset-{}", std::process::id()));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    file.set_len(5 * GB).unwrap();
+    for (i, offset) in [0, 2 * GB, 4 * GB, 4 * GB + PAGE as u64].iter().enumerate() {
+        file.write_all_at(&[i as u8 + 1], *offset).unwrap();
+    }
+
+    for (i, offset) in [0, 2 * GB, 4 * GB, 4 * GB + PAGE as u64].iter().enumerate() {
+        let p = unsafe {
+            libc::mmap64(
+                null_mut(),
+                PAGE,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                *offset as i64,
+            )
+        };
+        assert!(
+            p != libc::MAP_FAILED,
+            "mmap64 at {:#x}: {}",
+            offset,
+            Errno::last()
+        );
+        assert_eq!(
+            unsafe { *(p as *const u8) },
+            i as u8 + 1,
+            "wrong data at {:#x}",
+            offset
+        );
+        unsafe { libc::munmap(p, PAGE) };
+        println!("mmap64 at {:#x}: ok", offset);
+    }
+
+    // unaligned and negative offsets are rejected
+    for (offset, err) in [
+        (PAGE as i64 / 2, Errno::EINVAL),
+        (-(PAGE as i64), Errno::EOVERFLOW),
+    ] {
+        let p = unsafe {
+            libc::mmap64(
+                null_mut(),
+                PAGE,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                offset,
+            )
+        };
+        assert_eq!(p, libc::MAP_FAILED);
+        assert_eq!(Errno::last(), err);
+    }
+    println!("invalid offsets: ok (EINVAL / EOVERFLOW)");
+}
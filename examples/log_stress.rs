@@ -0,0 +1,97 @@
+// Hammers the mmap and munmap hooks from several threads at once, with the output sent to a file,
+// and checks that every line mosalloc logged for them came out whole, not interleaved with the
+// lines of the other threads nor lost, e.g.
+// `run_mosalloc --config pool.csv target/debug/examples/log_stress`
+
+use std::fs;
+use std::io::Write;
+use std::thread;
+
+use nix::libc;
+
+const THREADS: usize = 16;
+const CALLS: usize = 2000;
+// an odd number of pages, so that the lines of the example's mmaps stand out
+const LEN: usize = 11 << 12;
+
+fn hammer() {
+    for _ in 0..CALLS {
+        unsafe {
+            let prot = libc::PROT_READ | libc::PROT_WRITE;
+            let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+            let buf = libc::mmap(std::ptr::null_mut(), LEN, prot, flags, -1, 0);
+            assert_ne!(buf, libc::MAP_FAILED, "log_stress: mmap failed");
+            assert_eq!(libc::munmap(buf, LEN), 0);
+        }
+    }
+}
+
+// mmap 0x<addr>, len: <len>, fd: <fd>
+fn mmap_line(line: &str) -> Option<usize> {
+    let rest = line.strip_prefix("mmap 0x")?;
+    let (addr, rest) = rest.split_once(", len: ")?;
+    let (len, fd) = rest.split_once(", fd: ")?;
+    usize::from_str_radix(addr, 16).ok()?;
+    fd.parse::<i32>().ok()?;
+    len.parse::<usize>().ok()
+}
+
+// munmap 0x<addr> <len>
+fn munmap_line(line: &str) -> Option<usize> {
+    let (addr, len) = line.strip_prefix("munmap 0x")?.split_once(' ')?;
+    usize::from_str_radix(addr, 16).ok()?;
+    len.parse::<usize>().ok()
+}
+
+fn main() {
+    let path = format!("/tmp/log_stress-{}.log", std::process::id());
+    let log = fs::File::create(&path).unwrap();
+
+    // send the output of the hooks to the log, for the duration of the run
+    let stdout = unsafe {
+        let stdout = libc::dup(1);
+        std::io::stdout().flush().unwrap();
+        libc::dup2(std::os::fd::AsRawFd::as_raw_fd(&log), 1);
+        stdout
+    };
+
+    let threads = (0..THREADS)
+        .map(|_| thread::spawn(hammer))
+        .collect::<Vec<_>>();
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    unsafe {
+        libc::dup2(stdout, 1);
+        libc::close(stdout);
+    }
+
+    let out = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    let (mut mmaps, mut munmaps, mut torn) = (0, 0, 0);
+    for line in out.lines() {
+        if line.contains("mmap 0x") {
+            match mmap_line(line).or_else(|| munmap_line(line)) {
+                Some(LEN) if line.starts_with("mmap") => mmaps += 1,
+                Some(LEN) => munmaps += 1,
+                Some(_) => {}
+                None => torn += 1,
+            }
+        }
+    }
+
+    if mmaps == 0 && munmaps == 0 && torn == 0 {
+        println!("log_stress: not running under mosalloc, nothing to check");
+        return;
+    }
+    println!(
+        "log_stress: {} threads, {} mmap and {} munmap lines logged, {} torn",
+        THREADS, mmaps, munmaps, torn
+    );
+    assert_eq!(torn, 0, "log_stress: torn lines");
+    assert_eq!(mmaps, THREADS * CALLS, "log_stress: mmap lines lost");
+    assert_eq!(munmaps, THREADS * CALLS, "log_stress: munmap lines lost");
+    println!("log_stress: ok");
+}
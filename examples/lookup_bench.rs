@@ -0,0 +1,121 @@
+// Times the address lookups of the allocation paths, the page size lookup of a pool offset and
+// the region lookup of an address, against the linear scans they replaced, e.g.
+// `cargo run --release --example lookup_bench`
+
+use std::hint::black_box;
+use std::sync::atomic::AtomicUsize;
+use std::time::Instant;
+
+use mosalloc::utils::htlb::{AllocType, Interval, Pool, PAGE_SIZE};
+use mosalloc::utils::misc::find_range;
+
+const PAGE: usize = 2 << 20;
+const LOOKUPS: usize = 1 << 22;
+
+// n intervals of 2MB pages, each 4 pages long and followed by a 4 page gap
+fn pool(n: usize) -> Pool {
+    Pool {
+        alloc_type: AllocType::ANON,
+        intervals: (0..n)
+            .map(|i| Interval {
+                pagesz: PAGE,
+                start: i * 8 * PAGE,
+                end: (i * 8 + 4) * PAGE,
+                name: None,
+            })
+            .collect(),
+    }
+}
+
+// the linear scan the pool lookup replaced
+fn linear_pagesz_range(pool: &Pool, offset: usize) -> (usize, usize) {
+    if let Some(x) = pool
+        .intervals
+        .iter()
+        .find(|x| x.start <= offset && offset < x.end)
+    {
+        return (x.pagesz, x.end);
+    }
+
+    let end = pool
+        .intervals
+        .iter()
+        .filter(|x| x.start > offset)
+        .map(|x| x.start)
+        .min()
+        .unwrap_or(pool.span());
+
+    (*PAGE_SIZE, end)
+}
+
+// xorshift, so that the random offsets are the same across runs
+fn offsets(span: usize, random: bool) -> Vec<usize> {
+    let mut x = 0x2545f4914f6cdd1du64;
+    (0..LOOKUPS)
+        .map(|i| {
+            if random {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as usize % span
+            } else {
+                // a page by page walk, like the mapping of a range
+                (i * *PAGE_SIZE) % span
+            }
+        })
+        .collect()
+}
+
+fn time(name: &str, n: usize, offsets: &[usize], f: impl Fn(usize) -> usize) {
+    let start = Instant::now();
+    let mut acc = 0;
+    for &x in offsets {
+        acc ^= f(black_box(x));
+    }
+    black_box(acc);
+    println!(
+        "{:<24} {:>6} {:>8.1} ns/lookup",
+        name,
+        n,
+        start.elapsed().as_nanos() as f64 / offsets.len() as f64
+    );
+}
+
+fn main() {
+    println!("{:<24} {:>6} {:>8}", "LOOKUP", "N", "TIME");
+
+    for n in [1, 4, 16, 64, 256, 1024] {
+        let pool = pool(n);
+        let hint = AtomicUsize::new(0);
+
+        for random in [false, true] {
+            let offsets = offsets(pool.span(), random);
+            let pattern = if random { "random" } else { "walk" };
+
+            time(&format!("pool linear ({})", pattern), n, &offsets, |x| {
+                linear_pagesz_range(&pool, x).1
+            });
+            time(&format!("pool hinted ({})", pattern), n, &offsets, |x| {
+                pool.pagesz_range(x, &hint, *PAGE_SIZE).1
+            });
+        }
+    }
+
+    for n in [2, 4, 8, 16, 64] {
+        // regions of 1GB, 1GB apart
+        let regions = (0..n)
+            .map(|i| (i << 31, (i << 31) + (1 << 30)))
+            .collect::<Vec<(usize, usize)>>();
+        let offsets = offsets(n << 31, true);
+
+        time("region linear", n, &offsets, |x| {
+            regions
+                .iter()
+                .position(|r| r.0 <= x && x < r.1)
+                .unwrap_or(n)
+        });
+        time("region sorted", n, &offsets, |x| {
+            find_range(&regions, x, |r| *r).unwrap_or(n)
+        });
+    }
+}
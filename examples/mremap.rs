@@ -0,0 +1,144 @@
+// Exercises realloc-driven and direct mremaps, run it under run_mosalloc to check the mosalloc
+// mremap path, e.g. `run_mosalloc --config pool.csv target/debug/examples/mremap`
+
+use std::ptr::null_mut;
+
+use nix::errno::Errno;
+use nix::libc;
+
+const MB: usize = 1 << 20;
+
+unsafe fn fill(p: *mut u8, len: usize) {
+    for i in (0..len).step_by(4096) {
+        *p.add(i) = (i / 4096) as u8;
+    }
+}
+
+unsafe fn check(p: *const u8, len: usize) -> bool {
+    (0..len)
+        .step_by(4096)
+        .all(|i| *p.add(i) == (i / 4096) as u8)
+}
+
+unsafe fn mmap(len: usize) -> *mut u8 {
+    let p = libc::mmap(
+        null_mut(),
+        len,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+        -1,
+        0,
+    );
+    assert!(p != libc::MAP_FAILED);
+    p as *mut u8
+}
+
+unsafe fn mremap(p: *mut u8, old: usize, new: usize, flags: i32, new_addr: *mut u8) -> *mut u8 {
+    libc::mremap(
+        p as *mut libc::c_void,
+        old,
+        new,
+        flags,
+        new_addr as *mut libc::c_void,
+    ) as *mut u8
+}
+
+// glibc serves large allocations with mmap and resizes them with mremap(MREMAP_MAYMOVE)
+unsafe fn realloc_growth() {
+    let mut len = MB;
+    let mut p = libc::malloc(len) as *mut u8;
+    fill(p, len);
+
+    while len < 64 * MB {
+        p = libc::realloc(p as *mut libc::c_void, len * 2) as *mut u8;
+        assert!(!p.is_null());
+        assert!(check(p, len), "realloc lost data growing to {}", len * 2);
+        len *= 2;
+        fill(p, len);
+    }
+
+    while len > MB {
+        len /= 2;
+        p = libc::realloc(p as *mut libc::c_void, len) as *mut u8;
+        assert!(!p.is_null());
+        assert!(check(p, len), "realloc lost data shrinking to {}", len);
+    }
+
+    libc::free(p as *mut libc::c_void);
+    println!("realloc growth / shrink: ok");
+}
+
+unsafe fn mremap_flags() {
+    let len = 4 * MB;
+    let p = mmap(len);
+    fill(p, len);
+
+    // in-place only growth either succeeds in place or fails with ENOMEM
+    let q = mremap(p, len, 2 * len, 0, null_mut());
+    assert!(
+        q == p || (q as *mut libc::c_void == libc::MAP_FAILED && Errno::last() == Errno::ENOMEM)
+    );
+    let len = if q == p { 2 * len } else { len };
+    println!(
+        "in-place growth: ok ({})",
+        if q == p { "expanded" } else { "ENOMEM" }
+    );
+
+    // in-place shrink always succeeds
+    let q = mremap(p, len, len / 2, 0, null_mut());
+    assert_eq!(q, p);
+    let len = len / 2;
+    println!("in-place shrink: ok");
+
+    // MREMAP_FIXED and MREMAP_DONTUNMAP require MREMAP_MAYMOVE
+    for flags in [libc::MREMAP_FIXED, libc::MREMAP_DONTUNMAP] {
+        let q = mremap(p, len, len, flags, p.add(16 * MB));
+        assert_eq!(q as *mut libc::c_void, libc::MAP_FAILED);
+        assert_eq!(Errno::last(), Errno::EINVAL);
+    }
+    println!("FIXED / DONTUNMAP without MAYMOVE: ok (EINVAL)");
+
+    // MREMAP_DONTUNMAP can't resize
+    let q = mremap(
+        p,
+        len,
+        2 * len,
+        libc::MREMAP_MAYMOVE | libc::MREMAP_DONTUNMAP,
+        null_mut(),
+    );
+    assert_eq!(q as *mut libc::c_void, libc::MAP_FAILED);
+    assert_eq!(Errno::last(), Errno::EINVAL);
+    println!("DONTUNMAP resize: ok (EINVAL)");
+
+    // moving growth keeps the data
+    let q = mremap(p, len, 4 * len, libc::MREMAP_MAYMOVE, null_mut());
+    assert!(q as *mut libc::c_void != libc::MAP_FAILED);
+    assert!(check(q, len));
+    let (p, len) = (q, 4 * len);
+    fill(p, len);
+    println!("moving growth: ok");
+
+    // MREMAP_DONTUNMAP moves the data and leaves the old range mapped and zero-filled
+    let q = mremap(
+        p,
+        len,
+        len,
+        libc::MREMAP_MAYMOVE | libc::MREMAP_DONTUNMAP,
+        null_mut(),
+    );
+    assert!(q as *mut libc::c_void != libc::MAP_FAILED);
+    assert!(q != p);
+    assert!(check(q, len));
+    assert!((0..len).step_by(4096).all(|i| *p.add(i) == 0));
+    println!("DONTUNMAP move: ok");
+
+    libc::munmap(p as *mut libc::c_void, len);
+    libc::munmap(q as *mut libc::c_void, len);
+}
+
+fn main() {
+    unsafe {
+        realloc_growth();
+        mremap_flags();
+    }
+}
@@ -0,0 +1,84 @@
+// Maps anonymous memory with PROT_MTE, tags its first granule and accesses, protects and unmaps
+// it through the tagged pointer, checking that the tagging survives the interposition on MTE
+// capable systems, e.g.
+// `run_mosalloc --config pool.csv target/debug/examples/mte`
+
+#[cfg(target_arch = "aarch64")]
+mod mte {
+    use std::arch::asm;
+
+    use nix::libc;
+
+    const PROT_MTE: i32 = 0x20;
+    const HWCAP2_MTE: u64 = 1 << 18;
+
+    const PR_SET_TAGGED_ADDR_CTRL: i32 = 55;
+    const PR_TAGGED_ADDR_ENABLE: u64 = 1;
+    const PR_MTE_TCF_SYNC: u64 = 1 << 1;
+    // tags the random tag generation may pick, all but 0
+    const PR_MTE_TAG_MASK: u64 = 0xfffe << 3;
+
+    const LEN: usize = 64 << 10;
+
+    // a random tag for ptr, set on its 16 byte granule
+    unsafe fn tag(ptr: *mut u8) -> *mut u8 {
+        let mut tagged = ptr;
+        asm!(
+            ".arch_extension memtag",
+            "irg {0}, {0}",
+            "stg {0}, [{0}]",
+            inout(reg) tagged,
+        );
+        tagged
+    }
+
+    pub fn run() {
+        unsafe {
+            if libc::getauxval(libc::AT_HWCAP2) & HWCAP2_MTE == 0 {
+                println!("mte: not supported by the CPU, nothing to check");
+                return;
+            }
+            let ctrl = PR_TAGGED_ADDR_ENABLE | PR_MTE_TCF_SYNC | PR_MTE_TAG_MASK;
+            assert_eq!(
+                libc::prctl(PR_SET_TAGGED_ADDR_CTRL, ctrl, 0, 0, 0),
+                0,
+                "mte: tagged address ABI unavailable"
+            );
+
+            let prot = libc::PROT_READ | libc::PROT_WRITE | PROT_MTE;
+            let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+            let ptr = libc::mmap(std::ptr::null_mut(), LEN, prot, flags, -1, 0);
+            assert_ne!(ptr, libc::MAP_FAILED, "mte: mmap with PROT_MTE failed");
+
+            let tagged = tag(ptr as *mut u8);
+            println!(
+                "mte: 0x{:x} tagged as 0x{:x}",
+                ptr as usize, tagged as usize
+            );
+
+            // checked accesses, a tag mismatch would fault synchronously
+            tagged.write_volatile(0xaa);
+            assert_eq!(tagged.read_volatile(), 0xaa);
+
+            // the interposed calls get the tagged pointer the application holds
+            let addr = tagged as *mut libc::c_void;
+            assert_eq!(libc::mprotect(addr, LEN, prot), 0, "mte: tagged mprotect");
+            assert_eq!(tagged.read_volatile(), 0xaa);
+            assert_eq!(
+                libc::madvise(addr, LEN, libc::MADV_DONTNEED),
+                0,
+                "mte: tagged madvise"
+            );
+            assert_eq!(libc::munmap(addr, LEN), 0, "mte: tagged munmap");
+
+            println!("mte: tagged allocation ok");
+        }
+    }
+}
+
+fn main() {
+    #[cfg(target_arch = "aarch64")]
+    mte::run();
+    #[cfg(not(target_arch = "aarch64"))]
+    println!("mte: aarch64 only, nothing to check");
+}
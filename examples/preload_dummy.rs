@@ -0,0 +1,53 @@
+// A dummy preload library that allocates from its constructor and from a background thread while
+// the program starts, to check that mosalloc copes with other preloaded libraries, e.g.
+// `LD_PRELOAD=target/debug/examples/libpreload_dummy.so run_mosalloc --config pool.csv
+// [--init-first] target/debug/examples/mremap`
+
+use std::ptr::null_mut;
+use std::thread;
+use std::time::Duration;
+
+use nix::libc;
+
+const MB: usize = 1 << 20;
+
+unsafe fn mmap_touch(len: usize) -> *mut libc::c_void {
+    let p = libc::mmap(
+        null_mut(),
+        len,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+        -1,
+        0,
+    );
+    assert!(p != libc::MAP_FAILED, "preload_dummy: mmap failed");
+    *(p as *mut u8) = 1;
+    p
+}
+
+extern "C" fn preload_dummy_init() {
+    unsafe {
+        // long-lived allocations, like a profiler's buffers
+        let buf = libc::malloc(64 << 10);
+        assert!(!buf.is_null(), "preload_dummy: malloc failed");
+        mmap_touch(MB);
+    }
+
+    // keep allocating while the rest of the constructors (and mosalloc's drain) run
+    thread::spawn(|| {
+        for _ in 0..100 {
+            unsafe {
+                let p = mmap_touch(MB);
+                libc::munmap(p, MB);
+                libc::free(libc::malloc(4096));
+            }
+            thread::sleep(Duration::from_micros(100));
+        }
+    });
+
+    println!("preload_dummy: initialized");
+}
+
+#[used]
+#[link_section = ".init_array"]
+static PRELOAD_DUMMY_INIT: extern "C" fn() = preload_dummy_init;
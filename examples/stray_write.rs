@@ -0,0 +1,27 @@
+// Writes to the first read-only anonymous mapping, i.e. the mosalloc region maps when they're
+// protected, which should fault instead of silently corrupting them, e.g.
+// `run_mosalloc --config pool.csv --protect-metadata target/debug/examples/stray_write`
+
+use std::fs;
+
+fn main() {
+    let maps = fs::read_to_string("/proc/self/maps").unwrap();
+
+    let addr = maps.lines().find_map(|line| {
+        let fields = line.split_whitespace().collect::<Vec<&str>>();
+        // anonymous mappings have no path and a zero inode
+        if fields.len() != 5 || fields[1] != "r--p" || fields[4] != "0" {
+            return None;
+        }
+        usize::from_str_radix(fields[0].split_once('-')?.0, 16).ok()
+    });
+
+    match addr {
+        Some(addr) => {
+            println!("stray_write: writing to 0x{:x}", addr);
+            unsafe { *(addr as *mut u8) = 0xff };
+            println!("stray_write: write went through");
+        }
+        None => println!("stray_write: no read-only anonymous mapping, metadata not protected?"),
+    }
+}
@@ -0,0 +1,52 @@
+// Maps a buffer from the main thread, registered with mosalloc_register_thread, and from two
+// named threads, run it under run_mosalloc with a thread filter to check that only the mmaps of
+// the selected threads land in the pools, e.g.
+// `run_mosalloc --config pool.csv --threads compute target/debug/examples/threads`
+
+use std::thread;
+
+use nix::libc;
+
+const LEN: usize = 4 << 20;
+
+type RegisterThread = unsafe extern "C" fn(libc::pid_t) -> libc::c_int;
+
+fn map(who: &str) {
+    unsafe {
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+        let buf = libc::mmap(std::ptr::null_mut(), LEN, prot, flags, -1, 0);
+        assert_ne!(buf, libc::MAP_FAILED, "threads: mmap failed");
+        (buf as *mut u8).write_bytes(0xaa, LEN);
+        println!(
+            "threads: {} (tid {}) mapped 0x{:x}",
+            who,
+            libc::gettid(),
+            buf as usize
+        );
+        assert_eq!(libc::munmap(buf, LEN), 0);
+    }
+}
+
+fn main() {
+    unsafe {
+        // only exported when running under mosalloc
+        let sym = libc::dlsym(libc::RTLD_DEFAULT, c"mosalloc_register_thread".as_ptr());
+        if sym.is_null() {
+            println!("not running under mosalloc, no thread registered");
+        } else {
+            let register = std::mem::transmute::<*mut libc::c_void, RegisterThread>(sym);
+            assert_eq!(register(0), 0);
+        }
+    }
+    map("main");
+
+    for name in ["compute", "other"] {
+        thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || map(name))
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+}
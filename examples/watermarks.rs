@@ -0,0 +1,66 @@
+// Grows and shrinks the heap with sbrk, run it under run_mosalloc with watermarks configured to
+// check that crossing them is reported, e.g.
+// `run_mosalloc --config pool.csv --watermarks 50,90 target/debug/examples/watermarks`
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use nix::libc;
+
+const STEP: isize = 256 << 10;
+
+type WatermarkCallback =
+    unsafe extern "C" fn(libc::c_int, libc::c_uint, libc::size_t, libc::size_t, *mut libc::c_void);
+type SetWatermarkCallback =
+    unsafe extern "C" fn(Option<WatermarkCallback>, *mut libc::c_void) -> libc::c_int;
+
+static CROSSED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe extern "C" fn on_watermark(
+    region: libc::c_int,
+    pct: libc::c_uint,
+    allocated: libc::size_t,
+    size: libc::size_t,
+    arg: *mut libc::c_void,
+) {
+    (*(arg as *const AtomicUsize)).fetch_add(1, Ordering::Relaxed);
+    println!(
+        "callback: region {} crossed {}% ({} of {} bytes)",
+        region, pct, allocated, size
+    );
+}
+
+fn main() {
+    unsafe {
+        // only exported when running under mosalloc
+        let sym = libc::dlsym(
+            libc::RTLD_DEFAULT,
+            c"mosalloc_set_watermark_callback".as_ptr(),
+        );
+        if sym.is_null() {
+            println!("not running under mosalloc, no callback registered");
+        } else {
+            let set = std::mem::transmute::<*mut libc::c_void, SetWatermarkCallback>(sym);
+            let arg = &CROSSED as *const AtomicUsize as *mut libc::c_void;
+            assert_eq!(set(Some(on_watermark), arg), 0);
+        }
+
+        // grow until the brk region is exhausted
+        let mut grown = 0;
+        while libc::sbrk(STEP) as isize != -1 {
+            grown += 1;
+        }
+        println!("grew the heap by {} KB", grown * (STEP >> 10));
+
+        // shrinking below the watermarks re-arms them, growing back crosses them again
+        let before = CROSSED.load(Ordering::Relaxed);
+        libc::sbrk(-grown * STEP);
+        for _ in 0..grown {
+            libc::sbrk(STEP);
+        }
+        println!(
+            "watermarks crossed: {} growing, {} growing again",
+            before,
+            CROSSED.load(Ordering::Relaxed) - before
+        );
+    }
+}
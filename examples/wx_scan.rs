@@ -0,0 +1,28 @@
+// Lists the mappings that are both writable and executable, which hardened kernels (SELinux
+// execmem, PaX) refuse, and fails if there are any, e.g.
+// `run_mosalloc --config pool.csv target/debug/examples/wx_scan`
+
+use std::fs;
+use std::process::exit;
+
+fn main() {
+    let maps = fs::read_to_string("/proc/self/maps").unwrap();
+
+    let wx = maps
+        .lines()
+        .filter(|line| {
+            line.split_whitespace()
+                .nth(1)
+                .is_some_and(|perms| perms.contains('w') && perms.contains('x'))
+        })
+        .collect::<Vec<&str>>();
+
+    for line in wx.iter() {
+        println!("wx_scan: {}", line);
+    }
+    println!("wx_scan: {} writable and executable mappings", wx.len());
+
+    if !wx.is_empty() {
+        exit(1);
+    }
+}
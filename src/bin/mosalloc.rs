@@ -0,0 +1,882 @@
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::{Parser, Subcommand};
+
+use mosalloc::utils::advice::{pidfd_open, AdviceBatch, MADV_COLLAPSE};
+use mosalloc::utils::argparse::{
+    parse_addr, parse_file_path, parse_hook_type, parse_node, parse_size,
+};
+use mosalloc::utils::attach::{attach_targets, move_to_node, process_maps};
+use mosalloc::utils::backend_diff::{diff_runs, run_backend, DIFF_BACKENDS};
+use mosalloc::utils::config::{error_str, PoolConfig};
+use mosalloc::utils::conformance::{diff_suites, run_suite, write_cases, CASES};
+use mosalloc::utils::control::{
+    self, control_sockets, policies, query, set_policy, socket_path, ProcessStats, POLICY_KEYS,
+};
+use mosalloc::utils::doctor::diagnose;
+#[cfg(feature = "test-fixtures")]
+use mosalloc::utils::fixture::SysfsFixture;
+use mosalloc::utils::htlb::{
+    supported_htlb_sizes, HTLBReq, HookType, MosallocConfig, DEFAULT_ENV_PREFIX,
+};
+use mosalloc::utils::hugetlbfs::{hugetlbfs_mounts, mount_private};
+use mosalloc::utils::misc::size_to_str;
+use mosalloc::utils::rangelist::Id;
+use mosalloc::utils::report::{diff_reports, Report};
+use mosalloc::utils::schema::json_schema;
+use mosalloc::utils::selftest::{probe, suggestions, ProbeKind};
+#[cfg(feature = "test-fixtures")]
+use mosalloc::utils::sysfs_path::SYSFS_ROOT_VAR;
+use nix::unistd::{getgid, getuid, Gid, Uid};
+
+#[derive(Parser)]
+#[clap(author, version, about)]
+struct Cli {
+    #[clap(subcommand)]
+    cmd: Cmd,
+}
+
+#[derive(Subcommand)]
+enum ConfigCmd {
+    /// Converts a config (e.g. a legacy CSV one) to the current versioned schema.
+    Migrate {
+        #[clap(value_parser = parse_file_path, help = "Config to convert")]
+        input: String,
+        #[clap(value_parser, help = "Output versioned config")]
+        output: String,
+    },
+    /// Validates a config, reporting the line of each invalid interval.
+    Check {
+        #[clap(value_parser = parse_file_path, help = "Config to validate")]
+        config: String,
+        #[clap(long, value_parser = parse_size, default_value_t = 1 << 20, help = "Anon FFA size the config is run with")]
+        anon_ffa_size: usize,
+        #[clap(long, value_parser = parse_size, default_value_t = 1 << 10, help = "File FFA size the config is run with")]
+        file_ffa_size: usize,
+    },
+}
+
+#[derive(Subcommand)]
+enum ReportCmd {
+    /// Prints a summary of a run report.
+    Show {
+        #[clap(value_parser = parse_file_path, help = "Report written by run_mosalloc --report")]
+        report: String,
+    },
+    /// Diffs two run reports: the exit, reservation, resource usage, the stats of each region
+    /// (matched by label) and the warnings.
+    Diff {
+        #[clap(value_parser = parse_file_path, help = "Report of the first run")]
+        a: String,
+        #[clap(value_parser = parse_file_path, help = "Report of the second run")]
+        b: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum Cmd {
+    /// Computes the hugepage kernel boot parameters (default_hugepagesz, hugepagesz, hugepages)
+    /// needed to statically reserve the brk and anon pools of a config, for environments where
+    /// runtime reservation isn't possible. If a NUMA node is given, the per-node hugepages syntax
+    /// (kernel 5.13+) is used instead. The parameters are printed both as a kernel cmdline snippet
+    /// and as a grub config line.
+    BootConfig {
+        #[clap(long, value_parser = parse_file_path, help = "Brk and anon (mmap) pool intervals configuration (CSV)")]
+        config: String,
+        #[clap(short, long, value_parser = parse_node, help = "NUMA node (default: no node restriction)")]
+        node: Option<Id>,
+    },
+    /// Tries to map a single page of each supported hugepage size through anonymous MAP_HUGETLB
+    /// mappings, hugetlbfs files and hugetlb memfds, and reports which paths work with the current
+    /// privileges and limits, along with the changes needed to fix the ones that don't.
+    Selftest,
+    /// Looks for the hugepages leaked by crashed runs on shared machines: the --release-pages
+    /// reservations of run_mosalloc processes that are gone (from the reservations state file,
+    /// MOSALLOC_STATE_FILE), the mosalloc hugetlbfs temp files of exited processes, the empty
+    /// private hugetlbfs mounts, and the hugepages in use while no process maps any. With --fix,
+    /// it offers to clean each leak up: releasing the reservation, removing the file or
+    /// unmounting. Exits with 1 if leaks remain.
+    Doctor {
+        #[clap(long, action, help = "Offer to clean up each leak found")]
+        fix: bool,
+        #[clap(short, long, action, help = "Clean up without asking, with --fix")]
+        yes: bool,
+    },
+    /// Applies the placement advice of a pool config to an already running process that can't be
+    /// restarted under mosalloc. The brk intervals are applied relative to the start of the heap
+    /// and the anon intervals relative to the largest anonymous mapping of the process. Hugepage
+    /// intervals are collapsed to THPs with process_madvise(MADV_COLLAPSE) (kernel 6.1+), and if a
+    /// NUMA node is given their pages are migrated to it with move_pages (mbind only applies to
+    /// the calling process).
+    Attach {
+        #[clap(value_parser, help = "PID of the running process")]
+        pid: i32,
+        #[clap(long, value_parser = parse_file_path, help = "Brk and anon (mmap) pool intervals configuration (CSV)")]
+        config: String,
+        #[clap(short, long, value_parser = parse_node, help = "Migrate the pool ranges to the given NUMA node")]
+        node: Option<Id>,
+        #[clap(long, value_parser, help = "Don't collapse the pool ranges")]
+        no_collapse: bool,
+    },
+    /// Pool config schema tools.
+    Config {
+        #[clap(subcommand)]
+        cmd: ConfigCmd,
+    },
+    /// Lists the hugetlbfs mounts per page size, or acts as the privileged helper creating and
+    /// removing hugetlbfs mounts for the file-backed hugepage features. New mounts are placed
+    /// under the temp dir and owned by the invoking user (SUDO_UID / SUDO_GID when run through
+    /// sudo).
+    Hugetlbfs {
+        #[clap(long, value_parser = parse_size, help = "Mount a new hugetlbfs instance for the given page size")]
+        mount: Option<usize>,
+        #[clap(
+            long,
+            value_parser,
+            help = "Unmount a hugetlbfs instance created with --mount (mosalloc-hugetlbfs-*)"
+        )]
+        unmount: Option<PathBuf>,
+    },
+    /// Prints the environment run_mosalloc exports for a pool config, with the default options,
+    /// as shell export lines, to run a program under libmosalloc with a manual LD_PRELOAD.
+    Env {
+        #[clap(long, value_parser = parse_file_path, help = "Brk and anon (mmap) pool intervals configuration")]
+        config: String,
+        #[clap(long, value_parser = parse_hook_type, default_value = "preload", help = "hook type (preload, seccomp or hybrid)")]
+        hook_type: HookType,
+        #[clap(short, long, value_parser = parse_file_path, help = "mosalloc library path (default: ./libmosalloc.so)")]
+        lib: Option<String>,
+        #[clap(long, value_parser, default_value = DEFAULT_ENV_PREFIX, help = "Prefix of the libmosalloc config vars")]
+        prefix: String,
+    },
+    /// Creates a fake sysfs / procfs tree with the hugepage, THP, overcommit and NUMA topology
+    /// files, to run the reservation and status logic against without hugepages or root, by
+    /// pointing MOSALLOC_SYSFS_ROOT at it. The counters are plain files, reserving pages only
+    /// updates nr_hugepages. Built with the test-fixtures feature.
+    #[cfg(feature = "test-fixtures")]
+    Fixture {
+        #[clap(value_parser, help = "Root of the tree")]
+        dir: PathBuf,
+        #[clap(long, value_parser, default_value_t = 1, help = "NUMA nodes")]
+        nodes: usize,
+        #[clap(long, value_parser = parse_size, use_value_delimiter = true, default_value = "2MB,1GB", help = "Hugepage sizes, the first one is the default")]
+        sizes: Vec<usize>,
+    },
+    /// Live monitor of the processes running under mosalloc with a control socket (run_mosalloc
+    /// --control-dir). For every region it shows the pool utilization, the hugepage-backed bytes,
+    /// the allocation and free rates and the lock contention, refreshed every interval.
+    Top {
+        #[clap(
+            long,
+            value_parser,
+            help = "Control socket directory (default: the temp dir)"
+        )]
+        dir: Option<PathBuf>,
+        #[clap(
+            short,
+            long,
+            value_parser,
+            default_value_t = 1000,
+            help = "Refresh interval (ms)"
+        )]
+        interval: u64,
+        #[clap(
+            short = 'n',
+            long,
+            value_parser,
+            help = "Exit after the given number of refreshes"
+        )]
+        iterations: Option<usize>,
+        #[clap(value_parser, help = "PIDs to monitor (default: all)")]
+        pids: Vec<i32>,
+    },
+    /// Shows, or changes without restarting it, the runtime policies of a process running with a
+    /// control socket: the reclaim policy, the utilization watermarks (e.g. 50,90 or none), the
+    /// internal allocator mmap threshold and the brk / anon / file size limits (soft[:hard]).
+    /// With --compact it runs an experimental compaction pass first, moving the ranges the
+    /// application registered as movable (mosalloc_register_movable) down with mremap to squeeze
+    /// the free space of the anon pool together, and reports the hugepage-aligned space recovered.
+    /// With --page-size-of it reports the page size backing the given addresses, as planned by
+    /// mosalloc and as observed from the kernel's side (smaps, pagemap), e.g. to check placement.
+    Ctl {
+        #[clap(
+            long,
+            value_parser,
+            help = "Control socket directory (default: the temp dir)"
+        )]
+        dir: Option<PathBuf>,
+        #[clap(
+            long,
+            action,
+            help = "Compact the anon pool, only for applications that registered movable ranges and a move callback"
+        )]
+        compact: bool,
+        #[clap(
+            long,
+            value_parser = parse_addr,
+            help = "Report the page size backing the given address (hex), can be repeated"
+        )]
+        page_size_of: Vec<usize>,
+        #[clap(value_parser, help = "PID of the process")]
+        pid: i32,
+        #[clap(value_parser = parse_policy, help = "Policies to change, as key=value")]
+        set: Vec<(String, String)>,
+    },
+    /// Runs the same deterministic workload (e.g. mosalloc_synth with a fixed seed on a single
+    /// thread) under the preload and the seccomp backends, with ASLR disabled, and diffs their
+    /// traces and the hashes of the allocator layout at exit, flagging the divergences to keep
+    /// the two backends in sync. Exits with 1 if they diverge.
+    BackendDiff {
+        #[clap(long, value_parser = parse_file_path, help = "Brk and anon (mmap) pool intervals configuration")]
+        config: String,
+        #[clap(short, long, value_parser = parse_file_path, help = "mosalloc library path (default: ./libmosalloc.so)")]
+        lib: Option<String>,
+        #[clap(long, value_parser, default_value = DEFAULT_ENV_PREFIX, help = "Prefix of the libmosalloc config vars")]
+        prefix: String,
+        #[clap(
+            long,
+            action,
+            help = "Run without hugepages, the bookkeeping is the same"
+        )]
+        dryrun: bool,
+        #[clap(long, value_parser, default_value_t = 1 << 20, help = "Trace ring buffer size (records), large enough for the whole workload")]
+        trace_size: usize,
+        #[clap(
+            long,
+            value_parser,
+            default_value_t = 20,
+            help = "Differing records to show"
+        )]
+        max_diffs: usize,
+        #[clap(value_parser, required = true, help = "Workload and its arguments")]
+        cmd: Vec<String>,
+    },
+    /// Issues a matrix of mmap, munmap, mprotect, madvise, mremap and brk edge cases (bad flags,
+    /// unaligned addresses, zero and huge lengths and offsets) as raw syscalls, natively and under
+    /// the seccomp backend, and diffs their return values and errnos, so that the seccomp hooks
+    /// return what the kernel would. Exits with 1 if any case differs.
+    Conformance {
+        #[clap(long, value_parser = parse_file_path, required_unless_present = "run-cases", help = "Brk and anon (mmap) pool intervals configuration")]
+        config: Option<String>,
+        #[clap(short, long, value_parser = parse_file_path, help = "mosalloc library path (default: ./libmosalloc.so)")]
+        lib: Option<String>,
+        #[clap(long, value_parser, default_value = DEFAULT_ENV_PREFIX, help = "Prefix of the libmosalloc config vars")]
+        prefix: String,
+        #[clap(
+            long,
+            action,
+            help = "Run without hugepages, the bookkeeping is the same"
+        )]
+        dryrun: bool,
+        // run the cases in this process and write their outcomes to the file, for the children
+        #[clap(long, value_parser, hide = true)]
+        run_cases: Option<PathBuf>,
+    },
+    /// Shows or diffs the reports written by run_mosalloc --report, which combine the hugepage
+    /// reservation, the region layout and stats, the resource usage and the warnings of a run.
+    Report {
+        #[clap(subcommand)]
+        cmd: ReportCmd,
+    },
+    /// Prints the JSON schema of the stats and trace records, generated from their definitions.
+    /// Fields are only ever added to the records, and the readers default the fields missing in
+    /// older records and skip the ones they don't know about.
+    Schema,
+}
+
+// key=value of a runtime policy
+fn parse_policy(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if POLICY_KEYS.contains(&key) => {
+            Ok((key.to_string(), value.to_string()))
+        }
+        Some((key, _)) => Err(format!(
+            "Unknown policy {} (expected one of {})",
+            key,
+            POLICY_KEYS.join(", ")
+        )),
+        None => Err(format!("Invalid policy {} (expected key=value)", s)),
+    }
+}
+
+// single-quote a value for the shell, unless it's made of safe characters only
+fn shell_quote(s: &str) -> String {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:,=+%@".contains(c))
+    {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
+
+// per second rate of a cumulative counter
+fn rate(cur: usize, prev: Option<usize>, secs: f64) -> String {
+    match prev {
+        Some(prev) if secs > 0.0 => format!("{:.0}", cur.saturating_sub(prev) as f64 / secs),
+        _ => "-".to_string(),
+    }
+}
+
+// a single mosalloc top screen
+fn top_frame(stats: &[ProcessStats], prev: &[ProcessStats], secs: f64) -> String {
+    let mut out = format!("mosalloc top - {} processes\n", stats.len());
+
+    for p in stats.iter() {
+        out += &format!(
+            "\npid {}\n{:<6} {:>8} {:>8} {:>6} {:>8} {:>8} {:>8} {:>8} {:>9} {:>9} {:>9} {:>9} \
+             {:>6}\n",
+            p.pid,
+            "REGION",
+            "SIZE",
+            "USED",
+            "USE%",
+            "PEAK",
+            "HIGH",
+            "HUGE",
+            "RSS",
+            "ALLOCS/s",
+            "FREES/s",
+            "BYTES/s",
+            "LOCKS/s",
+            "CONT%"
+        );
+
+        let prev = prev.iter().find(|x| x.pid == p.pid);
+        for r in p.regions.iter() {
+            let last = prev.and_then(|x| {
+                x.regions
+                    .iter()
+                    .find(|y| y.alloc_type == r.alloc_type && y.name == r.name)
+            });
+            let bytes = match last {
+                Some(last) if secs > 0.0 => size_to_str(
+                    (r.bytes_allocated.saturating_sub(last.bytes_allocated) as f64 / secs) as usize,
+                ),
+                _ => "-".to_string(),
+            };
+            let contended = match last {
+                Some(last) if r.lock_acquired > last.lock_acquired => format!(
+                    "{:.1}",
+                    (r.lock_contended - last.lock_contended) as f64 * 100.0
+                        / (r.lock_acquired - last.lock_acquired) as f64
+                ),
+                _ => "-".to_string(),
+            };
+
+            out += &format!(
+                "{:<6} {:>8} {:>8} {:>6.1} {:>8} {:>8} {:>8} {:>8} {:>9} {:>9} {:>9} {:>9} {:>6}\n",
+                r.label(),
+                size_to_str(r.len),
+                size_to_str(r.used),
+                if r.len > 0 {
+                    r.used as f64 * 100.0 / r.len as f64
+                } else {
+                    0.0
+                },
+                size_to_str(r.peak),
+                size_to_str(r.high_water),
+                size_to_str(r.huge),
+                // the hugetlb pages aren't part of the rss
+                size_to_str(r.rss + r.private_hugetlb),
+                rate(r.allocs, last.map(|x| x.allocs), secs),
+                rate(r.frees, last.map(|x| x.frees), secs),
+                bytes,
+                rate(r.lock_acquired, last.map(|x| x.lock_acquired), secs),
+                contended
+            );
+        }
+
+        if let Some(i) = &p.internal {
+            out += &format!(
+                "internal arena {} of {} (peak {}), mmap {}, failed {}, mapped {} (rss {})\n",
+                size_to_str(i.arena_allocated),
+                size_to_str(i.arena_size),
+                size_to_str(i.arena_peak),
+                size_to_str(i.mmap_allocated),
+                i.failed,
+                size_to_str(i.mapped),
+                i.rss.map_or("n/a".to_string(), size_to_str)
+            );
+        }
+    }
+
+    out
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    match &cli.cmd {
+        Cmd::BootConfig { config, node } => {
+            let htlb_req = HTLBReq::from_config(Path::new(config), node.unwrap_or(0));
+            let params = htlb_req.boot_params(node.is_some()).join(" ");
+
+            println!("# kernel cmdline");
+            println!("{}", params);
+            println!();
+            println!("# /etc/default/grub (then run update-grub or grub2-mkconfig and reboot)");
+            println!(
+                "GRUB_CMDLINE_LINUX_DEFAULT=\"$GRUB_CMDLINE_LINUX_DEFAULT {}\"",
+                params
+            );
+        }
+        Cmd::Selftest => {
+            let mut failed = 0;
+
+            for pagesz in supported_htlb_sizes() {
+                for kind in [ProbeKind::ANON, ProbeKind::HUGETLBFS, ProbeKind::MEMFD] {
+                    let res = probe(kind, pagesz);
+                    match res.result {
+                        Ok(()) => println!("{} {}: ok", size_to_str(pagesz), kind.as_str()),
+                        Err(e) => {
+                            failed += 1;
+                            println!("{} {}: failed ({})", size_to_str(pagesz), kind.as_str(), e);
+                            for s in suggestions(&res) {
+                                println!("  hint: {}", s);
+                            }
+                        }
+                    }
+                }
+            }
+
+            if failed > 0 {
+                std::process::exit(1);
+            }
+        }
+        Cmd::Doctor { fix, yes } => {
+            let findings = diagnose();
+            let mut left = 0;
+
+            for x in findings.iter() {
+                println!("leak: {}", x.problem);
+                let Some(cleanup) = x.fix.as_ref().filter(|_| *fix) else {
+                    left += 1;
+                    continue;
+                };
+
+                if !*yes {
+                    print!("{}? [y/N] ", cleanup.describe());
+                    io::stdout().flush().unwrap();
+                    let mut answer = String::new();
+                    io::stdin().lock().read_line(&mut answer).unwrap();
+                    if !answer.trim().eq_ignore_ascii_case("y") {
+                        left += 1;
+                        continue;
+                    }
+                }
+                match cleanup.apply() {
+                    Ok(()) => println!("{}: done", cleanup.describe()),
+                    Err(e) => {
+                        println!("{}: {}", cleanup.describe(), e);
+                        left += 1;
+                    }
+                }
+            }
+
+            if findings.is_empty() {
+                println!("no leaks found");
+            }
+            if left > 0 {
+                std::process::exit(1);
+            }
+        }
+        Cmd::Attach {
+            pid,
+            config,
+            node,
+            no_collapse,
+        } => {
+            let maps = process_maps(*pid).unwrap();
+            let targets = attach_targets(&maps, Path::new(config));
+
+            for (range, pagesz) in targets.iter() {
+                println!(
+                    "0x{:x}-0x{:x} ({} pages)",
+                    range.start,
+                    range.end,
+                    size_to_str(*pagesz)
+                );
+            }
+
+            if !no_collapse {
+                let mut batch = AdviceBatch::remote(pidfd_open(*pid).unwrap(), MADV_COLLAPSE);
+                for (range, _) in targets.iter() {
+                    batch.add(range.start, range.len());
+                }
+
+                let total = targets.iter().map(|(x, _)| x.len()).sum::<usize>();
+                match batch.flush() {
+                    Ok(advised) => println!(
+                        "collapsed {} / {}",
+                        size_to_str(advised),
+                        size_to_str(total)
+                    ),
+                    Err(e) => println!("collapse failed ({})", e),
+                }
+            }
+
+            if let Some(node) = node {
+                for (range, _) in targets.iter() {
+                    match move_to_node(*pid, range, *node) {
+                        Ok(0) => println!("0x{:x}: moved to node {}", range.start, node),
+                        Ok(left) => println!(
+                            "0x{:x}: moved to node {} ({} pages left)",
+                            range.start, node, left
+                        ),
+                        Err(e) => println!("0x{:x}: move failed ({})", range.start, e),
+                    }
+                }
+            }
+        }
+        Cmd::Config { cmd } => {
+            let path = match cmd {
+                ConfigCmd::Migrate { input, .. } => input,
+                ConfigCmd::Check { config, .. } => config,
+            };
+
+            let config = match PoolConfig::from_path(Path::new(path)) {
+                Ok(x) => x,
+                Err(e) => {
+                    println!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+
+            match cmd {
+                ConfigCmd::Migrate { output, .. } => {
+                    fs::write(output, config.to_versioned()).unwrap();
+                    println!(
+                        "{}: migrated {} intervals from version {}",
+                        output,
+                        config.intervals.len(),
+                        config.version
+                    );
+                }
+                ConfigCmd::Check {
+                    anon_ffa_size,
+                    file_ffa_size,
+                    ..
+                } => {
+                    if let Err(e) = config.check_ffa(*anon_ffa_size, *file_ffa_size) {
+                        println!("{}", error_str(Path::new(path), &e));
+                        std::process::exit(1);
+                    }
+                    println!(
+                        "{}: ok (version {}, {} intervals)",
+                        path,
+                        config.version,
+                        config.intervals.len()
+                    );
+                }
+            }
+        }
+        Cmd::Hugetlbfs { mount, unmount } => {
+            if let Some(pagesz) = mount {
+                // the user sudo was invoked by
+                let uid = env::var("SUDO_UID")
+                    .map(|x| Uid::from_raw(x.parse().unwrap()))
+                    .unwrap_or_else(|_| getuid());
+                let gid = env::var("SUDO_GID")
+                    .map(|x| Gid::from_raw(x.parse().unwrap()))
+                    .unwrap_or_else(|_| getgid());
+
+                match mount_private(*pagesz, uid, gid) {
+                    Ok(mnt) => println!("{}", mnt.path.display()),
+                    Err(e) => {
+                        println!("failed to mount hugetlbfs ({})", e);
+                        std::process::exit(1);
+                    }
+                }
+            } else if let Some(path) = unmount {
+                // only the instances mounted by --mount
+                let mnt = match hugetlbfs_mounts()
+                    .into_iter()
+                    .find(|x| &x.path == path && x.private)
+                {
+                    Some(mnt) => mnt,
+                    None => {
+                        println!("{}: not a hugetlbfs mount of mosalloc", path.display());
+                        std::process::exit(1);
+                    }
+                };
+
+                if let Err(e) = mnt.unmount() {
+                    println!("failed to unmount {} ({})", path.display(), e);
+                    std::process::exit(1);
+                }
+            } else {
+                for mnt in hugetlbfs_mounts() {
+                    println!(
+                        "{} {}{}",
+                        size_to_str(mnt.pagesz),
+                        mnt.path.display(),
+                        if mnt.writable() { "" } else { " (read-only)" }
+                    );
+                }
+            }
+        }
+        Cmd::Env {
+            config,
+            hook_type,
+            lib,
+            prefix,
+        } => {
+            if let Err(e) = PoolConfig::from_path(Path::new(config)) {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+
+            // absolute paths, so that the exports work from any directory
+            let abs = |x: &str| {
+                fs::canonicalize(x)
+                    .map(|x| x.to_string_lossy().into_owned())
+                    .unwrap_or_else(|_| x.to_string())
+            };
+
+            let vars = MosallocConfig {
+                pool_config: abs(config),
+                hook: *hook_type,
+                ..Default::default()
+            }
+            .env_vars(prefix);
+            for (var, val) in vars {
+                println!("export {}={}", var, shell_quote(&val));
+            }
+            println!(
+                "export LD_PRELOAD={}",
+                shell_quote(&abs(lib.as_deref().unwrap_or("./libmosalloc.so")))
+            );
+        }
+        #[cfg(feature = "test-fixtures")]
+        Cmd::Fixture { dir, nodes, sizes } => {
+            SysfsFixture::create(dir, *nodes, sizes).unwrap();
+            println!("{}: fixture created", dir.display());
+            println!(
+                "export {}={}",
+                SYSFS_ROOT_VAR,
+                shell_quote(&dir.to_string_lossy())
+            );
+        }
+        Cmd::Top {
+            dir,
+            interval,
+            iterations,
+            pids,
+        } => {
+            let dir = dir.clone().unwrap_or_else(env::temp_dir);
+            let mut prev: Vec<ProcessStats> = Vec::new();
+            let mut last = Instant::now();
+            let mut n = 0;
+
+            loop {
+                // processes started since the last refresh are picked up too
+                let stats = control_sockets(&dir)
+                    .into_iter()
+                    .filter(|(pid, _)| pids.is_empty() || pids.contains(pid))
+                    .filter_map(|(_, path)| query(&path).ok())
+                    .collect::<Vec<ProcessStats>>();
+                let secs = last.elapsed().as_secs_f64();
+                last = Instant::now();
+
+                // clear the screen and move to the top left corner
+                print!("\x1b[2J\x1b[H{}", top_frame(&stats, &prev, secs));
+                prev = stats;
+
+                n += 1;
+                if iterations.is_some_and(|x| n >= x) {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(*interval));
+            }
+        }
+        Cmd::Ctl {
+            dir,
+            compact,
+            page_size_of,
+            pid,
+            set,
+        } => {
+            let dir = dir.clone().unwrap_or_else(env::temp_dir);
+            let path = socket_path(&dir, *pid);
+
+            if *compact {
+                match control::compact(&path) {
+                    Ok(lines) => {
+                        for x in lines.iter() {
+                            println!("compaction: {}", x);
+                        }
+                    }
+                    Err(e) => {
+                        println!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+
+            for &addr in page_size_of.iter() {
+                match control::page_size_of(&path, addr) {
+                    Ok((planned, observed)) => println!(
+                        "0x{:x}: {} planned, {} observed",
+                        addr,
+                        size_to_str(planned),
+                        observed.map_or("unknown (not populated?)".to_string(), size_to_str)
+                    ),
+                    Err(e) => {
+                        println!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
+
+            for (key, value) in set.iter() {
+                if let Err(e) = set_policy(&path, key, value) {
+                    println!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+
+            match policies(&path) {
+                Ok(policies) => {
+                    for (key, value) in policies.iter() {
+                        println!("{:<16} {}", key, value);
+                    }
+                }
+                Err(e) => {
+                    println!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Cmd::BackendDiff {
+            config,
+            lib,
+            prefix,
+            dryrun,
+            trace_size,
+            max_diffs,
+            cmd,
+        } => {
+            if let Err(e) = PoolConfig::from_path(Path::new(config)) {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+
+            let config = MosallocConfig {
+                pool_config: config.clone(),
+                dryrun: *dryrun,
+                trace_size: *trace_size,
+                ..Default::default()
+            };
+            let lib = lib.as_deref().unwrap_or("./libmosalloc.so");
+
+            let runs = DIFF_BACKENDS
+                .iter()
+                .map(|&hook| run_backend(&config, hook, prefix, lib, cmd))
+                .collect::<Result<Vec<_>, String>>()
+                .unwrap_or_else(|e| {
+                    println!("{}", e);
+                    std::process::exit(1);
+                });
+            for x in runs.iter() {
+                println!(
+                    "{}: exit {:?}, {} records, layout {:016x}",
+                    x.hook.as_str(),
+                    x.status,
+                    x.trace.len(),
+                    x.layout
+                );
+            }
+
+            let diffs = diff_runs(&runs[0], &runs[1], *max_diffs);
+            if diffs.is_empty() {
+                println!("the backends agree");
+            } else {
+                for x in diffs.iter() {
+                    println!("{}", x);
+                }
+                std::process::exit(1);
+            }
+        }
+        Cmd::Conformance {
+            config,
+            lib,
+            prefix,
+            dryrun,
+            run_cases,
+        } => {
+            if let Some(path) = run_cases {
+                if let Err(e) = write_cases(path) {
+                    println!("{}", e);
+                    std::process::exit(1);
+                }
+                return;
+            }
+
+            let config = config.as_ref().unwrap();
+            if let Err(e) = PoolConfig::from_path(Path::new(config)) {
+                println!("{}", e);
+                std::process::exit(1);
+            }
+
+            let config = MosallocConfig {
+                pool_config: config.clone(),
+                dryrun: *dryrun,
+                ..Default::default()
+            };
+            let lib = lib.as_deref().unwrap_or("./libmosalloc.so");
+            let args = ["conformance", "--run-cases"];
+
+            let suites = [None, Some((&config, HookType::SECCOMP))]
+                .into_iter()
+                .map(|x| run_suite(x, prefix, lib, &args))
+                .collect::<Result<Vec<_>, String>>()
+                .unwrap_or_else(|e| {
+                    println!("{}", e);
+                    std::process::exit(1);
+                });
+
+            let diffs = diff_suites(&suites[0], &suites[1]);
+            println!(
+                "{} cases, {} differ from the native ones",
+                CASES.len(),
+                diffs.len()
+            );
+            for x in diffs.iter() {
+                println!("{}", x);
+            }
+            if !diffs.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Cmd::Report { cmd } => {
+            let load = |path: &String| {
+                Report::from_path(Path::new(path)).unwrap_or_else(|e| {
+                    println!("{}", e);
+                    std::process::exit(1);
+                })
+            };
+            let lines = match cmd {
+                ReportCmd::Show { report } => load(report).show(),
+                ReportCmd::Diff { a, b } => {
+                    let diffs = diff_reports(&load(a), &load(b));
+                    if diffs.is_empty() {
+                        vec!["the reports agree".to_string()]
+                    } else {
+                        diffs
+                    }
+                }
+            };
+            for x in lines.iter() {
+                println!("{}", x);
+            }
+        }
+        Cmd::Schema => print!("{}", json_schema()),
+    }
+}
@@ -0,0 +1,231 @@
+use std::fs;
+use std::io::{self, Read};
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+
+use mosalloc::utils::control::ProcessStats;
+use mosalloc::utils::htlb::AllocType;
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about)]
+struct Cli {
+    #[clap(
+        short,
+        long,
+        value_parser,
+        help = "Address to listen on (unix:<path> or <host>:<port>)"
+    )]
+    listen: String,
+
+    #[clap(short, long, value_parser, help = "Merged job report (CSV)")]
+    output: String,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Exit once the given number of processes sent their final stats"
+    )]
+    expect: Option<usize>,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 5000,
+        help = "Rewrite the report every given ms"
+    )]
+    report_period: u64,
+}
+
+enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl Listener {
+    fn bind(addr: &str) -> io::Result<Self> {
+        match addr.strip_prefix("unix:") {
+            Some(path) => {
+                let _ = fs::remove_file(path);
+                UnixListener::bind(path).map(Listener::Unix)
+            }
+            None => TcpListener::bind(addr).map(Listener::Tcp),
+        }
+    }
+
+    // the next pushed message, a slow sender can't stall the collector for more than a second
+    fn next(&self) -> io::Result<String> {
+        let timeout = Some(Duration::from_secs(1));
+        let mut content = String::new();
+
+        match self {
+            Listener::Unix(x) => {
+                let (mut stream, _) = x.accept()?;
+                stream.set_read_timeout(timeout)?;
+                stream.read_to_string(&mut content)?;
+            }
+            Listener::Tcp(x) => {
+                let (mut stream, _) = x.accept()?;
+                stream.set_read_timeout(timeout)?;
+                stream.read_to_string(&mut content)?;
+            }
+        }
+
+        Ok(content)
+    }
+}
+
+// per process and region rows, followed by the per region totals over the job
+fn job_report(stats: &[ProcessStats]) -> String {
+    let mut jobs = stats
+        .iter()
+        .filter_map(|x| x.job.as_deref())
+        .collect::<Vec<&str>>();
+    jobs.sort();
+    jobs.dedup();
+    let mut hosts = stats.iter().map(|x| x.host.as_str()).collect::<Vec<&str>>();
+    hosts.sort();
+    hosts.dedup();
+
+    let mut out = format!(
+        "# mosalloc job report\n# job {}, {} processes on {} hosts, {} finished\n",
+        if jobs.is_empty() {
+            "-".to_string()
+        } else {
+            jobs.join(" ")
+        },
+        stats.len(),
+        hosts.len(),
+        stats.iter().filter(|x| x.last).count()
+    );
+    out += "host,pid,rank,region,size,allocated,peak,huge,allocs,frees,bytes_allocated,\
+            lock_acquired,lock_contended,used,high_water,brk,finished\n";
+
+    for p in stats.iter() {
+        for r in p.regions.iter() {
+            out += &format!(
+                "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}\n",
+                p.host,
+                p.pid,
+                p.rank.map(|x| x.to_string()).unwrap_or_default(),
+                r.label(),
+                r.len,
+                r.allocated,
+                r.peak,
+                r.huge,
+                r.allocs,
+                r.frees,
+                r.bytes_allocated,
+                r.lock_acquired,
+                r.lock_contended,
+                r.used,
+                r.high_water,
+                r.brk,
+                p.last
+            );
+        }
+    }
+
+    // the peaks are summed, processes may peak at different times, the named anon regions are
+    // counted along with the default one
+    for alloc_type in [AllocType::BRK, AllocType::ANON, AllocType::FILE] {
+        let regions = stats
+            .iter()
+            .flat_map(|x| x.regions.iter())
+            .filter(|x| x.alloc_type == alloc_type);
+        let total = regions.fold([0usize; 12], |mut acc, r| {
+            for (a, x) in acc.iter_mut().zip([
+                r.len,
+                r.allocated,
+                r.peak,
+                r.huge,
+                r.allocs,
+                r.frees,
+                r.bytes_allocated,
+                r.lock_acquired,
+                r.lock_contended,
+                r.used,
+                r.high_water,
+                r.brk,
+            ]) {
+                *a += x;
+            }
+            acc
+        });
+
+        out += &format!(
+            "total,,,{},{},{}\n",
+            alloc_type.as_str(),
+            total
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<String>>()
+                .join(","),
+            stats.iter().filter(|x| x.last).count()
+        );
+    }
+
+    out
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let listener = Listener::bind(&cli.listen).unwrap();
+    println!("collector: listening on {}", cli.listen);
+
+    let mut stats: Vec<ProcessStats> = Vec::new();
+    let mut last_report = Instant::now();
+
+    loop {
+        let msg = match listener.next() {
+            Ok(x) => x,
+            Err(e) => {
+                println!("collector: {}", e);
+                continue;
+            }
+        };
+        let msg = match ProcessStats::from_text(&msg) {
+            Ok(x) => x,
+            Err(e) => {
+                println!("collector: {}", e);
+                continue;
+            }
+        };
+
+        let last = msg.last;
+        match stats
+            .iter_mut()
+            .find(|x| x.host == msg.host && x.pid == msg.pid)
+        {
+            // a periodic push racing with the final one
+            Some(x) if x.last => {}
+            Some(x) => *x = msg,
+            None => {
+                stats.push(msg);
+                stats.sort_by(|a, b| (a.rank, &a.host, a.pid).cmp(&(b.rank, &b.host, b.pid)));
+            }
+        }
+
+        let finished = stats.iter().filter(|x| x.last).count();
+        let done = cli.expect.is_some_and(|x| finished >= x);
+
+        if last || done || last_report.elapsed() >= Duration::from_millis(cli.report_period) {
+            fs::write(&cli.output, job_report(&stats)).unwrap();
+            last_report = Instant::now();
+        }
+
+        if done {
+            println!(
+                "collector: {} processes finished, report in {}",
+                finished, cli.output
+            );
+            if let Some(path) = cli.listen.strip_prefix("unix:") {
+                let _ = fs::remove_file(path);
+            }
+            break;
+        }
+    }
+}
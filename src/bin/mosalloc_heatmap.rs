@@ -0,0 +1,39 @@
+use std::path::Path;
+
+use clap::Parser;
+
+use mosalloc::utils::argparse::parse_file_path;
+use mosalloc::utils::heatmap::heatmap_from_path;
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about)]
+struct Cli {
+    #[clap(
+        short,
+        long,
+        value_parser,
+        default_value_t = 80,
+        help = "Columns (hugepage buckets) per interval"
+    )]
+    width: usize,
+
+    #[clap(
+        short,
+        long,
+        value_parser,
+        default_value_t = 20,
+        help = "Rows (time buckets) per interval"
+    )]
+    rows: usize,
+
+    #[clap(value_parser = parse_file_path, help = "Heatmap file exported by libmosalloc")]
+    heatmap: String,
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    for interval in heatmap_from_path(Path::new(&cli.heatmap)).unwrap() {
+        println!("{}", interval.render(cli.width, cli.rows));
+    }
+}
@@ -0,0 +1,596 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::ptr::null_mut;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+
+use clap::Parser;
+use nix::libc;
+
+use mosalloc::utils::argparse::{parse_file_path, parse_size, parse_trace_point};
+use mosalloc::utils::control::{ProcessStats, RegionStats};
+use mosalloc::utils::htlb::PAGE_SIZE;
+use mosalloc::utils::misc::{align_up, size_to_str};
+use mosalloc::utils::trace::{trace_from_path, TraceOp, TraceRecord};
+use mosalloc::utils::trace_state::{TracePoint, TraceState};
+
+// allocator paths a synthetic op goes through
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum Kind {
+    MALLOC,
+    MMAP,
+    MREMAP,
+    MPROTECT,
+    MADVISE,
+    BRK,
+}
+
+const KINDS: [Kind; 6] = [
+    Kind::MALLOC,
+    Kind::MMAP,
+    Kind::MREMAP,
+    Kind::MPROTECT,
+    Kind::MADVISE,
+    Kind::BRK,
+];
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Kind::MALLOC => "malloc",
+            Kind::MMAP => "mmap",
+            Kind::MREMAP => "mremap",
+            Kind::MPROTECT => "mprotect",
+            Kind::MADVISE => "madvise",
+            Kind::BRK => "brk",
+        }
+    }
+}
+
+// weight of each op kind, in the order of KINDS (e.g. malloc:60,mmap:20,brk:20)
+fn parse_mix(s: &str) -> Result<[usize; KINDS.len()], String> {
+    let mut mix = [0; KINDS.len()];
+
+    for x in s.split(',') {
+        let (kind, weight) = x
+            .split_once(':')
+            .ok_or_else(|| format!("Invalid mix {} (expected <op>:<weight>)", x))?;
+        let i = KINDS
+            .iter()
+            .position(|k| k.as_str() == kind)
+            .ok_or_else(|| format!("Unknown op: {}", kind))?;
+        mix[i] = weight
+            .parse::<usize>()
+            .map_err(|_| format!("Invalid weight {}", weight))?;
+    }
+
+    if mix.iter().all(|x| *x == 0) {
+        return Err("Empty mix".to_string());
+    }
+    Ok(mix)
+}
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about)]
+struct Cli {
+    #[clap(
+        short,
+        long,
+        value_parser,
+        default_value_t = 1,
+        help = "Worker threads"
+    )]
+    threads: usize,
+
+    #[clap(
+        short,
+        long,
+        value_parser,
+        default_value_t = 100000,
+        help = "Operations per thread"
+    )]
+    ops: usize,
+
+    #[clap(long, value_parser = parse_size, default_value = "16", help = "Smallest allocation size")]
+    min_size: usize,
+
+    #[clap(long, value_parser = parse_size, default_value = "1MB", help = "Largest allocation size, the sizes are log-uniformly distributed in between")]
+    max_size: usize,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 1024,
+        help = "Live allocations per thread, an allocation lives for this many allocations on average"
+    )]
+    live: usize,
+
+    #[clap(long, value_parser = parse_mix, default_value = "malloc:60,mmap:20,mremap:5,mprotect:5,madvise:5,brk:5", help = "Relative weights of the exercised paths (malloc, mmap, mremap, mprotect, madvise, brk)")]
+    mix: [usize; KINDS.len()],
+
+    #[clap(long, action, help = "Write to every page of the allocations")]
+    touch: bool,
+
+    #[clap(long, value_parser, default_value_t = 1, help = "Random seed")]
+    seed: u64,
+
+    #[clap(long, value_parser = parse_file_path, help = "Replay the calls of a libmosalloc trace instead, in sequence order on a single thread")]
+    replay: Option<String>,
+
+    #[clap(long, value_parser = parse_trace_point, requires = "replay", help = "Print the allocator state rebuilt from the trace at these points (seq:<n> or time:<seconds>) instead of replaying it")]
+    at: Vec<TracePoint>,
+
+    #[clap(
+        long,
+        action,
+        requires = "replay",
+        help = "Browse the allocator state rebuilt from the trace, reading the points to print from stdin"
+    )]
+    interactive: bool,
+
+    #[clap(long, value_parser = parse_file_path, help = "Stats file (MOSALLOC_STATS_FILE) of the traced run, for the layout of its regions and their free maps")]
+    stats: Option<String>,
+
+    #[clap(long, action, help = "List the live mappings along with the free maps")]
+    verbose: bool,
+}
+
+// xorshift64*, deterministic for a given seed
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    // log-uniform in [min, max]
+    fn size(&mut self, min: usize, max: usize) -> usize {
+        let (lo, hi) = ((min as f64).ln(), (max as f64).ln());
+        let x = lo + (hi - lo) * (self.next() as f64 / u64::MAX as f64);
+        (x.exp() as usize).clamp(min, max)
+    }
+
+    fn kind(&mut self, mix: &[usize; KINDS.len()]) -> Kind {
+        let mut x = self.below(mix.iter().sum());
+        for (kind, weight) in KINDS.iter().zip(mix.iter()) {
+            if x < *weight {
+                return *kind;
+            }
+            x -= weight;
+        }
+        unreachable!()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Alloc {
+    Malloc(usize),
+    Mmap(usize, usize, i32),
+}
+
+// per op kind counters
+#[derive(Debug, Default, Clone, Copy)]
+struct Stats {
+    ops: [usize; KINDS.len()],
+    failed: [usize; KINDS.len()],
+    bytes: usize,
+}
+
+impl Stats {
+    fn add(&mut self, other: &Stats) {
+        for i in 0..KINDS.len() {
+            self.ops[i] += other.ops[i];
+            self.failed[i] += other.failed[i];
+        }
+        self.bytes += other.bytes;
+    }
+
+    fn count(&mut self, kind: Kind, ok: bool) {
+        let i = KINDS.iter().position(|x| *x == kind).unwrap();
+        self.ops[i] += 1;
+        if !ok {
+            self.failed[i] += 1;
+        }
+    }
+}
+
+// (end, len) of the program break increments of the workers, brk is process wide and glibc grows
+// it too, so an increment is only given back if the break is still at its end
+static BRK: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+
+unsafe fn touch(addr: usize, len: usize) {
+    for off in (0..len).step_by(*PAGE_SIZE) {
+        *((addr + off) as *mut u8) = 1;
+    }
+}
+
+unsafe fn release(alloc: Alloc) {
+    match alloc {
+        Alloc::Malloc(addr) => libc::free(addr as *mut libc::c_void),
+        Alloc::Mmap(addr, len, _) => {
+            libc::munmap(addr as *mut libc::c_void, len);
+        }
+    }
+}
+
+unsafe fn brk_op(rng: &mut Rng, cli: &Cli, stats: &mut Stats) {
+    let mut grown = BRK.lock().unwrap();
+
+    // grow or give back the last increment, evenly
+    if grown.is_empty() || rng.below(2) == 0 {
+        let len = align_up(rng.size(cli.min_size, cli.max_size), *PAGE_SIZE);
+        let prev = libc::sbrk(len as libc::intptr_t);
+        let ok = prev as isize != -1;
+        if ok {
+            if cli.touch {
+                touch(prev as usize, len);
+            }
+            grown.push((prev as usize + len, len));
+            stats.bytes += len;
+        }
+        stats.count(Kind::BRK, ok);
+    } else {
+        let (end, len) = grown.pop().unwrap();
+        // someone else grew the heap past the increments, leave them
+        if libc::sbrk(0) as usize != end {
+            grown.clear();
+            return;
+        }
+        let ok = libc::sbrk(-(len as libc::intptr_t)) as isize != -1;
+        stats.count(Kind::BRK, ok);
+    }
+}
+
+fn worker(id: usize, cli: &Cli) -> Stats {
+    let mut rng = Rng::new(cli.seed ^ (id as u64).wrapping_mul(0x9e3779b97f4a7c15));
+    let mut slots: Vec<Option<Alloc>> = vec![None; cli.live.max(1)];
+    let mut stats = Stats::default();
+    let rw = libc::PROT_READ | libc::PROT_WRITE;
+
+    for _ in 0..cli.ops {
+        let kind = rng.kind(&cli.mix);
+        let slot = rng.below(slots.len());
+
+        unsafe {
+            match (kind, slots[slot]) {
+                (Kind::MALLOC, _) => {
+                    if let Some(x) = slots[slot].take() {
+                        release(x);
+                    }
+                    let len = rng.size(cli.min_size, cli.max_size);
+                    let p = libc::malloc(len) as usize;
+                    if p != 0 {
+                        if cli.touch {
+                            touch(p, len);
+                        }
+                        slots[slot] = Some(Alloc::Malloc(p));
+                        stats.bytes += len;
+                    }
+                    stats.count(kind, p != 0);
+                }
+                (Kind::MMAP, _) => {
+                    if let Some(x) = slots[slot].take() {
+                        release(x);
+                    }
+                    let len = align_up(rng.size(cli.min_size, cli.max_size), *PAGE_SIZE);
+                    let p = libc::mmap(
+                        null_mut(),
+                        len,
+                        rw,
+                        libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+                        -1,
+                        0,
+                    );
+                    let ok = p != libc::MAP_FAILED;
+                    if ok {
+                        if cli.touch {
+                            touch(p as usize, len);
+                        }
+                        slots[slot] = Some(Alloc::Mmap(p as usize, len, rw));
+                        stats.bytes += len;
+                    }
+                    stats.count(kind, ok);
+                }
+                (Kind::MREMAP, Some(Alloc::Mmap(addr, len, prot))) => {
+                    let new_len = align_up(rng.size(cli.min_size, cli.max_size), *PAGE_SIZE);
+                    let p = libc::mremap(
+                        addr as *mut libc::c_void,
+                        len,
+                        new_len,
+                        libc::MREMAP_MAYMOVE,
+                    );
+                    let ok = p != libc::MAP_FAILED;
+                    if ok {
+                        if cli.touch && prot == rw {
+                            touch(p as usize, new_len);
+                        }
+                        slots[slot] = Some(Alloc::Mmap(p as usize, new_len, prot));
+                    }
+                    stats.count(kind, ok);
+                }
+                // toggle between read-only and read-write
+                (Kind::MPROTECT, Some(Alloc::Mmap(addr, len, prot))) => {
+                    let prot = if prot == rw { libc::PROT_READ } else { rw };
+                    let ok = libc::mprotect(addr as *mut libc::c_void, len, prot) == 0;
+                    if ok {
+                        slots[slot] = Some(Alloc::Mmap(addr, len, prot));
+                    }
+                    stats.count(kind, ok);
+                }
+                (Kind::MADVISE, Some(Alloc::Mmap(addr, len, _))) => {
+                    let ok =
+                        libc::madvise(addr as *mut libc::c_void, len, libc::MADV_DONTNEED) == 0;
+                    stats.count(kind, ok);
+                }
+                (Kind::BRK, _) => brk_op(&mut rng, cli, &mut stats),
+                // the slot doesn't hold a mapping to operate on
+                _ => {}
+            }
+        }
+    }
+
+    for x in slots.into_iter().flatten() {
+        unsafe { release(x) };
+    }
+
+    stats
+}
+
+// the records of a trace, in sequence order
+fn trace_records(path: &str) -> Vec<TraceRecord> {
+    let (_, mut records) = trace_from_path(Path::new(path)).unwrap_or_else(|e| {
+        println!("{}: {}", path, e);
+        std::process::exit(1);
+    });
+    records.sort_by_key(|x| x.seq);
+    records
+}
+
+// the regions of the traced run, from its stats file
+fn traced_regions(path: &Option<String>) -> Vec<RegionStats> {
+    let Some(path) = path else {
+        return Vec::new();
+    };
+    fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|x| ProcessStats::from_text(&x))
+        .map(|x| x.regions)
+        .unwrap_or_else(|e| {
+            println!("{}: {}", path, e);
+            std::process::exit(1);
+        })
+}
+
+// print the allocator state at each point of the trace, from --at or, interactively, stdin: a
+// point (seq:<n> or time:<seconds>), +<n> to step n records forward, or q to quit
+fn inspect(path: &str, cli: &Cli) {
+    let records = trace_records(path);
+    let regions = traced_regions(&cli.stats);
+    if regions.is_empty() {
+        println!("no stats file, the free maps aren't known");
+    }
+    println!(
+        "{} records, seq {}-{}, {:.3}s",
+        records.len(),
+        records.first().map_or(0, |x| x.seq),
+        records.last().map_or(0, |x| x.seq),
+        records.last().map_or(0.0, |x| x.time as f64 / 1e9)
+    );
+
+    let mut state = TraceState::new(&regions);
+    let show = |point: TracePoint, state: &mut TraceState| {
+        let past = match point {
+            TracePoint::SEQ(seq) => seq < state.seq,
+            TracePoint::TIME(ns) => ns < state.time,
+        };
+        // going back in time replays from the start
+        if past {
+            *state = TraceState::new(&regions);
+        }
+        state.advance(&records, point);
+        print!("{}", state.as_string(cli.verbose));
+    };
+
+    for point in cli.at.iter() {
+        show(*point, &mut state);
+    }
+    if !cli.interactive {
+        return;
+    }
+
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+
+        let line = line.trim();
+        let point = match line {
+            "" => continue,
+            "q" | "quit" => break,
+            _ => match line.strip_prefix('+') {
+                Some(n) => n
+                    .parse::<u64>()
+                    .map(|n| {
+                        let idx = records.partition_point(|x| x.seq <= state.seq);
+                        let seq = records
+                            .get((idx + n as usize).saturating_sub(1))
+                            .or(records.last())
+                            .map_or(0, |x| x.seq);
+                        TracePoint::SEQ(seq)
+                    })
+                    .map_err(|_| format!("Invalid step: {}", line)),
+                None => line.parse::<TracePoint>(),
+            },
+        };
+        match point {
+            Ok(point) => show(point, &mut state),
+            Err(e) => println!("{}", e),
+        }
+    }
+}
+
+// replays the calls of a trace, the traced addresses are translated to the ones of the replay
+fn replay(path: &str) -> Stats {
+    let records = trace_records(path);
+
+    // traced start -> (replayed start, len)
+    let mut maps: BTreeMap<u64, (usize, usize)> = BTreeMap::new();
+    let translate = |maps: &BTreeMap<u64, (usize, usize)>, addr: u64| {
+        maps.range(..=addr)
+            .next_back()
+            .filter(|(start, (_, len))| addr < **start + *len as u64)
+            .map(|(start, (new, _))| new + (addr - start) as usize)
+    };
+    let mut traced_brk = None;
+    let mut stats = Stats::default();
+    let failed = libc::MAP_FAILED as u64;
+
+    for r in records.iter() {
+        let op = match TraceOp::from_u32(r.op) {
+            Some(x) => x,
+            None => continue,
+        };
+
+        unsafe {
+            match op {
+                TraceOp::MMAP if r.ret != failed => {
+                    let flags = (r.arg2 as i32 & !(libc::MAP_FIXED | libc::MAP_FIXED_NOREPLACE))
+                        | libc::MAP_ANONYMOUS;
+                    let p = libc::mmap(null_mut(), r.len as usize, r.arg as i32, flags, -1, 0);
+                    let ok = p != libc::MAP_FAILED;
+                    if ok {
+                        maps.insert(r.ret, (p as usize, r.len as usize));
+                        stats.bytes += r.len as usize;
+                    }
+                    stats.count(Kind::MMAP, ok);
+                }
+                TraceOp::MUNMAP => {
+                    if let Some(addr) = translate(&maps, r.addr) {
+                        libc::munmap(addr as *mut libc::c_void, r.len as usize);
+                        maps.remove(&r.addr);
+                    }
+                }
+                TraceOp::MPROTECT => {
+                    if let Some(addr) = translate(&maps, r.addr) {
+                        let ok =
+                            libc::mprotect(addr as *mut libc::c_void, r.len as usize, r.arg as i32)
+                                == 0;
+                        stats.count(Kind::MPROTECT, ok);
+                    }
+                }
+                TraceOp::MADVISE => {
+                    if let Some(addr) = translate(&maps, r.addr) {
+                        let ok =
+                            libc::madvise(addr as *mut libc::c_void, r.len as usize, r.arg as i32)
+                                == 0;
+                        stats.count(Kind::MADVISE, ok);
+                    }
+                }
+                TraceOp::MREMAP if r.ret != failed => {
+                    if let Some(addr) = translate(&maps, r.addr) {
+                        let p = libc::mremap(
+                            addr as *mut libc::c_void,
+                            r.len as usize,
+                            r.arg as usize,
+                            libc::MREMAP_MAYMOVE,
+                        );
+                        let ok = p != libc::MAP_FAILED;
+                        if ok {
+                            maps.remove(&r.addr);
+                            maps.insert(r.ret, (p as usize, r.arg as usize));
+                        }
+                        stats.count(Kind::MREMAP, ok);
+                    }
+                }
+                // sbrk records hold the increment, brk ones the requested break, which is
+                // replayed as the increment over the last traced break
+                TraceOp::BRK => {
+                    let delta = if r.arg == 1 {
+                        Some(r.addr as i64)
+                    } else {
+                        traced_brk.map(|x: u64| r.addr as i64 - x as i64)
+                    };
+                    traced_brk = Some(r.ret);
+                    if let Some(delta) = delta.filter(|x| *x != 0) {
+                        let ok = libc::sbrk(delta as libc::intptr_t) as isize != -1;
+                        stats.count(Kind::BRK, ok);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    stats
+}
+
+fn main() {
+    let cli = Cli::parse();
+    assert!(
+        cli.min_size > 0 && cli.min_size <= cli.max_size,
+        "invalid size range"
+    );
+
+    if let Some(path) = &cli.replay {
+        if !cli.at.is_empty() || cli.interactive {
+            inspect(path, &cli);
+            return;
+        }
+    }
+
+    let start = Instant::now();
+    let mut stats = Stats::default();
+    match &cli.replay {
+        Some(path) => stats = replay(path),
+        None => thread::scope(|s| {
+            let workers = (0..cli.threads)
+                .map(|i| {
+                    let cli = &cli;
+                    s.spawn(move || worker(i, cli))
+                })
+                .collect::<Vec<_>>();
+            for w in workers {
+                stats.add(&w.join().unwrap());
+            }
+        }),
+    }
+    let elapsed = start.elapsed();
+
+    let total = stats.ops.iter().sum::<usize>();
+    println!(
+        "{} ops in {:?} ({:.0} ops/s), {} allocated",
+        total,
+        elapsed,
+        total as f64 / elapsed.as_secs_f64(),
+        size_to_str(stats.bytes)
+    );
+    for (i, kind) in KINDS.iter().enumerate() {
+        if stats.ops[i] > 0 {
+            println!(
+                "{}: {} ops, {} failed",
+                kind.as_str(),
+                stats.ops[i],
+                stats.failed[i]
+            );
+        }
+    }
+}
@@ -1,8 +1,13 @@
-use clap::{Parser, Subcommand};
+use clap::{ArgGroup, Args, Parser, Subcommand};
 
-use mosalloc::utils::argparse::{default_node, parse_htlb_req, parse_node};
-use mosalloc::utils::htlb::{self, HTLBReq};
+use mosalloc::utils::argparse::{
+    default_node, parse_fit_config, parse_htlb_bytes, parse_htlb_req, parse_node,
+    parse_reserve_strategy,
+};
+use mosalloc::utils::htlb::{self, HTLBReq, ReserveStrategy};
+use mosalloc::utils::misc::size_to_str;
 use mosalloc::utils::rangelist::{Id, RangeList};
+use mosalloc::utils::session::{state_file, StateFile};
 use mosalloc::utils::sysfs_path::*;
 
 #[derive(Parser)]
@@ -12,6 +17,38 @@ struct Cli {
     cmd: Cmd,
 }
 
+// a HugeTLB request, as raw page counts, as byte budgets or covering the pools of a config
+#[derive(Args)]
+#[clap(group(ArgGroup::new("request").required(true).args(&["htlb-req", "bytes", "fit-config"])))]
+struct ReqArgs {
+    #[clap(value_parser = parse_htlb_req, help = "Requested HTLB pages (i:j:k:...)")]
+    htlb_req: Option<HTLBReq>,
+    #[clap(long, value_parser = parse_htlb_bytes, help = "Requested HTLB pages per page size, as byte budgets rounded up to whole pages or as page counts (e.g. 2MB=20GB,1GB=8)")]
+    bytes: Option<HTLBReq>,
+    #[clap(long, value_parser = parse_fit_config, help = "Request the HTLB pages the brk and anon pools of a config need")]
+    fit_config: Option<HTLBReq>,
+}
+
+impl ReqArgs {
+    fn req(&self) -> HTLBReq {
+        let req = [&self.htlb_req, &self.bytes, &self.fit_config]
+            .into_iter()
+            .find_map(|x| x.clone())
+            .unwrap();
+
+        println!(
+            "request: {}",
+            htlb::supported_htlb_sizes()
+                .iter()
+                .zip(req.req.iter())
+                .map(|(&sz, nr)| format!("{} {} pages", nr, size_to_str(sz)))
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+        req
+    }
+}
+
 #[derive(Subcommand)]
 enum Cmd {
     /// Takes a HugeTLB allocation request in the form of i:j:k:... Each of the i, j, k, etc)
@@ -21,16 +58,49 @@ enum Cmd {
     /// could be e.g. '20:10:0:1', for 20 64KB pages, 10 2MB pages, 0 1GB pages and 1 16GB page on
     /// an ARMv8 machine using a 4KB granule. Each size always correspond to the same index, and
     /// missing sizes are ignored, so that '20:10' is the same to '20:10:0:0'. Optionally, the NUMA
-    /// node on which the allocation is supposed to happen is provided.
+    /// node on which the allocation is supposed to happen is provided. With the overcommit
+    /// strategy, the request sets the system-wide surplus limits instead, so that pages are
+    /// allocated on demand and returned to the system when freed. Instead of the raw page counts,
+    /// the request can be given in bytes per page size (--bytes 2MB=20GB,1GB=8, a size without a
+    /// suffix being a page count), or as the pages a pool config needs (--fit-config). With
+    /// --allow-conversion, the pages that can't be reserved are covered by the other sizes:
+    /// excess free larger pages are demoted (kernel 5.16+), and larger pages still missing are
+    /// reallocated as smaller ones, each conversion being reported.
     Reserve {
         #[clap(short, long, value_parser = parse_node, default_value_t = default_node(), hide_default_value = true, help = "NUMA node (default: local)")]
         node: Id,
-        #[clap(value_parser = parse_htlb_req, help = "Requested HTLB pages")]
-        htlb_req: HTLBReq,
+        #[clap(short, long, value_parser = parse_reserve_strategy, default_value = "static", help = "Reservation strategy (static or overcommit)")]
+        strategy: ReserveStrategy,
+        #[clap(
+            long,
+            action,
+            help = "Cover the pages that can't be reserved with free pages of the other sizes, demoting larger pages or reallocating missing larger pages as smaller ones"
+        )]
+        allow_conversion: bool,
+        #[clap(flatten)]
+        req: ReqArgs,
+    },
+    /// Takes a HugeTLB allocation request in the same form as the reserve command, and before
+    /// reserving, demotes excess free pages of larger sizes (e.g. 1GB to 2MB) to cover sizes that
+    /// can't be satisfied by the free pages. Requires kernel 5.16+ for the demote interface.
+    Rebalance {
+        #[clap(short, long, value_parser = parse_node, default_value_t = default_node(), hide_default_value = true, help = "NUMA node (default: local)")]
+        node: Id,
+        #[clap(flatten)]
+        req: ReqArgs,
     },
     /// Prints the current configuration of the HugeTLB pages on the system and lists the supported
     /// sizes and a HugeTLB request template  for the reserve command.
     Status,
+    /// Lists the reservations kept across runs by the run_mosalloc sessions, from the
+    /// reservations state file (MOSALLOC_STATE_FILE, default /run/mosalloc/reservations).
+    Sessions,
+    /// Releases the reservation of a run_mosalloc session, restoring the hugepage pool of its node
+    /// (reserved pages and overcommit limits) to its state before the first run of the session.
+    Release {
+        #[clap(value_parser, help = "Session name")]
+        session: String,
+    },
 }
 
 fn main() {
@@ -42,16 +112,91 @@ fn main() {
             RangeList::from_path(sysfs_path_online_nodes())
                 .iter()
                 .for_each(|n| htlb::print_htlb_status_node(n));
+            htlb::print_htlb_overcommit_status();
         }
-        Cmd::Reserve { node, htlb_req } => {
+        Cmd::Reserve {
+            node,
+            strategy,
+            allow_conversion,
+            req,
+        } => {
+            let mut htlb_req = req.req();
             htlb_req.node = *node;
+            htlb_req.strategy = *strategy;
 
             htlb::print_htlb_status_node(*node);
 
             htlb::disable_thp(true);
             htlb::enable_overcommit(true);
 
+            let res = if *allow_conversion {
+                htlb_req.reserve_converting()
+            } else {
+                htlb_req.reserve_pages().map(|_| Vec::new())
+            };
+            match res {
+                Ok(conversions) => conversions
+                    .iter()
+                    .for_each(|x| println!("{}", x.describe())),
+                Err(e) => {
+                    println!("{}", e);
+                    htlb::print_htlb_status_node(*node);
+                    std::process::exit(1);
+                }
+            }
+
+            if *strategy == ReserveStrategy::OVERCOMMIT {
+                htlb::print_htlb_overcommit_status();
+            }
+        }
+        Cmd::Sessions => {
+            let path = state_file();
+            let state = StateFile::open(&path).unwrap();
+            let sizes = htlb::supported_htlb_sizes();
+
+            println!("{}: {} session(s)", path.display(), state.sessions.len());
+            for s in state.sessions.iter() {
+                println!(
+                    "{}: node {}, {} run(s), {}",
+                    s.name,
+                    s.saved.node,
+                    s.runs,
+                    sizes
+                        .iter()
+                        .zip(s.pages.iter())
+                        .map(|(&sz, nr)| format!("{} {} pages", nr, size_to_str(sz)))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                );
+            }
+        }
+        Cmd::Release { session } => {
+            let mut state = StateFile::open(&state_file()).unwrap();
+            let s = state.remove(session).unwrap_or_else(|| {
+                println!("{}: no such session", session);
+                std::process::exit(1);
+            });
+
+            s.saved.restore().unwrap();
+            state.save().unwrap();
+
+            println!("{}: released after {} run(s)", session, s.runs);
+            htlb::print_htlb_status_node(s.saved.node);
+        }
+        Cmd::Rebalance { node, req } => {
+            let mut htlb_req = req.req();
+            htlb_req.node = *node;
+
+            htlb::print_htlb_status_node(*node);
+
+            htlb_req
+                .rebalance()
+                .unwrap()
+                .iter()
+                .for_each(|x| println!("{}", x.describe()));
             htlb_req.reserve_pages().unwrap();
+
+            htlb::print_htlb_status_node(*node);
         }
     }
 }
@@ -1,12 +1,37 @@
 use std::env;
-use std::os::unix::process::CommandExt;
-use std::path::Path;
-use std::process::Command;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::time::{Duration, Instant};
 
 use clap::Parser;
+use nix::sys::signal::Signal;
 
-use mosalloc::utils::argparse::{default_node, parse_file_path, parse_hook_type, parse_size};
+use mosalloc::utils::argparse::{
+    default_node, parse_align, parse_budget, parse_config_path, parse_cpu_list, parse_drain_policy,
+    parse_early_policy, parse_fault_op, parse_fault_rate, parse_file_path, parse_heap_policy,
+    parse_hook_type, parse_placement_policy, parse_pool_backing, parse_reclaim_policy,
+    parse_reserve_strategy, parse_session, parse_signal, parse_size, parse_size_limit,
+    parse_size_or_ram, parse_swap_policy, parse_tlb_model, parse_trace_op, parse_watermark,
+    parse_window_trigger, parse_zero_policy,
+};
+use mosalloc::utils::autosize::{auto_config, estimate, prior_peaks};
+use mosalloc::utils::child;
+use mosalloc::utils::config::{error_str, PoolConfig};
+use mosalloc::utils::control::{region_label, ProcessStats};
+use mosalloc::utils::elf::ElfInfo;
 use mosalloc::utils::htlb::*;
+use mosalloc::utils::layout::{plan_regions, LayoutSnapshot};
+use mosalloc::utils::misc::{cpus_from_str, size_to_str};
+use mosalloc::utils::multirun::{self, Budget, Instance};
+use mosalloc::utils::on_demand;
+use mosalloc::utils::rangelist::Id;
+use mosalloc::utils::report::{perf_counters, probe_features, reservation, Report};
+use mosalloc::utils::session::{run_session, state_file, Session, StateFile};
+use mosalloc::utils::slurm::{sbatch_script, SlurmTask};
+use mosalloc::utils::tlb::{TlbModel, DEFAULT_TLB_MODELS};
+use mosalloc::utils::trace::TraceOp;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
@@ -17,77 +42,1358 @@ struct Cli {
     #[clap(short, long, action, help = "analyze the pool sizes")]
     analyze: bool,
 
-    #[clap(long, value_parser = parse_hook_type, help = "hook type (preload or seccomp)")]
+    #[clap(long, value_parser = parse_hook_type, help = "hook type (preload, seccomp or hybrid)")]
     hook_type: HookType,
 
+    #[clap(long, value_parser = parse_reserve_strategy, default_value = "static", help = "hugepage reservation strategy (static or overcommit), the overcommit limits are system-wide and are reset after the program exits")]
+    reserve_strategy: ReserveStrategy,
+
+    #[clap(long, value_parser = parse_pool_backing, default_value = "hugetlb", help = "pool hugepages backing (hugetlb, thp, collapse, or base: base pages only with THP disabled, keeping the layout of the pool for a baseline)")]
+    backing: PoolBacking,
+
+    #[clap(
+        long,
+        action,
+        help = "demote excess larger hugepages if the smaller sizes can't be reserved"
+    )]
+    rebalance: bool,
+
+    #[clap(
+        long,
+        action,
+        help = "cover the hugepages that can't be reserved with free pages of the other sizes (demoting larger pages, or reallocating missing larger pages as smaller ones), reporting each conversion"
+    )]
+    allow_conversion: bool,
+
     #[clap(short, long, value_parser = parse_file_path, help = "mosalloc library path (default: ./libmosalloc.so)")]
     lib: Option<String>,
 
-    #[clap(long, value_parser = parse_file_path, help = "Brk and anon (mmap) pool intervals configuration (CSV)")]
-    config: String,
+    #[clap(long, value_parser = parse_config_path, required_unless_present_any = &["auto-size", "run-list"], help = "Brk and anon (mmap) pool intervals configuration (versioned or legacy CSV), with --slurm %t is replaced by the rank and %j by the job id")]
+    config: Option<String>,
 
-    #[clap(long, value_parser = parse_size, default_value_t = 1 << 30, help = "Fole Pool size")]
+    #[clap(
+        long,
+        value_parser,
+        conflicts_with = "config",
+        help = "Size the brk and anon pools from the program's ELF metadata (and --prior-stats), write the config to the given path and use it"
+    )]
+    auto_size: Option<String>,
+
+    #[clap(long, value_parser = parse_file_path, requires = "auto-size", help = "Stats of a prior run to size the pools from, a mosalloc_collector job report or a control socket stats reply")]
+    prior_stats: Option<String>,
+
+    #[clap(long, value_parser = parse_size_or_ram, default_value_t = 1 << 30, help = "File pool size (e.g. 1GB or %5-of-RAM)")]
     file_pool_size: usize,
 
-    #[clap(long, value_parser = parse_size, default_value_t = 1 << 20, help = "Anon FFA size")]
+    #[clap(long, value_parser = parse_size_or_ram, default_value_t = 1 << 20, help = "Anon FFA size")]
     anon_ffa_size: usize,
 
-    #[clap(long, value_parser = parse_size, default_value_t = 1 << 10, help = "File FFA size")]
+    #[clap(long, value_parser = parse_size_or_ram, default_value_t = 1 << 10, help = "File FFA size")]
     file_ffa_size: usize,
 
-    #[clap(value_parser, help = "Binary to run")]
-    program: String,
+    #[clap(
+        long,
+        value_parser,
+        help = "Export per-hugepage touch counts to the given file"
+    )]
+    heatmap: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 1000,
+        help = "Heatmap sampling period (ms)"
+    )]
+    heatmap_period: u64,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Save the allocator bookkeeping to the given file at exit"
+    )]
+    snapshot: Option<String>,
+
+    #[clap(long, value_parser = parse_file_path, help = "Restore the allocator bookkeeping from the given snapshot (requires --dryrun)")]
+    restore: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Run the hugepage aging policy every given ms (conflicts with --heatmap)"
+    )]
+    aging_period: Option<u64>,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 10,
+        help = "Idle samples before demoting a hugepage to base pages"
+    )]
+    aging_cold: usize,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 3,
+        help = "Touched samples before promoting a demoted hugepage"
+    )]
+    aging_hot: usize,
+
+    #[clap(long, value_parser = parse_swap_policy, default_value = "none", help = "Push the cold THP or base page backed pool pages (demoted hugepages included) to swap: none, cold (MADV_COLD) or pageout (MADV_PAGEOUT), requires --aging-period")]
+    swap: SwapPolicy,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 20,
+        help = "Idle samples before pushing a swappable pool page to swap"
+    )]
+    swap_cold: usize,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 0,
+        help = "Number of per-thread anon range caches (0 disables them)"
+    )]
+    cpu_caches: usize,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 16,
+        help = "Ranges carved per anon range cache refill"
+    )]
+    cache_batch: usize,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Reconcile the consumed hugepages with the kernel counters every given ms"
+    )]
+    meminfo_period: Option<u64>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Check /proc/self/maps for foreign mappings inside the regions every given ms, excluding them from the free maps"
+    )]
+    reconcile_period: Option<u64>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Trace the mosalloc-handled calls to the given file"
+    )]
+    trace: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 65536,
+        help = "Trace ring buffer size in records, the oldest records are overwritten when full"
+    )]
+    trace_size: usize,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 100,
+        help = "Drain the trace ring buffer to disk every given ms"
+    )]
+    trace_flush_period: u64,
+
+    #[clap(long, value_parser = parse_fault_rate, help = "Fail the selected mosalloc-handled calls with the given probability")]
+    fault_rate: Option<f64>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Start failing the selected calls after the first N (every one, unless --fault-rate is set)"
+    )]
+    fault_after: Option<u64>,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 0,
+        help = "Seed of the fault injection schedule"
+    )]
+    fault_seed: u64,
+
+    #[clap(long, value_parser = parse_fault_op, use_value_delimiter = true, default_value = "mmap,brk,mremap", help = "Calls to inject failures into (mmap, brk, mremap)")]
+    fault_ops: Vec<TraceOp>,
+
+    #[clap(long, value_parser = parse_trace_op, use_value_delimiter = true, default_value = "mmap,munmap,mprotect,madvise,mremap,brk", help = "Calls intercepted by the hooks, for a lower overhead when the others don't need emulating (e.g. brk,mmap,munmap)")]
+    hooks: Vec<TraceOp>,
+
+    #[clap(
+        long,
+        use_value_delimiter = true,
+        help = "Only handle the mmaps called from the given objects (substrings of their paths, e.g. libjemalloc,libc.so), resolved from the return address, the rest are forwarded to libc (preload hooks)"
+    )]
+    callers: Vec<String>,
+
+    #[clap(
+        long,
+        use_value_delimiter = true,
+        help = "Only handle the mmaps of the given threads, by name (their comm, as set by pthread_setname_np, up to 15 characters) or tid, along with the ones registered with mosalloc_register_thread; the rest are forwarded to libc, while the frees and remaps of pool memory are handled whichever thread makes them"
+    )]
+    threads: Vec<String>,
+
+    #[clap(long, value_parser = parse_tlb_model, use_value_delimiter = true, default_value = DEFAULT_TLB_MODELS, help = "TLB models to report the reach of the final layout for, vs a 4KB only one, at exit: presets (skylake, icelake, zen3) or name:pagesz=entries[:...] (e.g. mycpu:4KB=1536:2MB=1536:1GB=16)")]
+    tlb: Vec<TlbModel>,
+
+    #[clap(
+        long,
+        conflicts_with = "tlb",
+        help = "Don't report the TLB reach at exit"
+    )]
+    no_tlb: bool,
+
+    #[clap(long, value_parser = parse_size_limit, help = "Brk region byte limits (soft[:hard]), warn past the soft limit and fail with ENOMEM past the hard one")]
+    brk_limit: Option<SizeLimit>,
+
+    #[clap(long, value_parser = parse_size_limit, help = "Anon (mmap) region byte limits (soft[:hard])")]
+    anon_limit: Option<SizeLimit>,
+
+    #[clap(long, value_parser = parse_size_limit, help = "File region byte limits (soft[:hard])")]
+    file_limit: Option<SizeLimit>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Max 2MB pool pages mapped at once, further allocations needing one fail with ENOMEM"
+    )]
+    max_2mb_pages: Option<usize>,
+
+    #[clap(long, value_parser, help = "Max 1GB pool pages mapped at once")]
+    max_1gb_pages: Option<usize>,
+
+    #[clap(long, value_parser = parse_reclaim_policy, default_value = "none", help = "Pool memory reclaim policy on malloc_trim and MADV_DONTNEED (none or release)")]
+    reclaim: ReclaimPolicy,
+
+    #[clap(
+        long,
+        action,
+        help = "Allocate a protection key per named sub-pool and tag its mappings with it"
+    )]
+    pkeys: bool,
+
+    #[clap(
+        long,
+        action,
+        help = "Pin the ranges registered with ibv_reg_mr / cudaHostRegister, so that reclaim, aging and compaction never touch them"
+    )]
+    pin_devices: bool,
+
+    #[clap(
+        long,
+        action,
+        help = "Place the private MAP_HUGETLB requests of the application in the pool intervals of their page size (MAP_HUGE_SHIFT encoded, the default hugepage size otherwise), so that they share the pool's budget, instead of forwarding them to libc; they fail with ENOMEM once those intervals are full, the ones of a page size the pool lacks are still forwarded"
+    )]
+    absorb_hugetlb: bool,
+
+    #[clap(
+        long,
+        action,
+        help = "Track the hugepages of each size reserved for the run (its share of them with several instances) against the pages the pool intervals require, warning when they fall short, and fail the mmaps that would map more hugepages than that with ENOMEM and a log of the pages missing, instead of a SIGBUS at their first touch (hugetlb backing, without --on-demand)"
+    )]
+    strict_reservation: bool,
+
+    #[clap(
+        long,
+        action,
+        help = "Keep the mosalloc region maps (free, protection, locked ranges ...) read-only outside of its critical sections, so that stray application writes to them fault (the region structs and the parsed config stay writable)"
+    )]
+    protect_metadata: bool,
+
+    #[clap(long, value_parser = parse_size, default_value = "256KB", help = "Arena size of the libmosalloc internal allocator, raise it on arena exhaustion (see the (arena) stats)")]
+    arena_size: usize,
+
+    #[clap(long, value_parser = parse_size, default_value = "4KB", help = "Internal allocations from this size up are mmap'd instead of carved from the arena")]
+    mmap_threshold: usize,
+
+    #[clap(long, value_parser = parse_align, default_value = "4KB", help = "Max alignment supported by the internal allocator")]
+    max_align: usize,
+
+    #[clap(long, value_parser = parse_size, help = "Reserve a region of this size for the libmosalloc internal mappings (arena, trace buffers ...), away from the pools")]
+    internal_region: Option<usize>,
+
+    #[clap(long, value_parser = parse_size, help = "Hard cap on the storage of each region's free map, past it the free ranges that can't be split or taken back fail the allocation or leak instead of growing it (see the free map stats)")]
+    freemap_max: Option<usize>,
+
+    #[clap(long, value_parser = parse_size, help = "Let each anon region grow past its pool, in base page chunks, by up to this size once it runs out of space, as long as the address space past it is free (see the grown stats)")]
+    anon_ffa_max: Option<usize>,
+
+    #[clap(long, value_parser = parse_drain_policy, default_value = "full", help = "glibc heap drain policy at startup (full, auto or none), auto skips it when glibc grows the heap through __morecore")]
+    drain: DrainPolicy,
+
+    #[clap(long, value_parser = parse_size, help = "Max bytes to drain from the glibc heap")]
+    drain_max: Option<usize>,
+
+    #[clap(long, value_parser = parse_early_policy, default_value = "enomem", help = "Handling of the anon mmap and brk requests of other threads while the glibc heap is drained (enomem, forward to libc, or wait up to a second for the drain and fail with ENOMEM past it)")]
+    early: EarlyPolicy,
+
+    #[clap(long, value_parser = parse_heap_policy, default_value = "relocate", help = "Pre-existing glibc heap handling (relocate, copy or detach), copy keeps its contents by placing the heap region over it, detach places the heap region away from the program break (which relocate falls back to if it doesn't fit right past it)")]
+    heap: HeapPolicy,
+
+    #[clap(long, value_parser = parse_zero_policy, default_value = "fault", help = "When the kernel zeroes the pool hugepages: on first touch (fault), at setup by populating the whole pools (prefault), or at mapping time, timed and reported apart (measure)")]
+    zero: ZeroPolicy,
+
+    #[clap(long, value_parser = parse_placement_policy, default_value = "first-fit", help = "Where the anon pool places the allocations: in the lowest free range that fits (first-fit), or filling the partially used hugepages first with the smaller allocations, to keep the free hugepages whole for the larger ones (fill)")]
+    placement: PlacementPolicy,
+
+    #[clap(long, value_parser = parse_watermark, use_value_delimiter = true, help = "Region utilization watermarks in percent (e.g. 80,95), crossing one prints a warning and the region stats and calls the registered callback")]
+    watermarks: Vec<usize>,
+
+    #[clap(long, value_parser = parse_cpu_list, help = "Pin the mosalloc service threads (samplers, flushers, the seccomp supervisor ...) to the given CPUs (e.g. 0-1,8), away from the workload's cores")]
+    housekeeping_cpus: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Serve live stats on a control socket in the given directory, for mosalloc top"
+    )]
+    control_dir: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Push the stats to a mosalloc_collector (unix:<path> or <host>:<port>)"
+    )]
+    collector: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 1000,
+        help = "Collector push period (ms)"
+    )]
+    collector_period: u64,
+
+    #[clap(long, value_parser = parse_window_trigger, help = "Start collecting the stats and the trace when a marker file appears (file:<path>), after some seconds (time:<secs>) or bytes allocated (alloc:<size>)")]
+    window_start: Option<WindowTrigger>,
+
+    #[clap(long, value_parser = parse_window_trigger, help = "Stop collecting the stats and the trace, the time and bytes are counted from the window start")]
+    window_stop: Option<WindowTrigger>,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 100,
+        help = "Window trigger poll period (ms)"
+    )]
+    window_period: u64,
+
+    #[clap(long, value_parser, default_value = DEFAULT_ENV_PREFIX, help = "Prefix of the exported libmosalloc config vars (the deprecated HPC_ ones are still read)")]
+    env_prefix: String,
+
+    #[clap(
+        long,
+        action,
+        help = "Preload libmosalloc after the libraries already in LD_PRELOAD, so that its constructor runs before theirs"
+    )]
+    init_first: bool,
+
+    #[clap(
+        long,
+        action,
+        help = "Running under srun, use the NUMA node holding most of the cpus the task is bound to and check the pools against its share of the node memory"
+    )]
+    slurm: bool,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Write an sbatch script launching every rank through run_mosalloc --slurm with the rest of the arguments, and exit"
+    )]
+    sbatch: Option<String>,
+
+    #[clap(
+        long,
+        action,
+        help = "Print the planned region layout, hugepage requirements and environment, and exit without reserving or running anything"
+    )]
+    plan_only: bool,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Terminate the program after the given seconds (SIGTERM, then SIGKILL after 5s), exiting with 124"
+    )]
+    timeout: Option<u32>,
+
+    #[clap(
+        long,
+        action,
+        help = "Restore the hugepage pool (reserved pages, overcommit limits) of the node after the program exits"
+    )]
+    release_pages: bool,
+
+    #[clap(
+        long,
+        value_parser = parse_session,
+        conflicts_with = "release-pages",
+        help = "Keep the hugepages reserved after the program exits, for the next runs of the given session to reuse, until the session is released (reserve_huge_pages release)"
+    )]
+    session: Option<String>,
+
+    #[clap(
+        long,
+        action,
+        conflicts_with_all = &["session", "phases", "dryrun"],
+        help = "Don't reserve the hugepages up front, bump nr_hugepages as the pool intervals are first touched by the program instead, for a lower idle reservation on shared machines"
+    )]
+    on_demand: bool,
+
+    #[clap(
+        long,
+        value_parser,
+        help = "Move the heatmap, trace and snapshot files of the run to the given directory after the program exits, under a directory per instance along with its output when running several"
+    )]
+    collect: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        conflicts_with_all = &["instances", "run-list", "phases", "plan-only"],
+        help = "Write a report of the run to the given file: the hugepage reservation, region layout and stats, resource usage, kernel features and warnings (see mosalloc report show/diff)"
+    )]
+    report: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 1,
+        conflicts_with_all = &["slurm", "sbatch", "plan-only"],
+        help = "Run the given number of instances of the program concurrently, %i in the config, heatmap, trace and snapshot paths is replaced by the instance index"
+    )]
+    instances: usize,
+
+    #[clap(
+        long,
+        value_parser = parse_file_path,
+        conflicts_with_all = &["config", "auto-size", "instances", "slurm", "sbatch", "plan-only", "program"],
+        help = "Run the instances listed in the given file concurrently, one `<config> <program> [args...]` per line"
+    )]
+    run_list: Option<String>,
+
+    #[clap(
+        long,
+        value_parser = parse_budget,
+        help = "Hugepages shared by the instances (e.g. 2MB:512,1GB:4), the pages of a size are split proportionally to the instance requests when these exceed its budget"
+    )]
+    budget: Option<Budget>,
+
+    #[clap(
+        long,
+        value_parser = parse_file_path,
+        use_value_delimiter = true,
+        conflicts_with_all = &["instances", "run-list", "budget", "auto-size", "slurm", "sbatch", "plan-only", "collect"],
+        help = "Pool configs of the phases after the first one (--config): at every checkpoint signal the program is terminated and re-executed with the config of the next phase (and MOSALLOC_PHASE set to its index), the hugepages staying reserved in between; %i in the heatmap, trace and snapshot paths is replaced by the phase index"
+    )]
+    phases: Vec<String>,
+
+    #[clap(
+        long,
+        value_parser = parse_signal,
+        default_value = "USR1",
+        help = "Checkpoint signal ending a phase, sent to run_mosalloc (e.g. by the program to its parent)"
+    )]
+    phase_signal: Signal,
+
+    #[clap(
+        value_parser,
+        required_unless_present = "run-list",
+        help = "Binary to run"
+    )]
+    program: Option<String>,
 
     #[clap(value_parser, help = "Program arguments")]
     args: Vec<String>,
 }
 
-fn main() {
-    let cli = Cli::parse();
+// the hugepage pools of each rank have to fit in its share of the node memory
+fn check_mem_budget(task: &SlurmTask, config: &str) {
+    let budget = match task.mem_budget() {
+        Some(x) => x,
+        None => return,
+    };
+    let pools = Pool::from_config(AllocType::BRK, Path::new(config)).size()
+        + Pool::from_config(AllocType::ANON, Path::new(config)).size()
+        + Pool::named_from_config(Path::new(config))
+            .iter()
+            .map(|(_, x)| x.size())
+            .sum::<usize>();
+
+    if pools > budget {
+        println!(
+            "slurm: the {} hugepage pools of {} exceed the {} per task budget ({} tasks on the node)",
+            size_to_str(pools),
+            config,
+            size_to_str(budget),
+            task.local_tasks
+        );
+        process::exit(1);
+    }
+}
+
+// print the layout, the hugepage requirements and the environment of a run
+fn print_plan(config: &MosallocConfig, node: Id, env_prefix: &str, preload: &str, cmd: &[String]) {
+    // the regions are placed against the maps of the target at startup
+    let snapshot = LayoutSnapshot::stopped(&cmd[0], &cmd[1..]).unwrap_or_else(|e| {
+        println!("plan: {}, using the maps of run_mosalloc", e);
+        LayoutSnapshot::current().unwrap()
+    });
+    println!(
+        "plan: {} mappings, program break {:x}",
+        snapshot.maps.len(),
+        snapshot.brk
+    );
+    if config.heap == HeapPolicy::COPY {
+        println!("plan: the brk region is placed over the glibc heap instead, if it fits");
+    }
+
+    let placed = PoolConfig::from_path(Path::new(&config.pool_config))
+        .and_then(|x| plan_regions(&snapshot, &x, config.file_pool_size));
+    match placed {
+        Ok(placed) => {
+            for (name, pool, start) in placed {
+                println!(
+                    "plan: {} {:x}-{:x} ({})",
+                    region_label(pool.alloc_type, name.as_deref()),
+                    start,
+                    start + pool.span(),
+                    size_to_str(pool.span())
+                );
+                // the file pool is backed by base pages
+                for x in pool.intervals.iter().filter(|x| x.pagesz > *PAGE_SIZE) {
+                    println!(
+                        "plan:   {} pages {:x}-{:x}{}",
+                        size_to_str(x.pagesz),
+                        start + x.start,
+                        start + x.end,
+                        x.name
+                            .as_ref()
+                            .map(|x| format!(" ({})", x))
+                            .unwrap_or_default()
+                    );
+                }
+            }
+        }
+        Err(e) => println!("plan: {}", e),
+    }
+
+    let req = HTLBReq::from_config(Path::new(&config.pool_config), node);
+    for (&pagesz, &nr) in supported_htlb_sizes().iter().zip(req.req.iter()) {
+        if nr == 0 {
+            continue;
+        }
+        if config.dryrun || config.backing != PoolBacking::HUGETLB {
+            println!(
+                "plan: {} {} pages, not reserved ({})",
+                nr,
+                size_to_str(pagesz),
+                if config.dryrun {
+                    "dryrun"
+                } else {
+                    config.backing.as_str()
+                }
+            );
+        } else {
+            println!(
+                "plan: {} {} pages on node {} ({} reserved, {} free)",
+                nr,
+                size_to_str(pagesz),
+                node,
+                get_htlb_pages_node(node, pagesz).unwrap(),
+                get_htlb_free_pages_node(node, pagesz).unwrap()
+            );
+        }
+    }
+
+    for (var, val) in config.env_vars(env_prefix) {
+        println!("plan: {}={}", var, val);
+    }
+    println!("plan: LD_PRELOAD={}", preload);
+    println!("plan: {}", cmd.join(" "));
+}
+
+// the pages of each size reserved by a request, passed to the lib for the strict reservation
+fn reserved_pages(req: &[usize]) -> Vec<(usize, usize)> {
+    supported_htlb_sizes()
+        .into_iter()
+        .zip(req.iter().copied())
+        .collect()
+}
+
+// reserves the pages of the request, optionally demoting or converting pages of the other sizes
+fn reserve(htlb_req: &mut HTLBReq, rebalance: bool, allow_conversion: bool) {
+    let res = if allow_conversion {
+        htlb_req.reserve_converting()
+    } else if rebalance {
+        htlb_req
+            .rebalance()
+            .and_then(|x| htlb_req.reserve_pages().map(|_| x))
+    } else {
+        htlb_req.reserve_pages().map(|_| Vec::new())
+    };
+
+    match res {
+        Ok(conversions) => conversions
+            .iter()
+            .for_each(|x| println!("{}", x.describe())),
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// runs the program through its phases, re-executed with the config of the next phase at every
+// checkpoint (signal); the reservation only grows over the phases and is kept in between, so the
+// pages of a phase are mostly there already for the next one
+fn run_phases(
+    phases: &[String],
+    config: &MosallocConfig,
+    htlb_req: &mut HTLBReq,
+    reserve: impl Fn(&mut HTLBReq),
+    command: impl Fn(usize, &MosallocConfig) -> Command,
+    timeout: Option<u32>,
+    signal: Signal,
+) -> child::ChildExit {
+    let pool_configs = [config.pool_config.clone()]
+        .into_iter()
+        .chain(phases.iter().cloned())
+        .collect::<Vec<String>>();
+
+    for (i, pool_config) in pool_configs.iter().enumerate() {
+        let expand = |x: &Option<String>| x.as_ref().map(|x| multirun::expand(x, i));
+        let mut config = MosallocConfig {
+            pool_config: pool_config.clone(),
+            heatmap: expand(&config.heatmap),
+            trace: expand(&config.trace),
+            snapshot: expand(&config.snapshot),
+            ..config.clone()
+        };
 
-    let path = Path::new(&cli.config);
+        if i > 0 {
+            let path = Path::new(pool_config);
+            let checked = PoolConfig::from_path(path).and_then(|c| {
+                c.check_ffa(config.anon_ffa_size, config.file_ffa_size)
+                    .map_err(|e| error_str(path, &e))
+            });
+            if let Err(e) = checked {
+                println!("{}", e);
+                process::exit(1);
+            }
 
-    let mmap = Pool::from_csv(AllocType::ANON, &path);
-    let brk = Pool::from_csv(AllocType::BRK, &path);
+            let req = HTLBReq::from_config(path, htlb_req.node).req;
+            htlb_req.req = htlb_req
+                .req
+                .iter()
+                .zip(req.iter())
+                .map(|(x, y)| *x.max(y))
+                .collect();
+            reserve(htlb_req);
+            if !config.reservation.is_empty() {
+                config.reservation = reserved_pages(&htlb_req.req);
+            }
+        }
 
-    let req = supported_htlb_sizes()
+        println!("phase {}: {}", i, pool_config);
+        let (exit, checkpointed) = child::run_phase(&mut command(i, &config), timeout, signal)
+            .unwrap_or_else(|e| {
+                println!("{}", e);
+                process::exit(1);
+            });
+        if !checkpointed || i == pool_configs.len() - 1 {
+            return exit;
+        }
+        println!("phase {}: checkpoint, {}", i, exit.as_string());
+    }
+
+    unreachable!()
+}
+
+// reserves the pages of a session, the reservation only grows over its runs and is reused as long
+// as it covers the request
+fn reserve_session(name: &str, htlb_req: &mut HTLBReq, rebalance: bool, allow_conversion: bool) {
+    let path = state_file();
+    let mut state = StateFile::open(&path).unwrap_or_else(|e| {
+        println!("--session: {}", e);
+        process::exit(1);
+    });
+
+    if state.find(name).is_none() {
+        state.sessions.push(Session {
+            name: name.to_string(),
+            pages: vec![0; htlb_req.req.len()],
+            saved: HTLBState::save(htlb_req.node).unwrap(),
+            runs: 0,
+            pid: None,
+        });
+    }
+    let session = state.find(name).unwrap();
+    if session.saved.node != htlb_req.node {
+        println!(
+            "--session: {} holds a reservation on node {}, not {}",
+            name, session.saved.node, htlb_req.node
+        );
+        process::exit(1);
+    }
+
+    htlb_req.req = htlb_req
+        .req
+        .iter()
+        .zip(session.pages.iter())
+        .map(|(x, y)| *x.max(y))
+        .collect();
+    let reserved = supported_htlb_sizes()
         .iter()
-        .map(|&x| mmap.nrpages(x) + brk.nrpages(x))
-        .collect::<Vec<usize>>();
+        .zip(htlb_req.req.iter())
+        .all(|(&sz, &nr)| {
+            let cur = if htlb_req.strategy == ReserveStrategy::OVERCOMMIT {
+                get_htlb_overcommit_pages(sz)
+            } else {
+                get_htlb_pages_node(htlb_req.node, sz)
+            };
+            cur.unwrap() >= nr
+        });
 
-    let node = default_node();
+    if reserved && session.runs > 0 {
+        println!(
+            "session {}: reusing the reservation of {} run(s)",
+            name, session.runs
+        );
+    } else {
+        reserve(htlb_req, rebalance, allow_conversion);
+    }
 
-    print_htlb_status_node(node);
+    session.pages = htlb_req.req.clone();
+    session.runs += 1;
+    let runs = session.runs;
+    state.save().unwrap();
+    println!(
+        "session {}: run {} (state in {})",
+        name,
+        runs,
+        path.display()
+    );
+}
 
-    disable_thp(true);
-    enable_overcommit(true);
+// records the --release-pages reservation of this run in the state file until it's released, so
+// that the reservations of crashed runs can be found (mosalloc doctor)
+fn track_run(saved: &HTLBState, pages: &[usize]) {
+    let pid = process::id() as i32;
+    if let Ok(mut state) = StateFile::open(&state_file()) {
+        state.sessions.push(Session {
+            name: run_session(pid),
+            pages: pages.to_vec(),
+            saved: saved.clone(),
+            runs: 1,
+            pid: Some(pid),
+        });
+        let _ = state.save();
+    }
+}
 
-    let htlb_req = HTLBReq { node, req };
-    if !cli.dryrun {
-        htlb_req.reserve_pages().unwrap();
+fn untrack_run() {
+    if let Ok(mut state) = StateFile::open(&state_file()) {
+        if state.remove(&run_session(process::id() as i32)).is_some() {
+            let _ = state.save();
+        }
     }
+}
 
-    print_htlb_status_node(node);
+// moves the output files of a run to a directory
+fn collect_files(dir: &str, files: &[&Option<String>]) {
+    fs::create_dir_all(dir).unwrap();
+
+    for path in files.iter().filter_map(|x| x.as_ref()) {
+        let path = Path::new(path);
+        if !path.exists() {
+            continue;
+        }
+
+        let dest = PathBuf::from(dir).join(path.file_name().unwrap());
+        // rename doesn't work across filesystems
+        if fs::rename(path, &dest).is_err() {
+            fs::copy(path, &dest).unwrap();
+            fs::remove_file(path).unwrap();
+        }
+        println!("collect: {} -> {}", path.display(), dest.display());
+    }
+}
+
+// write the auto-sized pool config of the program to the given path
+fn write_auto_config(path: &str, program: &str, prior: Option<&str>) {
+    let exe = if program.contains('/') {
+        Path::new(program).to_path_buf()
+    } else {
+        // resolve it through PATH, like exec does
+        env::var("PATH")
+            .unwrap_or_default()
+            .split(':')
+            .map(|x| Path::new(x).join(program))
+            .find(|x| x.is_file())
+            .unwrap_or_else(|| panic!("{} not found in PATH", program))
+    };
+
+    let elf = ElfInfo::from_path(&exe).unwrap_or_else(|e| panic!("{}", e));
+    println!(
+        "auto-size: {} ({}{}), image {}, data + bss {}, bss {}",
+        exe.display(),
+        if elf.pie { "pie" } else { "non-pie" },
+        if elf.dynamic { ", dynamic" } else { ", static" },
+        size_to_str(elf.image_size()),
+        size_to_str(elf.data_size()),
+        size_to_str(elf.bss)
+    );
+    match elf.stack {
+        Some((false, _)) => {}
+        Some((true, _)) => println!("auto-size: the program requests an executable stack"),
+        None => println!("auto-size: no PT_GNU_STACK, the program gets an executable stack"),
+    }
+    if let Some((_, size)) = elf.stack.filter(|(_, size)| *size > 0) {
+        println!("auto-size: main thread stack size {}", size_to_str(size));
+    }
+
+    let prior = prior
+        .map(|x| prior_peaks(Path::new(x)).unwrap_or_else(|e| panic!("{}", e)))
+        .unwrap_or_default();
+    let estimates = estimate(&elf, &prior);
+    for x in estimates.iter() {
+        println!(
+            "auto-size: {} pool {} ({})",
+            x.alloc_type.as_str(),
+            size_to_str(x.size),
+            x.source
+        );
+    }
+
+    let config = auto_config(&estimates);
+    for x in config.intervals.iter() {
+        println!(
+            "auto-size: {} {} pages 0x{:x}-0x{:x}",
+            x.alloc_type.as_str(),
+            size_to_str(x.interval.pagesz),
+            x.interval.start,
+            x.interval.end
+        );
+    }
+    fs::write(path, config.to_versioned()).unwrap();
+    println!("{}: pool config written", path);
+}
+
+// write an sbatch script running the current command line under srun, with --slurm
+fn write_sbatch_script(path: &str, program: &str) {
+    let mut args = Vec::new();
+    let mut skip = false;
+    for arg in env::args().skip(1) {
+        if skip {
+            skip = false;
+        } else if arg == "--sbatch" {
+            skip = true;
+        } else if !arg.starts_with("--sbatch=") {
+            args.push(arg);
+        }
+    }
+    if !args.iter().any(|x| x == "--slurm") {
+        args.insert(0, "--slurm".to_string());
+    }
+
+    let exe = env::current_exe().unwrap();
+    fs::write(path, sbatch_script(&exe.to_string_lossy(), &args, program)).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+    println!("{}: sbatch script written", path);
+}
+
+// the stats file of the program of a reported run
+fn report_stats_path() -> PathBuf {
+    env::temp_dir().join(format!("mosalloc-stats-{}", process::id()))
+}
+
+// writes the report of the run, before a released reservation is restored
+fn write_report(
+    path: &Path,
+    instance: &Instance,
+    exit: &child::ChildExit,
+    htlb_req: &HTLBReq,
+    mode: String,
+    wall: Duration,
+) {
+    let stats_path = report_stats_path();
+    let stats = fs::read_to_string(&stats_path)
+        .ok()
+        .and_then(|x| ProcessStats::from_text(&x).ok());
+    let _ = fs::remove_file(&stats_path);
+
+    let mut report = Report {
+        program: [instance.program.clone()]
+            .into_iter()
+            .chain(instance.args.iter().cloned())
+            .collect(),
+        config: instance.config.clone(),
+        mode,
+        exit: exit.as_string(),
+        code: exit.code(),
+        reservation: reservation(htlb_req),
+        features: probe_features(),
+        perf: perf_counters(wall),
+        stats,
+        warnings: Vec::new(),
+    };
+    report.derive_warnings();
+
+    match fs::write(path, report.to_text()) {
+        Ok(_) => println!("report: {}", path.display()),
+        Err(e) => println!("--report: {}: {}", path.display(), e),
+    }
+}
 
-    MosallocConfig {
-        pool_config: cli.config,
+fn main() {
+    let cli = Cli::parse();
+
+    // both the heatmap sampler and the aging policy reset the soft-dirty bits
+    assert!(
+        cli.heatmap.is_none() || cli.aging_period.is_none(),
+        "--heatmap and --aging-period can't be used together"
+    );
+    assert!(
+        cli.swap == SwapPolicy::NONE || cli.aging_period.is_some(),
+        "--swap needs --aging-period"
+    );
+    // the pool ranges handed out by mmap have to be unmapped through mosalloc too
+    assert!(
+        !cli.hooks.contains(&TraceOp::MMAP) || cli.hooks.contains(&TraceOp::MUNMAP),
+        "--hooks: mmap can't be hooked without munmap"
+    );
+
+    assert!(
+        cli.file_pool_size.is_multiple_of(*PAGE_SIZE),
+        "--file-pool-size must be a multiple of the page size"
+    );
+    assert!(
+        !cli.on_demand
+            || (cli.backing == PoolBacking::HUGETLB
+                && cli.reserve_strategy == ReserveStrategy::STATIC),
+        "--on-demand needs hugetlb backing and the static reservation strategy"
+    );
+
+    if let Some(path) = &cli.sbatch {
+        write_sbatch_script(path, cli.program.as_ref().unwrap());
+        return;
+    }
+
+    let task = cli.slurm.then(|| {
+        SlurmTask::from_env().unwrap_or_else(|e| {
+            println!("--slurm: {}", e);
+            process::exit(1);
+        })
+    });
+
+    // the generated config takes the place of --config
+    let mut config = cli
+        .auto_size
+        .clone()
+        .or_else(|| cli.config.clone())
+        .unwrap_or_default();
+    if let Some(task) = &task {
+        config = task.expand(&config);
+    }
+    if cli.auto_size.is_some() {
+        write_auto_config(
+            &config,
+            cli.program.as_ref().unwrap(),
+            cli.prior_stats.as_deref(),
+        );
+    }
+
+    let node = if let Some(task) = &task {
+        parse_file_path(&config).unwrap();
+        // dryruns don't reserve anything
+        if !cli.dryrun {
+            check_mem_budget(task, &config);
+        }
+
+        let node = task.numa_node();
+        println!(
+            "slurm: job {} rank {} (local {} of {}), node {}, config {}",
+            task.job, task.rank, task.local_rank, task.local_tasks, node, config
+        );
+        node
+    } else {
+        default_node()
+    };
+
+    let mosalloc_config = MosallocConfig {
+        pool_config: config.clone(),
         file_pool_size: cli.file_pool_size,
         anon_ffa_size: cli.anon_ffa_size,
         file_ffa_size: cli.file_ffa_size,
         analyze_regions: cli.analyze,
         dryrun: cli.dryrun,
         hook: cli.hook_type,
-    }
-    .save();
+        backing: cli.backing,
+        heatmap: cli.heatmap,
+        heatmap_period: cli.heatmap_period,
+        snapshot: cli.snapshot,
+        restore: cli.restore,
+        aging_period: cli.aging_period,
+        aging_cold: cli.aging_cold,
+        aging_hot: cli.aging_hot,
+        swap: cli.swap,
+        swap_cold: cli.swap_cold,
+        cpu_caches: cli.cpu_caches,
+        cache_batch: cli.cache_batch,
+        meminfo_period: cli.meminfo_period,
+        reconcile_period: cli.reconcile_period,
+        trace: cli.trace,
+        trace_size: cli.trace_size,
+        trace_flush_period: cli.trace_flush_period,
+        fault_rate: cli.fault_rate,
+        fault_after: cli.fault_after,
+        fault_seed: cli.fault_seed,
+        fault_ops: cli.fault_ops,
+        hooks: cli.hooks,
+        callers: cli.callers,
+        threads: cli.threads,
+        tlb: if cli.no_tlb { Vec::new() } else { cli.tlb },
+        brk_limit: cli.brk_limit.unwrap_or_default(),
+        anon_limit: cli.anon_limit.unwrap_or_default(),
+        file_limit: cli.file_limit.unwrap_or_default(),
+        max_2mb_pages: cli.max_2mb_pages,
+        max_1gb_pages: cli.max_1gb_pages,
+        reclaim: cli.reclaim,
+        pkeys: cli.pkeys,
+        pin_devices: cli.pin_devices,
+        absorb_hugetlb: cli.absorb_hugetlb,
+        strict_reservation: cli.strict_reservation,
+        reservation: Vec::new(),
+        protect_metadata: cli.protect_metadata,
+        arena_size: cli.arena_size,
+        mmap_threshold: cli.mmap_threshold,
+        max_align: cli.max_align,
+        internal_region: cli.internal_region,
+        freemap_max: cli.freemap_max,
+        anon_ffa_max: cli.anon_ffa_max,
+        drain: cli.drain,
+        drain_max: cli.drain_max,
+        early: cli.early,
+        heap: cli.heap,
+        zero: cli.zero,
+        placement: cli.placement,
+        watermarks: cli.watermarks,
+        housekeeping_cpus: cli
+            .housekeeping_cpus
+            .map(|x| cpus_from_str(&x).unwrap())
+            .unwrap_or_default(),
+        control_dir: cli.control_dir,
+        // the final stats of the program, for the report
+        stats_file: cli
+            .report
+            .as_ref()
+            .map(|_| report_stats_path().to_string_lossy().into_owned()),
+        reserve_socket: cli
+            .on_demand
+            .then(|| on_demand::socket_path().to_string_lossy().into_owned()),
+        collector: cli.collector,
+        collector_period: cli.collector_period,
+        window_start: cli.window_start,
+        window_stop: cli.window_stop,
+        window_period: cli.window_period,
+    };
 
     let preload = cli.lib.unwrap_or("./libmosalloc.so".to_string());
-    env::set_var(
-        "LD_PRELOAD",
-        format!(
-            "{}:{}",
-            preload,
-            env::var("LD_PRELOAD").unwrap_or("".to_string())
-        ),
+    let others = env::var("LD_PRELOAD").unwrap_or("".to_string());
+    // preloaded libraries are initialized in reverse order
+    let ld_preload = if cli.init_first {
+        format!("{}:{}", others, preload)
+    } else {
+        format!("{}:{}", preload, others)
+    };
+
+    if cli.plan_only {
+        let cmd = [cli.program.unwrap()]
+            .into_iter()
+            .chain(cli.args)
+            .collect::<Vec<String>>();
+        print_plan(&mosalloc_config, node, &cli.env_prefix, &ld_preload, &cmd);
+        return;
+    }
+
+    let instances = match &cli.run_list {
+        Some(path) => multirun::run_list(Path::new(path)).unwrap_or_else(|e| {
+            println!("--run-list: {}", e);
+            process::exit(1);
+        }),
+        None => vec![
+            Instance {
+                config,
+                program: cli.program.clone().unwrap(),
+                args: cli.args.clone(),
+            };
+            cli.instances
+        ],
+    };
+    let multi = instances.len() > 1;
+
+    let mut configs = instances
+        .iter()
+        .enumerate()
+        .map(|(i, x)| {
+            if !multi {
+                return mosalloc_config.clone();
+            }
+            let expand = |x: &Option<String>| x.as_ref().map(|x| multirun::expand(x, i));
+            MosallocConfig {
+                pool_config: multirun::expand(&x.config, i),
+                heatmap: expand(&mosalloc_config.heatmap),
+                trace: expand(&mosalloc_config.trace),
+                snapshot: expand(&mosalloc_config.snapshot),
+                restore: expand(&mosalloc_config.restore),
+                ..mosalloc_config.clone()
+            }
+        })
+        .collect::<Vec<MosallocConfig>>();
+    for x in configs.iter() {
+        parse_file_path(&x.pool_config).unwrap();
+        let path = Path::new(&x.pool_config);
+        let checked = PoolConfig::from_path(path).and_then(|c| {
+            c.check_ffa(x.anon_ffa_size, x.file_ffa_size)
+                .map_err(|e| error_str(path, &e))
+        });
+        if let Err(e) = checked {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
+    // the pages granted to each instance, capped through its max pages when short of its request
+    let sizes = supported_htlb_sizes();
+    let reqs = configs
+        .iter()
+        .map(|x| HTLBReq::from_config(Path::new(&x.pool_config), node).req)
+        .collect::<Vec<Vec<usize>>>();
+    let shares = match &cli.budget {
+        Some(budget) => budget.share(&reqs),
+        None => reqs.clone(),
+    };
+    for (i, config) in configs.iter_mut().enumerate() {
+        for (j, &sz) in sizes.iter().enumerate() {
+            if shares[i][j] == reqs[i][j] {
+                continue;
+            }
+            let max_pages = match sz {
+                0x200000 => &mut config.max_2mb_pages,
+                0x40000000 => &mut config.max_1gb_pages,
+                _ => {
+                    println!("--budget: {} pages can't be capped", size_to_str(sz));
+                    process::exit(1);
+                }
+            };
+            *max_pages = Some(max_pages.unwrap_or(usize::MAX).min(shares[i][j]));
+        }
+        if multi || cli.budget.is_some() {
+            println!(
+                "instance {}: {} ({}), pages {}",
+                i,
+                instances[i].program,
+                config.pool_config,
+                sizes
+                    .iter()
+                    .enumerate()
+                    .filter(|(j, _)| reqs[i][*j] > 0)
+                    .map(|(j, &sz)| format!("{} {}/{}", size_to_str(sz), shares[i][j], reqs[i][j]))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            );
+        }
+    }
+
+    print_htlb_status_node(node);
+
+    disable_thp(true);
+    enable_overcommit(true);
+
+    let mut htlb_req = HTLBReq {
+        req: (0..sizes.len())
+            .map(|j| shares.iter().map(|x| x[j]).sum())
+            .collect(),
+        node,
+        strategy: cli.reserve_strategy,
+    };
+    let mut htlb_state = None;
+    // THP-backed pools don't need any reservation
+    if !cli.dryrun && cli.backing == PoolBacking::HUGETLB {
+        if let Some(name) = &cli.session {
+            reserve_session(name, &mut htlb_req, cli.rebalance, cli.allow_conversion);
+        } else {
+            // the overcommit limits raised are system-wide, they're always reset
+            if cli.release_pages || cli.reserve_strategy == ReserveStrategy::OVERCOMMIT {
+                htlb_state = Some(HTLBState::save(node).unwrap());
+            }
+            if cli.on_demand {
+                on_demand::spawn_server(&on_demand::socket_path(), node).unwrap_or_else(|e| {
+                    println!("--on-demand: {}", e);
+                    process::exit(1);
+                });
+            } else {
+                reserve(&mut htlb_req, cli.rebalance, cli.allow_conversion);
+            }
+            if let (true, Some(state)) = (cli.release_pages, &htlb_state) {
+                track_run(state, &htlb_req.req);
+            }
+        }
+    }
+
+    print_htlb_status_node(node);
+    if cli.reserve_strategy == ReserveStrategy::OVERCOMMIT {
+        print_htlb_overcommit_status();
+    }
+    // each instance tracks its share of the pages reserved, the conversions may have reserved
+    // fewer of a size than the shares add up to
+    if cli.strict_reservation
+        && !cli.dryrun
+        && cli.backing == PoolBacking::HUGETLB
+        && !cli.on_demand
+    {
+        for (config, share) in configs.iter_mut().zip(shares.iter()) {
+            config.reservation = reserved_pages(&htlb_req.req)
+                .into_iter()
+                .zip(share.iter())
+                .map(|((pagesz, nr), &share)| (pagesz, nr.min(share)))
+                .collect();
+        }
+    }
+
+    let mut cmds = instances
+        .iter()
+        .zip(configs.iter())
+        .enumerate()
+        .map(|(i, (x, config))| {
+            let mut cmd = Command::new(&x.program);
+            cmd.args(&x.args)
+                .envs(config.env_vars(&cli.env_prefix))
+                .env("LD_PRELOAD", &ld_preload);
+            if let (true, Some(dir)) = (multi, &cli.collect) {
+                let dir = PathBuf::from(dir).join(i.to_string());
+                fs::create_dir_all(&dir).unwrap();
+                let log = fs::File::create(dir.join("output.log")).unwrap();
+                cmd.stdout(Stdio::from(log.try_clone().unwrap()))
+                    .stderr(Stdio::from(log));
+            }
+            cmd
+        })
+        .collect::<Vec<Command>>();
+    let started = Instant::now();
+    let exits = if cli.phases.is_empty() {
+        child::run_all(
+            &mut cmds.iter_mut().collect::<Vec<&mut Command>>(),
+            cli.timeout,
+        )
+        .unwrap_or_else(|e| {
+            println!("{}", e);
+            process::exit(1);
+        })
+    } else {
+        let reserve_phase = |req: &mut HTLBReq| {
+            if !cli.dryrun && cli.backing == PoolBacking::HUGETLB {
+                match &cli.session {
+                    Some(name) => reserve_session(name, req, cli.rebalance, cli.allow_conversion),
+                    None => reserve(req, cli.rebalance, cli.allow_conversion),
+                }
+                print_htlb_status_node(req.node);
+            }
+        };
+        let command = |i: usize, config: &MosallocConfig| {
+            let mut cmd = Command::new(&instances[0].program);
+            cmd.args(&instances[0].args)
+                .envs(config.env_vars(&cli.env_prefix))
+                .env(format!("{}PHASE", cli.env_prefix), i.to_string())
+                .env("LD_PRELOAD", &ld_preload);
+            cmd
+        };
+        vec![run_phases(
+            &cli.phases,
+            &configs[0],
+            &mut htlb_req,
+            reserve_phase,
+            command,
+            cli.timeout,
+            cli.phase_signal,
+        )]
+    };
+    for (i, exit) in exits.iter().enumerate() {
+        if multi {
+            println!(
+                "instance {}: {} {}",
+                i,
+                instances[i].program,
+                exit.as_string()
+            );
+        } else {
+            println!("{}: {}", instances[i].program, exit.as_string());
+        }
+    }
+
+    if cli.on_demand {
+        let _ = fs::remove_file(on_demand::socket_path());
+    }
+    if let Some(path) = &cli.report {
+        let mode = if cli.dryrun {
+            "dryrun".to_string()
+        } else if cli.backing == PoolBacking::BASE {
+            "base".to_string()
+        } else if cli.backing != PoolBacking::HUGETLB {
+            "thp".to_string()
+        } else if let Some(name) = &cli.session {
+            format!("session {}", name)
+        } else if cli.on_demand {
+            "on-demand".to_string()
+        } else {
+            cli.reserve_strategy.as_str().to_string()
+        };
+        write_report(
+            Path::new(path),
+            &instances[0],
+            &exits[0],
+            &htlb_req,
+            mode,
+            started.elapsed(),
+        );
+    }
+    match htlb_state {
+        Some(state) if cli.release_pages => {
+            state.restore().unwrap();
+            untrack_run();
+            print_htlb_status_node(node);
+        }
+        Some(state) => {
+            state.restore_overcommit().unwrap();
+            print_htlb_overcommit_status();
+        }
+        None => {}
+    }
+    if let Some(dir) = &cli.collect {
+        for (i, config) in configs.iter().enumerate() {
+            let dir = if multi {
+                format!("{}/{}", dir, i)
+            } else {
+                dir.clone()
+            };
+            collect_files(&dir, &[&config.heatmap, &config.trace, &config.snapshot]);
+        }
+    }
+
+    // the first failure is propagated
+    process::exit(
+        exits
+            .iter()
+            .map(|x| x.code())
+            .find(|x| *x != 0)
+            .unwrap_or_default(),
     );
-    println!("{}", Command::new(cli.program).args(cli.args).exec());
 }
@@ -0,0 +1,189 @@
+use std::fs::File;
+use std::thread;
+use std::time::Duration;
+
+use mosalloc::utils::htlb::{SwapPolicy, PAGE_SIZE};
+use mosalloc::utils::misc::size_to_str;
+
+use crate::init::mosalloc;
+use crate::pagemap::{clear_soft_dirty, page_present, range_touched};
+use crate::service;
+
+// hugepage backing transitions
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Transition {
+    // remap a cold hugepage to base pages
+    DEMOTE,
+    // remap a hot demoted hugepage back to a hugepage
+    PROMOTE,
+    // push a cold THP or demoted hugepage towards swap
+    SWAP,
+}
+
+impl Transition {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Transition::DEMOTE => "demote",
+            Transition::PROMOTE => "promote",
+            Transition::SWAP => "swap",
+        }
+    }
+}
+
+// sampled state of a single pool hugepage
+#[derive(Debug)]
+pub struct PageState {
+    pub addr: usize,
+    pub pagesz: usize,
+    pub demoted: bool,
+    // backed by a THP rather than a hugetlb page, and pushed towards swap since last touched
+    pub thp: bool,
+    pub swapped: bool,
+
+    // consecutive idle / touched samples
+    pub idle: usize,
+    pub hot: usize,
+}
+
+// threshold-based aging policy: demote hugepages idle for `cold` consecutive samples, promote
+// demoted hugepages touched in `hot` consecutive samples, and push the swappable ones (THPs and
+// demoted hugepages) idle for `swap_cold` samples towards swap
+#[derive(Debug, Clone, Copy)]
+pub struct AgingPolicy {
+    pub period: u64,
+    pub cold: usize,
+    pub hot: usize,
+    pub swap: SwapPolicy,
+    pub swap_cold: usize,
+}
+
+impl AgingPolicy {
+    // the policy hook, decides the transition (if any) for a sampled hugepage, the swapped pages
+    // are left alone until touched again, remapping them would swap them back in
+    pub fn transition(&self, page: &PageState) -> Option<Transition> {
+        if page.swapped {
+            None
+        } else if self.swap != SwapPolicy::NONE
+            && (page.thp || page.demoted)
+            && page.idle >= self.swap_cold
+        {
+            Some(Transition::SWAP)
+        } else if !page.demoted && page.idle >= self.cold {
+            Some(Transition::DEMOTE)
+        } else if page.demoted && page.hot >= self.hot {
+            Some(Transition::PROMOTE)
+        } else {
+            None
+        }
+    }
+}
+
+// record the outcome of a transition applied to a sampled hugepage
+fn apply(page: &mut PageState, transition: Transition, ret: Result<(), i32>) {
+    match ret {
+        Ok(()) => {
+            match transition {
+                Transition::DEMOTE => page.demoted = true,
+                Transition::PROMOTE => page.demoted = false,
+                Transition::SWAP => page.swapped = true,
+            }
+            println!(
+                "aging: {} {} page 0x{:x}",
+                transition.as_str(),
+                size_to_str(page.pagesz),
+                page.addr
+            );
+        }
+        // the page was trimmed and mapped again as a hugepage
+        Err(libc::EINVAL) if transition == Transition::PROMOTE => {
+            page.demoted = false;
+        }
+        Err(err) => {
+            println!(
+                "aging: failed to {} {} page 0x{:x}: errno {}",
+                transition.as_str(),
+                size_to_str(page.pagesz),
+                page.addr,
+                err
+            );
+        }
+    }
+
+    page.idle = 0;
+    page.hot = 0;
+}
+
+// spawn a thread sampling the given (addr, page size, THP backed) hugepages and applying the policy
+pub fn spawn(policy: AgingPolicy, hugepages: Vec<(usize, usize, bool)>) {
+    service::spawn("aging", move || {
+        let pagemap = File::open("/proc/self/pagemap").unwrap();
+
+        let mut pages = hugepages
+            .into_iter()
+            .map(|(addr, pagesz, thp)| PageState {
+                addr,
+                pagesz,
+                demoted: false,
+                thp,
+                swapped: false,
+                idle: 0,
+                hot: 0,
+            })
+            .collect::<Vec<PageState>>();
+
+        let mut swaps = Vec::new();
+
+        clear_soft_dirty();
+
+        loop {
+            thread::sleep(Duration::from_millis(policy.period));
+
+            for (idx, page) in pages.iter_mut().enumerate() {
+                // skip hugepages that haven't been populated yet
+                if !page_present(&pagemap, page.addr) {
+                    continue;
+                }
+
+                // demoted hugepages are backed by base pages, so check all of them
+                let len = if page.demoted {
+                    page.pagesz
+                } else {
+                    *PAGE_SIZE
+                };
+                if range_touched(&pagemap, page.addr, len) {
+                    page.hot += 1;
+                    page.idle = 0;
+                    page.swapped = false;
+                } else {
+                    page.idle += 1;
+                    page.hot = 0;
+                }
+
+                match policy.transition(page) {
+                    // queued, advised at once after the sample
+                    Some(Transition::SWAP) => swaps.push(idx),
+                    Some(transition) => {
+                        let mosalloc = unsafe { mosalloc().unwrap() };
+                        let ret = mosalloc.age_page(page.addr, page.pagesz, transition);
+                        apply(page, transition, ret);
+                    }
+                    None => {}
+                }
+            }
+
+            if !swaps.is_empty() {
+                let mosalloc = unsafe { mosalloc().unwrap() };
+                let ranges = swaps
+                    .iter()
+                    .map(|&i| (pages[i].addr, pages[i].pagesz))
+                    .collect::<Vec<(usize, usize)>>();
+                for (&i, ret) in swaps.iter().zip(mosalloc.swap_pages(&ranges)) {
+                    apply(&mut pages[i], Transition::SWAP, ret);
+                }
+                swaps.clear();
+            }
+
+            clear_soft_dirty();
+        }
+    });
+}
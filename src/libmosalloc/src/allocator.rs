@@ -1,171 +1,1763 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::hint::black_box;
 use std::io::{BufRead, BufReader};
+use std::iter;
 use std::path::Path;
-use std::ptr::null;
+#[cfg(feature = "ctl")]
+use std::path::PathBuf;
+use std::process;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use libc;
 
+use crate::aging::{self, AgingPolicy, Transition};
+use crate::callers;
+use crate::compaction::{self, MoveCallback};
+#[cfg(feature = "ctl")]
+use crate::control;
+use crate::fault::FaultInjector;
+use crate::heatmap;
 use crate::internal_allocator::InternalAllocator;
+use crate::internal_maps;
+use crate::intruders;
+use crate::io_uring::{self, Registration};
+use crate::lock::Lock;
+use crate::meminfo;
+use crate::metadata;
+use crate::on_demand;
+use crate::page_limits;
 use crate::preload_hooks;
+use crate::rawio;
 use crate::region::*;
-
-use mosalloc::utils::htlb::{AllocType, MosallocConfig, Pool};
-use mosalloc::utils::misc::align_up;
+#[cfg(feature = "seccomp")]
+use crate::seccomp_hooks;
+use crate::smaps::smaps_ranges;
+use crate::threads;
+#[cfg(feature = "trace")]
+use crate::trace::{self, TraceRing};
+use crate::window::{self, Window};
+
+use mosalloc::utils::advice::{AdviceBatch, MADV_COLD, MADV_PAGEOUT};
+use mosalloc::utils::attach::{process_maps, Mapping};
+#[cfg(feature = "ctl")]
+use mosalloc::utils::control::{push, socket_path};
+use mosalloc::utils::control::{ProcessStats, POLICY_KEYS};
+use mosalloc::utils::heatmap::HeatmapInterval;
+use mosalloc::utils::htlb::{
+    get_default_htlb_size, AllocType, DrainPolicy, EarlyPolicy, HeapPolicy, HookType,
+    MosallocConfig, Pool, PoolBacking, PoolExt, ReclaimPolicy, SizeLimit, SwapPolicy, ZeroPolicy,
+    PAGE_SIZE,
+};
+use mosalloc::utils::layout::{place_below, place_regions};
+use mosalloc::utils::misc::{align_down, align_up, find_range, is_aligned, size_to_str};
+use mosalloc::utils::snapshot::AllocatorSnapshot;
+use mosalloc::utils::tlb::{reach_report, TlbModel};
+use mosalloc::utils::trace::TraceOp;
+use mosalloc_core::brk::{brk_move, BrkMove};
 
 const CHUNK: usize = 64;
+// the reclaim policies, by their index in Allocator::reclaim
+const RECLAIM_POLICIES: [ReclaimPolicy; 2] = [ReclaimPolicy::NONE, ReclaimPolicy::RELEASE];
+// how long an early request waits for the drain before failing with ENOMEM, the drain may
+// be blocked on a glibc arena lock held by the waiting thread
+const EARLY_WAIT: Duration = Duration::from_secs(1);
 const NONSTD_FLAGS: i32 =
     libc::MAP_SHARED | libc::MAP_SHARED_VALIDATE | libc::MAP_GROWSDOWN | libc::MAP_HUGETLB;
 
 #[derive(Debug)]
 pub struct Allocator {
     heap: Region,
-    anon_region: Region,
-    file_region: Region,
+    // the anon (the default one and the named ones) and file regions, sorted by their start
+    // address, and the indices of the default anon region and the file region in it
+    regions: Vec<Region>,
+    anon: usize,
+    file: usize,
     analyze: bool,
     dryrun: bool,
+    // the default hugepage size the MAP_HUGETLB requests absorbed into the pools get when they
+    // don't encode one (0 if unknown), None if they're forwarded to libc
+    hugetlb_default: Option<usize>,
+
+    drained: AtomicBool,
+    drain: DrainPolicy,
+    drain_max: Option<usize>,
+    // bytes drained and whether draining stopped at drain_max, None if skipped
+    drain_stats: Option<(usize, bool)>,
+    // TLB models whose reach over the final layout is reported at exit
+    tlb: Vec<TlbModel>,
+    // whether the pre-existing glibc heap was copied into the heap region
+    heap_copied: bool,
+    // the kernel program break once the heap region is placed, and whether it moved since; raw
+    // brk syscalls (e.g. from glibc internals) bypass the preload hooks and move it behind
+    // mosalloc's back, None with the seccomp hooks which catch them
+    kernel_brk: Option<usize>,
+    kernel_brk_moved: AtomicBool,
+
+    // the anon mmap and brk requests of the other threads before the drain completes, the
+    // draining thread and the early lock serializing the forwarded requests with the drain
+    early: EarlyPolicy,
+    drainer: i32,
+    early_lock: Lock,
+    // early requests forwarded to libc, waiting for the drain, and waiting ones that timed out
+    early_forwarded: AtomicUsize,
+    early_waited: AtomicUsize,
+    early_timedout: AtomicUsize,
+
+    heatmap: Option<String>,
+    heatmap_period: u64,
+
+    snapshot: Option<String>,
+    stats_file: Option<String>,
+
+    aging: Option<AgingPolicy>,
+
+    #[cfg(feature = "trace")]
+    trace: Option<Arc<TraceRing>>,
+    #[cfg(feature = "trace")]
+    trace_flush_period: u64,
+
+    meminfo_period: Option<u64>,
+
+    // period (ms) of the /proc/self/maps scans for foreign mappings inside the regions
+    reconcile_period: Option<u64>,
+
+    fault: Option<Arc<FaultInjector>>,
+
+    // the policies below can be changed at runtime on the control socket, the reclaim policy as
+    // its index in RECLAIM_POLICIES
+    reclaim: AtomicU8,
+
+    // (sub-pool name, protection key)
+    pkeys: Vec<(String, i32)>,
+
+    // whether any watermarks are configured, and the registered callback and its argument
+    watermarks: AtomicBool,
+    watermark_callback: Option<(WatermarkCallback, usize)>,
+
+    // the callback the compaction reports the moved ranges to, and its argument
+    move_callback: Option<(MoveCallback, usize)>,
+
+    // stats control socket
+    #[cfg(feature = "ctl")]
+    control: Option<PathBuf>,
+
+    // stats collector address and push period (ms)
+    #[cfg(feature = "ctl")]
+    collector: Option<String>,
+    #[cfg(feature = "ctl")]
+    collector_period: u64,
+
+    // window of interest, when collection doesn't span the whole run
+    window: Option<Arc<Window>>,
+}
+
+// void (*)(int region, unsigned int pct, size_t allocated, size_t size, void *arg)
+pub type WatermarkCallback =
+    unsafe extern "C" fn(libc::c_int, libc::c_uint, libc::size_t, libc::size_t, *mut libc::c_void);
+
+// absorb the mappings found inside a region before mosalloc maps anything there
+fn reconcile(region: &mut Region) {
+    let maps = BufReader::new(File::open("/proc/self/maps").unwrap());
+
+    for line in maps.lines() {
+        let line = line.unwrap();
+        let (start, end) = line
+            .split_whitespace()
+            .next()
+            .and_then(|x| x.split_once('-'))
+            .map(|(s, e)| {
+                (
+                    usize::from_str_radix(s, 16).unwrap(),
+                    usize::from_str_radix(e, 16).unwrap(),
+                )
+            })
+            .unwrap();
+
+        if end <= region.start || start >= region.max {
+            continue;
+        }
+
+        let absorbed = region.absorb(start, end);
+        if absorbed > 0 {
+            println!(
+                "reconcile: ({}) absorbed {} of foreign mapping {}",
+                region.alloc_type.as_str(),
+                size_to_str(absorbed),
+                line
+            );
+        }
+    }
+}
+
+// the current kernel program break, /proc/self/stat only has the one the program started with
+fn kernel_brk() -> usize {
+    unsafe { libc::syscall(libc::SYS_brk, 0) as usize }
+}
+
+// explain where a detached heap region ended up, relative to the program break and the mapping
+// that keeps it from being placed right past it
+fn heap_report(maps: &[Mapping], brk: usize, start: usize, len: usize) {
+    let blocker = maps
+        .iter()
+        .find(|m| m.range.end > brk && m.range.start < start);
+    let why = match blocker {
+        Some(m) => format!(
+            "{:x}-{:x} {} leaves {} past it",
+            m.range.start,
+            m.range.end,
+            if m.name.is_empty() { "[anon]" } else { &m.name },
+            size_to_str(m.range.start.saturating_sub(brk))
+        ),
+        None => "the heap policy".to_string(),
+    };
+    println!(
+        "heap: {} brk region at {:x}, {} past the program break at {:x} ({}); brk and sbrk are \
+         served from it, the kernel program break stays put, so raw brk syscalls past the hooks \
+         (see --hook-type) grow a separate heap",
+        size_to_str(len),
+        start,
+        size_to_str(start - brk),
+        brk,
+        why
+    );
+}
+
+// (region start, heap start) when the heap region can be placed over the pre-existing glibc heap,
+// which is only safe while no other thread can touch the heap
+fn heap_placement(heap: &Region) -> Option<(usize, usize)> {
+    let threads = fs::read_to_string("/proc/self/status")
+        .ok()?
+        .lines()
+        .find_map(|x| x.strip_prefix("Threads:"))
+        .and_then(|x| x.trim().parse::<usize>().ok())?;
+    let maps = fs::read_to_string("/proc/self/maps").ok()?;
+    let brk = preload_hooks::libc_sbrk(0) as usize;
+
+    place_over_heap(threads, &maps, brk, heap.len, heap.max_pgsz)
+}
+
+// the heap policy decision of heap_placement, given the thread count, the maps of the process
+// and its program break
+fn place_over_heap(
+    threads: usize,
+    maps: &str,
+    brk: usize,
+    len: usize,
+    max_pgsz: usize,
+) -> Option<(usize, usize)> {
+    if threads > 1 {
+        println!("heap: {} threads running, relocating the heap", threads);
+        return None;
+    }
+
+    let mappings = maps
+        .lines()
+        .filter_map(|line| {
+            let (range, rest) = line.split_once(' ')?;
+            let (s, e) = range.split_once('-')?;
+            Some((
+                usize::from_str_radix(s, 16).ok()?,
+                usize::from_str_radix(e, 16).ok()?,
+                rest.trim_end().ends_with("[heap]"),
+            ))
+        })
+        .collect::<Vec<(usize, usize, bool)>>();
+
+    // nothing to keep without a heap
+    let idx = mappings.iter().position(|x| x.2)?;
+    let heap_start = mappings[idx].0;
+    let start = align_down(heap_start, max_pgsz);
+
+    let fits = brk - start <= len
+        && (idx == 0 || mappings[idx - 1].1 <= start)
+        && mappings
+            .get(idx + 1)
+            .is_none_or(|x| start.checked_add(len).is_some_and(|max| x.0 >= max));
+    if !fits {
+        println!(
+            "heap: no room for the heap region at {:x}, relocating the heap",
+            start
+        );
+        return None;
+    }
 
-    drained: bool,
+    Some((start, heap_start))
+}
+
+// move the live contents of the pre-existing heap [heap_start, brk) to the heap region mapped over
+// it, glibc's pointers (main_arena top included) stay valid as the addresses don't change
+unsafe fn copy_heap(heap: &mut Region, heap_start: usize, dryrun: bool) {
+    let brk = preload_hooks::libc_sbrk(0) as usize;
+    let len = brk - heap_start;
+    let prot = libc::PROT_READ | libc::PROT_WRITE;
+    let flags = libc::MAP_ANONYMOUS | libc::MAP_PRIVATE;
+
+    let tmp = preload_hooks::libc_mmap(ptr::null_mut(), len, prot, flags, -1, 0);
+    assert!(tmp != libc::MAP_FAILED);
+    ptr::copy_nonoverlapping(heap_start as *const u8, tmp as *mut u8, len);
+
+    // nothing may malloc until the contents are back, mosalloc itself uses the internal allocator
+    assert!(preload_hooks::libc_munmap(heap_start as *mut libc::c_void, len) == 0);
+    let start = heap.start;
+    assert!(
+        heap.alloc_range(start, brk - start, prot, flags, dryrun) == start,
+        "heap: failed to map the heap region over the glibc heap"
+    );
+    ptr::copy_nonoverlapping(tmp as *const u8, heap_start as *mut u8, len);
+    heap.brk = brk;
+
+    preload_hooks::libc_munmap(tmp, len);
+    println!("heap: copied {} at {:x}", size_to_str(len), heap_start);
+}
+
+// MAP_FAILED with the errno mmap returns when mosalloc can't allocate the requested range
+unsafe fn mmap_failed(flags: i32) -> usize {
+    if (flags & libc::MAP_FIXED_NOREPLACE) != 0 {
+        // for MAP_FIXED_NOREPLACE, return EEXIST if we cannot allocate the requested addr
+        *libc::__errno_location() = libc::EEXIST;
+    } else {
+        // for the rest, just return ENOMEM
+        *libc::__errno_location() = libc::ENOMEM;
+    }
+    libc::MAP_FAILED as usize
+}
+
+// the hugepages of each size run_mosalloc reserved for the run, against the pages the pool
+// intervals require
+fn track_reservation<'a>(
+    regions: impl Iterator<Item = &'a Region> + Clone,
+    reservation: &[(usize, usize)],
+) {
+    for &(pagesz, reserved) in reservation.iter() {
+        let required = regions
+            .clone()
+            .flat_map(|x| x.intervals())
+            .filter(|x| x.2 == pagesz)
+            .map(|(start, end, _)| (end - start) / pagesz)
+            .sum::<usize>();
+        if required == 0 && reserved == 0 {
+            continue;
+        }
+
+        page_limits::set_reservation(pagesz, reserved, required);
+        if reserved < required {
+            println!(
+                "reservation: the pools require {} {} pages but only {} are reserved, the mmaps past them fail with ENOMEM",
+                required,
+                size_to_str(pagesz),
+                reserved
+            );
+        }
+    }
 }
 
 impl Allocator {
     pub fn new(config: MosallocConfig, drained: bool) -> Self {
+        metadata::init(config.protect_metadata);
+        page_limits::set(2 << 20, config.max_2mb_pages);
+        page_limits::set(1 << 30, config.max_1gb_pages);
+        on_demand::set(config.reserve_socket.as_ref());
+
         let mut heap = Region::new(
-            Pool::from_csv(AllocType::BRK, Path::new(&config.pool_config)),
+            Pool::from_config(AllocType::BRK, Path::new(&config.pool_config)),
             AllocType::BRK,
+            config.backing,
             1,
         );
 
         let mut anon_region = Region::new(
-            Pool::from_csv(AllocType::ANON, Path::new(&config.pool_config)),
+            Pool::from_config(AllocType::ANON, Path::new(&config.pool_config)),
             AllocType::ANON,
+            config.backing,
             config.anon_ffa_size,
         );
 
         let mut file_region = Region::new(
-            Pool::new_file_pool(config.file_pool_size),
+            Pool::new_file_pool(config.file_pool_size, *PAGE_SIZE),
             AllocType::FILE,
+            PoolBacking::HUGETLB,
             config.file_ffa_size,
         );
 
-        let initial_brk = align_up(preload_hooks::libc_sbrk(0) as usize, heap.max_pgsz);
+        let mut named = Pool::named_from_config(Path::new(&config.pool_config))
+            .into_iter()
+            .map(|(entry, pool)| {
+                let mut region =
+                    Region::new(pool, AllocType::ANON, config.backing, config.anon_ffa_size);
+                region.set_name(&entry.name, entry.below);
+                region
+            })
+            .collect::<Vec<Region>>();
+
+        for region in iter::once(&mut anon_region).chain(named.iter_mut()) {
+            region.enable_caches(config.cpu_caches, config.cache_batch);
+        }
+
+        let mut pkeys: Vec<(String, i32)> = Vec::new();
+        if config.pkeys {
+            for name in heap
+                .pool_names()
+                .chain(anon_region.pool_names())
+                .chain(named.iter().flat_map(|x| x.pool_names()))
+            {
+                if pkeys.iter().any(|(x, _)| x == name) {
+                    continue;
+                }
+
+                let pkey = unsafe { libc::syscall(libc::SYS_pkey_alloc, 0, 0) } as i32;
+                if pkey == -1 {
+                    println!(
+                        "pkeys: failed to allocate a key for {}: errno {}",
+                        name,
+                        unsafe { *libc::__errno_location() }
+                    );
+                    break;
+                }
+                println!("pkeys: {} -> {}", name, pkey);
+                pkeys.push((name.clone(), pkey));
+            }
+
+            heap.set_pkeys(&pkeys);
+            for region in iter::once(&mut anon_region).chain(named.iter_mut()) {
+                region.set_pkeys(&pkeys);
+            }
+        }
+
+        for region in [&mut heap, &mut anon_region, &mut file_region]
+            .into_iter()
+            .chain(named.iter_mut())
+        {
+            region.set_watermarks(&config.watermarks);
+            region.set_freemap_max(config.freemap_max);
+        }
+        heap.set_zero(config.zero);
+        heap.set_limit(config.brk_limit);
+        file_region.set_limit(config.file_limit);
+        // the anon limit applies to each anon region on its own
+        for region in iter::once(&mut anon_region).chain(named.iter_mut()) {
+            region.set_zero(config.zero);
+            region.set_placement(config.placement);
+            region.set_limit(config.anon_limit);
+        }
+        if config.strict_reservation
+            && !config.dryrun
+            && config.backing == PoolBacking::HUGETLB
+            && !on_demand::enabled()
+        {
+            if config.reservation.is_empty() {
+                println!("reservation: none passed by run_mosalloc, not tracked");
+            }
+            track_reservation(
+                iter::once(&heap)
+                    .chain(iter::once(&anon_region))
+                    .chain(&named),
+                &config.reservation,
+            );
+        }
+
+        let initial_brk = align_up(preload_hooks::libc_sbrk(0) as usize, heap.max_pgsz);
+
+        let heap_copy = match config.heap {
+            HeapPolicy::COPY => heap_placement(&heap),
+            HeapPolicy::RELOCATE | HeapPolicy::DETACH => None,
+        };
+
+        let maps = process_maps(process::id() as i32).unwrap();
+
+        // the named regions without a bound are placed past the default anon region, the bounded
+        // ones in the first gap that fits them, once the rest are placed
+        let (mut bounded, unbounded): (Vec<_>, Vec<_>) =
+            named.iter_mut().partition(|x| x.below.is_some());
+
+        // the heap region is placed over the glibc heap, look for the anon region past it
+        let (from, mut regions) = match heap_copy {
+            Some((start, _)) => {
+                heap.init(start);
+                println!("brk {:x}", start);
+                (heap.max, vec![&mut anon_region])
+            }
+            None => (initial_brk, vec![&mut heap, &mut anon_region]),
+        };
+        regions.extend(unbounded);
+        regions.push(&mut file_region);
+
+        let specs = regions
+            .iter()
+            .map(|x| (x.alloc_type, x.len, x.max_pgsz))
+            .collect::<Vec<(AllocType, usize, usize)>>();
+        // a heap region that doesn't fit right past the program break (e.g. a library mapped
+        // close to it) is detached from it
+        let mut detached = config.heap == HeapPolicy::DETACH;
+        let starts = place_regions(&maps, from, &specs, detached)
+            .or_else(|e| {
+                if detached || heap_copy.is_some() {
+                    return Err(e);
+                }
+                let starts = place_regions(&maps, from, &specs, true).map_err(|_| e.clone())?;
+                println!(
+                    "heap: {}, detaching the brk region from the program break",
+                    e
+                );
+                detached = true;
+                Ok(starts)
+            })
+            .unwrap_or_else(|e| panic!("{}", e));
+
+        let mut placed = Vec::new();
+        for (region, start) in regions.into_iter().zip(starts) {
+            region.init(start);
+            placed.push(region.start..region.max);
+
+            match region.alloc_type {
+                AllocType::BRK if detached => {
+                    println!("brk {:x}", start);
+                    heap_report(&maps, initial_brk, start, region.len);
+                }
+                AllocType::BRK => {
+                    // move the program break to the start of the mosalloc managed heap
+                    assert!(preload_hooks::libc_brk(start as *mut libc::c_void) != -1);
+                    println!("brk {:x}", start);
+                }
+                AllocType::ANON => println!("{} {:x}", region.label(), start),
+                AllocType::FILE => println!("file {:x}", start),
+            }
+        }
+
+        for region in bounded.iter_mut() {
+            let start = place_below(
+                &maps,
+                &placed,
+                region.len,
+                region.max_pgsz,
+                region.below.unwrap(),
+            )
+            .unwrap_or_else(|e| panic!("{}: {}", region.label(), e));
+            region.init(start);
+            placed.push(region.start..region.max);
+            println!("{} {:x}", region.label(), start);
+        }
+
+        // FIXME: workaround to initialize the hooks
+        preload_hooks::libc_mmap(usize::MAX as *mut libc::c_void, 0, 0, 0, -1, 0);
+        preload_hooks::libc_munmap(usize::MAX as *mut libc::c_void, 0);
+        preload_hooks::libc_mprotect(usize::MAX as *mut libc::c_void, 0, 0);
+        preload_hooks::libc_madvise(usize::MAX as *mut libc::c_void, 0, 0);
+        preload_hooks::libc_mremap(
+            usize::MAX as *mut libc::c_void,
+            0,
+            0,
+            0,
+            usize::MAX as *mut libc::c_void,
+        );
+
+        if let Some((_, heap_start)) = heap_copy {
+            unsafe { copy_heap(&mut heap, heap_start, config.dryrun) };
+        }
+
+        // restored regions aren't backed by memory, so only allow it when simulating
+        if let Some(restore) = &config.restore {
+            assert!(
+                config.dryrun,
+                "snapshots can only be restored in dryrun mode"
+            );
+
+            let snapshot = AllocatorSnapshot::from_path(Path::new(restore)).unwrap();
+            for region in [&mut heap, &mut anon_region, &mut file_region]
+                .into_iter()
+                .chain(named.iter_mut())
+            {
+                if let Some(s) = snapshot.region(region.alloc_type, region.name.as_deref()) {
+                    region.restore(s);
+                }
+            }
+        }
+
+        // mappings created since the scan above (e.g. by other preloaded libraries' threads)
+        // (the copied heap was placed while single-threaded, and its own mappings aren't foreign)
+        if heap_copy.is_none() {
+            reconcile(&mut heap);
+        }
+        for region in [&mut anon_region, &mut file_region]
+            .into_iter()
+            .chain(named.iter_mut())
+        {
+            reconcile(region);
+        }
+
+        // the zeroing happens before the program starts, instead of on its first touches
+        if config.zero == ZeroPolicy::PREFAULT {
+            heap.prefault(config.dryrun);
+            for region in iter::once(&mut anon_region).chain(named.iter_mut()) {
+                region.prefault(config.dryrun);
+            }
+        }
+
+        // the region maps are only written under the region locks from now on
+        metadata::seal();
+
+        let mut regions = [anon_region, file_region]
+            .into_iter()
+            .chain(named)
+            .collect::<Vec<Region>>();
+        regions.sort_by_key(|x| x.start);
+        // the anon regions grow up to the next region, if allowed to
+        if let Some(max) = config.anon_ffa_max {
+            let starts = regions
+                .iter()
+                .map(|x| x.start)
+                .chain(iter::once(heap.start))
+                .collect::<Vec<usize>>();
+            for region in regions
+                .iter_mut()
+                .filter(|x| x.alloc_type == AllocType::ANON)
+            {
+                let next = starts
+                    .iter()
+                    .copied()
+                    .filter(|x| *x >= region.max)
+                    .min()
+                    .unwrap_or(usize::MAX);
+                let limit = region
+                    .max
+                    .saturating_add(align_down(max, *PAGE_SIZE))
+                    .min(next)
+                    .min(region.below.unwrap_or(usize::MAX));
+                region.set_grow_limit(limit);
+            }
+        }
+        let anon = regions
+            .iter()
+            .position(|x| x.alloc_type == AllocType::ANON && x.name.is_none())
+            .unwrap();
+        let file = regions
+            .iter()
+            .position(|x| x.alloc_type == AllocType::FILE)
+            .unwrap();
+
+        Self {
+            heap,
+            regions,
+            anon,
+            file,
+            analyze: config.analyze_regions,
+            dryrun: config.dryrun,
+            hugetlb_default: config
+                .absorb_hugetlb
+                .then(|| get_default_htlb_size().unwrap_or(0)),
+            drained: AtomicBool::new(drained),
+            drain: config.drain,
+            drain_max: config.drain_max,
+            drain_stats: None,
+            tlb: config.tlb.clone(),
+            heap_copied: heap_copy.is_some(),
+            // the kernel break is expected to move when brk isn't hooked
+            kernel_brk: (config.hook == HookType::PRELOAD && config.hooks.contains(&TraceOp::BRK))
+                .then(kernel_brk),
+            kernel_brk_moved: AtomicBool::new(false),
+            early: config.early,
+            drainer: unsafe { libc::gettid() },
+            early_lock: Lock::new(true, "early"),
+            early_forwarded: AtomicUsize::new(0),
+            early_waited: AtomicUsize::new(0),
+            early_timedout: AtomicUsize::new(0),
+            heatmap: config.heatmap,
+            heatmap_period: config.heatmap_period,
+            snapshot: config.snapshot,
+            stats_file: config.stats_file,
+            aging: config.aging_period.map(|period| AgingPolicy {
+                period,
+                cold: config.aging_cold,
+                hot: config.aging_hot,
+                swap: config.swap,
+                swap_cold: config.swap_cold,
+            }),
+            #[cfg(feature = "trace")]
+            trace: config
+                .trace
+                .map(|path| Arc::new(TraceRing::new(&path, config.trace_size))),
+            #[cfg(feature = "trace")]
+            trace_flush_period: config.trace_flush_period,
+            // there's nothing to reconcile unless the pools are backed by hugetlb pages
+            meminfo_period: config
+                .meminfo_period
+                .filter(|_| !config.dryrun && config.backing == PoolBacking::HUGETLB),
+            reconcile_period: config.reconcile_period,
+            // with only MOSALLOC_FAULT_AFTER_N set, every selected call after the first N fails
+            fault: (config.fault_rate.is_some() || config.fault_after.is_some()).then(|| {
+                Arc::new(FaultInjector::new(
+                    &config.fault_ops,
+                    config.fault_rate.unwrap_or(1.0),
+                    config.fault_after.unwrap_or(0),
+                    config.fault_seed,
+                ))
+            }),
+            reclaim: AtomicU8::new(
+                RECLAIM_POLICIES
+                    .iter()
+                    .position(|x| *x == config.reclaim)
+                    .unwrap() as u8,
+            ),
+            pkeys,
+            watermarks: AtomicBool::new(!config.watermarks.is_empty()),
+            watermark_callback: None,
+            move_callback: None,
+            #[cfg(feature = "ctl")]
+            control: config
+                .control_dir
+                .map(|dir| socket_path(Path::new(&dir), process::id() as i32)),
+            #[cfg(feature = "ctl")]
+            collector: config.collector,
+            #[cfg(feature = "ctl")]
+            collector_period: config.collector_period,
+            window: (config.window_start.is_some() || config.window_stop.is_some()).then(|| {
+                Arc::new(Window::new(
+                    config.window_start,
+                    config.window_stop,
+                    config.window_period,
+                ))
+            }),
+        }
+    }
+
+    // every region, the heap first and the rest by their start address
+    fn all_regions(&mut self) -> impl Iterator<Item = &mut Region> {
+        iter::once(&mut self.heap).chain(self.regions.iter_mut())
+    }
+
+    // the brk and anon regions, the ones backed by the hugepage pools
+    fn pool_regions(&self) -> impl Iterator<Item = &Region> {
+        iter::once(&self.heap).chain(
+            self.regions
+                .iter()
+                .filter(|x| x.alloc_type == AllocType::ANON),
+        )
+    }
+
+    fn pool_regions_mut(&mut self) -> impl Iterator<Item = &mut Region> {
+        iter::once(&mut self.heap).chain(
+            self.regions
+                .iter_mut()
+                .filter(|x| x.alloc_type == AllocType::ANON),
+        )
+    }
+
+    // the heap, the default anon region or the file region
+    fn default_region(&mut self, alloc_type: AllocType) -> &mut Region {
+        match alloc_type {
+            AllocType::BRK => &mut self.heap,
+            AllocType::ANON => &mut self.regions[self.anon],
+            AllocType::FILE => &mut self.regions[self.file],
+        }
+    }
+
+    // the brk or anon region of addr
+    fn pool_region_from_addr(&mut self, addr: usize) -> Option<&mut Region> {
+        if self.heap.contains(addr) {
+            return Some(&mut self.heap);
+        }
+        self.region_from_addr(addr)
+            .filter(|x| x.alloc_type == AllocType::ANON)
+    }
+
+    // (addr, page size, THP backed) of every hugepage in the brk and anon pools
+    pub fn hugepages(&self) -> Vec<(usize, usize, bool)> {
+        self.pool_regions()
+            .flat_map(|r| {
+                let thp = r.thp_backed();
+                r.intervals().map(move |x| (x, thp))
+            })
+            .filter(|((_, _, pagesz), _)| *pagesz > *PAGE_SIZE)
+            .flat_map(|((start, end, pagesz), thp)| {
+                (start..end).step_by(pagesz).map(move |x| (x, pagesz, thp))
+            })
+            .collect()
+    }
+
+    // start the hugepage aging policy, if enabled
+    pub fn spawn_aging(&self) {
+        if self.dryrun {
+            return;
+        }
+
+        if let Some(policy) = self.aging {
+            aging::spawn(policy, self.hugepages());
+        }
+    }
+
+    // apply an aging transition to the pool hugepage at addr
+    pub fn age_page(
+        &mut self,
+        addr: usize,
+        pagesz: usize,
+        transition: Transition,
+    ) -> Result<(), i32> {
+        if transition == Transition::SWAP {
+            return self.swap_pages(&[(addr, pagesz)]).remove(0);
+        }
+
+        let region = self.pool_region_from_addr(addr).ok_or(libc::EINVAL)?;
+
+        region.lock();
+        let ret = if transition == Transition::DEMOTE {
+            region.demote_page(addr, pagesz)
+        } else {
+            region.promote_page(addr, pagesz)
+        };
+        region.unlock();
+
+        ret
+    }
+
+    // push the given (addr, page size) cold pool pages towards swap, with a single
+    // process_madvise where possible, returns the result for each page
+    pub fn swap_pages(&mut self, pages: &[(usize, usize)]) -> Vec<Result<(), i32>> {
+        let advice = match self.aging.map_or(SwapPolicy::NONE, |x| x.swap) {
+            SwapPolicy::NONE => return pages.iter().map(|_| Ok(())).collect(),
+            SwapPolicy::COLD => MADV_COLD,
+            SwapPolicy::PAGEOUT => MADV_PAGEOUT,
+        };
+
+        let mut batch = AdviceBatch::local(advice);
+        let mut ret = pages
+            .iter()
+            .map(|&(addr, pagesz)| {
+                let region = self.pool_region_from_addr(addr).ok_or(libc::EINVAL)?;
+                region.lock();
+                let ret = region.swap_page(addr, pagesz, &mut batch);
+                region.unlock();
+                ret
+            })
+            .collect::<Vec<Result<(), i32>>>();
+
+        // applied once the region locks are released, the advice is only a hint anyway
+        if let Err(err) = batch.flush() {
+            ret.iter_mut()
+                .filter(|x| x.is_ok())
+                .for_each(|x| *x = Err(err as i32));
+        }
+
+        ret
+    }
+
+    // save the bookkeeping state of all the regions, if a snapshot file was requested
+    pub fn save_snapshot(&mut self) {
+        if let Some(path) = &self.snapshot {
+            let mut snapshot = AllocatorSnapshot::default();
+            for region in iter::once(&mut self.heap).chain(self.regions.iter_mut()) {
+                region.lock();
+                snapshot.regions.push(region.snapshot());
+                region.unlock();
+            }
+
+            snapshot.save(Path::new(path)).unwrap();
+        }
+    }
+
+    // iterate over the allocated ranges of all the mosalloc regions
+    pub fn allocations(&self) -> impl Iterator<Item = AllocationInfo> + '_ {
+        iter::once(&self.heap)
+            .chain(self.regions.iter())
+            .flat_map(|x| x.allocations())
+    }
+
+    // synchronously collapse a THP-backed pool range, returns the bytes of the THPs collapsed
+    pub fn collapse(&mut self, addr: usize, len: usize) -> Result<usize, i32> {
+        let region = self.pool_region_from_addr(addr).ok_or(libc::EINVAL)?;
+
+        region.lock();
+        let ret = region.collapse(addr, len);
+        region.unlock();
+
+        ret
+    }
+
+    // the page size backing addr in the bookkeeping, None outside the regions
+    pub fn page_size_of(&mut self, addr: usize) -> Option<usize> {
+        let dryrun = self.dryrun;
+        let region = if self.heap.contains(addr) {
+            &mut self.heap
+        } else {
+            self.region_from_addr(addr)?
+        };
+
+        region.lock();
+        let ret = region.page_size_of(addr, dryrun);
+        region.unlock();
+
+        Some(ret)
+    }
+
+    // (used bytes, high-water mark, program break) of a region, by its index as in the watermark
+    // callback (the default anon region for mmap)
+    pub fn usage(&mut self, region: i32) -> Option<(usize, usize, usize)> {
+        let region = match region {
+            0 => self.default_region(AllocType::BRK),
+            1 => self.default_region(AllocType::ANON),
+            2 => self.default_region(AllocType::FILE),
+            _ => return None,
+        };
+
+        region.lock();
+        let ret = region.extent();
+        region.unlock();
+
+        Some(ret)
+    }
+
+    pub fn set_watermark_callback(&mut self, callback: Option<(WatermarkCallback, usize)>) {
+        self.watermark_callback = callback;
+    }
+
+    pub fn set_move_callback(&mut self, callback: Option<(MoveCallback, usize)>) {
+        self.move_callback = callback;
+    }
+
+    // track the fixed buffers an io_uring_register call on the ring fd (un)registered, keeping
+    // their pages in place for as long as the ring may use them
+    pub fn io_uring_register(&mut self, fd: i32, reg: Registration) {
+        io_uring::record(&reg);
+
+        for region in self.all_regions() {
+            region.lock();
+            match &reg {
+                Registration::Pin(ranges) => {
+                    for x in ranges.iter() {
+                        let start = untagged(x.start);
+                        region.pin(PinOwner::IoUring(fd), start, start + x.len());
+                    }
+                }
+                Registration::Unpin => region.unpin_all(PinOwner::IoUring(fd)),
+                Registration::Other => (),
+            }
+            region.unlock();
+        }
+    }
+
+    // pin [addr, addr + len) for a device of the application (an RDMA memory region, a CUDA host
+    // registration ...), the ranges outside the regions are left alone anyway
+    pub fn pin(&mut self, addr: usize, len: usize) {
+        let addr = untagged(addr);
+        for region in self.all_regions() {
+            region.lock();
+            region.pin(PinOwner::Application, addr, addr + len);
+            region.unlock();
+        }
+    }
+
+    // drop the application's pins starting at addr
+    pub fn unpin(&mut self, addr: usize) -> Result<(), i32> {
+        let addr = untagged(addr);
+        let mut ret = Err(libc::ENOENT);
+        for region in self.all_regions() {
+            region.lock();
+            if region.unpin(addr).is_ok() {
+                ret = Ok(());
+            }
+            region.unlock();
+        }
+
+        ret
+    }
+
+    pub fn set_pin_callback(&mut self, callback: Option<(PinCallback, usize)>) {
+        for region in self.all_regions() {
+            region.lock();
+            region.set_pin_callback(callback);
+            region.unlock();
+        }
+    }
+
+    pub fn register_movable(&mut self, addr: usize, len: usize) -> Result<(), i32> {
+        let region = self
+            .region_from_addr(addr)
+            .filter(|x| x.alloc_type == AllocType::ANON)
+            .ok_or(libc::EINVAL)?;
+
+        region.lock();
+        let ret = region.register_movable(addr, len);
+        region.unlock();
+
+        ret
+    }
+
+    pub fn unregister_movable(&mut self, addr: usize) -> Result<(), i32> {
+        let region = self
+            .region_from_addr(addr)
+            .filter(|x| x.alloc_type == AllocType::ANON)
+            .ok_or(libc::ENOENT)?;
+
+        region.lock();
+        let ret = region.unregister_movable(addr);
+        region.unlock();
+
+        ret
+    }
+
+    // move the movable ranges of the anon regions down to squeeze their free space together,
+    // reporting each move to the callback once the region is unlocked; experimental, the
+    // application must not touch the ranges until told where they went
+    pub fn compact(&mut self) -> Result<Vec<String>, String> {
+        let (callback, arg) = self
+            .move_callback
+            .ok_or_else(|| "no move callback registered".to_string())?;
+        let dryrun = self.dryrun;
+
+        let mut out = Vec::new();
+        for region in self.regions.iter_mut() {
+            if region.alloc_type != AllocType::ANON {
+                continue;
+            }
+
+            region.lock();
+            let x = region.compact(dryrun);
+            region.unlock();
+
+            for m in x.moves.iter() {
+                unsafe {
+                    callback(
+                        m.from as *mut libc::c_void,
+                        m.to as *mut libc::c_void,
+                        m.len,
+                        arg as *mut libc::c_void,
+                    )
+                };
+            }
+            compaction::record(&x);
+            out.push(x.as_string(&region.label()));
+        }
+
+        Ok(out)
+    }
+
+    // report the watermarks crossed by the last call, outside of the region locks so that the
+    // callback can allocate
+    fn check_watermarks(&mut self) {
+        if !self.watermarks.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let callback = self.watermark_callback;
+
+        for region in self.all_regions() {
+            region.lock();
+            let pct = region.take_watermark();
+            region.unlock();
+
+            if let Some(pct) = pct {
+                let (allocated, len) = region.usage();
+                println!(
+                    "watermark: ({}) {}% used, {} of {}",
+                    region.label(),
+                    pct,
+                    size_to_str(allocated),
+                    size_to_str(len)
+                );
+                region.print_stats();
+
+                if let Some((callback, arg)) = callback {
+                    unsafe {
+                        callback(
+                            region.alloc_type as libc::c_int,
+                            pct as libc::c_uint,
+                            allocated,
+                            len,
+                            arg as *mut libc::c_void,
+                        )
+                    };
+                }
+            }
+        }
+    }
+
+    // protection key of a named sub-pool
+    pub fn pkey(&self, name: &str) -> Option<i32> {
+        self.pkeys
+            .iter()
+            .find(|(x, _)| x == name)
+            .map(|(_, pkey)| *pkey)
+    }
+
+    // number of protection keys held by mosalloc
+    pub fn nr_pkeys(&self) -> usize {
+        self.pkeys.len()
+    }
+
+    pub fn pkey_mprotect(&mut self, addr: usize, len: usize, prot: i32, pkey: i32) -> i32 {
+        println!("pkey_mprotect 0x{:x} {} {} {}", addr, len, prot, pkey);
+
+        let region = if self.heap.contains(addr) {
+            Some(&mut self.heap)
+        } else {
+            self.region_from_addr(addr)
+        };
+
+        match region {
+            Some(region) if region.alloc_type != AllocType::FILE => {
+                region.lock();
+                let ret = region.pkey_mprotect(untagged(addr), len, prot, pkey);
+                region.unlock();
+                ret
+            }
+            // forward the rest to the kernel
+            _ => unsafe { libc::syscall(libc::SYS_pkey_mprotect, addr, len, prot, pkey) as i32 },
+        }
+    }
+
+    // sub-pool keys can't be freed by the application
+    pub fn pkey_free(&mut self, pkey: i32) -> i32 {
+        if self.pkeys.iter().any(|(_, x)| *x == pkey) {
+            unsafe { *libc::__errno_location() = libc::EINVAL };
+            return -1;
+        }
+
+        unsafe { libc::syscall(libc::SYS_pkey_free, pkey) as i32 }
+    }
+
+    // release the fully free pool hugepages of the brk and anon regions, according to the reclaim
+    // policy, returns the released bytes
+    pub fn trim(&mut self) -> usize {
+        if self.reclaim() == ReclaimPolicy::NONE {
+            return 0;
+        }
+
+        let dryrun = self.dryrun;
+        let mut released = 0;
+        for region in self.pool_regions_mut() {
+            region.lock();
+            released += region.trim(dryrun);
+            region.unlock();
+        }
+
+        println!("trim: released {}", size_to_str(released));
+        released
+    }
+
+    pub fn print_stats(&self) {
+        self.check_kernel_brk();
+        self.heap.print_stats();
+        for region in self.regions.iter() {
+            region.print_stats();
+        }
+        #[cfg(feature = "trace")]
+        if let Some(trace) = &self.trace {
+            trace.print_stats();
+        }
+        if let Some(fault) = &self.fault {
+            fault.print_stats();
+        }
+        if let Some(window) = &self.window {
+            window.print_stats();
+        }
+        page_limits::print_stats();
+        on_demand::print_stats();
+        compaction::print_stats();
+        io_uring::print_stats();
+        InternalAllocator::print_stats();
+        internal_maps::print_stats();
+        preload_hooks::print_nested_stats();
+        callers::print_stats();
+        threads::print_stats();
+        #[cfg(feature = "seccomp")]
+        seccomp_hooks::print_caught_stats();
+        match self.drain_stats {
+            Some((drained, bounded)) => println!(
+                "drain: {} drained{}",
+                size_to_str(drained),
+                if bounded { " (bounded)" } else { "" }
+            ),
+            None if self.drained.load(Ordering::Relaxed) => println!("drain: skipped"),
+            None => {}
+        }
+
+        let forwarded = self.early_forwarded.load(Ordering::Relaxed);
+        let waited = self.early_waited.load(Ordering::Relaxed);
+        if forwarded > 0 || waited > 0 {
+            println!(
+                "early: {} forwarded, {} waited for the drain ({} timed out)",
+                forwarded,
+                waited,
+                self.early_timedout.load(Ordering::Relaxed)
+            );
+        }
+
+        if !self.tlb.is_empty() {
+            let backed = self.backed();
+            if backed.iter().any(|(_, bytes)| *bytes > 0) {
+                for line in reach_report(&self.tlb, &backed) {
+                    println!("{}", line);
+                }
+            }
+        }
+    }
+
+    // bytes backed per page size over all the regions, according to smaps: the resident THPs
+    // as 2MB pages, the rest of the resident memory as base pages, plus the hugetlb pages mapped
+    fn backed(&self) -> Vec<(usize, usize)> {
+        let regions = iter::once(&self.heap)
+            .chain(self.regions.iter())
+            .collect::<Vec<_>>();
+        let ranges = regions.iter().map(|x| x.start..x.max).collect::<Vec<_>>();
+        let residency = smaps_ranges(&ranges, &["Rss", "AnonHugePages"]);
+
+        let mut backed = vec![(*PAGE_SIZE, 0), (2 << 20, 0)];
+        for (region, x) in regions.iter().zip(residency) {
+            backed[0].1 += x[0].saturating_sub(x[1]);
+            backed[1].1 += x[1];
+            for &(pagesz, nr) in region.htlb_mapped() {
+                match backed.iter_mut().find(|(sz, _)| *sz == pagesz) {
+                    Some((_, bytes)) => *bytes += pagesz * nr,
+                    None => backed.push((pagesz, pagesz * nr)),
+                }
+            }
+        }
+        backed.sort();
+        backed
+    }
+
+    // start the background threads (heatmap sampler, aging policy, trace flusher, meminfo
+    // checker, window trigger poller)
+    pub fn spawn_services(&self) {
+        self.spawn_heatmap();
+        self.spawn_aging();
+        #[cfg(feature = "trace")]
+        if let Some(trace) = &self.trace {
+            trace::spawn(trace.clone(), self.trace_flush_period);
+        }
+        if let Some(period) = self.meminfo_period {
+            meminfo::spawn(period, self.pool_hugepages());
+        }
+        if let Some(period) = self.reconcile_period {
+            intruders::spawn(period);
+        }
+        #[cfg(feature = "ctl")]
+        if let Some(path) = &self.control {
+            control::spawn(path.clone());
+        }
+        #[cfg(feature = "ctl")]
+        if let Some(collector) = &self.collector {
+            control::spawn_push(collector.clone(), self.collector_period);
+        }
+        if let Some(window) = &self.window {
+            window::spawn(window.clone());
+        }
+    }
+
+    // exclude the foreign mappings found inside the regions from their free maps, logging the
+    // intruders
+    pub fn absorb_intruders(&mut self, maps: &[Mapping]) {
+        for region in self.all_regions() {
+            let (start, max) = (region.start, region.max);
+            for m in maps
+                .iter()
+                .filter(|x| x.range.start < max && x.range.end > start)
+            {
+                // mosalloc's own pool pages are anonymous, hugetlb ones included
+                let anonymous = matches!(m.name.as_str(), "" | "/anon_hugepage" | "[heap]");
+
+                region.lock();
+                let absorbed = region.absorb_intruder(m.range.start, m.range.end, anonymous);
+                region.unlock();
+
+                if absorbed > 0 {
+                    println!(
+                        "intruders: ({}) foreign mapping {:x}-{:x} {}, excluded {} from the free \
+                         map",
+                        region.label(),
+                        m.range.start,
+                        m.range.end,
+                        if anonymous { "(anonymous)" } else { &m.name },
+                        size_to_str(absorbed)
+                    );
+                }
+            }
+        }
+    }
+
+    // current stats of all the regions, as served on the control socket, along with their
+    // residency according to smaps, read in a single pass without the region locks
+    pub fn stats(&mut self) -> ProcessStats {
+        let mut stats = ProcessStats::current();
+        let mut ranges = Vec::new();
+        let mut thp = Vec::new();
+
+        for region in self.all_regions() {
+            region.lock();
+            stats.regions.push(region.stats());
+            region.unlock();
+
+            ranges.push(region.start..region.max);
+            thp.push(region.thp_backed());
+        }
+
+        let residency = smaps_ranges(
+            &ranges,
+            &["AnonHugePages", "Rss", "Private_Hugetlb", "Swap"],
+        );
+        for ((s, thp), x) in stats.regions.iter_mut().zip(thp).zip(residency) {
+            if thp {
+                s.huge = x[0];
+            }
+            s.rss = x[1];
+            s.private_hugetlb = x[2];
+            s.swap = x[3];
+        }
+        stats.internal = Some(InternalAllocator::stats());
+
+        stats
+    }
+
+    // bytes allocated from all the regions since startup
+    pub fn bytes_allocated(&mut self) -> usize {
+        self.stats().regions.iter().map(|x| x.bytes_allocated).sum()
+    }
+
+    pub fn open_window(&mut self) {
+        if let Some(window) = self.window.clone() {
+            window.open(self.stats().regions);
+        }
+    }
+
+    // also called at exit, closing a window still open
+    pub fn close_window(&mut self) {
+        if let Some(window) = self.window.clone() {
+            window.close(self.stats().regions);
+        }
+    }
+
+    fn reclaim(&self) -> ReclaimPolicy {
+        RECLAIM_POLICIES[self.reclaim.load(Ordering::Relaxed) as usize]
+    }
+
+    // the current values of the policies that can be changed at runtime
+    pub fn policies(&mut self) -> Vec<(String, String)> {
+        // the watermarks are the same for all the regions
+        self.heap.lock();
+        let watermarks = self
+            .heap
+            .watermarks()
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>()
+            .join(",");
+        self.heap.unlock();
+
+        let mut policies = vec![
+            ("reclaim".to_string(), self.reclaim().as_str().to_string()),
+            ("watermarks".to_string(), watermarks),
+            (
+                "mmap_threshold".to_string(),
+                InternalAllocator::mmap_threshold().to_string(),
+            ),
+        ];
+        // the anon limit is the same for all the anon regions
+        for (key, alloc_type) in [
+            ("brk_limit", AllocType::BRK),
+            ("anon_limit", AllocType::ANON),
+            ("file_limit", AllocType::FILE),
+        ] {
+            let region = self.default_region(alloc_type);
+            region.lock();
+            policies.push((key.to_string(), region.limit().as_string()));
+            region.unlock();
+        }
+
+        // label:index of the disabled intervals of all the regions
+        let mut disabled = Vec::new();
+        for region in self.all_regions() {
+            region.lock();
+            let label = region.label();
+            disabled.extend(
+                region
+                    .disabled_intervals()
+                    .iter()
+                    .map(|i| format!("{}:{}", label, i)),
+            );
+            region.unlock();
+        }
+        if disabled.is_empty() {
+            disabled.push("none".to_string());
+        }
+        policies.push(("disabled_intervals".to_string(), disabled.join(",")));
+
+        policies
+    }
+
+    // change a policy at runtime, the layout (pools, regions) can't be changed
+    pub fn set_policy(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "reclaim" => {
+                let reclaim = value.parse::<ReclaimPolicy>()?;
+                let idx = RECLAIM_POLICIES.iter().position(|x| *x == reclaim).unwrap();
+                self.reclaim.store(idx as u8, Ordering::Relaxed);
+            }
+            "watermarks" => {
+                // none (or empty) disables them
+                let watermarks = value
+                    .split(',')
+                    .filter(|pct| !pct.is_empty() && *pct != "none")
+                    .map(|pct| {
+                        pct.parse::<usize>()
+                            .map_err(|_| format!("Invalid watermark: {}", pct))
+                    })
+                    .collect::<Result<Vec<usize>, String>>()?;
+
+                for region in self.all_regions() {
+                    region.lock();
+                    region.set_watermarks(&watermarks);
+                    region.unlock();
+                }
+                self.watermarks
+                    .store(!watermarks.is_empty(), Ordering::Relaxed);
+            }
+            "mmap_threshold" => {
+                InternalAllocator::set_mmap_threshold(
+                    value
+                        .parse::<usize>()
+                        .map_err(|_| format!("Invalid mmap threshold: {}", value))?,
+                );
+            }
+            "brk_limit" | "anon_limit" | "file_limit" => {
+                let limit = value.parse::<SizeLimit>()?;
+                let alloc_type = match key {
+                    "brk_limit" => AllocType::BRK,
+                    "anon_limit" => AllocType::ANON,
+                    _ => AllocType::FILE,
+                };
+                // the anon limit applies to each anon region on its own
+                for region in self.all_regions().filter(|x| x.alloc_type == alloc_type) {
+                    region.lock();
+                    region.set_limit(limit);
+                    region.unlock();
+                }
+            }
+            "disabled_intervals" => {
+                // label:index of the intervals to disable, the rest are enabled, none (or empty)
+                // enables them all
+                let disabled = value
+                    .split(',')
+                    .filter(|x| !x.is_empty() && *x != "none")
+                    .map(|x| {
+                        x.rsplit_once(':')
+                            .and_then(|(label, i)| Some((label, i.parse::<usize>().ok()?)))
+                            .ok_or_else(|| format!("Invalid interval: {}", x))
+                    })
+                    .collect::<Result<Vec<(&str, usize)>, String>>()?;
+
+                // checked up front, so that a bad value leaves the intervals as they were
+                for (label, i) in disabled.iter() {
+                    match self.all_regions().find(|x| x.label() == *label) {
+                        Some(x) if *i < x.intervals().count() => {}
+                        Some(_) => return Err(format!("No interval {} in {}", i, label)),
+                        None => return Err(format!("Unknown region: {}", label)),
+                    }
+                }
+
+                for region in self.all_regions() {
+                    region.lock();
+                    let label = region.label();
+                    for i in region.disabled_intervals().to_vec() {
+                        region.enable_interval(i);
+                    }
+                    for (_, i) in disabled.iter().filter(|(x, _)| *x == label) {
+                        region.disable_interval(*i).unwrap();
+                    }
+                    region.unlock();
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "Unknown policy {} (expected one of {})",
+                    key,
+                    POLICY_KEYS.join(", ")
+                ))
+            }
+        }
+
+        println!("control: {} set to {}", key, value);
+        Ok(())
+    }
 
-        // TODO: split this to a separate function
-        let maps = BufReader::new(File::open("/proc/self/maps").unwrap());
+    // write the final stats to the stats file, if one was requested
+    pub fn save_stats(&mut self) {
+        if let Some(path) = self.stats_file.clone() {
+            let mut stats = self.stats();
+            stats.last = true;
+            if let Err(e) = rawio::write_file(&path, stats.to_text().as_bytes()) {
+                println!("stats: {}", e);
+            }
+        }
+    }
 
-        let mut last = 0;
-        let mut upper = initial_brk;
-        let mut region = &mut heap;
+    // remove the control socket and send the final stats to the collector, called at exit
+    #[cfg(feature = "ctl")]
+    pub fn close_control(&mut self) {
+        if let Some(path) = &self.control {
+            let _ = fs::remove_file(path);
+        }
 
-        'outer: for line in maps.lines() {
-            let line = line.unwrap();
-            let mapping: Vec<&str> = line.split_whitespace().collect();
+        if let Some(collector) = self.collector.clone() {
+            let mut stats = self.stats();
+            stats.last = true;
+            if let Err(e) = push(&collector, &stats) {
+                println!("collector: {}", e);
+            }
+        }
+    }
 
-            let addr_range: Vec<usize> = mapping[0]
-                .splitn(2, '-')
-                .map(|x| usize::from_str_radix(x, 16).unwrap())
-                .collect();
+    // (page size, nr) of the hugepages in the brk and anon pools
+    fn pool_hugepages(&self) -> Vec<(usize, usize)> {
+        let mut out: Vec<(usize, usize)> = Vec::new();
+        for (pagesz, nr) in self
+            .pool_regions()
+            .flat_map(|r| r.intervals())
+            .map(|(start, end, pagesz)| (pagesz, (end - start) / pagesz))
+        {
+            match out.iter_mut().find(|(sz, _)| *sz == pagesz) {
+                Some((_, total)) => *total += nr,
+                None => out.push((pagesz, nr)),
+            }
+        }
+        out
+    }
 
-            if last < upper {
-                last = align_up(addr_range[1], region.max_pgsz);
-                continue;
+    // (page size, nr) of the hugetlb pages currently mapped by the brk and anon regions
+    pub fn htlb_mapped(&mut self) -> Vec<(usize, usize)> {
+        let mut out: Vec<(usize, usize)> = Vec::new();
+        for region in self.pool_regions_mut() {
+            region.lock();
+            for &(pagesz, nr) in region.htlb_mapped() {
+                match out.iter_mut().find(|(sz, _)| *sz == pagesz) {
+                    Some((_, total)) => *total += nr,
+                    None => out.push((pagesz, nr)),
+                }
             }
+            region.unlock();
+        }
+        out
+    }
 
-            // found gap for the region
-            while last >= upper && last < addr_range[0] && addr_range[0] - last >= region.len {
-                region.init(last);
-                upper = region.max;
+    // drain whatever is left in the trace ring, called at exit
+    #[cfg(feature = "trace")]
+    pub fn flush_trace(&self) {
+        if let Some(trace) = &self.trace {
+            trace.flush();
+        }
+    }
 
-                match region.alloc_type {
-                    AllocType::BRK => {
-                        // move the program break to the start of the mosalloc managed heap
-                        assert!(preload_hooks::libc_brk(heap.start as *mut libc::c_void) != -1);
-                        println!("brk {:x}", last);
+    // whether an allocation failure should be injected for op, only once drained so that mosalloc
+    // itself isn't affected
+    #[inline]
+    fn inject_fault(&self, op: TraceOp) -> bool {
+        self.drained.load(Ordering::Relaxed) && self.fault.as_ref().is_some_and(|x| x.inject(op))
+    }
 
-                        region = &mut anon_region;
-                        upper = align_up(upper, region.max_pgsz);
-                        last = upper;
-                    }
-                    AllocType::ANON => {
-                        println!("mmap {:x}", last);
-                        region = &mut file_region;
-                        upper = align_up(upper, region.max_pgsz);
-                        last = upper;
-                    }
-                    AllocType::FILE => {
-                        println!("file {:x}", last);
-                        break 'outer;
-                    }
-                }
+    #[inline]
+    #[cfg(feature = "trace")]
+    fn trace(&self, op: TraceOp, addr: usize, len: usize, arg: usize, arg2: usize, ret: usize) {
+        if let Some(trace) = &self.trace {
+            if self.window.as_ref().is_none_or(|x| x.is_active()) {
+                trace.record(op, addr, len, arg, arg2, ret);
+            }
+        }
+    }
+
+    #[inline(always)]
+    #[cfg(not(feature = "trace"))]
+    fn trace(&self, _: TraceOp, _: usize, _: usize, _: usize, _: usize, _: usize) {}
+
+    // start the hugepage touch sampler, if a heatmap file was requested
+    pub fn spawn_heatmap(&self) {
+        if let Some(path) = &self.heatmap {
+            let intervals = self
+                .pool_regions()
+                .flat_map(|r| r.intervals().map(move |x| (r.label(), x)))
+                .filter(|(_, (_, _, pagesz))| *pagesz > *PAGE_SIZE)
+                .enumerate()
+                .map(|(id, (label, (start, end, pagesz)))| {
+                    HeatmapInterval::new(id, &label, pagesz, start, (end - start) / pagesz)
+                })
+                .collect::<Vec<HeatmapInterval>>();
+
+            heatmap::spawn(path.clone(), self.heatmap_period, intervals);
+        }
+    }
+
+    pub unsafe fn drain(&mut self) {
+        // the copied heap keeps growing in place, there's no pre-existing heap left to drain
+        let skip = self.heap_copied
+            || match self.drain {
+                DrainPolicy::FULL => false,
+                DrainPolicy::AUTO => preload_hooks::morecore_active(),
+                DrainPolicy::NONE => true,
+            };
+
+        if !skip {
+            let max = self.drain_max.map_or(usize::MAX, |x| x / CHUNK);
+            let mut n = 0;
+            while n < max && !black_box(libc::malloc(CHUNK)).is_null() {
+                n += 1;
             }
+            *libc::__errno_location() = 0;
+            self.drain_stats = Some((n * CHUNK, n == max));
+        }
 
-            if region.alloc_type == AllocType::BRK || mapping[mapping.len() - 1].trim() == "[stack]"
+        self.early_lock.lock();
+        self.drained.store(true, Ordering::Release);
+        // the early mappings forwarded to libc that landed in an anon region become foreign
+        // mappings of it
+        if self.early == EarlyPolicy::FORWARD {
+            for region in self
+                .regions
+                .iter_mut()
+                .filter(|x| x.alloc_type == AllocType::ANON)
             {
-                // no space
-                panic!();
+                region.lock();
+                reconcile(region);
+                region.unlock();
             }
         }
+        self.early_lock.unlock();
+    }
 
-        // FIXME: workaround to initialize the hooks
-        preload_hooks::libc_mmap(usize::MAX as *mut libc::c_void, 0, 0, 0, -1, 0);
-        preload_hooks::libc_munmap(usize::MAX as *mut libc::c_void, 0);
-        preload_hooks::libc_mprotect(usize::MAX as *mut libc::c_void, 0, 0);
-        preload_hooks::libc_madvise(usize::MAX as *mut libc::c_void, 0, 0);
-        preload_hooks::libc_mremap(
-            usize::MAX as *mut libc::c_void,
-            0,
-            0,
-            0,
-            usize::MAX as *mut libc::c_void,
-        );
+    // how an anon mmap or brk request is handled, None once drained; the draining thread's own
+    // requests always fail, the drain relies on it
+    fn early_policy(&self) -> Option<EarlyPolicy> {
+        if self.drained.load(Ordering::Acquire) {
+            None
+        } else if unsafe { libc::gettid() } == self.drainer {
+            Some(EarlyPolicy::ENOMEM)
+        } else {
+            Some(self.early)
+        }
+    }
 
-        Self {
-            heap,
-            anon_region,
-            file_region,
-            analyze: config.analyze_regions,
-            dryrun: config.dryrun,
-            drained,
+    // whether a request for addr is an early one forwarded to libc, i.e. on a mapping of an anon
+    // region that isn't absorbed yet
+    fn forwards_early(&self, addr: usize) -> bool {
+        self.in_anon_region(addr) && self.early_policy() == Some(EarlyPolicy::FORWARD)
+    }
+
+    // run an early request through libc, serialized with the end of the drain so that the
+    // mappings it creates are absorbed; None if the drain completed meanwhile
+    fn forward_early<R>(&mut self, f: impl FnOnce() -> R) -> Option<R> {
+        self.early_lock.lock();
+        let ret = (!self.drained.load(Ordering::Acquire)).then(f);
+        self.early_lock.unlock();
+
+        if ret.is_some() {
+            self.early_forwarded.fetch_add(1, Ordering::Relaxed);
         }
+        ret
     }
 
-    pub unsafe fn drain(&mut self) {
-        while black_box(libc::malloc(CHUNK)) as *const u8 != null() {}
-        *libc::__errno_location() = 0;
-        self.drained = true;
-        InternalAllocator::print_stats();
+    // block an early request until the drain completes, false if it timed out
+    fn wait_drained(&self) -> bool {
+        self.early_waited.fetch_add(1, Ordering::Relaxed);
+
+        let start = Instant::now();
+        while !self.drained.load(Ordering::Acquire) {
+            if start.elapsed() >= EARLY_WAIT {
+                self.early_timedout.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+            thread::yield_now();
+        }
+        true
     }
 
     // brk helper for sbrk and brk
     pub unsafe fn do_brk(&mut self, addr: Option<usize>, incr: Option<isize>) -> usize {
-        if !self.drained {
+        let ret = self.brk_helper(addr, incr);
+        self.check_watermarks();
+        self.trace(
+            TraceOp::BRK,
+            addr.unwrap_or_else(|| incr.unwrap() as usize),
+            0,
+            addr.is_none() as usize,
+            0,
+            ret,
+        );
+        ret
+    }
+
+    // warn once if the kernel program break moved since the heap region was placed, i.e. a raw brk
+    // syscall went past the preload hooks and may have unmapped or overlapped the heap region
+    fn check_kernel_brk(&self) {
+        if let Some(placed) = self.kernel_brk {
+            let cur = kernel_brk();
+            if cur != placed && !self.kernel_brk_moved.swap(true, Ordering::Relaxed) {
+                println!(
+                    "brk: warning: the kernel program break moved from {:x} to {:x} behind \
+                     mosalloc's back, a raw brk syscall (e.g. from glibc internals) bypassed the \
+                     preload hooks and the heap region may be corrupted; use the seccomp or the hybrid \
+                     hooks (--hook-type seccomp|hybrid), which catch raw syscalls",
+                    placed, cur
+                );
+            }
+        }
+    }
+
+    unsafe fn brk_helper(&mut self, addr: Option<usize>, incr: Option<isize>) -> usize {
+        self.check_kernel_brk();
+
+        // the program break is emulated, there's nothing to forward
+        let ready = match self.early_policy() {
+            None => true,
+            Some(EarlyPolicy::ENOMEM) => false,
+            Some(EarlyPolicy::FORWARD | EarlyPolicy::WAIT) => self.wait_drained(),
+        };
+        if !ready {
             *libc::__errno_location() = libc::ENOMEM;
             return usize::MAX;
         }
 
         self.heap.lock();
 
-        let oldbrk = self.heap.end;
-        let newbrk = addr.unwrap_or_else(|| oldbrk.checked_add_signed(incr.unwrap()).unwrap());
-
+        let oldbrk = self.heap.brk;
         // make sure brk doesn't exceed the mosalloc-managed heap
-        if !self.heap.contains(newbrk) {
-            self.heap.unlock();
-            *libc::__errno_location() = libc::ENOMEM;
-            usize::MAX
-        } else {
-            if newbrk > oldbrk {
+        let moved =
+            brk_move(self.heap.start..self.heap.max, oldbrk, addr, incr).filter(|x| match &x.1 {
+                BrkMove::GROW(r) => {
+                    self.heap.within_limit(r.len()) && !self.inject_fault(TraceOp::BRK)
+                }
+                _ => true,
+            });
+
+        let newbrk = match moved {
+            None => {
+                self.heap.unlock();
+                *libc::__errno_location() = libc::ENOMEM;
+                return usize::MAX;
+            }
+            Some((newbrk, BrkMove::GROW(r))) => {
                 let prot = libc::PROT_READ | libc::PROT_WRITE;
                 let flags = libc::MAP_ANONYMOUS | libc::MAP_PRIVATE;
-                let len = newbrk - oldbrk;
 
-                self.heap.alloc_range(oldbrk, len, prot, flags, self.dryrun);
-            } else if newbrk < oldbrk {
-                self.heap.free_range(newbrk, oldbrk - newbrk);
+                // e.g. over the hugepage caps
+                if self
+                    .heap
+                    .alloc_range(r.start, r.len(), prot, flags, self.dryrun)
+                    == usize::MAX
+                {
+                    self.heap.unlock();
+                    *libc::__errno_location() = libc::ENOMEM;
+                    return usize::MAX;
+                }
+                newbrk
             }
-            self.heap.unlock();
-            oldbrk
-        }
+            Some((newbrk, BrkMove::SHRINK(r))) => {
+                self.heap.free_range(r.start, r.len());
+                newbrk
+            }
+            Some((newbrk, BrkMove::NONE)) => newbrk,
+        };
+        self.heap.brk = newbrk;
+        self.heap.unlock();
+        oldbrk
     }
 
     pub unsafe fn brk(&mut self, addr: usize) -> i32 {
@@ -182,32 +1774,60 @@ impl Allocator {
         self.do_brk(None, Some(incr))
     }
 
+    // the emulated program break
+    pub fn current_brk(&mut self) -> usize {
+        self.heap.lock();
+        let brk = self.heap.brk;
+        self.heap.unlock();
+        brk
+    }
+
+    // index of the anon or file region of addr, the regions are sorted and disjoint
+    #[inline]
+    fn region_idx(&self, addr: usize) -> Option<usize> {
+        find_range(&self.regions, untagged(addr), |x| (x.start, x.max))
+    }
+
+    #[inline]
+    fn in_anon_region(&self, addr: usize) -> bool {
+        self.region_idx(addr)
+            .is_some_and(|x| self.regions[x].alloc_type == AllocType::ANON)
+    }
+
     #[inline]
     fn region_from_addr(&mut self, addr: usize) -> Option<&mut Region> {
         // FIXME: make sure that we don't mess with the mosalloc-managed heap
         assert!(!self.heap.contains(addr));
 
-        if self.anon_region.contains(addr) {
-            Some(&mut self.anon_region)
-        } else if self.file_region.contains(addr) {
-            Some(&mut self.file_region)
-        } else {
-            None
-        }
+        self.region_idx(addr).map(|x| &mut self.regions[x])
     }
 
     #[inline]
     fn region_from_fd(&mut self, fd: i32) -> &mut Region {
         if fd == -1 {
-            &mut self.anon_region
+            &mut self.regions[self.anon]
         } else {
-            &mut self.file_region
+            &mut self.regions[self.file]
         }
     }
 
     #[inline]
-    fn region_from_req(&mut self, addr: usize, fd: i32) -> Option<&mut Region> {
+    fn region_from_req(&mut self, addr: usize, flags: i32, fd: i32) -> Option<&mut Region> {
         if addr == 0 {
+            // MAP_32BIT requests go to a region within the first 2GB, e.g. a named anon region
+            // placed there, or to libc if there's none
+            #[cfg(target_arch = "x86_64")]
+            if flags & libc::MAP_32BIT != 0 {
+                let alloc_type = if fd == -1 {
+                    AllocType::ANON
+                } else {
+                    AllocType::FILE
+                };
+                return self
+                    .regions
+                    .iter_mut()
+                    .find(|x| x.alloc_type == alloc_type && x.max <= 1 << 31);
+            }
             Some(self.region_from_fd(fd))
         } else {
             // FIXME: there's a corner case where we might get a request for an address of e.g. the
@@ -224,13 +1844,63 @@ impl Allocator {
         flags: i32,
         fd: i32,
         offset: i64,
+    ) -> usize {
+        let ret = self.mmap_helper(addr, len, prot, flags, fd, offset);
+        self.check_watermarks();
+        self.trace(TraceOp::MMAP, addr, len, prot as usize, flags as usize, ret);
+        ret
+    }
+
+    unsafe fn mmap_helper(
+        &mut self,
+        addr: usize,
+        len: usize,
+        prot: i32,
+        flags: i32,
+        fd: i32,
+        offset: i64,
     ) -> usize {
         println!("mmap 0x{:x}, len: {}, fd: {}", addr, len, fd);
 
+        let anon = if addr == 0 {
+            fd == -1
+        } else {
+            self.in_anon_region(addr)
+        };
+        if anon {
+            match self.early_policy() {
+                Some(EarlyPolicy::ENOMEM) => {
+                    *libc::__errno_location() = libc::ENOMEM;
+                    return libc::MAP_FAILED as usize;
+                }
+                Some(EarlyPolicy::FORWARD) => {
+                    if let Some(ret) = self.forward_early(|| {
+                        preload_hooks::libc_mmap(
+                            addr as *mut libc::c_void,
+                            len,
+                            prot,
+                            flags,
+                            fd,
+                            offset,
+                        )
+                    }) {
+                        return ret as usize;
+                    }
+                }
+                Some(EarlyPolicy::WAIT) if !self.wait_drained() => {
+                    *libc::__errno_location() = libc::ENOMEM;
+                    return libc::MAP_FAILED as usize;
+                }
+                None | Some(EarlyPolicy::WAIT) => {}
+            }
+        }
+
         let dryrun = self.dryrun;
-        let drained = self.drained;
+        let drained = self.drained.load(Ordering::Relaxed);
+        let fault = self.fault.clone();
+        let hugetlb_default = self.hugetlb_default;
 
-        let region = self.region_from_req(addr, fd);
+        let region = self.region_from_req(addr, flags, fd);
 
         // forward mmaps outside mosalloc regions and non-standard anon private requests to libc
         if region.is_none() {
@@ -240,9 +1910,43 @@ impl Allocator {
 
         let region = region.unwrap();
 
-        if !drained && region.alloc_type == AllocType::ANON {
-            *libc::__errno_location() = libc::ENOMEM;
-            return libc::MAP_FAILED as usize;
+        // file offsets are 64-bit even on 32-bit targets (mmap64, mmap2), check them like the
+        // kernel does before reserving any range
+        if region.alloc_type == AllocType::FILE {
+            let err = if offset < 0 {
+                libc::EOVERFLOW
+            } else if !(offset as u64).is_multiple_of(*PAGE_SIZE as u64) {
+                libc::EINVAL
+            } else {
+                0
+            };
+            if err != 0 {
+                *libc::__errno_location() = err;
+                return libc::MAP_FAILED as usize;
+            }
+        }
+
+        // absorb the private explicit hugetlb requests into the pool intervals of their page size,
+        // so that they share the pool's budget, if the pool has any
+        if let Some(default) = hugetlb_default.filter(|_| {
+            region.alloc_type == AllocType::ANON
+                && addr == 0
+                && flags & NONSTD_FLAGS == libc::MAP_HUGETLB
+        }) {
+            if drained && fault.as_ref().is_some_and(|x| x.inject(TraceOp::MMAP)) {
+                return mmap_failed(flags);
+            }
+            region.lock();
+            let ret = match region.within_limit(len) {
+                true => region.alloc_hugetlb(len, prot, flags, default, dryrun),
+                false => Some(usize::MAX),
+            };
+            region.unlock();
+            match ret {
+                Some(usize::MAX) => return mmap_failed(flags),
+                Some(x) => return x,
+                None => {}
+            }
         }
 
         // use libc for 'non-std' anon mapping (i.e. shared mappings, explicit hugetlb requests, stack mappings)
@@ -254,31 +1958,67 @@ impl Allocator {
         // make sure the mmap doesn't span regions
         assert!(addr == 0 || addr + len <= region.max);
 
+        if drained && fault.is_some_and(|x| x.inject(TraceOp::MMAP)) {
+            return mmap_failed(flags);
+        }
+
+        // try the per-thread caches first for plain RW anon requests
+        if addr == 0
+            && region.alloc_type == AllocType::ANON
+            && prot == libc::PROT_READ | libc::PROT_WRITE
+        {
+            if let Some(addr) = region.cache_alloc(len, dryrun) {
+                return addr;
+            }
+        }
+
         region.lock();
+        // MAP_FIXED over already allocated memory doesn't allocate anything new
+        let realloc =
+            addr != 0 && flags & libc::MAP_FIXED != 0 && region.is_allocated(addr, addr + len);
+        if !realloc && !region.within_limit(len) {
+            region.unlock();
+            *libc::__errno_location() = libc::ENOMEM;
+            return libc::MAP_FAILED as usize;
+        }
         let addr = region.alloc_range(addr, len, prot, flags, dryrun);
         region.unlock();
 
         if addr == usize::MAX {
-            if (flags & libc::MAP_FIXED_NOREPLACE) != 0 {
-                // for MAP_FIXED_NOREPLACE, return EEXIST if we cannot allocate the requested addr
-                *libc::__errno_location() = libc::EEXIST;
-            } else {
-                // for the rest, just return ENOMEM
-                *libc::__errno_location() = libc::ENOMEM;
-            }
-            return libc::MAP_FAILED as usize;
+            return mmap_failed(flags);
         }
 
         if region.alloc_type == AllocType::FILE {
-            return preload_hooks::libc_mmap(addr as *mut libc::c_void, len, prot, flags, fd, offset)
-                as usize;
+            let ret =
+                preload_hooks::libc_mmap(addr as *mut libc::c_void, len, prot, flags, fd, offset);
+            // e.g. EOVERFLOW for offsets past the end of the file's addressable range
+            if ret == libc::MAP_FAILED {
+                region.lock();
+                region.free_range(addr, len);
+                region.unlock();
+            }
+            return ret as usize;
         }
         addr
     }
 
     pub fn munmap(&mut self, addr: usize, len: usize) -> i32 {
+        let ret = self.munmap_helper(addr, len);
+        self.trace(TraceOp::MUNMAP, addr, len, 0, 0, ret as usize);
+        ret
+    }
+
+    fn munmap_helper(&mut self, addr: usize, len: usize) -> i32 {
         println!("munmap 0x{:x} {}", addr, len);
 
+        if self.forwards_early(addr) {
+            if let Some(ret) =
+                self.forward_early(|| preload_hooks::libc_munmap(addr as *mut libc::c_void, len))
+            {
+                return ret;
+            }
+        }
+
         // forward munmaps outside mosalloc regions to libc
         let region = self.region_from_addr(addr);
         if region.is_none() {
@@ -286,43 +2026,127 @@ impl Allocator {
         }
 
         let region = region.unwrap();
+        let addr = untagged(addr);
 
         // make sure the munmap doesn't span regions
         assert!(addr + len <= region.max);
 
+        // the parts of the range within absorbed foreign mappings are the application's own
         region.lock();
-        region.free_range(addr, len);
+        let pieces = region.release_foreign(addr, len);
+        for (r, _) in pieces.iter().filter(|x| !x.1) {
+            region.free_range(r.start, r.len());
+        }
         region.unlock();
 
         if region.alloc_type == AllocType::FILE {
-            preload_hooks::libc_munmap(addr as *mut libc::c_void, len)
-        } else {
-            0
+            return preload_hooks::libc_munmap(addr as *mut libc::c_void, len);
+        }
+
+        let mut ret = 0;
+        for (r, _) in pieces.iter().filter(|x| x.1) {
+            if preload_hooks::libc_munmap(r.start as *mut libc::c_void, r.len()) != 0 {
+                ret = -1;
+            }
         }
+        ret
     }
 
     pub fn mprotect(&mut self, addr: usize, len: usize, prot: i32) -> i32 {
+        let ret = self.mprotect_helper(addr, len, prot);
+        self.trace(TraceOp::MPROTECT, addr, len, prot as usize, 0, ret as usize);
+        ret
+    }
+
+    fn mprotect_helper(&mut self, addr: usize, len: usize, prot: i32) -> i32 {
         println!("mprotect 0x{:x} {} {}", addr, len, prot);
+
+        if self.forwards_early(addr) {
+            if let Some(ret) = self.forward_early(|| {
+                preload_hooks::libc_mprotect(addr as *mut libc::c_void, len, prot)
+            }) {
+                return ret;
+            }
+        }
+
         // forward mprotect outside mosalloc mem regions to libc
         let region = self.region_from_addr(addr);
-        if region.is_none() || region.unwrap().alloc_type == AllocType::FILE {
+        if region.is_none() {
+            return preload_hooks::libc_mprotect(addr as *mut libc::c_void, len, prot);
+        }
+
+        let region = region.unwrap();
+        let addr = untagged(addr);
+
+        let end = addr + align_up(len, *PAGE_SIZE);
+
+        if region.is_foreign(addr, end) {
+            return preload_hooks::libc_mprotect(addr as *mut libc::c_void, len, prot);
+        }
+
+        region.lock();
+        if region.is_allocated(addr, end) {
+            region.protect(addr, end, prot);
+        }
+        region.unlock();
+
+        if region.alloc_type == AllocType::FILE {
             preload_hooks::libc_mprotect(addr as *mut libc::c_void, len, prot)
         } else {
-            // ignore mprotect for heap + anon regions for now
+            // the anon pages are shared, they get the union of the protections on them
             0
         }
     }
 
     pub fn madvise(&mut self, addr: usize, len: usize, advice: i32) -> i32 {
+        let ret = self.madvise_helper(addr, len, advice);
+        self.trace(
+            TraceOp::MADVISE,
+            addr,
+            len,
+            advice as usize,
+            0,
+            ret as usize,
+        );
+        ret
+    }
+
+    fn madvise_helper(&mut self, addr: usize, len: usize, advice: i32) -> i32 {
         println!("madvise 0x{:x} {} {}", addr, len, advice);
 
-        // forward madvise outside mosalloc mem regions to libc
-        let region = self.region_from_addr(addr);
-        if region.is_none() || region.unwrap().alloc_type == AllocType::FILE {
-            preload_hooks::libc_madvise(addr as *mut libc::c_void, len, advice)
+        if self.forwards_early(addr) {
+            if let Some(ret) = self.forward_early(|| {
+                preload_hooks::libc_madvise(addr as *mut libc::c_void, len, advice)
+            }) {
+                return ret;
+            }
+        }
+
+        let reclaim = self.reclaim() == ReclaimPolicy::RELEASE;
+
+        // glibc discards the free chunks of the heap on trims
+        let region = if self.heap.contains(addr) {
+            Some(&mut self.heap)
         } else {
-            // ignore madvise for heap + anon regions for now
-            0
+            self.region_from_addr(addr)
+        };
+
+        match region {
+            // forward madvise outside mosalloc mem regions to libc
+            None => preload_hooks::libc_madvise(addr as *mut libc::c_void, len, advice),
+            Some(region) if region.alloc_type == AllocType::FILE => {
+                preload_hooks::libc_madvise(addr as *mut libc::c_void, len, advice)
+            }
+            Some(region)
+                if reclaim && (advice == libc::MADV_DONTNEED || advice == libc::MADV_FREE) =>
+            {
+                region.lock();
+                let ret = region.discard(untagged(addr), len);
+                region.unlock();
+                ret
+            }
+            // ignore the rest of the madvise calls for heap + anon regions for now
+            Some(_) => 0,
         }
     }
 
@@ -333,13 +2157,50 @@ impl Allocator {
         new_size: usize,
         flags: i32,
         new_address: usize,
+    ) -> usize {
+        let ret = self.mremap_helper(old_address, old_size, new_size, flags, new_address);
+        self.check_watermarks();
+        self.trace(
+            TraceOp::MREMAP,
+            old_address,
+            old_size,
+            new_size,
+            new_address,
+            ret,
+        );
+        ret
+    }
+
+    unsafe fn mremap_helper(
+        &mut self,
+        old_address: usize,
+        old_size: usize,
+        new_size: usize,
+        flags: i32,
+        new_address: usize,
     ) -> usize {
         println!(
             "mremap 0x{:x} {} {} {:x}",
             old_address, old_size, new_size, new_address
         );
 
+        if self.forwards_early(old_address) {
+            if let Some(ret) = self.forward_early(|| {
+                preload_hooks::libc_mremap(
+                    old_address as *mut libc::c_void,
+                    old_size,
+                    new_size,
+                    flags,
+                    new_address as *mut libc::c_void,
+                )
+            }) {
+                return ret as usize;
+            }
+        }
+
         let dryrun = self.dryrun;
+        let drained = self.drained.load(Ordering::Relaxed);
+        let fault = self.fault.clone();
 
         // forward mremaps outside mosalloc regions to libc
         let region = self.region_from_addr(old_address);
@@ -354,61 +2215,212 @@ impl Allocator {
         }
 
         let region = region.unwrap();
+        let (old_address, new_address) = (untagged(old_address), untagged(new_address));
+
+        let fixed = flags & libc::MREMAP_FIXED != 0;
+        let maymove = flags & libc::MREMAP_MAYMOVE != 0;
+        let dontunmap = flags & libc::MREMAP_DONTUNMAP != 0;
+        let old_size = align_up(old_size, *PAGE_SIZE);
+        let new_size = align_up(new_size, *PAGE_SIZE);
+
+        // same argument validation as the kernel
+        if flags & !(libc::MREMAP_FIXED | libc::MREMAP_MAYMOVE | libc::MREMAP_DONTUNMAP) != 0
+            || !is_aligned(old_address, *PAGE_SIZE)
+            || old_size == 0
+            || new_size == 0
+            || ((fixed || dontunmap) && !maymove)
+            || (dontunmap && old_size != new_size)
+            || (fixed && !is_aligned(new_address, *PAGE_SIZE))
+            || (fixed
+                && new_address < old_address + old_size
+                && old_address < new_address + new_size)
+        {
+            *libc::__errno_location() = libc::EINVAL;
+            return libc::MAP_FAILED as usize;
+        }
+
+        // make sure the new mapping belongs in the same region
+        assert!(!fixed || (region.contains(new_address) && new_address + new_size <= region.max));
+
+        // only growing remaps allocate
+        if new_size > old_size && drained && fault.is_some_and(|x| x.inject(TraceOp::MREMAP)) {
+            *libc::__errno_location() = libc::ENOMEM;
+            return libc::MAP_FAILED as usize;
+        }
+
+        let rw = libc::PROT_READ | libc::PROT_WRITE;
+        let anon = libc::MAP_ANONYMOUS | libc::MAP_PRIVATE;
+
         region.lock();
 
-        // make the new mapping belongs in the same region
-        assert!(((flags & libc::MREMAP_FIXED) != 0) && ((new_address + new_size) <= region.max));
+        // the remapped range keeps the protection of the old one, like with the kernel
+        let prot = region.prot_at(old_address);
 
-        let mut req_addr = new_address;
+        // the old range is only released after the new one has been allocated, but only the net
+        // growth counts against the hard limit
+        let growth = if dontunmap {
+            new_size
+        } else {
+            new_size.saturating_sub(old_size)
+        };
 
-        // non-fixed remap
-        if flags & libc::MREMAP_FIXED == 0 {
-            // FIXME: argument validation
-            if old_size >= new_size {
-                // we can always in-place shrink
+        let ret = if growth > 0 && !region.within_limit(growth) {
+            *libc::__errno_location() = libc::ENOMEM;
+            libc::MAP_FAILED as usize
+        } else if !fixed && !dontunmap && new_size <= old_size {
+            // we can always in-place shrink
+            if new_size < old_size {
                 region.free_range(old_address + new_size, old_size - new_size);
-                return old_address;
+                if region.alloc_type == AllocType::FILE {
+                    preload_hooks::libc_munmap(
+                        (old_address + new_size) as *mut libc::c_void,
+                        old_size - new_size,
+                    );
+                }
+            }
+            old_address
+        } else if !fixed
+            && !dontunmap
+            && region.alloc_range(
+                old_address + old_size,
+                new_size - old_size,
+                prot,
+                anon | libc::MAP_FIXED_NOREPLACE,
+                dryrun,
+            ) == old_address + old_size
+        {
+            // expanded in place, the new pages are backed according to the intervals they fall in
+            if region.alloc_type == AllocType::FILE
+                && preload_hooks::libc_mremap(
+                    old_address as *mut libc::c_void,
+                    old_size,
+                    new_size,
+                    0,
+                    ptr::null_mut(),
+                ) == libc::MAP_FAILED
+            {
+                let err = *libc::__errno_location();
+                region.free_range(old_address + old_size, new_size - old_size);
+                *libc::__errno_location() = err;
+                libc::MAP_FAILED as usize
             } else {
-                // for expansions, we need to check if there's space
-                let addr = region.alloc_range(
-                    old_address + old_size,
-                    new_size - old_size,
-                    libc::PROT_READ | libc::PROT_WRITE,
-                    libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
-                    dryrun,
-                );
+                old_address
+            }
+        } else if !maymove {
+            *libc::__errno_location() = libc::ENOMEM;
+            libc::MAP_FAILED as usize
+        } else {
+            let (req_addr, req_flags) = if fixed {
+                (new_address, anon | libc::MAP_FIXED)
+            } else {
+                (0, anon)
+            };
 
-                // return if we were able to expand the range, else continue to the fixed path
-                if addr == old_address + old_size {
-                    return old_address;
+            let addr = region.alloc_range(req_addr, new_size, rw, req_flags, dryrun);
+            if addr == usize::MAX {
+                *libc::__errno_location() = libc::ENOMEM;
+                libc::MAP_FAILED as usize
+            } else if region.alloc_type == AllocType::FILE
+                && preload_hooks::libc_mremap(
+                    old_address as *mut libc::c_void,
+                    old_size,
+                    new_size,
+                    flags | libc::MREMAP_FIXED,
+                    addr as *mut libc::c_void,
+                ) == libc::MAP_FAILED
+            {
+                // the old mapping is left as it was
+                let err = *libc::__errno_location();
+                region.free_range(addr, new_size);
+                *libc::__errno_location() = err;
+                libc::MAP_FAILED as usize
+            } else {
+                if region.alloc_type != AllocType::FILE {
+                    // the contents are moved through RW pages
+                    if prot != rw {
+                        region.protect(old_address, old_address + old_size, rw);
+                    }
+                    ptr::copy_nonoverlapping(
+                        old_address as *const u8,
+                        addr as *mut u8,
+                        old_size.min(new_size),
+                    );
+                    // the old range stays mapped but reads back as zero-filled
+                    if dontunmap {
+                        ptr::write_bytes(old_address as *mut u8, 0, old_size);
+                    }
+                    if prot != rw {
+                        region.protect(addr, addr + new_size, prot);
+                        if dontunmap {
+                            region.protect(old_address, old_address + old_size, prot);
+                        }
+                    }
                 }
-                assert_eq!(addr, usize::MAX);
 
-                if flags & libc::MREMAP_MAYMOVE == 0 {
-                    *libc::__errno_location() = libc::ENOMEM;
-                    return libc::MAP_FAILED as usize;
+                if !dontunmap {
+                    region.free_range(old_address, old_size);
                 }
-                req_addr = 0;
+                addr
             }
-        }
+        };
 
-        let addr = region.alloc_range(
-            req_addr,
-            new_size,
-            libc::PROT_READ | libc::PROT_WRITE,
-            libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
-            dryrun,
+        region.unlock();
+
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAPS: &str = "\
+55d0a0000000-55d0a0021000 r-xp 00000000 08:01 1234 /usr/bin/prog
+55d0a1234000-55d0a1255000 rw-p 00000000 00:00 0    [heap]
+7f0000000000-7f0000200000 r-xp 00000000 08:01 5678 /usr/lib/libc.so.6
+";
+
+    #[test]
+    fn place_over_heap_aligned_to_the_page_size() {
+        assert_eq!(
+            place_over_heap(1, MAPS, 0x55d0a1255000, 1 << 30, 2 << 20),
+            Some((0x55d0a1200000, 0x55d0a1234000))
         );
+    }
 
-        region.unlock();
+    #[test]
+    fn place_over_heap_with_threads() {
+        assert_eq!(
+            place_over_heap(2, MAPS, 0x55d0a1255000, 1 << 30, 2 << 20),
+            None
+        );
+    }
 
-        // for MAP_FIXED, return error if we cannot allocate the requested addr
-        if (req_addr != addr) && (flags & libc::MREMAP_FIXED != 0) {
-            // FIXME: which error code makes sense for MAP_FIXED?
-            *libc::__errno_location() = libc::EEXIST;
-            return libc::MAP_FAILED as usize;
-        }
+    #[test]
+    fn place_over_heap_without_heap() {
+        let maps = MAPS.replace("[heap]", "");
+        assert_eq!(
+            place_over_heap(1, &maps, 0x55d0a1255000, 1 << 30, 2 << 20),
+            None
+        );
+    }
 
-        new_address
+    #[test]
+    fn place_over_heap_without_room() {
+        // the region would overlap the next mapping
+        assert_eq!(
+            place_over_heap(1, MAPS, 0x55d0a1255000, 0x7f0000000000, 2 << 20),
+            None
+        );
+        // the heap is already larger than the region
+        assert_eq!(
+            place_over_heap(1, MAPS, 0x55d0a1255000, 0x20000, 4096),
+            None
+        );
+        // the aligned start would overlap the previous mapping
+        assert_eq!(
+            place_over_heap(1, MAPS, 0x55d0a1255000, 1 << 30, 1 << 30),
+            None
+        );
     }
 }
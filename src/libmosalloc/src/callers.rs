@@ -0,0 +1,79 @@
+use std::ffi::CStr;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+use libc::c_void;
+
+// frames walked up from the hook looking for the first one outside libmosalloc
+const MAX_FRAMES: usize = 16;
+
+// the objects whose mmaps are handled (substrings of their paths, e.g. libjemalloc), all if unset
+static CALLERS: OnceLock<Vec<String>> = OnceLock::new();
+
+// mmaps from the other objects forwarded to libc, and the ones whose caller couldn't be resolved
+static FORWARDED: AtomicUsize = AtomicUsize::new(0);
+static UNRESOLVED: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set(callers: &[String]) {
+    if !callers.is_empty() {
+        CALLERS.set(callers.to_vec()).unwrap();
+    }
+}
+
+fn dl_info(addr: *const c_void) -> Option<libc::Dl_info> {
+    let mut info = MaybeUninit::<libc::Dl_info>::uninit();
+    match unsafe { libc::dladdr(addr, info.as_mut_ptr()) } {
+        0 => None,
+        _ => Some(unsafe { info.assume_init() }),
+    }
+}
+
+// the path of the object the hooked call was made from, the first frame outside libmosalloc
+fn caller() -> Option<String> {
+    let own = dl_info(caller as *const c_void)?.dli_fbase;
+
+    let mut frames = [std::ptr::null_mut(); MAX_FRAMES];
+    let nr = unsafe { libc::backtrace(frames.as_mut_ptr(), MAX_FRAMES as i32) } as usize;
+
+    frames[..nr]
+        .iter()
+        .filter_map(|&x| dl_info(x))
+        .find(|x| x.dli_fbase != own && !x.dli_fname.is_null())
+        .map(|x| {
+            unsafe { CStr::from_ptr(x.dli_fname) }
+                .to_string_lossy()
+                .into_owned()
+        })
+}
+
+// whether the calling mmap is to be handled, it has to come from one of the selected objects
+pub fn selected() -> bool {
+    let callers = match CALLERS.get() {
+        Some(callers) => callers,
+        None => return true,
+    };
+
+    let selected = match caller() {
+        Some(path) => callers.iter().any(|x| path.contains(x.as_str())),
+        None => {
+            UNRESOLVED.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    };
+    if !selected {
+        FORWARDED.fetch_add(1, Ordering::Relaxed);
+    }
+    selected
+}
+
+pub fn print_stats() {
+    if let Some(callers) = CALLERS.get() {
+        println!(
+            "callers: {} mmaps from objects other than {} forwarded ({} unresolved)",
+            FORWARDED.load(Ordering::Relaxed),
+            callers.join(", "),
+            UNRESOLVED.load(Ordering::Relaxed)
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use std::ffi::CStr;
+
+use libc::{c_char, c_int, c_void, pid_t, size_t};
+
+use crate::allocator::WatermarkCallback;
+use crate::compaction::MoveCallback;
+use crate::init::mosalloc;
+use crate::pagemap;
+use crate::region::PinCallback;
+use crate::threads;
+
+// C API exported by libmosalloc, for applications that want to interact with mosalloc
+
+// int mosalloc_collapse(void *addr, size_t len);
+// synchronously promote a THP-backed pool range, returns 0 if the whole range is THP-backed
+#[no_mangle]
+pub unsafe extern "C" fn mosalloc_collapse(addr: *mut c_void, len: size_t) -> c_int {
+    match mosalloc().map(|m| m.collapse(addr as usize, len)) {
+        Some(Ok(thp)) if thp == len => 0,
+        Some(Ok(_)) => {
+            *libc::__errno_location() = libc::EAGAIN;
+            -1
+        }
+        Some(Err(err)) => {
+            *libc::__errno_location() = err;
+            -1
+        }
+        None => {
+            *libc::__errno_location() = libc::ENODEV;
+            -1
+        }
+    }
+}
+
+// int mosalloc_pkey(const char *name);
+// protection key of a named sub-pool (MOSALLOC_PKEYS), to be used with pkey_set
+#[no_mangle]
+pub unsafe extern "C" fn mosalloc_pkey(name: *const c_char) -> c_int {
+    let name = CStr::from_ptr(name).to_string_lossy();
+    match mosalloc().and_then(|m| m.pkey(&name)) {
+        Some(pkey) => pkey,
+        None => {
+            *libc::__errno_location() = libc::ENOENT;
+            -1
+        }
+    }
+}
+
+// int mosalloc_set_watermark_callback(void (*cb)(int region, unsigned int pct, size_t allocated,
+//                                                size_t size, void *arg), void *arg);
+// called when a region crosses one of the configured watermarks (MOSALLOC_WATERMARKS), region is 0 for
+// brk, 1 for anon and 2 for file; a NULL cb unregisters the callback
+#[no_mangle]
+pub unsafe extern "C" fn mosalloc_set_watermark_callback(
+    cb: Option<WatermarkCallback>,
+    arg: *mut c_void,
+) -> c_int {
+    match mosalloc() {
+        Some(m) => {
+            m.set_watermark_callback(cb.map(|cb| (cb, arg as usize)));
+            0
+        }
+        None => {
+            *libc::__errno_location() = libc::ENODEV;
+            -1
+        }
+    }
+}
+
+// int mosalloc_usage(int region, size_t *used, size_t *high_water, size_t *brk);
+// usage of a region (0 for brk, 1 for anon and 2 for file): the bytes handed out to the
+// application, the high-water mark and the program break (0 but for brk), the last two as offsets
+// from the region start; any of the pointers may be NULL
+#[no_mangle]
+pub unsafe extern "C" fn mosalloc_usage(
+    region: c_int,
+    used: *mut size_t,
+    high_water: *mut size_t,
+    brk: *mut size_t,
+) -> c_int {
+    match mosalloc().map(|m| m.usage(region)) {
+        Some(Some((u, h, b))) => {
+            for (ptr, x) in [(used, u), (high_water, h), (brk, b)] {
+                if !ptr.is_null() {
+                    *ptr = x;
+                }
+            }
+            0
+        }
+        Some(None) => {
+            *libc::__errno_location() = libc::EINVAL;
+            -1
+        }
+        None => {
+            *libc::__errno_location() = libc::ENODEV;
+            -1
+        }
+    }
+}
+
+// int mosalloc_register_movable(void *addr, size_t len);
+// let the compaction (mosalloc ctl --compact) move an anon pool range, which has to be allocated
+// and span whole pool pages of a single page size; the registration is dropped when the range is
+// freed. Experimental: the range mustn't be accessed during a compaction until the move callback
+// reports where it went
+#[no_mangle]
+pub unsafe extern "C" fn mosalloc_register_movable(addr: *mut c_void, len: size_t) -> c_int {
+    match mosalloc().map(|m| m.register_movable(addr as usize, len)) {
+        Some(Ok(())) => 0,
+        Some(Err(err)) => {
+            *libc::__errno_location() = err;
+            -1
+        }
+        None => {
+            *libc::__errno_location() = libc::ENODEV;
+            -1
+        }
+    }
+}
+
+// int mosalloc_unregister_movable(void *addr);
+// drop the registration of the movable range starting at addr (its current address)
+#[no_mangle]
+pub unsafe extern "C" fn mosalloc_unregister_movable(addr: *mut c_void) -> c_int {
+    match mosalloc().map(|m| m.unregister_movable(addr as usize)) {
+        Some(Ok(())) => 0,
+        Some(Err(err)) => {
+            *libc::__errno_location() = err;
+            -1
+        }
+        None => {
+            *libc::__errno_location() = libc::ENODEV;
+            -1
+        }
+    }
+}
+
+// int mosalloc_set_move_callback(void (*cb)(void *old, void *new, size_t len, void *arg), void *arg);
+// called from the control thread for every range moved by a compaction, which only runs with a
+// callback registered; a NULL cb unregisters the callback
+#[no_mangle]
+pub unsafe extern "C" fn mosalloc_set_move_callback(
+    cb: Option<MoveCallback>,
+    arg: *mut c_void,
+) -> c_int {
+    match mosalloc() {
+        Some(m) => {
+            m.set_move_callback(cb.map(|cb| (cb, arg as usize)));
+            0
+        }
+        None => {
+            *libc::__errno_location() = libc::ENODEV;
+            -1
+        }
+    }
+}
+
+// int mosalloc_pin(void *addr, size_t len);
+// keep the pages of [addr, addr + len) in place, for a device the application registers them
+// with (an RDMA memory region, a CUDA host registration ...): reclaim, aging and compaction leave
+// them alone until unpinned, even past a free
+#[no_mangle]
+pub unsafe extern "C" fn mosalloc_pin(addr: *mut c_void, len: size_t) -> c_int {
+    match mosalloc() {
+        Some(m) => {
+            m.pin(addr as usize, len);
+            0
+        }
+        None => {
+            *libc::__errno_location() = libc::ENODEV;
+            -1
+        }
+    }
+}
+
+// int mosalloc_unpin(void *addr);
+// drop the pin starting at addr
+#[no_mangle]
+pub unsafe extern "C" fn mosalloc_unpin(addr: *mut c_void) -> c_int {
+    match mosalloc().map(|m| m.unpin(addr as usize)) {
+        Some(Ok(())) => 0,
+        Some(Err(err)) => {
+            *libc::__errno_location() = err;
+            -1
+        }
+        None => {
+            *libc::__errno_location() = libc::ENODEV;
+            -1
+        }
+    }
+}
+
+// int mosalloc_set_pin_callback(int (*cb)(void *addr, size_t len, void *arg), void *arg);
+// asked before reclaim, aging or compaction touch a pool range, which is left alone if cb returns
+// non-zero, for the pinning the application tracks itself; called with a region locked, so it
+// mustn't allocate. A NULL cb unregisters the callback
+#[no_mangle]
+pub unsafe extern "C" fn mosalloc_set_pin_callback(
+    cb: Option<PinCallback>,
+    arg: *mut c_void,
+) -> c_int {
+    match mosalloc() {
+        Some(m) => {
+            m.set_pin_callback(cb.map(|cb| (cb, arg as usize)));
+            0
+        }
+        None => {
+            *libc::__errno_location() = libc::ENODEV;
+            -1
+        }
+    }
+}
+
+// int mosalloc_register_thread(pid_t tid);
+// only handle the mmaps of the registered threads (and the ones of MOSALLOC_THREADS), the calling
+// one if tid is 0; the other threads' mmaps are forwarded to libc, the frees and remaps of pool
+// memory are still handled whichever thread makes them
+#[no_mangle]
+pub unsafe extern "C" fn mosalloc_register_thread(tid: pid_t) -> c_int {
+    match threads::register(tid) {
+        true => 0,
+        false => {
+            *libc::__errno_location() = libc::ENOSPC;
+            -1
+        }
+    }
+}
+
+// int mosalloc_unregister_thread(pid_t tid);
+// forward the mmaps of a registered thread (the calling one if tid is 0) to libc again
+#[no_mangle]
+pub unsafe extern "C" fn mosalloc_unregister_thread(tid: pid_t) -> c_int {
+    match threads::unregister(tid) {
+        true => 0,
+        false => {
+            *libc::__errno_location() = libc::ENOENT;
+            -1
+        }
+    }
+}
+
+// size_t mosalloc_page_size_of(const void *addr, int observed);
+// the page size backing addr: as planned by the bookkeeping (its pool interval's, base pages once
+// demoted), or, if observed is non-zero, as the kernel backs it (the hugetlb page size of its
+// VMA or the THP size, the latter needs CAP_SYS_ADMIN); 0 with ENOENT outside the pools, and
+// with ENODATA if the page isn't populated or its backing can't be told
+#[no_mangle]
+pub unsafe extern "C" fn mosalloc_page_size_of(addr: *const c_void, observed: c_int) -> size_t {
+    let planned = match mosalloc() {
+        Some(m) => m.page_size_of(addr as usize),
+        None => {
+            *libc::__errno_location() = libc::ENODEV;
+            return 0;
+        }
+    };
+
+    match planned {
+        Some(_) if observed != 0 => {
+            pagemap::backing_page_size(addr as usize).unwrap_or_else(|| {
+                *libc::__errno_location() = libc::ENODATA;
+                0
+            })
+        }
+        Some(pagesz) => pagesz,
+        None => {
+            *libc::__errno_location() = libc::ENOENT;
+            0
+        }
+    }
+}
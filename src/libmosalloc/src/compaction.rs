@@ -0,0 +1,76 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use mosalloc::utils::misc::size_to_str;
+
+// the alignment of the free space the compaction tries to recover, hugepages need it
+pub const ALIGN: usize = 2 << 20;
+
+// void (*)(void *old, void *new, size_t len, void *arg)
+pub type MoveCallback =
+    unsafe extern "C" fn(*mut libc::c_void, *mut libc::c_void, libc::size_t, *mut libc::c_void);
+
+// compaction passes, the ranges moved (and their bytes), the ones that failed to, and the aligned
+// free space gained
+static RUNS: AtomicUsize = AtomicUsize::new(0);
+static MOVED: AtomicUsize = AtomicUsize::new(0);
+static MOVED_BYTES: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+static RECOVERED: AtomicUsize = AtomicUsize::new(0);
+
+// a movable range relocated by the compaction
+#[derive(Debug, Clone, Copy)]
+pub struct Move {
+    pub from: usize,
+    pub to: usize,
+    pub len: usize,
+}
+
+// the outcome of a compaction pass over a region, along with its aligned free space below the
+// high-water mark before and after (total, largest contiguous)
+#[derive(Debug, Default)]
+pub struct Compaction {
+    pub moves: Vec<Move>,
+    pub failed: usize,
+    pub before: (usize, usize),
+    pub after: (usize, usize),
+}
+
+impl Compaction {
+    pub fn as_string(&self, label: &str) -> String {
+        format!(
+            "({}) {} moved ({}), {} failed, aligned free {} -> {}, largest {} -> {}",
+            label,
+            self.moves.len(),
+            size_to_str(self.moves.iter().map(|x| x.len).sum()),
+            self.failed,
+            size_to_str(self.before.0),
+            size_to_str(self.after.0),
+            size_to_str(self.before.1),
+            size_to_str(self.after.1)
+        )
+    }
+}
+
+pub fn record(x: &Compaction) {
+    RUNS.fetch_add(1, Ordering::Relaxed);
+    MOVED.fetch_add(x.moves.len(), Ordering::Relaxed);
+    MOVED_BYTES.fetch_add(x.moves.iter().map(|x| x.len).sum(), Ordering::Relaxed);
+    FAILED.fetch_add(x.failed, Ordering::Relaxed);
+    RECOVERED.fetch_add(x.after.1.saturating_sub(x.before.1), Ordering::Relaxed);
+}
+
+pub fn print_stats() {
+    let runs = RUNS.load(Ordering::Relaxed);
+    if runs == 0 {
+        return;
+    }
+
+    println!(
+        "compaction: {} passes, {} ranges moved ({}), {} failed, {} of contiguous aligned space recovered",
+        runs,
+        MOVED.load(Ordering::Relaxed),
+        size_to_str(MOVED_BYTES.load(Ordering::Relaxed)),
+        FAILED.load(Ordering::Relaxed),
+        size_to_str(RECOVERED.load(Ordering::Relaxed))
+    );
+}
@@ -0,0 +1,108 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use mosalloc::utils::argparse::parse_addr;
+use mosalloc::utils::control::{push, POLICIES_HEADER};
+
+use crate::init::mosalloc;
+use crate::pagemap::backing_page_size;
+use crate::service;
+
+// how long to wait for the request of a connection, the clients that don't send one get the stats
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(100);
+
+// reply to a single line request: stats (the default), policies, set <key> <value>, compact, or
+// pagesize <addr>
+fn serve(stream: &mut UnixStream) -> String {
+    let mut req = String::new();
+    let _ = stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+    let _ = BufReader::new(&*stream).read_line(&mut req);
+
+    let mosalloc = unsafe { mosalloc().unwrap() };
+    let fields = req.split_whitespace().collect::<Vec<&str>>();
+
+    match fields.as_slice() {
+        [] | ["stats"] => mosalloc.stats().to_text(),
+        ["policies"] => mosalloc
+            .policies()
+            .iter()
+            .fold(format!("{}\n", POLICIES_HEADER), |out, (key, value)| {
+                out + &format!("{} {}\n", key, value)
+            }),
+        ["set", key, value @ ..] if value.len() <= 1 => {
+            match mosalloc.set_policy(key, value.first().unwrap_or(&"")) {
+                Ok(()) => "ok\n".to_string(),
+                Err(e) => format!("error {}\n", e),
+            }
+        }
+        ["compact"] => match mosalloc.compact() {
+            Ok(lines) => lines
+                .iter()
+                .fold("ok\n".to_string(), |out, x| out + x + "\n"),
+            Err(e) => format!("error {}\n", e),
+        },
+        ["pagesize", addr] => match parse_addr(addr).map(|x| (x, mosalloc.page_size_of(x))) {
+            Ok((addr, Some(planned))) => format!(
+                "ok {} {}\n",
+                planned,
+                backing_page_size(addr).map_or("-".to_string(), |x| x.to_string())
+            ),
+            Ok((addr, None)) => format!("error 0x{:x} isn't in a mosalloc region\n", addr),
+            Err(e) => format!("error {}\n", e),
+        },
+        _ => format!("error invalid request: {}\n", req.trim()),
+    }
+}
+
+// serve the stats and the runtime policies on a unix socket, each connection gets a reply to a
+// single request and is closed
+pub fn spawn(path: PathBuf) {
+    // a leftover of an earlier process with the same pid
+    let _ = fs::remove_file(&path);
+
+    let listener = match UnixListener::bind(&path) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("control: {}: {}", path.display(), e);
+            return;
+        }
+    };
+    println!("control: listening on {}", path.display());
+
+    service::spawn("control", move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+
+            let reply = serve(&mut stream);
+            let _ = stream.write_all(reply.as_bytes());
+        }
+    });
+}
+
+// push the current stats to a collector every `period` ms
+pub fn spawn_push(collector: String, period: u64) {
+    service::spawn("push", move || {
+        // only report changes, e.g. a collector that isn't up yet
+        let mut last: Option<String> = None;
+
+        loop {
+            thread::sleep(Duration::from_millis(period));
+
+            let stats = unsafe { mosalloc().unwrap().stats() };
+            let err = push(&collector, &stats).err();
+            if err != last {
+                if let Some(e) = &err {
+                    println!("collector: {}", e);
+                }
+                last = err;
+            }
+        }
+    });
+}
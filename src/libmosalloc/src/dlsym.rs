@@ -0,0 +1,279 @@
+use std::ffi::CStr;
+use std::mem::MaybeUninit;
+use std::ptr::null_mut;
+use std::sync::OnceLock;
+
+use libc::{c_char, c_int, c_void};
+
+use crate::preload_hooks;
+
+// dlsym / dlvsym interposition, so that lookups of the hooked calls don't bypass mosalloc: the
+// exports of libmosalloc are unversioned, which only satisfies non-exact version requests, so
+// e.g. dlvsym(RTLD_DEFAULT, "mmap", "GLIBC_2.2.5") or dlsym(dlopen("libc.so.6"), "mmap")
+// resolve to the libc calls
+
+const RTLD_DL_LINKMAP: c_int = 2;
+
+const DT_NULL: isize = 0;
+const DT_STRTAB: isize = 5;
+const DT_SYMTAB: isize = 6;
+const DT_GNU_HASH: isize = 0x6ffffef5;
+const DT_VERSYM: isize = 0x6ffffff0;
+
+// versym bit of the non-default versions
+const VERSYM_HIDDEN: u16 = 0x8000;
+
+type DlsymFn = unsafe extern "C" fn(*mut c_void, *const c_char) -> *mut c_void;
+type DlvsymFn = unsafe extern "C" fn(*mut c_void, *const c_char, *const c_char) -> *mut c_void;
+
+// the public part of struct link_map
+#[repr(C)]
+struct LinkMap {
+    l_addr: usize,
+    l_name: *const c_char,
+    l_ld: *const ElfDyn,
+    l_next: *mut LinkMap,
+    l_prev: *mut LinkMap,
+}
+
+#[repr(C)]
+struct ElfDyn {
+    d_tag: isize,
+    d_val: usize,
+}
+
+#[cfg(target_pointer_width = "64")]
+#[repr(C)]
+struct ElfSym {
+    st_name: u32,
+    st_info: u8,
+    st_other: u8,
+    st_shndx: u16,
+    st_value: usize,
+    st_size: usize,
+}
+
+#[cfg(target_pointer_width = "32")]
+#[repr(C)]
+struct ElfSym {
+    st_name: u32,
+    st_value: usize,
+    st_size: usize,
+    st_info: u8,
+    st_other: u8,
+    st_shndx: u16,
+}
+
+// the mosalloc hook of a hooked call
+fn hook_of(name: &[u8]) -> Option<*mut c_void> {
+    let hook = match name {
+        b"mmap" => preload_hooks::mmap::mmap as *const (),
+        b"mmap64" => preload_hooks::mmap64::mmap64 as *const (),
+        b"__mmap" => preload_hooks::__mmap as *const (),
+        b"munmap" => preload_hooks::munmap::munmap as *const (),
+        b"__munmap" => preload_hooks::__munmap as *const (),
+        b"mprotect" => preload_hooks::mprotect::mprotect as *const (),
+        b"__mprotect" => preload_hooks::__mprotect as *const (),
+        b"madvise" => preload_hooks::madvise::madvise as *const (),
+        b"__madvise" => preload_hooks::__madvise as *const (),
+        b"mremap" => preload_hooks::mremap::mremap as *const (),
+        b"brk" => preload_hooks::brk::brk as *const (),
+        b"sbrk" => preload_hooks::sbrk::sbrk as *const (),
+        b"__sbrk" => preload_hooks::__sbrk as *const (),
+        #[cfg(feature = "malloc-interpose")]
+        b"malloc_trim" => preload_hooks::malloc_trim::malloc_trim as *const (),
+        b"pkey_alloc" => preload_hooks::pkey_alloc::pkey_alloc as *const (),
+        b"pkey_free" => preload_hooks::pkey_free::pkey_free as *const (),
+        b"pkey_mprotect" => preload_hooks::pkey_mprotect::pkey_mprotect as *const (),
+        b"syscall" => preload_hooks::syscall as *const (),
+        _ => return None,
+    };
+    Some(hook as *mut c_void)
+}
+
+// the object defining addr
+unsafe fn link_map_of(addr: *const c_void) -> *mut LinkMap {
+    let mut info = MaybeUninit::<libc::Dl_info>::uninit();
+    let mut map: *mut LinkMap = null_mut();
+
+    if libc::dladdr1(
+        addr,
+        info.as_mut_ptr(),
+        &mut map as *mut *mut LinkMap as *mut *mut c_void,
+        RTLD_DL_LINKMAP,
+    ) == 0
+    {
+        return null_mut();
+    }
+    map
+}
+
+fn gnu_hash(name: &[u8]) -> u32 {
+    name.iter()
+        .fold(5381u32, |h, &c| h.wrapping_mul(33).wrapping_add(c as u32))
+}
+
+// look up the default version of a libdl symbol (part of libc since 2.34) in its dynamic symbol
+// table, dlsym and dlvsym can't be used to find themselves once interposed
+unsafe fn libdl_symbol(name: &[u8]) -> Option<usize> {
+    let map = link_map_of(libc::dlerror as *const c_void);
+    if map.is_null() {
+        return None;
+    }
+
+    let base = (*map).l_addr;
+    // the dynamic section entries are relocated in place, except on a few architectures
+    let ptr = |x: usize| if x < base { x + base } else { x };
+
+    let (mut strtab, mut symtab, mut gnu_hash_tab, mut versym) = (0, 0, 0, 0);
+    let mut dynamic = (*map).l_ld;
+    while (*dynamic).d_tag != DT_NULL {
+        match (*dynamic).d_tag {
+            DT_STRTAB => strtab = ptr((*dynamic).d_val),
+            DT_SYMTAB => symtab = ptr((*dynamic).d_val),
+            DT_GNU_HASH => gnu_hash_tab = ptr((*dynamic).d_val),
+            DT_VERSYM => versym = ptr((*dynamic).d_val),
+            _ => {}
+        }
+        dynamic = dynamic.add(1);
+    }
+    if strtab == 0 || symtab == 0 || gnu_hash_tab == 0 {
+        return None;
+    }
+
+    let header = gnu_hash_tab as *const u32;
+    let (nbuckets, symoffset, bloom_size) = (*header, *header.add(1), *header.add(2));
+    let buckets = (header.add(4) as *const usize).add(bloom_size as usize) as *const u32;
+    let chain = buckets.add(nbuckets as usize);
+
+    let hash = gnu_hash(name);
+    let mut idx = *buckets.add((hash % nbuckets) as usize);
+    if idx < symoffset {
+        return None;
+    }
+
+    loop {
+        let h = *chain.add((idx - symoffset) as usize);
+        let sym = (symtab as *const ElfSym).add(idx as usize);
+
+        if (h | 1) == (hash | 1)
+            && (*sym).st_shndx != 0
+            && CStr::from_ptr((strtab + (*sym).st_name as usize) as *const c_char).to_bytes()
+                == name
+            && (versym == 0 || *(versym as *const u16).add(idx as usize) & VERSYM_HIDDEN == 0)
+        {
+            return Some(base + (*sym).st_value);
+        }
+
+        if h & 1 != 0 {
+            return None;
+        }
+        idx += 1;
+    }
+}
+
+fn real_dlsym() -> DlsymFn {
+    static REAL: OnceLock<usize> = OnceLock::new();
+    let addr = *REAL.get_or_init(|| unsafe { libdl_symbol(b"dlsym") }.expect("no libc dlsym"));
+    unsafe { std::mem::transmute::<usize, DlsymFn>(addr) }
+}
+
+fn real_dlvsym() -> DlvsymFn {
+    static REAL: OnceLock<usize> = OnceLock::new();
+    let addr = *REAL.get_or_init(|| unsafe { libdl_symbol(b"dlvsym") }.expect("no libc dlvsym"));
+    unsafe { std::mem::transmute::<usize, DlvsymFn>(addr) }
+}
+
+// look up a symbol with dlsym or dlvsym
+unsafe fn lookup(
+    handle: *mut c_void,
+    symbol: *const c_char,
+    version: *const c_char,
+) -> *mut c_void {
+    if version.is_null() {
+        real_dlsym()(handle, symbol)
+    } else {
+        real_dlvsym()(handle, symbol, version)
+    }
+}
+
+// return the mosalloc hook instead of a hooked call resolved to libc
+unsafe fn redirect(addr: *mut c_void, symbol: *const c_char) -> *mut c_void {
+    let libc_map = link_map_of(libc::gnu_get_libc_version as *const c_void);
+    if addr.is_null() || link_map_of(addr) != libc_map {
+        return addr;
+    }
+
+    hook_of(CStr::from_ptr(symbol).to_bytes()).unwrap_or(addr)
+}
+
+// RTLD_NEXT is relative to the caller, which would be libmosalloc if forwarded as is, so for
+// other callers search the objects loaded after the caller's one by one instead
+unsafe fn lookup_next(
+    caller: *const c_void,
+    symbol: *const c_char,
+    version: *const c_char,
+) -> *mut c_void {
+    let own = link_map_of(hook_of as *const c_void);
+    let map = link_map_of(caller);
+    if map.is_null() || map == own {
+        return lookup(libc::RTLD_NEXT, symbol, version);
+    }
+
+    let mut passed_own = false;
+    let mut cur = (*map).l_next;
+    while !cur.is_null() {
+        passed_own |= cur == own;
+
+        // link maps are only valid handles once dlopened
+        let handle = libc::dlopen((*cur).l_name, libc::RTLD_LAZY | libc::RTLD_NOLOAD);
+        if !handle.is_null() {
+            let addr = lookup(handle, symbol, version);
+            libc::dlclose(handle);
+
+            // a lookup through a handle also searches its dependencies
+            if !addr.is_null() && link_map_of(addr) == cur {
+                return if passed_own {
+                    redirect(addr, symbol)
+                } else {
+                    addr
+                };
+            }
+        }
+        cur = (*cur).l_next;
+    }
+
+    null_mut()
+}
+
+// void *dlsym(void *handle, const char *symbol);
+#[no_mangle]
+pub unsafe extern "C" fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void {
+    if handle == libc::RTLD_NEXT {
+        return lookup_next(
+            core::arch::return_address!() as *const c_void,
+            symbol,
+            null_mut(),
+        );
+    }
+
+    redirect(real_dlsym()(handle, symbol), symbol)
+}
+
+// void *dlvsym(void *handle, const char *symbol, const char *version);
+#[no_mangle]
+pub unsafe extern "C" fn dlvsym(
+    handle: *mut c_void,
+    symbol: *const c_char,
+    version: *const c_char,
+) -> *mut c_void {
+    if handle == libc::RTLD_NEXT {
+        return lookup_next(
+            core::arch::return_address!() as *const c_void,
+            symbol,
+            version,
+        );
+    }
+
+    redirect(real_dlvsym()(handle, symbol, version), symbol)
+}
@@ -0,0 +1,77 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use mosalloc::utils::trace::TraceOp;
+
+const GOLDEN_GAMMA: u64 = 0x9e3779b97f4a7c15;
+
+// splitmix64 finalizer
+#[inline]
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+// allocation failure injection, the first `after` selected calls go through and each of the
+// following ones fails with probability `rate`, the decision for the n-th call only depends on
+// the seed and n, so a single-threaded run fails the same calls every time
+#[derive(Debug)]
+pub struct FaultInjector {
+    // bitmask of the selected ops
+    ops: u32,
+    rate: f64,
+    after: u64,
+    seed: u64,
+    calls: AtomicU64,
+    injected: AtomicU64,
+}
+
+impl FaultInjector {
+    pub fn new(ops: &[TraceOp], rate: f64, after: u64, seed: u64) -> Self {
+        assert!((0.0..=1.0).contains(&rate), "fault rate must be in [0, 1]");
+
+        Self {
+            ops: ops.iter().fold(0, |acc, &x| acc | 1 << x as u32),
+            rate,
+            after,
+            seed,
+            calls: AtomicU64::new(0),
+            injected: AtomicU64::new(0),
+        }
+    }
+
+    // whether the current call of op should fail
+    pub fn inject(&self, op: TraceOp) -> bool {
+        if self.ops & (1 << op as u32) == 0 {
+            return false;
+        }
+
+        let n = self.calls.fetch_add(1, Ordering::Relaxed);
+        if n < self.after {
+            return false;
+        }
+
+        let z = self
+            .seed
+            .wrapping_add(n.wrapping_add(1).wrapping_mul(GOLDEN_GAMMA));
+        // top 53 bits, uniform in [0, 1)
+        let p = (mix(z) >> 11) as f64 / (1u64 << 53) as f64;
+        let fail = p < self.rate;
+
+        if fail {
+            self.injected.fetch_add(1, Ordering::Relaxed);
+        }
+        fail
+    }
+
+    pub fn print_stats(&self) {
+        println!(
+            "fault: injected {} failures in {} calls (rate {}, after {}, seed {})",
+            self.injected.load(Ordering::Relaxed),
+            self.calls.load(Ordering::Relaxed),
+            self.rate,
+            self.after,
+            self.seed
+        );
+    }
+}
@@ -0,0 +1,138 @@
+use std::mem::size_of;
+use std::ops::{Deref, DerefMut, Range};
+
+use mosalloc::utils::misc::size_to_str;
+use mosalloc_core::freemap::Ranges;
+
+use crate::metadata::{MetaAlloc, MetaVec};
+
+const ENTRY: usize = size_of::<Range<usize>>();
+
+// storage of a region's free map: reserved up front for the FFA size, its soft limit, past which
+// it spills over to larger buffers (served by the internal allocator's large path, i.e. mremap'd,
+// unless the metadata is protected), up to an optional hard cap on its bytes where it stops
+// growing: the free range splits fail, and the ranges freed that don't merge are left out
+#[derive(Debug)]
+pub struct FreeMapStore {
+    ranges: MetaVec<Range<usize>>,
+    soft: usize,
+    hard: Option<usize>,
+    // most ranges held at once, growths past the soft limit and the inserts refused
+    peak: usize,
+    spilled: usize,
+    refused: usize,
+}
+
+impl FreeMapStore {
+    // the soft limit in ranges
+    pub fn new(soft: usize) -> Self {
+        Self {
+            ranges: Vec::with_capacity_in(soft, MetaAlloc),
+            soft,
+            hard: None,
+            peak: 0,
+            spilled: 0,
+            refused: 0,
+        }
+    }
+
+    // the hard cap in bytes
+    pub fn set_hard(&mut self, hard: Option<usize>) {
+        self.hard = hard.map(|x| x / ENTRY);
+    }
+
+    // make room for one more range, doubling the storage up to the hard cap
+    fn reserve(&mut self) -> bool {
+        let len = self.ranges.len();
+        let max = self.hard.unwrap_or(usize::MAX);
+        if len >= max {
+            self.refused += 1;
+            return false;
+        }
+        if len < self.ranges.capacity() {
+            return true;
+        }
+
+        let additional = len.max(1).min(max - len);
+        if self.ranges.try_reserve_exact(additional).is_err() {
+            self.refused += 1;
+            return false;
+        }
+        if self.ranges.capacity() > self.soft {
+            self.spilled += 1;
+        }
+        true
+    }
+
+    pub fn push(&mut self, range: Range<usize>) -> bool {
+        let len = self.ranges.len();
+        self.insert(len, range)
+    }
+
+    pub fn clear(&mut self) {
+        self.ranges.clear();
+    }
+
+    pub fn retain(&mut self, f: impl FnMut(&Range<usize>) -> bool) {
+        self.ranges.retain(f);
+    }
+
+    // the inserts refused so far
+    #[inline]
+    pub fn refused(&self) -> usize {
+        self.refused
+    }
+
+    // bytes of storage held
+    pub fn capacity(&self) -> usize {
+        self.ranges.capacity() * ENTRY
+    }
+
+    // whether the map outgrew its soft limit or hit its hard cap
+    pub fn strained(&self) -> bool {
+        self.spilled > 0 || self.refused > 0
+    }
+
+    pub fn as_string(&self) -> String {
+        format!(
+            "{} ranges (peak {}), {} of storage (soft limit {}, hard cap {}), {} spills, {} refused",
+            self.ranges.len(),
+            self.peak,
+            size_to_str(self.capacity()),
+            size_to_str(self.soft * ENTRY),
+            self.hard
+                .map_or("none".to_string(), |x| size_to_str(x * ENTRY)),
+            self.spilled,
+            self.refused
+        )
+    }
+}
+
+impl Deref for FreeMapStore {
+    type Target = [Range<usize>];
+
+    fn deref(&self) -> &Self::Target {
+        &self.ranges
+    }
+}
+
+impl DerefMut for FreeMapStore {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.ranges
+    }
+}
+
+impl Ranges for FreeMapStore {
+    fn insert(&mut self, idx: usize, range: Range<usize>) -> bool {
+        if !self.reserve() {
+            return false;
+        }
+        self.ranges.insert(idx, range);
+        self.peak = self.peak.max(self.ranges.len());
+        true
+    }
+
+    fn remove(&mut self, idx: usize) -> Range<usize> {
+        self.ranges.remove(idx)
+    }
+}
@@ -0,0 +1,49 @@
+use std::fs::File;
+use std::io::Write;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mosalloc::utils::heatmap::{HeatmapInterval, HEATMAP_HEADER};
+use mosalloc::utils::htlb::PAGE_SIZE;
+
+use crate::pagemap::{clear_soft_dirty, range_touched};
+use crate::rawio::RawFile;
+use crate::service;
+
+// spawn a thread sampling the given intervals every `period` ms
+pub fn spawn(path: String, period: u64, intervals: Vec<HeatmapInterval>) {
+    service::spawn("heatmap", move || {
+        let mut out = RawFile::create(&path).unwrap();
+        let pagemap = File::open("/proc/self/pagemap").unwrap();
+
+        writeln!(out, "{}", HEATMAP_HEADER).unwrap();
+        for interval in intervals.iter() {
+            writeln!(out, "{}", interval.header_line()).unwrap();
+        }
+
+        let start = Instant::now();
+        clear_soft_dirty();
+
+        loop {
+            thread::sleep(Duration::from_millis(period));
+
+            let t = start.elapsed().as_millis() as u64;
+            for interval in intervals.iter() {
+                let touched = (0..interval.nrpages)
+                    .map(|i| {
+                        range_touched(&pagemap, interval.start + i * interval.pagesz, *PAGE_SIZE)
+                    })
+                    .collect::<Vec<bool>>();
+                writeln!(
+                    out,
+                    "{}",
+                    HeatmapInterval::sample_line(interval.id, t, &touched)
+                )
+                .unwrap();
+            }
+
+            out.flush().unwrap();
+            clear_soft_dirty();
+        }
+    });
+}
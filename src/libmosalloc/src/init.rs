@@ -1,20 +1,106 @@
-use ctor::ctor;
+use ctor::{ctor, dtor};
 
+#[allow(unused_imports)]
 use mosalloc::utils::htlb::{HookType, MosallocConfig};
 
+use crate::allocator::Allocator;
+use crate::internal_allocator::InternalAllocator;
+use crate::internal_maps;
+use crate::preload_hooks::preload_alloc;
+#[cfg(feature = "preload")]
 use crate::preload_hooks::preload_init;
-use crate::seccomp_hooks::seccomp_init;
+#[cfg(all(feature = "preload", feature = "seccomp"))]
+use crate::preload_hooks::track_inside;
+#[cfg(all(feature = "preload", feature = "seccomp"))]
+use crate::seccomp_hooks::hybrid_init;
+#[cfg(feature = "seccomp")]
+use crate::seccomp_hooks::{seccomp_alloc, seccomp_init};
+use crate::service;
+
+// the active allocator instance, regardless of the hook type
+pub unsafe fn mosalloc() -> Option<&'static mut Allocator> {
+    #[cfg(feature = "seccomp")]
+    return preload_alloc().or_else(|| seccomp_alloc());
+    #[cfg(not(feature = "seccomp"))]
+    return preload_alloc();
+}
+
+// the features configured but not built in, they're left off
+fn check_features(config: &MosallocConfig) {
+    let missing = [
+        (
+            "trace",
+            config.trace.is_some() && cfg!(not(feature = "trace")),
+        ),
+        (
+            "ctl",
+            (config.control_dir.is_some() || config.collector.is_some())
+                && cfg!(not(feature = "ctl")),
+        ),
+        (
+            "stats",
+            config.stats_file.is_some() && cfg!(not(feature = "stats")),
+        ),
+    ];
+    for (feature, _) in missing.iter().filter(|x| x.1) {
+        println!("mosalloc: the {} feature isn't built in, ignored", feature);
+    }
+}
 
 #[ctor]
 unsafe fn activate_mosalloc() {
+    // the unit tests run without the hooks
+    if cfg!(test) {
+        return;
+    }
+
     let config = MosallocConfig::load();
+    if let Some(size) = config.internal_region {
+        internal_maps::reserve(size);
+    }
+    InternalAllocator::configure(config.arena_size, config.mmap_threshold, config.max_align);
+    service::set_housekeeping(&config.housekeeping_cpus);
+    check_features(&config);
 
     match config.hook {
+        #[cfg(feature = "preload")]
         HookType::PRELOAD => {
             preload_init(config);
         }
+        #[cfg(feature = "seccomp")]
         HookType::SECCOMP => {
             seccomp_init(config);
         }
+        #[cfg(all(feature = "preload", feature = "seccomp"))]
+        HookType::HYBRID => {
+            // the threads inside mosalloc are tracked from the start, its services included
+            track_inside();
+            let hooks = config.hooks.clone();
+            preload_init(config);
+            hybrid_init(&hooks);
+        }
+        #[allow(unreachable_patterns)]
+        hook => {
+            println!(
+                "mosalloc: the {} hooks aren't built in, disabled",
+                hook.as_str()
+            );
+        }
+    }
+}
+
+#[dtor]
+unsafe fn deactivate_mosalloc() {
+    if let Some(mosalloc) = mosalloc() {
+        #[cfg(feature = "trace")]
+        mosalloc.flush_trace();
+        mosalloc.close_window();
+        #[cfg(feature = "stats")]
+        mosalloc.print_stats();
+        mosalloc.save_snapshot();
+        #[cfg(feature = "stats")]
+        mosalloc.save_stats();
+        #[cfg(feature = "ctl")]
+        mosalloc.close_control();
     }
 }
@@ -5,31 +5,51 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 
 use libc;
 
-use crate::preload_hooks;
+use crate::internal_maps;
 
+use mosalloc::utils::control::InternalStats;
 use mosalloc::utils::misc::align_up;
 
+// size of the static arena, used until (and unless) a larger one is configured
 const ARENA_SIZE: usize = 256 * 1024;
 const MAX_SUPPORTED_ALIGN: usize = 4096;
 const MMAP_THRESHOLD: usize = 4096;
+const PAGE: usize = 4096;
 
 /// Internal alloator for libmosalloc / Rust internal allocations.
 /// Based on the simple example allocator in GlobalAlloc documentation.
 /// Uses a small statically allocated arena for the small allocations and
 /// falls back to mmap (page-sized) allocations for larger requests.
 /// The static arena only supports freeing from the top.
+/// The arena size, mmap threshold and max alignment can be changed at
+/// startup through configure, a larger arena is mmap'd then.
 #[repr(C, align(4096))]
 pub struct InternalAllocator {
     arena: UnsafeCell<[u8; ARENA_SIZE]>,
     idx: AtomicUsize,
+    // the arena in use (0 for the static one) and its size
+    base: AtomicUsize,
+    size: AtomicUsize,
+    mmap_threshold: AtomicUsize,
+    max_align: AtomicUsize,
+    peak: AtomicUsize,
+    // allocations that returned null, because of an exhausted arena or an unsupported alignment
+    failed: AtomicUsize,
     mmap_total: AtomicUsize,
     mmap_overhead: AtomicUsize,
 }
 
-#[global_allocator]
+// the unit tests use the system allocator, the harness outgrows the static arena
+#[cfg_attr(not(test), global_allocator)]
 static INTERNAL_ALLOCATOR: InternalAllocator = InternalAllocator {
     arena: UnsafeCell::new([0; ARENA_SIZE]),
     idx: AtomicUsize::new(0),
+    base: AtomicUsize::new(0),
+    size: AtomicUsize::new(ARENA_SIZE),
+    mmap_threshold: AtomicUsize::new(MMAP_THRESHOLD),
+    max_align: AtomicUsize::new(MAX_SUPPORTED_ALIGN),
+    peak: AtomicUsize::new(0),
+    failed: AtomicUsize::new(0),
     mmap_total: AtomicUsize::new(0),
     mmap_overhead: AtomicUsize::new(0),
 };
@@ -37,69 +57,167 @@ static INTERNAL_ALLOCATOR: InternalAllocator = InternalAllocator {
 unsafe impl Sync for InternalAllocator {}
 
 impl InternalAllocator {
-    pub unsafe fn print_stats() {
-        let arena_total = INTERNAL_ALLOCATOR.idx.load(Ordering::Relaxed);
-        let arena_rem = ARENA_SIZE - arena_total;
+    // apply the configured arena sizing, has to be called before any other thread is started,
+    // the allocations already served by the static arena stay valid but are never reused
+    pub fn configure(arena_size: usize, mmap_threshold: usize, max_align: usize) {
+        let a = &INTERNAL_ALLOCATOR;
+
+        a.mmap_threshold.store(mmap_threshold, Ordering::Relaxed);
+        a.max_align.store(max_align, Ordering::Relaxed);
+
+        if arena_size <= ARENA_SIZE {
+            a.size.store(arena_size, Ordering::Relaxed);
+            return;
+        }
+
+        let arena = a.mmap_alloc(arena_size, PAGE);
+        if arena.is_null() {
+            println!(
+                "(arena) failed to map a {:.02}KB arena, keeping the static one",
+                arena_size as f64 / 1024.0
+            );
+            return;
+        }
+
+        a.base.store(arena as usize, Ordering::Relaxed);
+        a.size.store(arena_size, Ordering::Relaxed);
+        a.idx.store(0, Ordering::Release);
+    }
+
+    // the mmap threshold can be changed at any time, e.g. on the control socket
+    pub fn mmap_threshold() -> usize {
+        INTERNAL_ALLOCATOR.mmap_threshold.load(Ordering::Relaxed)
+    }
+
+    pub fn set_mmap_threshold(mmap_threshold: usize) {
+        INTERNAL_ALLOCATOR
+            .mmap_threshold
+            .store(mmap_threshold, Ordering::Relaxed);
+    }
+
+    pub fn stats() -> InternalStats {
+        let a = &INTERNAL_ALLOCATOR;
+        let (mapped, rss) = internal_maps::usage();
+
+        InternalStats {
+            arena_size: a.size.load(Ordering::Relaxed),
+            arena_allocated: a.idx.load(Ordering::Relaxed),
+            arena_peak: a.peak.load(Ordering::Relaxed),
+            mmap_allocated: a.mmap_total.load(Ordering::Relaxed),
+            mmap_overhead: a.mmap_overhead.load(Ordering::Relaxed),
+            failed: a.failed.load(Ordering::Relaxed),
+            mapped,
+            rss,
+        }
+    }
+
+    pub fn print_stats() {
+        let stats = Self::stats();
         println!(
-            "(arena) allocated: {:.02}KB, remaining: {:.02}KB",
-            arena_total as f64 / 1024.0,
-            arena_rem as f64 / 1024.0
+            "(arena) allocated: {:.02}KB, remaining: {:.02}KB, peak: {:.02}KB, failed: {}",
+            stats.arena_allocated as f64 / 1024.0,
+            stats.arena_size.saturating_sub(stats.arena_allocated) as f64 / 1024.0,
+            stats.arena_peak as f64 / 1024.0,
+            stats.failed
         );
-
-        let mmap_total = INTERNAL_ALLOCATOR.mmap_total.load(Ordering::Relaxed);
-        let mmap_overhead = INTERNAL_ALLOCATOR.mmap_overhead.load(Ordering::Relaxed);
         println!(
             "(mmap) allocated: {:.02}MB, overhead: {:.02}KB",
-            mmap_total as f64 / 1024.0 / 1024.0,
-            mmap_overhead as f64 / 1024.0
+            stats.mmap_allocated as f64 / 1024.0 / 1024.0,
+            stats.mmap_overhead as f64 / 1024.0
         );
     }
 
-    fn mmap_alloc(&self, size: usize) -> *mut u8 {
-        preload_hooks::libc_mmap(
-            null_mut() as *mut _,
-            size,
-            libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
-            libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
-            -1,
-            0,
-        ) as *mut u8
+    #[inline]
+    fn arena_base(&self) -> usize {
+        match self.base.load(Ordering::Relaxed) {
+            0 => self.arena.get() as usize,
+            base => base,
+        }
+    }
+
+    // whether ptr was served by the static or the configured arena, rather than by mmap
+    #[inline]
+    fn in_arena(&self, ptr: *mut u8) -> bool {
+        let ptr = ptr as usize;
+        let arena = self.arena.get() as usize;
+        let base = self.base.load(Ordering::Relaxed);
+
+        (ptr >= arena && ptr < arena + ARENA_SIZE)
+            || (base != 0 && ptr >= base && ptr < base + self.size.load(Ordering::Relaxed))
+    }
+
+    // page-sized mapping, over-allocated and trimmed for alignments above the page size, never
+    // executable (W^X), libmosalloc doesn't generate code
+    fn mmap_alloc(&self, size: usize, align: usize) -> *mut u8 {
+        let len = if align > PAGE { size + align } else { size };
+        let ret = internal_maps::map(len, 0);
+        if ret == libc::MAP_FAILED {
+            return null_mut();
+        }
+
+        let raw = ret as usize;
+        let start = align_up(raw, align);
+        if len > size {
+            let end = align_up(start + size, PAGE);
+            if start > raw {
+                internal_maps::unmap(raw as *mut _, start - raw);
+            }
+            if raw + len > end {
+                internal_maps::unmap(end as *mut _, raw + len - end);
+            }
+        }
+
+        start as *mut u8
     }
 
     unsafe fn alloc_helper(&self, layout: Layout, zero: bool) -> *mut u8 {
         let size = layout.size();
         let align = layout.align();
 
-        if align > MAX_SUPPORTED_ALIGN {
+        if align > self.max_align.load(Ordering::Relaxed) {
+            self.failed.fetch_add(1, Ordering::Relaxed);
             return null_mut();
         }
 
-        if size >= MMAP_THRESHOLD {
+        if size >= self.mmap_threshold.load(Ordering::Relaxed) {
+            let ptr = self.mmap_alloc(size, align);
+            if ptr.is_null() {
+                self.failed.fetch_add(1, Ordering::Relaxed);
+                return ptr;
+            }
+
             self.mmap_total.fetch_add(size, Ordering::Relaxed);
             self.mmap_overhead
-                .fetch_add(align_up(size, 4096) - size, Ordering::Relaxed);
+                .fetch_add(align_up(size, PAGE) - size, Ordering::Relaxed);
 
-            return self.mmap_alloc(size) as *mut u8;
+            return ptr;
         }
 
+        let base = self.arena_base();
+        let arena_size = self.size.load(Ordering::Relaxed);
         match self
             .idx
-            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |mut idx| {
-                idx = align_up(idx, align);
-                let new_idx = idx.checked_add(size).unwrap();
-                if new_idx > ARENA_SIZE {
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |idx| {
+                // align the address, the configured arena is only page aligned
+                let new_idx = (align_up(base + idx, align) - base).checked_add(size)?;
+                if new_idx > arena_size {
                     return None;
                 }
                 Some(new_idx)
             }) {
             Ok(prev_idx) => {
-                let ptr = (self.arena.get() as *mut u8).add(align_up(prev_idx, align));
+                let ptr = align_up(base + prev_idx, align) as *mut u8;
+                self.peak
+                    .fetch_max(ptr as usize - base + size, Ordering::Relaxed);
                 if zero {
                     write_bytes(ptr, 0, size);
                 }
                 ptr
             }
-            Err(_) => null_mut(),
+            Err(_) => {
+                self.failed.fetch_add(1, Ordering::Relaxed);
+                null_mut()
+            }
         }
     }
 }
@@ -112,18 +230,20 @@ unsafe impl GlobalAlloc for InternalAllocator {
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let size = layout.size();
 
-        if size >= MMAP_THRESHOLD {
+        // the threshold may have changed since ptr was allocated
+        if !self.in_arena(ptr) {
             self.mmap_total.fetch_sub(size, Ordering::Relaxed);
             self.mmap_overhead
-                .fetch_sub(align_up(size, 4096) - size, Ordering::Relaxed);
+                .fetch_sub(align_up(size, PAGE) - size, Ordering::Relaxed);
 
-            assert_eq!(preload_hooks::libc_munmap(ptr as *mut _, layout.size()), 0);
+            assert_eq!(internal_maps::unmap(ptr as *mut _, layout.size()), 0);
             return;
         }
 
+        let base = self.arena_base();
         self.idx
             .fetch_update(Ordering::AcqRel, Ordering::Acquire, |idx| {
-                let top = (self.arena.get() as *mut u8).add(idx);
+                let top = (base as *mut u8).add(idx);
                 if ptr.add(size) != top {
                     return None;
                 }
@@ -140,30 +260,39 @@ unsafe impl GlobalAlloc for InternalAllocator {
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
         let old_size = layout.size();
         let align = layout.align();
+        let mmapped = !self.in_arena(ptr);
 
-        if (old_size >= MMAP_THRESHOLD) ^ (new_size >= MMAP_THRESHOLD) {
+        // mremap doesn't keep alignments above the page size
+        if mmapped != (new_size >= self.mmap_threshold.load(Ordering::Relaxed))
+            || (mmapped && align > PAGE)
+        {
             let new_ptr = self.alloc(Layout::from_size_align(new_size, align).unwrap());
+            if new_ptr.is_null() {
+                return new_ptr;
+            }
             copy_nonoverlapping(ptr, new_ptr, old_size.min(new_size));
             self.dealloc(ptr, layout);
             return new_ptr;
         }
 
-        if old_size >= MMAP_THRESHOLD {
+        if mmapped {
             self.mmap_total.fetch_sub(old_size, Ordering::Relaxed);
             self.mmap_overhead
-                .fetch_sub(align_up(old_size, 4096) - old_size, Ordering::Relaxed);
+                .fetch_sub(align_up(old_size, PAGE) - old_size, Ordering::Relaxed);
             self.mmap_total.fetch_add(new_size, Ordering::Relaxed);
             self.mmap_overhead
-                .fetch_add(align_up(new_size, 4096) - new_size, Ordering::Relaxed);
+                .fetch_add(align_up(new_size, PAGE) - new_size, Ordering::Relaxed);
 
-            let ret = libc::mremap(ptr as *mut _, old_size, new_size, libc::MREMAP_MAYMOVE);
+            let ret = internal_maps::remap(ptr as *mut _, old_size, new_size);
             assert!(ret != libc::MAP_FAILED);
             return ret as *mut u8;
         } else {
-            if self
+            let base = self.arena_base();
+            let arena_size = self.size.load(Ordering::Relaxed);
+            match self
                 .idx
                 .fetch_update(Ordering::AcqRel, Ordering::Acquire, |idx| {
-                    let top = (self.arena.get() as *mut u8).add(idx);
+                    let top = (base as *mut u8).add(idx);
                     if ptr.add(old_size) != top {
                         return None;
                     }
@@ -172,19 +301,24 @@ unsafe impl GlobalAlloc for InternalAllocator {
                         return Some(idx - (old_size - new_size));
                     }
 
-                    if idx + (new_size - old_size) > ARENA_SIZE {
+                    if idx + (new_size - old_size) > arena_size {
                         return None;
                     }
 
                     Some(idx + (new_size - old_size))
-                })
-                .is_err()
-            {
-                let new_ptr = self.alloc(Layout::from_size_align(new_size, align).unwrap());
-                copy_nonoverlapping(ptr, new_ptr, old_size.min(new_size));
-                new_ptr
-            } else {
-                ptr
+                }) {
+                Ok(idx) => {
+                    self.peak
+                        .fetch_max(idx + new_size - old_size, Ordering::Relaxed);
+                    ptr
+                }
+                Err(_) => {
+                    let new_ptr = self.alloc(Layout::from_size_align(new_size, align).unwrap());
+                    if !new_ptr.is_null() {
+                        copy_nonoverlapping(ptr, new_ptr, old_size.min(new_size));
+                    }
+                    new_ptr
+                }
             }
         }
     }
@@ -0,0 +1,271 @@
+use std::ffi::CStr;
+use std::ptr::{copy_nonoverlapping, null_mut};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use libc::{self, c_void};
+
+use crate::preload_hooks;
+use crate::smaps::smaps_named_field;
+
+use mosalloc::utils::misc::{align_up, size_to_str};
+
+const PAGE: usize = 4096;
+
+// from linux/prctl.h, naming anonymous mappings needs a 5.17+ kernel built with
+// CONFIG_ANON_VMA_NAME
+const PR_SET_VMA: i32 = 0x53564d41;
+const PR_SET_VMA_ANON_NAME: usize = 0;
+
+// mosalloc's own mappings show up as [anon:mosalloc:internal] in /proc/<pid>/maps
+const NAME: &CStr = c"mosalloc:internal";
+pub const MAPS_NAME: &str = "[anon:mosalloc:internal]";
+
+// free ranges of the internal region, the ones that don't fit are leaked
+const MAX_HOLES: usize = 64;
+
+// address space mapped for mosalloc itself (internal arena and allocations, metadata, trace
+// buffers), its peak and the mappings that didn't fit in the internal region
+static MAPPED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static SPILLED: AtomicUsize = AtomicUsize::new(0);
+static NAMED: AtomicBool = AtomicBool::new(true);
+
+static AREA: AtomicUsize = AtomicUsize::new(0);
+static AREA_SIZE: AtomicUsize = AtomicUsize::new(0);
+static HOLES: Mutex<([(usize, usize); MAX_HOLES], usize)> = Mutex::new(([(0, 0); MAX_HOLES], 0));
+
+// name an anonymous mapping (the kernel copies the name), given up on for good once the kernel
+// turns out not to support it
+pub fn set_name(addr: *mut c_void, len: usize, name: &CStr) {
+    if !NAMED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let ret = unsafe {
+        libc::prctl(
+            PR_SET_VMA,
+            PR_SET_VMA_ANON_NAME,
+            addr as usize,
+            len,
+            name.as_ptr() as usize,
+        )
+    };
+    if ret != 0
+        && unsafe { *libc::__errno_location() } == libc::EINVAL
+        && NAMED.swap(false, Ordering::Relaxed)
+    {
+        println!("(internal) the kernel can't name mappings, the internal rss isn't reported");
+    }
+}
+
+fn name(addr: *mut c_void, len: usize) {
+    set_name(addr, len, NAME);
+}
+
+fn account(len: isize) {
+    if len < 0 {
+        MAPPED.fetch_sub(len.unsigned_abs(), Ordering::Relaxed);
+    } else {
+        let mapped = MAPPED.fetch_add(len as usize, Ordering::Relaxed) + len as usize;
+        PEAK.fetch_max(mapped, Ordering::Relaxed);
+    }
+}
+
+#[inline]
+fn in_area(addr: usize) -> bool {
+    let area = AREA.load(Ordering::Relaxed);
+    area != 0 && addr >= area && addr < area + AREA_SIZE.load(Ordering::Relaxed)
+}
+
+// first fit out of the internal region holes
+fn take(len: usize) -> Option<usize> {
+    let mut guard = HOLES.lock().unwrap();
+    let (holes, n) = &mut *guard;
+
+    let i = (0..*n).find(|&i| holes[i].1 - holes[i].0 >= len)?;
+    let addr = holes[i].0;
+    holes[i].0 += len;
+    if holes[i].0 == holes[i].1 {
+        holes.copy_within(i + 1..*n, i);
+        *n -= 1;
+    }
+    Some(addr)
+}
+
+// give [start, end) back to the internal region, merged with its neighbours
+fn give(start: usize, end: usize) {
+    let mut guard = HOLES.lock().unwrap();
+    let (holes, n) = &mut *guard;
+
+    let i = (0..*n).find(|&i| holes[i].0 >= end).unwrap_or(*n);
+    let prev = i > 0 && holes[i - 1].1 == start;
+    let next = i < *n && holes[i].0 == end;
+
+    match (prev, next) {
+        (true, true) => {
+            holes[i - 1].1 = holes[i].1;
+            holes.copy_within(i + 1..*n, i);
+            *n -= 1;
+        }
+        (true, false) => holes[i - 1].1 = end,
+        (false, true) => holes[i].0 = start,
+        (false, false) if *n < MAX_HOLES => {
+            holes.copy_within(i..*n, i + 1);
+            holes[i] = (start, end);
+            *n += 1;
+        }
+        (false, false) => {}
+    }
+}
+
+// reserve the internal region, has to be called before the first internal mapping; it's left to
+// the kernel, which places it top-down along with the shared libraries, far from the pools that
+// are laid out up from the program break
+pub fn reserve(size: usize) {
+    let size = align_up(size, PAGE);
+    let area = preload_hooks::libc_mmap(
+        null_mut(),
+        size,
+        libc::PROT_NONE,
+        libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_NORESERVE,
+        -1,
+        0,
+    );
+    if area == libc::MAP_FAILED {
+        println!(
+            "(internal) failed to reserve a {} internal region",
+            size_to_str(size)
+        );
+        return;
+    }
+
+    name(area, size);
+    *HOLES.lock().unwrap() = {
+        let mut holes = [(0, 0); MAX_HOLES];
+        holes[0] = (area as usize, area as usize + size);
+        (holes, 1)
+    };
+    AREA_SIZE.store(size, Ordering::Relaxed);
+    AREA.store(area as usize, Ordering::Relaxed);
+}
+
+// anonymous RW mapping for mosalloc itself, named and accounted apart from the target's memory,
+// out of the internal region when there's one
+pub fn map(len: usize, flags: i32) -> *mut c_void {
+    let len = align_up(len, PAGE);
+    let prot = libc::PROT_READ | libc::PROT_WRITE;
+    let flags = flags | libc::MAP_ANONYMOUS | libc::MAP_PRIVATE;
+
+    let ret = match take(len) {
+        Some(addr) => {
+            let ret = preload_hooks::libc_mmap(
+                addr as *mut c_void,
+                len,
+                prot,
+                flags | libc::MAP_FIXED,
+                -1,
+                0,
+            );
+            if ret == libc::MAP_FAILED {
+                give(addr, addr + len);
+            }
+            ret
+        }
+        None => {
+            if AREA.load(Ordering::Relaxed) != 0 {
+                SPILLED.fetch_add(1, Ordering::Relaxed);
+            }
+            preload_hooks::libc_mmap(null_mut(), len, prot, flags, -1, 0)
+        }
+    };
+    if ret == libc::MAP_FAILED {
+        return ret;
+    }
+
+    name(ret, len);
+    account(len as isize);
+    ret
+}
+
+// name and account a mapping of mosalloc's made outside of the internal region, e.g. a large
+// reservation
+pub fn adopt(addr: *mut c_void, len: usize) {
+    name(addr, len);
+    account(align_up(len, PAGE) as isize);
+}
+
+pub fn unmap(addr: *mut c_void, len: usize) -> i32 {
+    let len = align_up(len, PAGE);
+
+    if !in_area(addr as usize) {
+        let ret = preload_hooks::libc_munmap(addr, len);
+        if ret == 0 {
+            account(-(len as isize));
+        }
+        return ret;
+    }
+
+    // keep the range reserved, the kernel would hand it out to the target otherwise
+    let ret = preload_hooks::libc_mmap(
+        addr,
+        len,
+        libc::PROT_NONE,
+        libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_NORESERVE | libc::MAP_FIXED,
+        -1,
+        0,
+    );
+    if ret == libc::MAP_FAILED {
+        return -1;
+    }
+    name(addr, len);
+    give(addr as usize, addr as usize + len);
+    account(-(len as isize));
+    0
+}
+
+// resize an internal mapping, the name sticks to the mapping when the kernel moves it
+pub fn remap(addr: *mut c_void, old_len: usize, new_len: usize) -> *mut c_void {
+    if in_area(addr as usize) {
+        let ret = map(new_len, 0);
+        if ret != libc::MAP_FAILED {
+            unsafe { copy_nonoverlapping(addr as *const u8, ret as *mut u8, old_len.min(new_len)) };
+            unmap(addr, old_len);
+        }
+        return ret;
+    }
+
+    let ret = preload_hooks::libc_mremap(addr, old_len, new_len, libc::MREMAP_MAYMOVE, null_mut());
+    if ret != libc::MAP_FAILED {
+        account(align_up(new_len, PAGE) as isize - align_up(old_len, PAGE) as isize);
+    }
+    ret
+}
+
+// address space mapped for mosalloc itself, and the rss of it if the mappings could be named
+pub fn usage() -> (usize, Option<usize>) {
+    let rss = NAMED
+        .load(Ordering::Relaxed)
+        .then(|| smaps_named_field(MAPS_NAME, "Rss"));
+    (MAPPED.load(Ordering::Relaxed), rss)
+}
+
+pub fn print_stats() {
+    let (mapped, rss) = usage();
+    println!(
+        "(internal) mapped: {}, peak: {}, rss: {}",
+        size_to_str(mapped),
+        size_to_str(PEAK.load(Ordering::Relaxed)),
+        rss.map_or("n/a".to_string(), size_to_str)
+    );
+
+    let area = AREA.load(Ordering::Relaxed);
+    if area != 0 {
+        println!(
+            "(internal) region {:x}-{:x}, {} mappings spilled out of it",
+            area,
+            area + AREA_SIZE.load(Ordering::Relaxed),
+            SPILLED.load(Ordering::Relaxed)
+        );
+    }
+}
@@ -0,0 +1,38 @@
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use mosalloc::utils::attach::{process_maps, Mapping};
+
+use crate::init::mosalloc;
+use crate::service;
+
+// spawn a thread reconciling the regions with /proc/self/maps every `period` ms, the mappings
+// mosalloc didn't create inside them (kernel-injected ones, io_uring rings, other preloads ...)
+// are excluded from the free maps; a mapping has to show up in two consecutive scans, so that the
+// ones going away meanwhile (e.g. a file allocation being unmapped) aren't mistaken for intruders
+pub fn spawn(period: u64) {
+    service::spawn("intruders", move || {
+        let mut last: Vec<Mapping> = Vec::new();
+
+        loop {
+            let maps = match process_maps(process::id() as i32) {
+                Ok(x) => x,
+                Err(err) => {
+                    println!("intruders: {}", err);
+                    return;
+                }
+            };
+
+            let seen = maps
+                .iter()
+                .filter(|x| last.contains(x))
+                .cloned()
+                .collect::<Vec<Mapping>>();
+            unsafe { mosalloc().unwrap().absorb_intruders(&seen) };
+            last = maps;
+
+            thread::sleep(Duration::from_millis(period));
+        }
+    });
+}
@@ -0,0 +1,140 @@
+use std::mem::size_of;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use mosalloc::utils::misc::size_to_str;
+
+// the io_uring_register opcodes (un)registering fixed buffers
+const IORING_REGISTER_BUFFERS: u32 = 0;
+const IORING_UNREGISTER_BUFFERS: u32 = 1;
+const IORING_REGISTER_BUFFERS2: u32 = 15;
+const IORING_REGISTER_BUFFERS_UPDATE: u32 = 16;
+
+// opcode flag of the calls passing a registered ring index instead of the ring fd, either way the
+// ring is told apart by the value passed
+const IORING_REGISTER_USE_REGISTERED_RING: u32 = 1 << 31;
+
+// IORING_REGISTER_BUFFERS2 flag of the tables registered empty
+const IORING_RSRC_REGISTER_SPARSE: u32 = 1;
+
+// the kernel's IORING_MAX_REG_BUFFERS
+const MAX_BUFFERS: usize = 1 << 14;
+
+// buffer registrations seen, the buffers they pinned (and their bytes), and the unregistrations
+static REGISTERED: AtomicUsize = AtomicUsize::new(0);
+static BUFFERS: AtomicUsize = AtomicUsize::new(0);
+static BYTES: AtomicUsize = AtomicUsize::new(0);
+static UNREGISTERED: AtomicUsize = AtomicUsize::new(0);
+
+// what an io_uring_register call does to the fixed buffers of its ring
+#[derive(Debug)]
+pub enum Registration {
+    Pin(Vec<Range<usize>>),
+    Unpin,
+    Other,
+}
+
+// the nr iovecs at addr, as ranges, through read(addr, buf) -> whether buf was filled
+fn iovecs(addr: usize, nr: usize, read: &impl Fn(usize, &mut [u8]) -> bool) -> Vec<Range<usize>> {
+    let word = size_of::<usize>();
+    let mut buf = vec![0u8; nr.min(MAX_BUFFERS) * 2 * word];
+    if addr == 0 || !read(addr, &mut buf) {
+        return Vec::new();
+    }
+
+    let words = buf
+        .chunks_exact(word)
+        .map(|x| usize::from_ne_bytes(x.try_into().unwrap()))
+        .collect::<Vec<usize>>();
+    words
+        .chunks_exact(2)
+        // the updates clear the slots of the null ones
+        .filter(|x| x[0] != 0 && x[1] != 0)
+        .map(|x| x[0]..x[0].saturating_add(x[1]))
+        .collect()
+}
+
+// the u32 and u64 fields at off of buf
+fn field_u32(buf: &[u8], off: usize) -> u32 {
+    u32::from_ne_bytes(buf[off..off + 4].try_into().unwrap())
+}
+
+fn field_u64(buf: &[u8], off: usize) -> u64 {
+    u64::from_ne_bytes(buf[off..off + 8].try_into().unwrap())
+}
+
+// read the calling process' memory at addr into buf, for the calls that went through already (the
+// kernel read it too)
+pub fn read_local(addr: usize, buf: &mut [u8]) -> bool {
+    unsafe { std::ptr::copy_nonoverlapping(addr as *const u8, buf.as_mut_ptr(), buf.len()) };
+    true
+}
+
+// decode an io_uring_register(fd, opcode, arg, nr) call, reading the application memory through
+// read(addr, buf) -> whether buf was filled
+pub fn parse(
+    opcode: u32,
+    arg: usize,
+    nr: u32,
+    read: impl Fn(usize, &mut [u8]) -> bool,
+) -> Registration {
+    match opcode & !IORING_REGISTER_USE_REGISTERED_RING {
+        IORING_REGISTER_BUFFERS => Registration::Pin(iovecs(arg, nr as usize, &read)),
+        IORING_UNREGISTER_BUFFERS => Registration::Unpin,
+        // struct io_uring_rsrc_register { u32 nr; u32 flags; u64 resv2; u64 data; u64 tags; }
+        IORING_REGISTER_BUFFERS2 => {
+            let mut buf = [0u8; 32];
+            if !read(arg, &mut buf) || field_u32(&buf, 4) & IORING_RSRC_REGISTER_SPARSE != 0 {
+                return Registration::Other;
+            }
+            Registration::Pin(iovecs(
+                field_u64(&buf, 16) as usize,
+                field_u32(&buf, 0) as usize,
+                &read,
+            ))
+        }
+        // struct io_uring_rsrc_update2 { u32 offset; u32 resv; u64 data; u64 tags; u32 nr; ... },
+        // the buffers replaced stay pinned until the whole table is unregistered
+        IORING_REGISTER_BUFFERS_UPDATE => {
+            let mut buf = [0u8; 32];
+            if !read(arg, &mut buf) {
+                return Registration::Other;
+            }
+            Registration::Pin(iovecs(
+                field_u64(&buf, 8) as usize,
+                field_u32(&buf, 24) as usize,
+                &read,
+            ))
+        }
+        _ => Registration::Other,
+    }
+}
+
+pub fn record(x: &Registration) {
+    match x {
+        Registration::Pin(ranges) => {
+            REGISTERED.fetch_add(1, Ordering::Relaxed);
+            BUFFERS.fetch_add(ranges.len(), Ordering::Relaxed);
+            BYTES.fetch_add(ranges.iter().map(|x| x.len()).sum(), Ordering::Relaxed);
+        }
+        Registration::Unpin => {
+            UNREGISTERED.fetch_add(1, Ordering::Relaxed);
+        }
+        Registration::Other => (),
+    }
+}
+
+pub fn print_stats() {
+    let registered = REGISTERED.load(Ordering::Relaxed);
+    if registered == 0 {
+        return;
+    }
+
+    println!(
+        "io_uring: {} buffer registrations of {} buffers ({}), {} unregistrations",
+        registered,
+        BUFFERS.load(Ordering::Relaxed),
+        size_to_str(BYTES.load(Ordering::Relaxed)),
+        UNREGISTERED.load(Ordering::Relaxed)
+    );
+}
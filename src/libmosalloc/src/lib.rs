@@ -1,11 +1,60 @@
 #![feature(mixed_integer_ops)]
 #![feature(bench_black_box)]
 #![feature(int_roundings)]
+#![feature(return_address)]
+#![feature(allocator_api)]
+#![feature(c_variadic)]
 
+// the crate's output goes through raw write syscalls instead of the stdio locks and buffers, which
+// may be held by the thread a hook interrupted, see rawio
+macro_rules! println {
+    () => {
+        $crate::rawio::print_line(1, format_args!(""))
+    };
+    ($($arg:tt)*) => {
+        $crate::rawio::print_line(1, format_args!($($arg)*))
+    };
+}
+
+macro_rules! eprintln {
+    ($($arg:tt)*) => {
+        $crate::rawio::print_line(2, format_args!($($arg)*))
+    };
+}
+
+pub mod aging;
 pub mod allocator;
+pub mod callers;
+pub mod capi;
+pub mod compaction;
+#[cfg(feature = "ctl")]
+pub mod control;
+#[cfg(feature = "preload")]
+pub mod dlsym;
+pub mod fault;
+pub mod freemap_store;
+pub mod heatmap;
 pub mod init;
 pub mod internal_allocator;
+pub mod internal_maps;
+pub mod intruders;
+pub mod io_uring;
 pub mod lock;
+pub mod lockdep;
+pub mod meminfo;
+pub mod metadata;
+pub mod on_demand;
+pub mod page_limits;
+pub mod pagemap;
 pub mod preload_hooks;
+pub mod rawio;
 pub mod region;
+pub mod remap;
+#[cfg(feature = "seccomp")]
 pub mod seccomp_hooks;
+pub mod service;
+pub mod smaps;
+pub mod threads;
+#[cfg(feature = "trace")]
+pub mod trace;
+pub mod window;
@@ -1,29 +1,51 @@
 use std::hint;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread;
 
+use crate::lockdep;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
 #[derive(Debug)]
 pub struct Lock {
     lock: AtomicBool,
+    // for the lock ordering reports of the debug builds
+    id: usize,
+    name: &'static str,
+    // acquisitions, and the ones that had to wait for another holder
+    acquired: AtomicUsize,
+    contended: AtomicUsize,
 }
 
 const LOOPS_PER_YIELD: u16 = 1000;
 
 impl Lock {
-    pub fn new(val: bool) -> Self {
+    pub fn new(val: bool, name: &'static str) -> Self {
         Self {
             lock: AtomicBool::new(val),
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            name,
+            acquired: AtomicUsize::new(0),
+            contended: AtomicUsize::new(0),
         }
     }
 
     #[inline]
     pub fn lock(&mut self) {
+        lockdep::acquire(self.id, self.name);
+        self.acquired.fetch_add(1, Ordering::Relaxed);
+
         let mut loops = 0;
+        let mut waited = false;
         while self
             .lock
             .compare_exchange(true, false, Ordering::Acquire, Ordering::Relaxed)
             .is_err()
         {
+            if !waited {
+                waited = true;
+                self.contended.fetch_add(1, Ordering::Relaxed);
+            }
             loops += 1;
             if loops == LOOPS_PER_YIELD {
                 loops = 0;
@@ -33,8 +55,17 @@ impl Lock {
         }
     }
 
+    // (acquired, contended) counts
+    pub fn stats(&self) -> (usize, usize) {
+        (
+            self.acquired.load(Ordering::Relaxed),
+            self.contended.load(Ordering::Relaxed),
+        )
+    }
+
     #[inline]
     pub fn unlock(&mut self) {
+        lockdep::release(self.id);
         self.lock.store(true, Ordering::Release);
     }
 }
@@ -0,0 +1,152 @@
+use std::cell::Cell;
+use std::hint;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use libc;
+
+// lock ordering checks of the debug builds: every thread keeps the stack of the locks it holds
+// and every (held, acquired) pair seen is recorded, so that taking a lock the thread already
+// holds (e.g. a hooked call re-entered while holding a region lock), or two locks in the
+// opposite order of an earlier acquisition, aborts with a report instead of deadlocking
+
+const MAX_HELD: usize = 8;
+const MAX_EDGES: usize = 256;
+
+#[derive(Clone, Copy)]
+struct Class {
+    id: usize,
+    name: &'static str,
+}
+
+const NONE: Class = Class { id: 0, name: "" };
+
+thread_local! {
+    static HELD: Cell<[Class; MAX_HELD]> = const { Cell::new([NONE; MAX_HELD]) };
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+// (held, acquired) pairs, the lock order seen so far
+static mut EDGES: [(Class, Class); MAX_EDGES] = [(NONE, NONE); MAX_EDGES];
+static mut NR_EDGES: usize = 0;
+static EDGES_LOCK: AtomicBool = AtomicBool::new(true);
+
+fn with_edges<R>(f: impl FnOnce(&mut [(Class, Class); MAX_EDGES], &mut usize) -> R) -> R {
+    while EDGES_LOCK
+        .compare_exchange(true, false, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        hint::spin_loop();
+    }
+    let ret = unsafe {
+        f(
+            &mut *ptr::addr_of_mut!(EDGES),
+            &mut *ptr::addr_of_mut!(NR_EDGES),
+        )
+    };
+    EDGES_LOCK.store(true, Ordering::Release);
+    ret
+}
+
+// the chain of recorded edges from `from` to `to`, if any
+fn path(edges: &[(Class, Class)], from: usize, to: usize, depth: usize) -> Option<Vec<Class>> {
+    if depth > MAX_HELD {
+        return None;
+    }
+
+    for &(a, b) in edges.iter().filter(|(a, _)| a.id == from) {
+        if b.id == to {
+            return Some(vec![a, b]);
+        }
+        if let Some(mut rest) = path(edges, b.id, to, depth + 1) {
+            rest.insert(0, a);
+            return Some(rest);
+        }
+    }
+
+    None
+}
+
+fn report(held: &[Class], lock: Class, reason: &str) -> ! {
+    let tid = unsafe { libc::syscall(libc::SYS_gettid) };
+    eprintln!("lockdep: possible deadlock in thread {}: {}", tid, reason);
+    eprintln!(
+        "lockdep: acquiring {} (#{}) while holding:",
+        lock.name, lock.id
+    );
+    for x in held.iter().rev() {
+        eprintln!("lockdep:   {} (#{})", x.name, x.id);
+    }
+    unsafe { libc::abort() }
+}
+
+pub fn acquire(id: usize, name: &'static str) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+
+    let lock = Class { id, name };
+    let depth = DEPTH.get();
+    let stack = HELD.get();
+    let held = &stack[..depth.min(MAX_HELD)];
+
+    if held.iter().any(|x| x.id == id) {
+        report(
+            held,
+            lock,
+            "already held by this thread, re-entered while holding it?",
+        );
+    }
+
+    with_edges(|edges, nr| {
+        for h in held.iter() {
+            if let Some(chain) = path(&edges[..*nr], id, h.id, 0) {
+                let order = chain
+                    .iter()
+                    .map(|x| format!("{} (#{})", x.name, x.id))
+                    .collect::<Vec<String>>()
+                    .join(" -> ");
+                EDGES_LOCK.store(true, Ordering::Release);
+                report(
+                    held,
+                    lock,
+                    &format!("inverts the lock order seen before: {}", order),
+                );
+            }
+
+            if *nr < MAX_EDGES && !edges[..*nr].iter().any(|(a, b)| a.id == h.id && b.id == id) {
+                edges[*nr] = (*h, lock);
+                *nr += 1;
+            }
+        }
+    });
+
+    if depth < MAX_HELD {
+        let mut stack = stack;
+        stack[depth] = lock;
+        HELD.set(stack);
+    }
+    DEPTH.set(depth + 1);
+}
+
+pub fn release(id: usize) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+
+    let depth = DEPTH.get();
+    if depth == 0 {
+        return;
+    }
+
+    let mut stack = HELD.get();
+    let held = depth.min(MAX_HELD);
+    // locks aren't necessarily released in the reverse order
+    if let Some(idx) = stack[..held].iter().rposition(|x| x.id == id) {
+        stack.copy_within(idx + 1..held, idx);
+        HELD.set(stack);
+        DEPTH.set(depth - 1);
+    } else if depth > MAX_HELD {
+        DEPTH.set(depth - 1);
+    }
+}
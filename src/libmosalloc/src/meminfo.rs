@@ -0,0 +1,78 @@
+use std::thread;
+use std::time::Duration;
+
+use mosalloc::utils::htlb::get_htlb_usage;
+use mosalloc::utils::misc::size_to_str;
+
+use crate::init::mosalloc;
+use crate::service;
+
+// spawn a thread reconciling the hugetlb pages mapped by mosalloc with the kernel counters every
+// `period` ms, `pool` holds the (page size, nr) hugepages of the brk and anon pools
+pub fn spawn(period: u64, pool: Vec<(usize, usize)>) {
+    service::spawn("meminfo", move || {
+        // pages used by other processes when we started, and the last warning per page size
+        let mut baseline: Vec<Option<usize>> = vec![None; pool.len()];
+        let mut last: Vec<Option<String>> = vec![None; pool.len()];
+
+        loop {
+            let mapped = unsafe { mosalloc().unwrap().htlb_mapped() };
+
+            for (i, &(pagesz, nrpages)) in pool.iter().enumerate() {
+                let usage = match get_htlb_usage(pagesz) {
+                    Ok(x) => x,
+                    Err(err) => {
+                        println!("meminfo: {}", err);
+                        continue;
+                    }
+                };
+
+                let ours = mapped
+                    .iter()
+                    .find(|(sz, _)| *sz == pagesz)
+                    .map(|(_, nr)| *nr)
+                    .unwrap_or(0);
+                let others = usage.used().saturating_sub(ours);
+                let baseline = *baseline[i].get_or_insert(others);
+                let avail = usage.free.saturating_sub(usage.rsvd);
+                let needed = nrpages.saturating_sub(ours);
+
+                let warning = if usage.used() < ours {
+                    Some(format!(
+                        "mosalloc maps {} pages but the kernel only accounts for {}",
+                        ours,
+                        usage.used()
+                    ))
+                } else if avail < needed {
+                    Some(format!(
+                        "{} free pages left for the {} pool pages not mapped yet ({} pages taken \
+                         by others since startup)",
+                        avail,
+                        needed,
+                        others.saturating_sub(baseline)
+                    ))
+                } else if others > baseline {
+                    Some(format!(
+                        "{} pages consumed outside mosalloc since startup",
+                        others - baseline
+                    ))
+                } else {
+                    None
+                };
+
+                // only report changes
+                if warning != last[i] {
+                    match &warning {
+                        Some(x) => {
+                            println!("meminfo: warning: {} pages: {}", size_to_str(pagesz), x)
+                        }
+                        None => println!("meminfo: {} pages: consistent", size_to_str(pagesz)),
+                    }
+                    last[i] = warning;
+                }
+            }
+
+            thread::sleep(Duration::from_millis(period));
+        }
+    });
+}
@@ -0,0 +1,207 @@
+use std::alloc::{AllocError, Allocator, Global, Layout};
+use std::hint;
+use std::ptr::{self, null_mut, NonNull};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use libc;
+
+use crate::internal_maps;
+use crate::preload_hooks;
+use crate::rawio;
+use crate::remap;
+
+use mosalloc::utils::htlb::PAGE_SIZE;
+use mosalloc::utils::misc::{align_up, size_to_str};
+
+// virtual space reserved for the metadata, only the pages actually used get backed
+#[cfg(target_pointer_width = "64")]
+const AREA_SIZE: usize = 16 << 30;
+#[cfg(not(target_pointer_width = "64"))]
+const AREA_SIZE: usize = 256 << 20;
+
+// power of two size classes, from 16 bytes up
+const MIN_CLASS: usize = 4;
+const CLASSES: usize = usize::BITS as usize;
+
+// whether the metadata lives on the dedicated area, and whether it's kept read-only outside of
+// the region critical sections; only the region maps live there, the region structs (with their
+// locks and counters, written outside of the critical sections) and the parsed config don't
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SEALED: AtomicBool = AtomicBool::new(false);
+
+static AREA: AtomicUsize = AtomicUsize::new(0);
+static BUMP: AtomicUsize = AtomicUsize::new(0);
+static FREE: [AtomicUsize; CLASSES] = [const { AtomicUsize::new(0) }; CLASSES];
+
+// critical sections currently writing to the metadata
+static WRITERS: AtomicUsize = AtomicUsize::new(0);
+
+static LOCK: AtomicBool = AtomicBool::new(true);
+
+static mut OLD_SEGV: Option<libc::sigaction> = None;
+
+fn with_lock<R>(f: impl FnOnce() -> R) -> R {
+    while LOCK
+        .compare_exchange(true, false, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        hint::spin_loop();
+    }
+    let ret = f();
+    LOCK.store(true, Ordering::Release);
+    ret
+}
+
+// allocator of the region maps (free, protection, foreign ranges ...), falls back to the internal
+// allocator unless the metadata is protected
+#[derive(Debug, Clone, Copy)]
+pub struct MetaAlloc;
+
+pub type MetaVec<T> = Vec<T, MetaAlloc>;
+
+fn class(layout: Layout) -> usize {
+    layout
+        .size()
+        .max(layout.align())
+        .max(1 << MIN_CLASS)
+        .next_power_of_two()
+        .trailing_zeros() as usize
+}
+
+unsafe impl Allocator for MetaAlloc {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return Global.allocate(layout);
+        }
+
+        let c = class(layout);
+        let addr = with_lock(|| {
+            let head = FREE[c].load(Ordering::Relaxed);
+            if head != 0 {
+                FREE[c].store(unsafe { *(head as *const usize) }, Ordering::Relaxed);
+                return Ok(head);
+            }
+
+            let start = align_up(BUMP.load(Ordering::Relaxed), 1 << c);
+            if start + (1 << c) > AREA_SIZE {
+                return Err(AllocError);
+            }
+            BUMP.store(start + (1 << c), Ordering::Relaxed);
+            Ok(AREA.load(Ordering::Relaxed) + start)
+        })?;
+
+        let ptr = NonNull::new(addr as *mut u8).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, 1 << c))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return Global.deallocate(ptr, layout);
+        }
+
+        let c = class(layout);
+        with_lock(|| {
+            *(ptr.as_ptr() as *mut usize) = FREE[c].load(Ordering::Relaxed);
+            FREE[c].store(ptr.as_ptr() as usize, Ordering::Relaxed);
+        });
+    }
+}
+
+// reserve the metadata area, has to be called before any region is created
+pub fn init(enable: bool) {
+    if !enable {
+        return;
+    }
+
+    let area = preload_hooks::libc_mmap(
+        null_mut(),
+        AREA_SIZE,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_NORESERVE,
+        -1,
+        0,
+    );
+    if area == libc::MAP_FAILED {
+        println!("metadata: failed to reserve the metadata area, not protecting it");
+        return;
+    }
+    internal_maps::adopt(area, AREA_SIZE);
+
+    AREA.store(area as usize, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+fn set_prot(prot: i32) {
+    let len = align_up(BUMP.load(Ordering::Relaxed), *PAGE_SIZE);
+    if len > 0 {
+        let ret = preload_hooks::libc_mprotect(AREA.load(Ordering::Relaxed) as *mut _, len, prot);
+        assert!(ret == 0, "metadata: mprotect failed");
+    }
+}
+
+// the metadata is writable while a region is locked
+pub fn unprotect() {
+    if !SEALED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    with_lock(|| {
+        if WRITERS.fetch_add(1, Ordering::Relaxed) == 0 {
+            set_prot(libc::PROT_READ | libc::PROT_WRITE);
+        }
+    });
+}
+
+pub fn protect() {
+    if !SEALED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    with_lock(|| {
+        if WRITERS.fetch_sub(1, Ordering::Relaxed) == 1 {
+            set_prot(libc::PROT_READ);
+        }
+    });
+}
+
+// report the writes to the protected metadata before crashing, forward the rest of the faults
+extern "C" fn segv_handler(sig: i32, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    unsafe {
+        let addr = (*info).si_addr() as usize;
+        let area = AREA.load(Ordering::Relaxed);
+
+        if addr >= area && addr < area + AREA_SIZE {
+            let msg = b"metadata: write to the read-only mosalloc metadata, corrupted by the application?\n";
+            let _ = rawio::write_fd(2, msg);
+            // the access faults again with the default action
+            libc::signal(libc::SIGSEGV, libc::SIG_DFL);
+            return;
+        }
+
+        remap::forward(*ptr::addr_of!(OLD_SEGV), sig, info, ctx);
+    }
+}
+
+// make the metadata read-only from now on, once the regions are set up
+pub fn seal() {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = segv_handler as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO;
+        let mut old: libc::sigaction = std::mem::zeroed();
+        if libc::sigaction(libc::SIGSEGV, &action, &mut old) == 0 {
+            *ptr::addr_of_mut!(OLD_SEGV) = Some(old);
+        }
+    }
+
+    with_lock(|| set_prot(libc::PROT_READ));
+    SEALED.store(true, Ordering::Relaxed);
+    println!(
+        "metadata: {} of region maps protected",
+        size_to_str(BUMP.load(Ordering::Relaxed))
+    );
+}
@@ -0,0 +1,70 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use mosalloc::utils::misc::size_to_str;
+use mosalloc::utils::on_demand;
+
+// the run_mosalloc socket the pool hugepages are requested on, reserved up front if unset
+static SOCKET: OnceLock<String> = OnceLock::new();
+
+// requests made, the ones that couldn't be (fully) served, the bytes requested, and the time
+// spent waiting for them
+static REQUESTS: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+static BYTES: AtomicUsize = AtomicUsize::new(0);
+static WAIT_US: AtomicU64 = AtomicU64::new(0);
+static MAX_WAIT_US: AtomicU64 = AtomicU64::new(0);
+
+pub fn set(socket: Option<&String>) {
+    if let Some(socket) = socket {
+        let _ = SOCKET.set(socket.clone());
+    }
+}
+
+pub fn enabled() -> bool {
+    SOCKET.get().is_some()
+}
+
+// request the nr pages of pagesz of an interval being first touched, blocking until run_mosalloc
+// reserved them; if it couldn't the mapping falls back to base pages as usual
+pub fn request(label: &str, pagesz: usize, nr: usize) {
+    let socket = match SOCKET.get() {
+        Some(x) => x,
+        None => return,
+    };
+
+    let start = Instant::now();
+    let ret = on_demand::request(socket, pagesz, nr);
+    let waited = start.elapsed().as_micros() as u64;
+
+    REQUESTS.fetch_add(1, Ordering::Relaxed);
+    BYTES.fetch_add(pagesz * nr, Ordering::Relaxed);
+    WAIT_US.fetch_add(waited, Ordering::Relaxed);
+    MAX_WAIT_US.fetch_max(waited, Ordering::Relaxed);
+    if let Err(e) = ret {
+        FAILED.fetch_add(1, Ordering::Relaxed);
+        println!(
+            "({}) on-demand: {} {} pages: {}",
+            label,
+            nr,
+            size_to_str(pagesz),
+            e
+        );
+    }
+}
+
+pub fn print_stats() {
+    if !enabled() {
+        return;
+    }
+
+    println!(
+        "on-demand: {} requests ({} failed), {} requested, waited {} ms (max {} ms)",
+        REQUESTS.load(Ordering::Relaxed),
+        FAILED.load(Ordering::Relaxed),
+        size_to_str(BYTES.load(Ordering::Relaxed)),
+        WAIT_US.load(Ordering::Relaxed) / 1000,
+        MAX_WAIT_US.load(Ordering::Relaxed) / 1000
+    );
+}
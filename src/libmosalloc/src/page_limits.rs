@@ -0,0 +1,174 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use mosalloc::utils::misc::size_to_str;
+
+const NONE: usize = usize::MAX;
+// the page sizes capped or tracked at once, more than the hugepage sizes of any arch
+const SIZES: usize = 8;
+
+// caps on the pool hugepages of a size mapped by all the regions at once, for sharing the
+// hugepages of a machine between the runs of different users, and the hugepages reserved for the
+// run when tracked, which the pool intervals require all of
+struct PageLimit {
+    // 0 for a free slot
+    pagesz: AtomicUsize,
+    max: AtomicUsize,
+    reserved: AtomicUsize,
+    required: AtomicUsize,
+    mapped: AtomicUsize,
+    peak: AtomicUsize,
+    // requests failed with ENOMEM because of the cap or the reservation
+    denied: AtomicUsize,
+}
+
+impl PageLimit {
+    const fn new() -> Self {
+        Self {
+            pagesz: AtomicUsize::new(0),
+            max: AtomicUsize::new(NONE),
+            reserved: AtomicUsize::new(NONE),
+            required: AtomicUsize::new(0),
+            mapped: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            denied: AtomicUsize::new(0),
+        }
+    }
+}
+
+static LIMITS: [PageLimit; SIZES] = [const { PageLimit::new() }; SIZES];
+
+fn limit(pagesz: usize) -> Option<&'static PageLimit> {
+    LIMITS
+        .iter()
+        .find(|x| x.pagesz.load(Ordering::Relaxed) == pagesz)
+}
+
+// the slot of pagesz, taking a free one the first time it's capped or tracked
+fn slot(pagesz: usize) -> Option<&'static PageLimit> {
+    limit(pagesz).or_else(|| {
+        LIMITS.iter().find(|x| {
+            x.pagesz
+                .compare_exchange(0, pagesz, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        })
+    })
+}
+
+pub fn set(pagesz: usize, max: Option<usize>) {
+    let l = match max {
+        Some(_) => slot(pagesz),
+        None => limit(pagesz),
+    };
+    if let Some(l) = l {
+        l.max.store(max.unwrap_or(NONE), Ordering::Relaxed);
+    }
+}
+
+// track the pages of pagesz reserved for the run, and the ones the pool intervals require
+pub fn set_reservation(pagesz: usize, reserved: usize, required: usize) {
+    if let Some(l) = slot(pagesz) {
+        l.reserved.store(reserved, Ordering::Relaxed);
+        l.required.store(required, Ordering::Relaxed);
+    }
+}
+
+// account nr freshly mapped pages of pagesz, false (and nothing accounted) if that would exceed
+// the cap or the reservation
+pub fn reserve(pagesz: usize, nr: usize) -> bool {
+    let l = match limit(pagesz) {
+        Some(l) => l,
+        None => return true,
+    };
+    let max = l
+        .max
+        .load(Ordering::Relaxed)
+        .min(l.reserved.load(Ordering::Relaxed));
+
+    match l
+        .mapped
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| {
+            x.checked_add(nr).filter(|&x| x <= max)
+        }) {
+        Ok(prev) => {
+            l.peak.fetch_max(prev + nr, Ordering::Relaxed);
+            true
+        }
+        Err(_) => {
+            l.denied.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+}
+
+pub fn release(pagesz: usize, nr: usize) {
+    if let Some(l) = limit(pagesz) {
+        let _ = l
+            .mapped
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| {
+                Some(x.saturating_sub(nr))
+            });
+    }
+}
+
+// why nr more pages of pagesz can't be mapped, for the log of the denied requests
+pub fn describe(pagesz: usize, nr: usize) -> String {
+    let l = match limit(pagesz) {
+        Some(l) => l,
+        None => return String::new(),
+    };
+    let (max, reserved) = (
+        l.max.load(Ordering::Relaxed),
+        l.reserved.load(Ordering::Relaxed),
+    );
+    let mapped = l.mapped.load(Ordering::Relaxed);
+
+    if reserved <= max {
+        format!(
+            "needs {} fresh {} pages, {} left of the {} reserved ({} mapped, the pools require {})",
+            nr,
+            size_to_str(pagesz),
+            reserved.saturating_sub(mapped),
+            reserved,
+            mapped,
+            l.required.load(Ordering::Relaxed)
+        )
+    } else {
+        format!(
+            "needs {} fresh {} pages, {} left under the cap of {}",
+            nr,
+            size_to_str(pagesz),
+            max.saturating_sub(mapped),
+            max
+        )
+    }
+}
+
+pub fn print_stats() {
+    for l in LIMITS.iter() {
+        let pagesz = l.pagesz.load(Ordering::Relaxed);
+        let max = l.max.load(Ordering::Relaxed);
+        let reserved = l.reserved.load(Ordering::Relaxed);
+        if pagesz == 0 || (max == NONE && reserved == NONE) {
+            continue;
+        }
+
+        let mapped = l.mapped.load(Ordering::Relaxed);
+        println!(
+            "({} pages) mapped: {}, peak: {} of {}, denied: {}",
+            size_to_str(pagesz),
+            mapped,
+            l.peak.load(Ordering::Relaxed),
+            max.min(reserved),
+            l.denied.load(Ordering::Relaxed)
+        );
+        if reserved != NONE {
+            println!(
+                "({} pages) reservation: {} reserved, the pools require {}, {} of them not mapped yet",
+                size_to_str(pagesz),
+                reserved,
+                l.required.load(Ordering::Relaxed),
+                l.required.load(Ordering::Relaxed).saturating_sub(mapped)
+            );
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use std::fs::{self, File};
+use std::os::unix::fs::FileExt;
+
+use mosalloc::utils::htlb::PAGE_SIZE;
+
+use crate::smaps::smaps_field;
+
+// pagemap entry bits, see Documentation/admin-guide/mm/pagemap.rst
+const PM_PRESENT: u64 = 1 << 63;
+const PM_SOFT_DIRTY: u64 = 1 << 55;
+const PM_PFN: u64 = (1 << 55) - 1;
+// kpageflags bits, see Documentation/admin-guide/mm/pagemap.rst
+const KPF_THP: u64 = 1 << 22;
+
+// reset the soft-dirty bits of all the process' PTEs
+pub fn clear_soft_dirty() {
+    fs::write("/proc/self/clear_refs", "4").unwrap();
+}
+
+fn pagemap_entries(pagemap: &File, addr: usize, len: usize) -> Vec<u64> {
+    let mut buf = vec![0u8; len / *PAGE_SIZE * 8];
+    if pagemap
+        .read_exact_at(&mut buf, (addr / *PAGE_SIZE * 8) as u64)
+        .is_err()
+    {
+        return Vec::new();
+    }
+
+    buf.chunks(8)
+        .map(|x| u64::from_ne_bytes(x.try_into().unwrap()))
+        .collect()
+}
+
+// whether the page at addr is populated
+pub fn page_present(pagemap: &File, addr: usize) -> bool {
+    pagemap_entries(pagemap, addr, *PAGE_SIZE)
+        .iter()
+        .any(|&x| x & PM_PRESENT != 0)
+}
+
+// a range is considered touched if any of its pages is present and soft-dirty since the last
+// clear_soft_dirty(), for hugepages checking the first base page is enough
+pub fn range_touched(pagemap: &File, addr: usize, len: usize) -> bool {
+    pagemap_entries(pagemap, addr, len)
+        .iter()
+        .any(|&x| x & PM_PRESENT != 0 && x & PM_SOFT_DIRTY != 0)
+}
+
+// whether the populated page at addr is part of a THP, None if it isn't populated or its page
+// flags can't be read (the PFNs and kpageflags need CAP_SYS_ADMIN)
+fn page_thp(pagemap: &File, addr: usize) -> Option<bool> {
+    let entry = *pagemap_entries(pagemap, addr, *PAGE_SIZE).first()?;
+    let pfn = entry & PM_PFN;
+    if entry & PM_PRESENT == 0 || pfn == 0 {
+        return None;
+    }
+
+    let mut buf = [0u8; 8];
+    File::open("/proc/kpageflags")
+        .and_then(|x| x.read_exact_at(&mut buf, pfn * 8))
+        .ok()?;
+    Some(u64::from_ne_bytes(buf) & KPF_THP != 0)
+}
+
+// the page size the kernel backs addr with: the hugetlb page size of its VMA, or the THP size if
+// it's part of a THP; None if it isn't populated or that can't be told
+pub fn backing_page_size(addr: usize) -> Option<usize> {
+    let vma = smaps_field(addr, addr + 1, "KernelPageSize");
+    if vma > *PAGE_SIZE {
+        return Some(vma);
+    }
+
+    let pagemap = File::open("/proc/self/pagemap").ok()?;
+    match page_thp(&pagemap, addr)? {
+        true => thp_size(),
+        false => Some(*PAGE_SIZE),
+    }
+}
+
+// the THP (PMD) size, None without THP support
+pub fn thp_size() -> Option<usize> {
+    fs::read_to_string("/sys/kernel/mm/transparent_hugepage/hpage_pmd_size")
+        .ok()
+        .and_then(|x| x.trim().parse::<usize>().ok())
+}
@@ -1,19 +1,200 @@
-use libc::{c_int, c_void, intptr_t, off_t, ptrdiff_t, size_t};
+// the minimal builds leave some of the hooks, and their imports, out
+#![cfg_attr(not(feature = "malloc-interpose"), allow(unused_imports))]
+
+use libc::{c_int, c_long, c_uint, c_void, intptr_t, off64_t, off_t, ptrdiff_t, size_t};
+#[cfg(feature = "preload")]
 use redhook::{hook, real};
+use std::cell::Cell;
+use std::ffi::CStr;
+use std::ptr::addr_of_mut;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicUsize, Ordering};
+use std::sync::OnceLock;
 
 use crate::allocator::Allocator;
+use crate::callers;
+use crate::init::mosalloc;
+use crate::io_uring;
+use crate::service;
+use crate::threads;
 
 use mosalloc::utils::htlb::MosallocConfig;
+use mosalloc::utils::trace::TraceOp;
 
 // mosalloc allocator instance when LD_PRELOAD hooks are used
 static mut PRELOAD_ALLOC: Option<Allocator> = None;
 
+// the preload allocator instance, if initialized
+pub unsafe fn preload_alloc() -> Option<&'static mut Allocator> {
+    (*addr_of_mut!(PRELOAD_ALLOC)).as_mut()
+}
+
+// hooked calls made from within a hooked call (libc internals, a watermark callback ...) are
+// forwarded to libc instead of re-entering the allocator, which may hold a region lock
+thread_local! {
+    static IN_HOOK: Cell<bool> = const { Cell::new(false) };
+}
+
+// with the hybrid hooks, the tids of the threads inside mosalloc (a hooked call or one of its own
+// syscalls), whose syscalls the seccomp filter lets through; 0 marks a free slot, and the slot and
+// nesting depth of the calling thread are kept thread-local
+const MAX_INSIDE: usize = 1024;
+static TRACK_INSIDE: AtomicBool = AtomicBool::new(false);
+static INSIDE: [AtomicI32; MAX_INSIDE] = [const { AtomicI32::new(0) }; MAX_INSIDE];
+
+thread_local! {
+    static INSIDE_SLOT: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+}
+
+pub fn track_inside() {
+    TRACK_INSIDE.store(true, Ordering::Relaxed);
+}
+
+// run f with the calling thread published as inside mosalloc
+fn inside<R>(f: impl FnOnce() -> R) -> R {
+    if !TRACK_INSIDE.load(Ordering::Relaxed) {
+        return f();
+    }
+
+    let (mut slot, depth) = INSIDE_SLOT.get();
+    if depth == 0 {
+        let tid = unsafe { libc::gettid() };
+        // a full table only happens with more threads inside mosalloc at once than slots
+        slot = loop {
+            if let Some(x) = INSIDE.iter().position(|x| {
+                x.compare_exchange(0, tid, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+            }) {
+                break x;
+            }
+            std::thread::yield_now();
+        };
+    }
+    INSIDE_SLOT.set((slot, depth + 1));
+
+    let ret = f();
+
+    INSIDE_SLOT.set((slot, depth));
+    if depth == 0 {
+        INSIDE[slot].store(0, Ordering::Release);
+    }
+    ret
+}
+
+// whether the thread tid is inside mosalloc
+pub fn is_inside(tid: i32) -> bool {
+    INSIDE.iter().any(|x| x.load(Ordering::Acquire) == tid)
+}
+
+const HOOKS: [&str; 11] = [
+    "mmap",
+    "munmap",
+    "mprotect",
+    "madvise",
+    "mremap",
+    "brk",
+    "sbrk",
+    "pkey_free",
+    "pkey_mprotect",
+    "malloc_trim",
+    "io_uring_register",
+];
+
+// nested calls forwarded to libc, per hook
+static NESTED: [AtomicUsize; HOOKS.len()] = [const { AtomicUsize::new(0) }; HOOKS.len()];
+
+// the calls intercepted, as a mask of their ops, the rest are forwarded to libc
+static HOOKED: AtomicU32 = AtomicU32::new(u32::MAX);
+
+pub fn set_hooked(ops: &[TraceOp]) {
+    HOOKED.store(
+        ops.iter().fold(0, |acc, &x| acc | 1 << x as u32),
+        Ordering::Relaxed,
+    );
+}
+
+// whether a hook intercepts its call, the ones that don't emulate a syscall always do
+#[cfg(feature = "preload")]
+fn hooked(hook: &str) -> bool {
+    let op = match hook {
+        "sbrk" => Some(TraceOp::BRK),
+        "pkey_mprotect" => Some(TraceOp::MPROTECT),
+        _ => hook.parse::<TraceOp>().ok(),
+    };
+    op.is_none_or(|x| HOOKED.load(Ordering::Relaxed) & 1 << x as u32 != 0)
+}
+
+// run a hooked call through the allocator, unless there's none or this thread is already inside
+// a hooked call
+#[cfg(feature = "preload")]
+fn guarded<R>(
+    hook: &str,
+    mosalloc: Option<&'static mut Allocator>,
+    f: impl FnOnce(&mut Allocator) -> R,
+    real: impl FnOnce() -> R,
+) -> R {
+    match mosalloc {
+        // the service threads' own memory stays out of the pools and the stats
+        Some(_) if service::is_service() || !hooked(hook) => real(),
+        // resolving the caller may call back into the hooks, the first time around
+        Some(mosalloc) if !IN_HOOK.get() => inside(|| {
+            IN_HOOK.set(true);
+            let ret = if hook != "mmap" || (threads::selected() && callers::selected()) {
+                f(mosalloc)
+            } else {
+                real()
+            };
+            IN_HOOK.set(false);
+            ret
+        }),
+        Some(_) => {
+            if let Some(idx) = HOOKS.iter().position(|x| *x == hook) {
+                NESTED[idx].fetch_add(1, Ordering::Relaxed);
+            }
+            real()
+        }
+        None => real(),
+    }
+}
+
+pub fn print_nested_stats() {
+    let nested = HOOKS
+        .iter()
+        .zip(NESTED.iter())
+        .map(|(name, nr)| (name, nr.load(Ordering::Relaxed)))
+        .filter(|(_, nr)| *nr > 0)
+        .map(|(name, nr)| format!("{}: {}", name, nr))
+        .collect::<Vec<String>>();
+
+    if !nested.is_empty() {
+        println!(
+            "nested hooked calls forwarded to libc: {}",
+            nested.join(", ")
+        );
+    }
+}
+
+// the libc call, past its hook when the preload hooks are built in
+#[cfg(feature = "preload")]
+macro_rules! libc_call {
+    ($f:ident) => {
+        real!($f)
+    };
+}
+#[cfg(not(feature = "preload"))]
+macro_rules! libc_call {
+    ($f:ident) => {
+        libc::$f
+    };
+}
+
 // malloc __morecore hook for glibc<=2.33
+#[cfg(feature = "malloc-interpose")]
 extern "C" {
     static mut __morecore: extern "C" fn(intptr_t) -> *mut c_void;
 }
 
 // void *mmap(void *addr, size_t length, int prot, int flags, int fd, off_t offset);
+#[cfg(feature = "preload")]
 hook! {
     unsafe fn mmap(addr: *mut c_void,
                    len: size_t,
@@ -21,84 +202,112 @@ hook! {
                    flags: c_int,
                    fd: c_int,
                    offset: off_t) -> *mut c_void => mosalloc_mmap {
-        if let Some(mosalloc) = PRELOAD_ALLOC.as_mut() {
-            mosalloc.mmap(addr as usize, len, prot, flags, fd, offset) as *mut c_void
-        } else {
-            real!(mmap)(addr, len, prot, flags, fd, offset)
-        }
+        guarded(
+            "mmap",
+            preload_alloc(),
+            |m| m.mmap(addr as usize, len, prot, flags, fd, offset as i64) as *mut c_void,
+            || real!(mmap)(addr, len, prot, flags, fd, offset),
+        )
+    }
+}
+
+// void *mmap64(void *addr, size_t length, int prot, int flags, int fd, off64_t offset);
+#[cfg(feature = "preload")]
+hook! {
+    unsafe fn mmap64(addr: *mut c_void,
+                     len: size_t,
+                     prot: c_int,
+                     flags: c_int,
+                     fd: c_int,
+                     offset: off64_t) -> *mut c_void => mosalloc_mmap64 {
+        guarded(
+            "mmap",
+            preload_alloc(),
+            |m| m.mmap(addr as usize, len, prot, flags, fd, offset) as *mut c_void,
+            || real!(mmap64)(addr, len, prot, flags, fd, offset),
+        )
     }
 }
 
+// the offset is always 64-bit, mmap64 takes file offsets past 4GB on 32-bit targets too
 pub fn libc_mmap(
     addr: *mut c_void,
     len: size_t,
     prot: c_int,
     flags: c_int,
     fd: c_int,
-    offset: off_t,
+    offset: i64,
 ) -> *mut c_void {
-    unsafe { real!(mmap)(addr, len, prot, flags, fd, offset) }
+    inside(|| unsafe { libc_call!(mmap64)(addr, len, prot, flags, fd, offset) })
 }
 
 // int munmap(void *addr, size_t length);
+#[cfg(feature = "preload")]
 hook! {
     unsafe fn munmap(addr: *mut c_void,
                      len: size_t) -> c_int => mosalloc_munmap {
-        if let Some(mosalloc) = PRELOAD_ALLOC.as_mut() {
-            mosalloc.munmap(addr as usize, len)
-        } else {
-            real!(munmap)(addr, len)
-        }
+        guarded(
+            "munmap",
+            preload_alloc(),
+            |m| m.munmap(addr as usize, len),
+            || real!(munmap)(addr, len),
+        )
     }
 }
 
 pub fn libc_munmap(addr: *mut c_void, len: size_t) -> c_int {
-    unsafe { real!(munmap)(addr, len) }
+    inside(|| unsafe { libc_call!(munmap)(addr, len) })
 }
 
 // int mprotect(void *addr, size_t length, int prot);
+#[cfg(feature = "preload")]
 hook! {
     unsafe fn mprotect(addr: *mut c_void,
                      len: size_t, prot: c_int) -> c_int => mosalloc_mprotect {
-        if let Some(mosalloc) = PRELOAD_ALLOC.as_mut() {
-            mosalloc.mprotect(addr as usize, len, prot)
-        } else {
-            real!(mprotect)(addr, len, prot)
-        }
+        guarded(
+            "mprotect",
+            preload_alloc(),
+            |m| m.mprotect(addr as usize, len, prot),
+            || real!(mprotect)(addr, len, prot),
+        )
     }
 }
 
 pub fn libc_mprotect(addr: *mut c_void, len: size_t, prot: c_int) -> c_int {
-    unsafe { real!(mprotect)(addr, len, prot) }
+    inside(|| unsafe { libc_call!(mprotect)(addr, len, prot) })
 }
 
 // int madvise(void *addr, size_t length, int advice);
+#[cfg(feature = "preload")]
 hook! {
     // FIXME: some of the madvise calls should be probably mocked by mosalloc for the mappings
     // under its control
     unsafe fn madvise(addr: *mut c_void,
                      len: size_t, advice: c_int) -> c_int => mosalloc_madvise {
-        if let Some(mosalloc) = PRELOAD_ALLOC.as_mut() {
-            mosalloc.madvise(addr as usize, len, advice)
-        } else {
-            real!(madvise)(addr, len, advice)
-        }
+        guarded(
+            "madvise",
+            preload_alloc(),
+            |m| m.madvise(addr as usize, len, advice),
+            || real!(madvise)(addr, len, advice),
+        )
     }
 }
 
 pub fn libc_madvise(addr: *mut c_void, len: size_t, advice: c_int) -> c_int {
-    unsafe { real!(madvise)(addr, len, advice) }
+    inside(|| unsafe { libc_call!(madvise)(addr, len, advice) })
 }
 
 // void *mremap(void *old_address, size_t old_size, size_t new_size, int flags, ...)
+#[cfg(feature = "preload")]
 hook! {
     // FIXME: handle mremap to mosalloc-managed mappings
     unsafe fn mremap(old_address: *mut c_void, old_size: size_t, new_size: size_t, flags: c_int, new_address: *mut c_void) -> *mut c_void => mosalloc_mremap {
-        if let Some(mosalloc) = PRELOAD_ALLOC.as_mut() {
-            mosalloc.mremap(old_address as usize, old_size, new_size, flags, new_address as usize) as *mut c_void
-        } else {
-            real!(mremap)(old_address, old_size, new_size, flags, new_address)
-        }
+        guarded(
+            "mremap",
+            preload_alloc(),
+            |m| m.mremap(old_address as usize, old_size, new_size, flags, new_address as usize) as *mut c_void,
+            || real!(mremap)(old_address, old_size, new_size, flags, new_address),
+        )
     }
 }
 
@@ -109,54 +318,306 @@ pub fn libc_mremap(
     flags: c_int,
     new_address: *mut c_void,
 ) -> *mut c_void {
-    unsafe { real!(mremap)(old_address, old_size, new_size, flags, new_address) }
+    inside(|| unsafe { libc_call!(mremap)(old_address, old_size, new_size, flags, new_address) })
 }
 
 // int brk(void *addr);
+#[cfg(feature = "preload")]
 hook! {
     unsafe fn brk(addr: *mut c_void) -> c_int => mosalloc_brk {
-        if let Some(mosalloc) = PRELOAD_ALLOC.as_mut() {
-            mosalloc.brk(addr as usize)
-        } else {
-            real!(brk)(addr)
-        }
+        guarded(
+            "brk",
+            preload_alloc(),
+            |m| m.brk(addr as usize),
+            || real!(brk)(addr),
+        )
     }
 }
 
 pub fn libc_brk(addr: *mut c_void) -> c_int {
     println!("{:x}", addr as usize);
-    unsafe { real!(brk)(addr) }
+    inside(|| unsafe { libc_call!(brk)(addr) })
 }
 
 // void *sbrk(intptr_t increment);
+#[cfg(feature = "preload")]
 hook! {
     unsafe fn sbrk(incr: intptr_t) -> *mut c_void => mosalloc_sbrk {
-        if let Some(mosalloc) = PRELOAD_ALLOC.as_mut() {
-            mosalloc.sbrk(incr) as *mut c_void
+        guarded(
+            "sbrk",
+            preload_alloc(),
+            |m| m.sbrk(incr) as *mut c_void,
+            || real!(sbrk)(incr),
+        )
+    }
+}
+
+pub fn libc_sbrk(incr: intptr_t) -> *mut c_void {
+    inside(|| unsafe { libc_call!(sbrk)(incr) })
+}
+
+// int pkey_alloc(unsigned int flags, unsigned int access_rights);
+#[cfg(feature = "preload")]
+hook! {
+    unsafe fn pkey_alloc(flags: c_uint, access_rights: c_uint) -> c_int => mosalloc_pkey_alloc {
+        let ret = real!(pkey_alloc)(flags, access_rights);
+        if ret == -1 && *libc::__errno_location() == libc::ENOSPC {
+            if let Some(mosalloc) = mosalloc() {
+                println!("pkey_alloc: out of keys, {} held by mosalloc sub-pools", mosalloc.nr_pkeys());
+            }
+        }
+        ret
+    }
+}
+
+// int pkey_free(int pkey);
+#[cfg(feature = "preload")]
+hook! {
+    unsafe fn pkey_free(pkey: c_int) -> c_int => mosalloc_pkey_free {
+        guarded(
+            "pkey_free",
+            mosalloc(),
+            |m| m.pkey_free(pkey),
+            || real!(pkey_free)(pkey),
+        )
+    }
+}
+
+// int pkey_mprotect(void *addr, size_t len, int prot, int pkey);
+#[cfg(feature = "preload")]
+hook! {
+    unsafe fn pkey_mprotect(addr: *mut c_void, len: size_t, prot: c_int, pkey: c_int) -> c_int => mosalloc_pkey_mprotect {
+        guarded(
+            "pkey_mprotect",
+            mosalloc(),
+            |m| m.pkey_mprotect(addr as usize, len, prot, pkey),
+            || real!(pkey_mprotect)(addr, len, prot, pkey),
+        )
+    }
+}
+
+// int malloc_trim(size_t pad);
+#[cfg(feature = "malloc-interpose")]
+hook! {
+    unsafe fn malloc_trim(pad: size_t) -> c_int => mosalloc_malloc_trim {
+        let ret = real!(malloc_trim)(pad);
+        // 1 if any memory was released, by glibc or mosalloc
+        if guarded("malloc_trim", mosalloc(), |m| m.trim(), || 0) > 0 {
+            1
         } else {
-            real!(sbrk)(incr)
+            ret
         }
     }
 }
 
-pub fn libc_sbrk(incr: intptr_t) -> *mut c_void {
-    unsafe { real!(sbrk)(incr) }
+// pin the ranges the application registers with a device, with --pin-devices
+#[cfg(feature = "preload")]
+static PIN_DEVICES: AtomicBool = AtomicBool::new(false);
+
+// pin [addr, addr + len) ahead of a device registration, so that none of its pages move meanwhile
+#[cfg(feature = "preload")]
+fn pin_device(addr: *mut c_void, len: size_t) -> bool {
+    if !PIN_DEVICES.load(Ordering::Relaxed) {
+        return false;
+    }
+    unsafe { guarded("pin", preload_alloc(), |m| m.pin(addr as usize, len), || ()) };
+    true
 }
 
-#[no_mangle]
-pub extern "C" fn mosalloc_morecore(incr: ptrdiff_t) -> *mut c_void {
+#[cfg(feature = "preload")]
+fn unpin_device(addr: *mut c_void) {
     unsafe {
-        if let Some(mosalloc) = PRELOAD_ALLOC.as_mut() {
-            mosalloc.sbrk(incr) as *mut c_void
+        guarded(
+            "pin",
+            preload_alloc(),
+            |m| {
+                let _ = m.unpin(addr as usize);
+            },
+            || (),
+        )
+    };
+}
+
+// struct ibv_mr *ibv_reg_mr(struct ibv_pd *pd, void *addr, size_t length, int access);
+#[cfg(feature = "preload")]
+hook! {
+    unsafe fn ibv_reg_mr(pd: *mut c_void, addr: *mut c_void, length: size_t, access: c_int) -> *mut c_void => mosalloc_ibv_reg_mr {
+        let pinned = pin_device(addr, length);
+        let mr = real!(ibv_reg_mr)(pd, addr, length, access);
+        if mr.is_null() && pinned {
+            unpin_device(addr);
+        }
+        mr
+    }
+}
+
+// int ibv_dereg_mr(struct ibv_mr *mr);
+#[cfg(feature = "preload")]
+hook! {
+    unsafe fn ibv_dereg_mr(mr: *mut c_void) -> c_int => mosalloc_ibv_dereg_mr {
+        // struct ibv_mr { struct ibv_context *context; struct ibv_pd *pd; void *addr; ... }
+        let addr = if mr.is_null() {
+            std::ptr::null_mut()
         } else {
-            real!(sbrk)(incr)
+            *(mr as *const *mut c_void).add(2)
+        };
+        let ret = real!(ibv_dereg_mr)(mr);
+        if ret == 0 && PIN_DEVICES.load(Ordering::Relaxed) {
+            unpin_device(addr);
         }
+        ret
+    }
+}
+
+// cudaError_t cudaHostRegister(void *ptr, size_t size, unsigned int flags);
+#[cfg(feature = "preload")]
+hook! {
+    unsafe fn cudaHostRegister(ptr: *mut c_void, size: size_t, flags: c_uint) -> c_int => mosalloc_cuda_host_register {
+        let pinned = pin_device(ptr, size);
+        let ret = real!(cudaHostRegister)(ptr, size, flags);
+        if ret != 0 && pinned {
+            unpin_device(ptr);
+        }
+        ret
+    }
+}
+
+// cudaError_t cudaHostUnregister(void *ptr);
+#[cfg(feature = "preload")]
+hook! {
+    unsafe fn cudaHostUnregister(ptr: *mut c_void) -> c_int => mosalloc_cuda_host_unregister {
+        let ret = real!(cudaHostUnregister)(ptr);
+        if ret == 0 && PIN_DEVICES.load(Ordering::Relaxed) {
+            unpin_device(ptr);
+        }
+        ret
+    }
+}
+
+#[cfg(feature = "preload")]
+type SyscallFn = unsafe extern "C" fn(c_long, ...) -> c_long;
+
+// the libc syscall(), shadowed by the one of libmosalloc
+#[cfg(feature = "preload")]
+fn libc_syscall() -> SyscallFn {
+    static REAL: OnceLock<usize> = OnceLock::new();
+    let addr =
+        *REAL.get_or_init(|| unsafe { libc::dlsym(libc::RTLD_NEXT, c"syscall".as_ptr()) as usize });
+    unsafe { std::mem::transmute::<usize, SyscallFn>(addr) }
+}
+
+// long syscall(long number, ...);
+// io_uring_register has no libc wrapper, so the fixed buffer registrations made through syscall()
+// are caught here (liburing's inline syscalls only by the seccomp filters), the rest of the calls
+// are passed on as is
+#[cfg(feature = "preload")]
+#[no_mangle]
+pub unsafe extern "C" fn syscall(nr: c_long, mut args: ...) -> c_long {
+    // like libc, always take 6 arguments
+    let mut a = [0 as c_long; 6];
+    for x in a.iter_mut() {
+        *x = args.next_arg::<c_long>();
     }
+    let real = || libc_syscall()(nr, a[0], a[1], a[2], a[3], a[4], a[5]);
+
+    if nr != libc::SYS_io_uring_register {
+        return real();
+    }
+    // with the seccomp hooks it's left to the filter
+    guarded(
+        "io_uring_register",
+        preload_alloc(),
+        |m| {
+            let ret = real();
+            if ret >= 0 {
+                let reg = io_uring::parse(
+                    a[1] as u32,
+                    a[2] as usize,
+                    a[3] as u32,
+                    io_uring::read_local,
+                );
+                m.io_uring_register(a[0] as i32, reg);
+            }
+            ret
+        },
+        real,
+    )
+}
+
+// libc aliases of the hooked calls, so that callers binding to them don't bypass mosalloc; the
+// exports are unversioned, which satisfies any versioned reference (e.g. mmap@GLIBC_2.2.5)
+
+#[cfg(feature = "preload")]
+#[no_mangle]
+pub unsafe extern "C" fn __mmap(
+    addr: *mut c_void,
+    len: size_t,
+    prot: c_int,
+    flags: c_int,
+    fd: c_int,
+    offset: off_t,
+) -> *mut c_void {
+    mosalloc_mmap(addr, len, prot, flags, fd, offset)
 }
 
+#[cfg(feature = "preload")]
+#[no_mangle]
+pub unsafe extern "C" fn __munmap(addr: *mut c_void, len: size_t) -> c_int {
+    mosalloc_munmap(addr, len)
+}
+
+#[cfg(feature = "preload")]
+#[no_mangle]
+pub unsafe extern "C" fn __mprotect(addr: *mut c_void, len: size_t, prot: c_int) -> c_int {
+    mosalloc_mprotect(addr, len, prot)
+}
+
+#[cfg(feature = "preload")]
+#[no_mangle]
+pub unsafe extern "C" fn __madvise(addr: *mut c_void, len: size_t, advice: c_int) -> c_int {
+    mosalloc_madvise(addr, len, advice)
+}
+
+#[cfg(feature = "preload")]
+#[no_mangle]
+pub unsafe extern "C" fn __sbrk(incr: intptr_t) -> *mut c_void {
+    mosalloc_sbrk(incr)
+}
+
+#[cfg(feature = "malloc-interpose")]
+#[no_mangle]
+pub extern "C" fn mosalloc_morecore(incr: ptrdiff_t) -> *mut c_void {
+    unsafe {
+        guarded(
+            "sbrk",
+            preload_alloc(),
+            |m| m.sbrk(incr) as *mut c_void,
+            || real!(sbrk)(incr),
+        )
+    }
+}
+
+// glibc only grows the main arena through __morecore up to 2.33
+pub fn morecore_active() -> bool {
+    let version = unsafe { CStr::from_ptr(libc::gnu_get_libc_version()) }.to_string_lossy();
+    let mut parts = version.split('.').map(|x| x.parse::<u32>().unwrap_or(0));
+
+    matches!((parts.next(), parts.next()), (Some(2), Some(minor)) if minor <= 33)
+}
+
+#[cfg(feature = "preload")]
 pub unsafe fn preload_init(config: MosallocConfig) {
-    __morecore = mosalloc_morecore as extern "C" fn(intptr_t) -> *mut c_void;
+    set_hooked(&config.hooks);
+    callers::set(&config.callers);
+    threads::set(&config.threads);
+    PIN_DEVICES.store(config.pin_devices, Ordering::Relaxed);
+    #[cfg(feature = "malloc-interpose")]
+    {
+        __morecore = mosalloc_morecore as extern "C" fn(intptr_t) -> *mut c_void;
+    }
 
     PRELOAD_ALLOC = Some(Allocator::new(config, false));
-    PRELOAD_ALLOC.as_mut().unwrap().drain();
+    let mosalloc = PRELOAD_ALLOC.as_mut().unwrap();
+    mosalloc.drain();
+    mosalloc.spawn_services();
 }
@@ -0,0 +1,174 @@
+use std::fmt;
+use std::io;
+
+use libc::c_long;
+
+// the bytes of a logged line written at once, longer lines are split over several writes; the
+// shorter ones don't interleave with the lines of the other threads (below PIPE_BUF)
+const LINE: usize = 1024;
+// the bytes a file buffers before writing them out
+const BUF: usize = 4096;
+
+fn errno() -> i32 {
+    unsafe { *libc::__errno_location() }
+}
+
+fn syscall_ret(ret: c_long) -> Result<usize, i32> {
+    match ret {
+        -1 => Err(errno()),
+        x => Ok(x as usize),
+    }
+}
+
+// write all of buf to fd with raw write syscalls, retrying the interrupted and partial ones
+pub fn write_fd(fd: i32, mut buf: &[u8]) -> Result<(), i32> {
+    while !buf.is_empty() {
+        match syscall_ret(unsafe { libc::syscall(libc::SYS_write, fd, buf.as_ptr(), buf.len()) }) {
+            Ok(0) => return Err(libc::EIO),
+            Ok(x) => buf = &buf[x..],
+            Err(libc::EINTR) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// a fixed buffer formatted into, written out to fd as it fills
+struct FmtBuf<const N: usize> {
+    fd: i32,
+    buf: [u8; N],
+    len: usize,
+    err: Option<i32>,
+}
+
+impl<const N: usize> FmtBuf<N> {
+    const fn new(fd: i32) -> Self {
+        Self {
+            fd,
+            buf: [0; N],
+            len: 0,
+            err: None,
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), i32> {
+        let ret = write_fd(self.fd, &self.buf[..self.len]);
+        self.len = 0;
+        if let Err(e) = ret {
+            self.err.get_or_insert(e);
+        }
+        ret
+    }
+
+    fn push(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            if self.len == N {
+                let _ = self.flush();
+            }
+            let n = bytes.len().min(N - self.len);
+            self.buf[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+            self.len += n;
+            bytes = &bytes[n..];
+        }
+    }
+}
+
+impl<const N: usize> fmt::Write for FmtBuf<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+// format a line into a stack buffer and write it to fd, without taking the stdio locks nor
+// allocating, so that it's safe from within the hooks; the backend of the crate's println!
+pub fn print_line(fd: i32, args: fmt::Arguments) {
+    let mut line = FmtBuf::<LINE>::new(fd);
+    let _ = fmt::write(&mut line, args);
+    line.push(b"\n");
+    let _ = line.flush();
+}
+
+// a file written with raw syscalls through a fixed buffer, flushed when full and when dropped
+pub struct RawFile {
+    out: FmtBuf<BUF>,
+}
+
+impl RawFile {
+    // create or truncate path
+    pub fn create(path: &str) -> Result<Self, String> {
+        // the path is copied into a nul terminated stack buffer
+        let mut cpath = [0u8; libc::PATH_MAX as usize];
+        if path.len() >= cpath.len() || path.as_bytes().contains(&0) {
+            return Err(format!("{}: invalid path", path));
+        }
+        cpath[..path.len()].copy_from_slice(path.as_bytes());
+
+        let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC;
+        let fd = syscall_ret(unsafe {
+            libc::syscall(
+                libc::SYS_openat,
+                libc::AT_FDCWD,
+                cpath.as_ptr(),
+                flags,
+                0o644,
+            )
+        })
+        .map_err(|e| format!("{}: {}", path, io::Error::from_raw_os_error(e)))?;
+
+        Ok(Self {
+            out: FmtBuf::new(fd as i32),
+        })
+    }
+
+    // write out the buffer and sync the file to disk
+    pub fn sync(&mut self) -> Result<(), i32> {
+        self.out.flush()?;
+        syscall_ret(unsafe { libc::syscall(libc::SYS_fsync, self.out.fd) }).map(|_| ())
+    }
+
+    // the first error the writes ran into, if any
+    pub fn error(&self) -> Option<i32> {
+        self.out.err
+    }
+}
+
+impl fmt::Debug for RawFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawFile")
+            .field("fd", &self.out.fd)
+            .field("buffered", &self.out.len)
+            .finish()
+    }
+}
+
+impl io::Write for RawFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.out.push(buf);
+        match self.out.err {
+            Some(e) => Err(io::Error::from_raw_os_error(e)),
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush().map_err(io::Error::from_raw_os_error)
+    }
+}
+
+impl Drop for RawFile {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+        unsafe { libc::syscall(libc::SYS_close, self.out.fd) };
+    }
+}
+
+// write a whole file with raw syscalls and sync it
+pub fn write_file(path: &str, contents: &[u8]) -> Result<(), String> {
+    let mut out = RawFile::create(path)?;
+    out.out.push(contents);
+    match out.sync().err().or(out.error()) {
+        Some(e) => Err(format!("{}: {}", path, io::Error::from_raw_os_error(e))),
+        None => Ok(()),
+    }
+}
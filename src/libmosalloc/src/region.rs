@@ -1,276 +1,2638 @@
 use libc;
+use std::ffi::CString;
+use std::fmt;
+use std::iter;
 use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-use mosalloc::utils::htlb::{AllocType, Pool, PAGE_SIZE};
-use mosalloc::utils::misc::{align_down, align_up};
+use mosalloc::utils::advice::{
+    AdviceBatch, MADV_COLLAPSE, MADV_POPULATE_READ, MADV_POPULATE_WRITE,
+};
+use mosalloc::utils::control::{region_label, RegionStats};
+use mosalloc::utils::htlb::{
+    AllocType, PlacementPolicy, Pool, PoolBacking, SizeLimit, ZeroPolicy, PAGE_SIZE,
+};
+use mosalloc::utils::misc::{align_down, align_up, is_aligned, size_to_str};
+use mosalloc::utils::snapshot::RegionSnapshot;
 
+use crate::compaction::{self, Compaction, Move};
+use crate::freemap_store::FreeMapStore;
+use crate::internal_maps;
 use crate::lock::Lock;
+use crate::metadata::{self, MetaAlloc, MetaVec};
+use crate::on_demand;
+use crate::page_limits;
+use crate::pagemap;
 use crate::preload_hooks;
+use crate::remap;
+
+use mosalloc_core::freemap;
+
+// number of per-thread cache size classes (1 - 64 base pages)
+const CACHE_CLASSES: usize = 7;
+
+// bounded retries of a failed hugetlb mapping, the hugepages of an exiting process take a while
+// to get back to the pool; right away, without sleeping under the region lock
+const MAP_RETRIES: u32 = 5;
+
+// the granularity an anon region grows past its pool at, see Region::grow
+const GROW_CHUNK: usize = 256 << 20;
+
+// per-thread cache of pre-carved ranges, with one free list per size class
+#[derive(Debug)]
+struct RangeCache {
+    lock: Lock,
+    ranges: [Vec<usize>; CACHE_CLASSES],
+}
+
+// who holds the pages of a pinned range: an io_uring ring (by its fd), or the application for a
+// device (an RDMA memory region, a CUDA host registration ...)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PinOwner {
+    IoUring(i32),
+    Application,
+}
+
+// int (*)(void *addr, size_t len, void *arg)
+pub type PinCallback =
+    unsafe extern "C" fn(*mut libc::c_void, libc::size_t, *mut libc::c_void) -> libc::c_int;
+
+// an allocated range backed by a single page size, as exposed by the introspection API
+#[derive(Debug, Clone, Copy)]
+pub struct AllocationInfo {
+    pub alloc_type: AllocType,
+    pub start: usize,
+    pub end: usize,
+    pub pagesz: usize,
+    // the protection requested by the application, and the one of the page backing it, along
+    // with whether the page is locked
+    pub prot: i32,
+    pub applied_prot: i32,
+    pub locked: bool,
+}
+
+const RW: i32 = libc::PROT_READ | libc::PROT_WRITE;
+
+#[cfg(target_arch = "x86_64")]
+const MAP_32BIT: i32 = libc::MAP_32BIT;
+#[cfg(not(target_arch = "x86_64"))]
+const MAP_32BIT: i32 = 0;
+
+// memory tagging (MTE) of the allocations requesting it, applied to the pages backing them
+#[cfg(target_arch = "aarch64")]
+pub const PROT_MTE: i32 = 0x20;
+#[cfg(not(target_arch = "aarch64"))]
+pub const PROT_MTE: i32 = 0;
+
+// the address without the tag in its top byte (MTE, TBI), which the kernel ignores for the
+// processes using the tagged address ABI; the addresses are compared and accounted untagged, while
+// the calls forwarded to the kernel keep their tags
+#[inline]
+pub fn untagged(addr: usize) -> usize {
+    if cfg!(target_arch = "aarch64") {
+        addr & !(0xff << 56)
+    } else {
+        addr
+    }
+}
+
+// the pieces of [start, end) inside and outside of the sorted, disjoint ranges, in order, along
+// with whether each is inside one
+fn split_at_ranges(ranges: &[Range<usize>], start: usize, end: usize) -> Vec<(Range<usize>, bool)> {
+    let mut pieces = Vec::new();
+    let mut cur = start;
+
+    let idx = ranges.partition_point(|x| x.end <= start);
+    for r in ranges[idx..].iter().take_while(|x| x.start < end) {
+        if cur < r.start {
+            pieces.push((cur..r.start, false));
+        }
+        let next = r.end.min(end);
+        pieces.push((cur.max(r.start)..next, true));
+        cur = next;
+    }
+    if cur < end {
+        pieces.push((cur..end, false));
+    }
+
+    pieces
+}
+
+// remove r, which has to lie within one of them, from the sorted, disjoint ranges
+fn remove_range(ranges: &mut MetaVec<Range<usize>>, r: &Range<usize>) {
+    let idx = ranges.partition_point(|x| x.end <= r.start);
+    assert!(ranges[idx].start <= r.start && r.end <= ranges[idx].end);
+
+    let x = ranges.remove(idx);
+    if r.end < x.end {
+        ranges.insert(idx, r.end..x.end);
+    }
+    if x.start < r.start {
+        ranges.insert(idx, x.start..r.start);
+    }
+}
+
+// the mmap flags of the application honoured for the pool-backed allocations (MAP_32BIT by the
+// region they're placed in), the rest (e.g. MAP_NORESERVE, MAP_STACK) are dropped, the pool
+// pages are always reserved
+const HONOURED_FLAGS: i32 = libc::MAP_PRIVATE
+    | libc::MAP_ANONYMOUS
+    | libc::MAP_FIXED
+    | libc::MAP_FIXED_NOREPLACE
+    | libc::MAP_POPULATE
+    | libc::MAP_LOCKED
+    | MAP_32BIT;
+
+// the page size bits of a MAP_HUGETLB request, as log2 of the size
+const MAP_HUGE_MASK: i32 = 0x3f;
+
+// a page backing part of an allocation, fresh if it's a pool hugepage not mapped yet
+#[derive(Debug, Clone, Copy)]
+struct PlannedPage {
+    addr: usize,
+    pagesz: usize,
+    fresh: bool,
+}
+
+// a pool page that couldn't be mapped, neither with its page size nor with base pages
+#[derive(Debug, Clone, Copy)]
+pub struct MapError {
+    pub alloc_type: AllocType,
+    // (index, start, end) of the pool interval the page belongs to
+    pub interval: Option<(usize, usize, usize)>,
+    pub addr: usize,
+    pub pagesz: usize,
+    pub errno: i32,
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "({}) failed to map the {} page at 0x{:x}",
+            self.alloc_type.as_str(),
+            size_to_str(self.pagesz),
+            self.addr
+        )?;
+        if let Some((idx, start, end)) = self.interval {
+            write!(f, " of interval {} [0x{:x}, 0x{:x})", idx, start, end)?;
+        }
+        write!(f, ": errno {}", self.errno)
+    }
+}
 
 // struct for heap, anon and file mosalloc regions
 #[derive(Debug)]
 pub struct Region {
     // region type
     pub alloc_type: AllocType,
+    // the name of a named anon region and the address it has to end below, None for the default
+    // ones
+    pub name: Option<String>,
+    pub below: Option<usize>,
+
+    // the htlb intervals pool, and the index of the last interval an address was looked up in
+    pool: Pool,
+    last_interval: AtomicUsize,
+    backing: PoolBacking,
+    // names of the mappings backing each interval, e.g. mosalloc:mmap:2MB:3, the last one for the
+    // base pages in between
+    names: Vec<CString>,
+
+    pub start: usize,
+    // the program break, only moved by brk / sbrk on the heap (the start for the other regions)
+    pub brk: usize,
+    // end of the highest range ever allocated, and of the highest page ever mapped, they never
+    // move down
+    high_water: usize,
+    mapped_high: usize,
+    pub max: usize,
+
+    pub max_pgsz: usize,
+    pub len: usize,
+
+    free_map: FreeMapStore,
+    // bytes freed that the free map couldn't take back, at its hard cap
+    freemap_leaked: usize,
+
+    // the address an anon region may grow up to past its pool once out of space (0 if it can't),
+    // the bytes it grew by, in how many steps, and the growths that didn't fit
+    grow_limit: usize,
+    grown: usize,
+    grow_events: usize,
+    grow_refused: usize,
+
+    // where the allocations without an address go, the bytes of each pool hugepage not in the
+    // free map (the hugepages of interval i start at fill_base[i]), and the allocations placed
+    // into partially used hugepages by the fill policy
+    placement: PlacementPolicy,
+    fill: MetaVec<usize>,
+    fill_base: MetaVec<usize>,
+    fill_placed: usize,
+
+    // sorted allocated ranges and their protection flags
+    prot_map: MetaVec<(Range<usize>, i32)>,
+
+    // sorted ranges allocated with MAP_LOCKED, and whether any backing page was mapped with a
+    // protection other than RW or locked (until then the pages never need syncing)
+    locked: MetaVec<Range<usize>>,
+    restricted: bool,
+
+    // allocations backed by pages with a wider protection than requested (shared with other
+    // allocations), locked, populated, and the ones with flags dropped
+    prot_widened: usize,
+    locked_allocs: usize,
+    // allocations requesting memory tagging, and the page runs whose backing couldn't get it (e.g.
+    // hugetlb pages on kernels without MTE support for them), left untagged
+    mte_allocs: usize,
+    mte_failed: usize,
+    populated: usize,
+    flags_dropped: usize,
+    // MAP_HUGETLB requests placed in the pool intervals of their page size
+    hugetlb_absorbed: usize,
+
+    // hugepages currently remapped to base pages by the aging policy
+    demoted: MetaVec<usize>,
+
+    // (page size, nr) of the hugetlb pages currently mapped
+    htlb_mapped: MetaVec<(usize, usize)>,
+
+    // successful / failed MADV_COLLAPSE requests for THP-backed pools
+    collapsed: usize,
+    collapse_failed: usize,
+
+    // cold pages pushed towards swap by the aging policy, and the bytes of them
+    swapped: usize,
+    swapped_bytes: usize,
+
+    // freshly mapped THP ranges to collapse, for COLLAPSE backed pools
+    collapse_batch: Option<AdviceBatch>,
+
+    // per-thread range caches, selected by hashing the tid
+    caches: Vec<RangeCache>,
+    cache_batch: usize,
+    cache_hits: AtomicUsize,
+    cache_refills: AtomicUsize,
+
+    // protection key of each pool interval (-1 for none)
+    pkeys: MetaVec<i32>,
+
+    // mappings mosalloc didn't create found inside the region, excluded from the free map
+    foreign: MetaVec<Range<usize>>,
+
+    // sorted ranges the application registered as movable by the compaction
+    movable: MetaVec<Range<usize>>,
+
+    // pinned ranges, whose pages a ring or a device holds until they're unpinned (even past a
+    // free), so they're never moved nor reclaimed, the application's callback telling about the
+    // ones it didn't pin explicitly, and the reclaims and moves skipped because of either
+    pins: MetaVec<(PinOwner, Range<usize>)>,
+    pin_callback: Option<(PinCallback, usize)>,
+    pin_kept: usize,
+
+    // pool intervals no new allocation is placed in (e.g. a 1GB interval whose backing failed),
+    // the allocations already in them are kept, and the pages of each interval that fell back to
+    // base pages or couldn't be mapped at all
+    disabled: MetaVec<usize>,
+    faults: MetaVec<usize>,
+    // pool intervals whose hugepages were requested from run_mosalloc, when reserved on demand
+    requested: MetaVec<bool>,
+
+    // utilization watermarks (percent, ascending), how many are currently crossed and the
+    // highest one crossed since the last check
+    watermarks: MetaVec<usize>,
+    watermarks_crossed: usize,
+    watermark_pending: Option<usize>,
+
+    // allocated bytes (not including the cached ranges) and the configured limits on them
+    allocated: usize,
+    peak: usize,
+    limit: SizeLimit,
+    soft_exceeded: usize,
+    hard_denied: usize,
+
+    // cumulative allocation counters, for the control socket stats
+    allocs: usize,
+    frees: usize,
+    bytes_allocated: usize,
+
+    // retried hugetlb mappings, and pool pages that fell back to base pages
+    map_retries: usize,
+    map_fallbacks: usize,
+
+    // hugetlb pages populated at mapping time or setup, and the time the kernel took to zero them
+    zero: ZeroPolicy,
+    zeroed: usize,
+    zero_time: Duration,
+
+    lock: Lock,
+}
+
+impl Region {
+    pub fn new(pool: Pool, alloc_type: AllocType, backing: PoolBacking, len: usize) -> Self {
+        let free_map = FreeMapStore::new(len);
+        let prot_map = Vec::with_capacity_in(len, MetaAlloc);
+
+        let (max_pgsz, len) = (pool.max_pagesz(), pool.span());
+
+        Self {
+            pool,
+            last_interval: AtomicUsize::new(0),
+            backing,
+            names: Vec::new(),
+            alloc_type,
+            name: None,
+            below: None,
+            start: 0,
+            brk: 0,
+            high_water: 0,
+            mapped_high: 0,
+            max: 0,
+            max_pgsz,
+            len,
+            free_map,
+            freemap_leaked: 0,
+            grow_limit: 0,
+            grown: 0,
+            grow_events: 0,
+            grow_refused: 0,
+            placement: PlacementPolicy::FIRSTFIT,
+            fill: Vec::new_in(MetaAlloc),
+            fill_base: Vec::new_in(MetaAlloc),
+            fill_placed: 0,
+            prot_map,
+            locked: Vec::new_in(MetaAlloc),
+            restricted: false,
+            prot_widened: 0,
+            locked_allocs: 0,
+            mte_allocs: 0,
+            mte_failed: 0,
+            populated: 0,
+            flags_dropped: 0,
+            hugetlb_absorbed: 0,
+            demoted: Vec::new_in(MetaAlloc),
+            htlb_mapped: Vec::new_in(MetaAlloc),
+            collapsed: 0,
+            collapse_failed: 0,
+            swapped: 0,
+            swapped_bytes: 0,
+            collapse_batch: (backing == PoolBacking::COLLAPSE)
+                .then(|| AdviceBatch::local(MADV_COLLAPSE)),
+            caches: Vec::new(),
+            cache_batch: 0,
+            cache_hits: AtomicUsize::new(0),
+            cache_refills: AtomicUsize::new(0),
+            pkeys: Vec::new_in(MetaAlloc),
+            foreign: Vec::new_in(MetaAlloc),
+            movable: Vec::new_in(MetaAlloc),
+            pins: Vec::new_in(MetaAlloc),
+            pin_callback: None,
+            pin_kept: 0,
+            disabled: Vec::new_in(MetaAlloc),
+            faults: Vec::new_in(MetaAlloc),
+            requested: Vec::new_in(MetaAlloc),
+            watermarks: Vec::new_in(MetaAlloc),
+            watermarks_crossed: 0,
+            watermark_pending: None,
+            allocated: 0,
+            peak: 0,
+            limit: SizeLimit::default(),
+            soft_exceeded: 0,
+            hard_denied: 0,
+            allocs: 0,
+            frees: 0,
+            bytes_allocated: 0,
+            map_retries: 0,
+            map_fallbacks: 0,
+            zero: ZeroPolicy::FAULT,
+            zeroed: 0,
+            zero_time: Duration::ZERO,
+            lock: Lock::new(
+                true,
+                match alloc_type {
+                    AllocType::BRK => "brk region",
+                    AllocType::ANON => "mmap region",
+                    AllocType::FILE => "file region",
+                },
+            ),
+        }
+    }
+
+    // make it a named anon region, placed below `below` if set
+    pub fn set_name(&mut self, name: &str, below: Option<usize>) {
+        self.name = Some(name.to_string());
+        self.below = below;
+    }
+
+    // the region type, along with its name for the named regions (e.g. mmap:low)
+    pub fn label(&self) -> String {
+        region_label(self.alloc_type, self.name.as_deref())
+    }
+
+    // enable the per-thread range caches, with `batch` ranges carved per refill
+    pub fn enable_caches(&mut self, nr: usize, batch: usize) {
+        self.caches = (0..nr)
+            .map(|_| RangeCache {
+                lock: Lock::new(true, "range cache"),
+                ranges: Default::default(),
+            })
+            .collect();
+        self.cache_batch = batch;
+    }
+
+    pub fn set_zero(&mut self, zero: ZeroPolicy) {
+        self.zero = zero;
+    }
+
+    // cap the bytes of the free map storage
+    pub fn set_freemap_max(&mut self, max: Option<usize>) {
+        self.free_map.set_hard(max);
+    }
+
+    // let the region grow past its end up to limit, once placed
+    pub fn set_grow_limit(&mut self, limit: usize) {
+        self.grow_limit = limit;
+    }
+
+    pub fn set_placement(&mut self, placement: PlacementPolicy) {
+        self.placement = placement;
+    }
+
+    // map and populate every pool hugepage, so that the kernel zeroes them before the program
+    // starts, up to the page caps
+    pub fn prefault(&mut self, dryrun: bool) {
+        if dryrun || self.backing != PoolBacking::HUGETLB {
+            return;
+        }
+
+        let start = Instant::now();
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+        for p in self.backing_plan(self.start, self.max) {
+            if !p.fresh
+                || self
+                    .foreign
+                    .iter()
+                    .any(|x| x.start < p.addr + p.pagesz && p.addr < x.end)
+            {
+                continue;
+            }
+            if !page_limits::reserve(p.pagesz, 1) {
+                println!(
+                    "({}) prefault stopped at 0x{:x}, {} page cap reached",
+                    self.alloc_type.as_str(),
+                    p.addr,
+                    size_to_str(p.pagesz)
+                );
+                break;
+            }
+
+            match self.alloc(p.addr, p.pagesz, libc::PROT_NONE, flags, dryrun) {
+                Ok(true) => {}
+                Ok(false) => page_limits::release(p.pagesz, 1),
+                Err(e) => {
+                    page_limits::release(p.pagesz, 1);
+                    println!("{}", e);
+                    break;
+                }
+            }
+        }
+
+        println!(
+            "({}) prefaulted {} hugepages in {:?}",
+            self.alloc_type.as_str(),
+            self.zeroed,
+            start.elapsed()
+        );
+    }
+
+    // assign the protection keys of the named sub-pools to their intervals
+    pub fn set_pkeys(&mut self, pkeys: &[(String, i32)]) {
+        self.pkeys.clear();
+        self.pkeys.extend(self.pool.intervals.iter().map(|x| {
+            pkeys
+                .iter()
+                .find(|(name, _)| Some(name) == x.name.as_ref())
+                .map_or(-1, |(_, pkey)| *pkey)
+        }));
+    }
+
+    // names of the region sub-pools
+    pub fn pool_names(&self) -> impl Iterator<Item = &String> + '_ {
+        self.pool.intervals.iter().filter_map(|x| x.name.as_ref())
+    }
+
+    #[inline]
+    fn get_addr_pkey(&self, addr: usize) -> i32 {
+        let offset = addr - self.start;
+
+        self.pool
+            .intervals
+            .iter()
+            .zip(self.pkeys.iter())
+            .find(|(x, _)| x.start <= offset && offset < x.end)
+            .map_or(-1, |(_, pkey)| *pkey)
+    }
+
+    // tag [addr, addr + len) with a protection key, the range has to be aligned to the page
+    // sizes backing it, the same way the kernel requires for hugetlb mappings
+    pub fn pkey_mprotect(&mut self, addr: usize, len: usize, prot: i32, pkey: i32) -> i32 {
+        let end = addr + align_up(len, *PAGE_SIZE);
+
+        let mut cur = addr;
+        while cur < end {
+            let (pagesz, next) = self.get_addr_pagesz_range(cur);
+            if !is_aligned(cur, pagesz) || !is_aligned(next.min(end), pagesz) {
+                unsafe { *libc::__errno_location() = libc::EINVAL };
+                return -1;
+            }
+            cur = next;
+        }
+
+        if self.is_allocated(addr, end) {
+            self.set_prot(addr, end, prot);
+        }
+
+        // the range is made of whole pages, the heap stays RW, same as with mprotect
+        let prot = if self.alloc_type == AllocType::BRK {
+            RW
+        } else {
+            self.page_prot(addr, end)
+        };
+        self.restricted |= prot != RW;
+        unsafe { libc::syscall(libc::SYS_pkey_mprotect, addr, end - addr, prot, pkey) as i32 }
+    }
+
+    pub fn set_limit(&mut self, limit: SizeLimit) {
+        self.limit = limit;
+    }
+
+    pub fn limit(&self) -> SizeLimit {
+        self.limit
+    }
+
+    // whether len more bytes can be allocated without exceeding the hard limit
+    pub fn within_limit(&mut self, len: usize) -> bool {
+        match self.limit.hard {
+            Some(hard) if self.allocated + align_up(len, *PAGE_SIZE) > hard => {
+                self.hard_denied += 1;
+                false
+            }
+            _ => true,
+        }
+    }
+
+    pub fn set_watermarks(&mut self, watermarks: &[usize]) {
+        self.watermarks.clear();
+        self.watermarks.extend_from_slice(watermarks);
+        self.watermarks.sort();
+        self.watermarks.dedup();
+
+        // the ones already crossed (e.g. when changed at runtime) are only reported once the
+        // usage drops below them and crosses them again
+        self.watermarks_crossed = self
+            .watermarks
+            .iter()
+            .take_while(|x| self.over_watermark(**x))
+            .count();
+        self.watermark_pending = None;
+    }
+
+    pub fn watermarks(&self) -> &[usize] {
+        &self.watermarks
+    }
+
+    // whether the allocated bytes are at or over pct percent of the region
+    #[inline]
+    fn over_watermark(&self, pct: usize) -> bool {
+        self.allocated as u64 * 100 >= pct as u64 * self.len as u64
+    }
+
+    // the highest watermark crossed since the last call
+    pub fn take_watermark(&mut self) -> Option<usize> {
+        self.watermark_pending.take()
+    }
+
+    // (allocated, size) of the region
+    pub fn usage(&self) -> (usize, usize) {
+        (self.allocated, self.len)
+    }
+
+    // bytes handed out to the application, the ranges in the thread caches aren't charged
+    pub fn used(&self) -> usize {
+        self.allocated
+    }
+
+    // (used bytes, high-water mark, program break), the last two as offsets from the region
+    // start, the program break is 0 but for the heap
+    pub fn extent(&self) -> (usize, usize, usize) {
+        let brk = if self.alloc_type == AllocType::BRK {
+            self.brk - self.start
+        } else {
+            0
+        };
+
+        (self.used(), self.high_water - self.start, brk)
+    }
+
+    fn charge(&mut self, len: usize) {
+        self.allocated += len;
+        self.bytes_allocated += len;
+        self.peak = self.peak.max(self.allocated);
+
+        while self.watermarks_crossed < self.watermarks.len()
+            && self.over_watermark(self.watermarks[self.watermarks_crossed])
+        {
+            self.watermark_pending = Some(self.watermarks[self.watermarks_crossed]);
+            self.watermarks_crossed += 1;
+        }
+
+        if let Some(soft) = self.limit.soft {
+            if self.allocated > soft {
+                // only warn when crossing the limit
+                if self.allocated - len <= soft {
+                    println!(
+                        "({}) warning: {} allocated, over the {} soft limit",
+                        self.alloc_type.as_str(),
+                        size_to_str(self.allocated),
+                        size_to_str(soft)
+                    );
+                }
+                self.soft_exceeded += 1;
+            }
+        }
+    }
+
+    pub fn init(&mut self, start: usize) {
+        self.start = start;
+        self.brk = self.start;
+        self.high_water = self.start;
+        self.mapped_high = self.start;
+        // 32-bit address spaces can't fit large pools
+        self.max = self
+            .start
+            .checked_add(self.len)
+            .expect("region doesn't fit in the address space");
+
+        self.free_map.push(self.start..self.max);
+        self.faults.resize(self.pool.intervals.len(), 0);
+        self.requested.resize(self.pool.intervals.len(), false);
+        for x in self.pool.intervals.iter() {
+            self.fill_base.push(self.fill.len());
+            if x.pagesz > *PAGE_SIZE {
+                self.fill
+                    .resize(self.fill.len() + (x.end - x.start) / x.pagesz, 0);
+            }
+        }
+
+        // the kernel rejects brackets, `, $, \ and non printable characters in the names
+        let label = self
+            .label()
+            .replace(|c: char| !c.is_ascii_graphic() || "[]`$\\".contains(c), "_");
+        self.names = self
+            .pool
+            .intervals
+            .iter()
+            .enumerate()
+            .map(|(i, x)| format!("mosalloc:{}:{}:{}", label, size_to_str(x.pagesz), i))
+            .chain(iter::once(format!(
+                "mosalloc:{}:{}",
+                label,
+                size_to_str(*PAGE_SIZE)
+            )))
+            .map(|x| CString::new(x).unwrap())
+            .collect();
+    }
+
+    // index of the pool interval containing addr
+    fn interval_of(&self, addr: usize) -> Option<usize> {
+        let offset = addr - self.start;
+        self.pool
+            .intervals
+            .iter()
+            .position(|x| x.start <= offset && offset < x.end)
+    }
+
+    // stop placing new allocations in a pool interval, the allocations in it are left alone
+    pub fn disable_interval(&mut self, idx: usize) -> Result<(), String> {
+        if idx >= self.pool.intervals.len() {
+            return Err(format!("({}) no interval {}", self.label(), idx));
+        }
+        if !self.disabled.contains(&idx) {
+            self.disabled.push(idx);
+        }
+        Ok(())
+    }
+
+    pub fn enable_interval(&mut self, idx: usize) {
+        self.disabled.retain(|x| *x != idx);
+    }
+
+    pub fn disabled_intervals(&self) -> &[usize] {
+        &self.disabled
+    }
+
+    // keep the next allocations away from an interval whose backing failed
+    fn disable_failed(&mut self, e: &MapError) {
+        if let Some((i, _, _)) = e.interval {
+            if !self.disabled.contains(&i) {
+                self.disabled.push(i);
+                println!("({}) interval {} disabled", self.label(), i);
+            }
+        }
+    }
+
+    // whether [start, end) overlaps a disabled pool interval
+    fn in_disabled(&self, start: usize, end: usize) -> bool {
+        self.disabled_ranges()
+            .any(|x| x.start < end && start < x.end)
+    }
+
+    // absolute ranges of the disabled pool intervals
+    fn disabled_ranges(&self) -> impl Iterator<Item = Range<usize>> + Clone + '_ {
+        self.disabled.iter().map(|&i| {
+            let x = &self.pool.intervals[i];
+            self.start + x.start..self.start + x.end
+        })
+    }
+
+    // health of each pool interval: ok, degraded (some of its pages fell back to base pages or
+    // failed to map) or disabled
+    pub fn interval_states(&self) -> Vec<&'static str> {
+        (0..self.pool.intervals.len())
+            .map(|i| {
+                if self.disabled.contains(&i) {
+                    "disabled"
+                } else if self.faults[i] > 0 {
+                    "degraded"
+                } else {
+                    "ok"
+                }
+            })
+            .collect()
+    }
+
+    // name a fresh mapping after the interval it backs, so that /proc/<pid>/smaps attributes it;
+    // hugetlb mappings are file backed and can't be named
+    fn name_backing(&self, addr: usize, len: usize) {
+        let offset = addr - self.start;
+        let idx = self.pool.interval_at(offset);
+        let name = match self.pool.intervals.get(idx) {
+            Some(x) if x.start <= offset => &self.names[idx],
+            _ => self.names.last().unwrap(),
+        };
+        internal_maps::set_name(addr as *mut libc::c_void, len, name);
+    }
+
+    // page size backing addr and the end of the same-page-size range containing it
+    #[inline]
+    fn get_addr_pagesz_range(&self, addr: usize) -> (usize, usize) {
+        // the space the region grew by is backed by base pages
+        if self.grown > 0 && addr >= self.max - self.grown {
+            return (*PAGE_SIZE, self.max);
+        }
+        let (pagesz, end) =
+            self.pool
+                .pagesz_range(addr - self.start, &self.last_interval, *PAGE_SIZE);
+        (pagesz, self.start + end)
+    }
+
+    // allocate memory for the given addr based on the pool config, false if it was already mapped
+    #[inline]
+    fn alloc(
+        &mut self,
+        addr: usize,
+        pagesz: usize,
+        prot: i32,
+        flags: i32,
+        dryrun: bool,
+    ) -> Result<bool, MapError> {
+        let huge = pagesz > *PAGE_SIZE && !dryrun;
+        let htlb = huge && self.backing == PoolBacking::HUGETLB;
+        let addr = align_down(addr, pagesz);
+
+        // the pages are mapped RW, the protection of the allocations on them is applied once mapped
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | (flags & libc::MAP_POPULATE);
+        let mut hflags = flags | libc::MAP_FIXED_NOREPLACE;
+        if htlb {
+            hflags |= libc::MAP_HUGETLB | (pagesz.trailing_zeros() as i32) << libc::MAP_HUGE_SHIFT;
+        }
+        // fault the page in while mapping it, the zeroing is timed along with the mmap
+        let populate = htlb && self.zero != ZeroPolicy::FAULT;
+        if populate {
+            hflags |= libc::MAP_POPULATE;
+        }
+
+        if htlb && on_demand::enabled() {
+            self.request_interval(addr);
+        }
+
+        let map = |flags: i32| {
+            preload_hooks::libc_mmap(addr as *mut libc::c_void, pagesz, RW, flags, -1, 0)
+        };
+        let errno = || unsafe { *libc::__errno_location() };
+
+        let mut mapped_at = Instant::now();
+        let mut ret = map(hflags);
+        for _ in 0..MAP_RETRIES {
+            if !(ret == libc::MAP_FAILED && htlb && matches!(errno(), libc::ENOMEM | libc::EAGAIN))
+            {
+                break;
+            }
+            self.map_retries += 1;
+            mapped_at = Instant::now();
+            ret = map(hflags);
+        }
+        if populate && ret != libc::MAP_FAILED {
+            self.zeroed += 1;
+            self.zero_time += mapped_at.elapsed();
+        }
+
+        if ret == libc::MAP_FAILED {
+            let err = errno();
+            // this can happen if we've already mapped this interval
+            if err == libc::EEXIST {
+                return Ok(false);
+            }
+
+            // back the page with base pages, like a demoted hugepage, so that the aging policy can
+            // promote it once hugepages are available again
+            if htlb && map(flags | libc::MAP_FIXED_NOREPLACE) != libc::MAP_FAILED {
+                println!(
+                    "({}) no {} page for 0x{:x} (errno {}), falling back to base pages",
+                    self.alloc_type.as_str(),
+                    size_to_str(pagesz),
+                    addr,
+                    err
+                );
+                self.map_fallbacks += 1;
+                if let Some(i) = self.interval_of(addr) {
+                    self.faults[i] += 1;
+                }
+                self.mapped_high = self.mapped_high.max(addr + pagesz);
+                self.demoted.push(addr);
+                page_limits::release(pagesz, 1);
+                self.name_backing(addr, pagesz);
+                self.apply_pkey(addr, pagesz, prot);
+                return Ok(true);
+            }
+
+            let interval = self.interval_of(addr);
+            if let Some(i) = interval {
+                self.faults[i] += 1;
+            }
+            return Err(MapError {
+                alloc_type: self.alloc_type,
+                interval: interval.map(|i| {
+                    let x = &self.pool.intervals[i];
+                    (i, self.start + x.start, self.start + x.end)
+                }),
+                addr,
+                pagesz,
+                errno: err,
+            });
+        }
+
+        self.mapped_high = self.mapped_high.max(addr + pagesz);
+        if htlb {
+            self.account_htlb(pagesz, 1);
+        } else {
+            self.name_backing(addr, pagesz);
+            if self.backing == PoolBacking::BASE && !dryrun {
+                // kept off THP even with THP set to always system-wide
+                preload_hooks::libc_madvise(ret, pagesz, libc::MADV_NOHUGEPAGE);
+            } else if huge {
+                self.thp_advise(ret as usize, pagesz);
+            }
+        }
+
+        self.apply_pkey(ret as usize, pagesz, prot);
+        Ok(true)
+    }
+
+    // request the hugepages of the whole interval of addr when it's first touched
+    fn request_interval(&mut self, addr: usize) {
+        if let Some(i) = self.interval_of(addr) {
+            if !self.requested[i] {
+                self.requested[i] = true;
+                let x = &self.pool.intervals[i];
+                on_demand::request(&self.label(), x.pagesz, (x.end - x.start) / x.pagesz);
+            }
+        }
+    }
+
+    // tag a freshly mapped range with the protection key of its sub-pool, if any
+    fn apply_pkey(&self, addr: usize, len: usize, prot: i32) {
+        let pkey = self.get_addr_pkey(addr);
+        if pkey != -1 {
+            unsafe {
+                libc::syscall(
+                    libc::SYS_pkey_mprotect,
+                    addr,
+                    len,
+                    prot | libc::PROT_READ | libc::PROT_WRITE,
+                    pkey,
+                );
+            }
+        }
+    }
+
+    fn account_htlb(&mut self, pagesz: usize, delta: isize) {
+        match self.htlb_mapped.iter_mut().find(|(sz, _)| *sz == pagesz) {
+            Some((_, nr)) => *nr = nr.checked_add_signed(delta).unwrap(),
+            None => self.htlb_mapped.push((pagesz, delta.try_into().unwrap())),
+        }
+    }
+
+    // (page size, nr) of the hugetlb pages currently mapped by the region
+    pub fn htlb_mapped(&self) -> &[(usize, usize)] {
+        &self.htlb_mapped
+    }
+
+    // mark a freshly mapped hugepage-sized range as THP-eligible, and queue it for collapsing if
+    // requested
+    fn thp_advise(&mut self, addr: usize, len: usize) {
+        preload_hooks::libc_madvise(addr as *mut libc::c_void, len, libc::MADV_HUGEPAGE);
+
+        if let Some(batch) = &mut self.collapse_batch {
+            batch.add(addr, len);
+        }
+    }
+
+    // collapse the THP ranges queued while mapping, with a single process_madvise where possible
+    fn flush_collapse(&mut self) {
+        if let Some(batch) = &mut self.collapse_batch {
+            if batch.is_empty() {
+                return;
+            }
+
+            match batch.flush() {
+                Ok(_) => self.collapsed += 1,
+                Err(err) => {
+                    println!("collapse failed: {}", err);
+                    self.collapse_failed += 1;
+                }
+            }
+        }
+    }
+
+    // synchronously collapse [addr, addr + len) into THPs (kernel 6.1+), returns the bytes of the
+    // THPs collapsed (or already there)
+    pub fn collapse(&mut self, addr: usize, len: usize) -> Result<usize, i32> {
+        let thp = pagemap::thp_size().ok_or(libc::EINVAL)?;
+
+        // one THP at a time, so that the collapsed ones are known; like the kernel, only the THPs
+        // wholly inside the range are collapsed
+        let (mut promoted, mut err) = (0, 0);
+        for page in (align_up(addr, thp)..align_down(addr + len, thp)).step_by(thp) {
+            if preload_hooks::libc_madvise(page as *mut libc::c_void, thp, MADV_COLLAPSE) == 0 {
+                promoted += thp;
+            } else {
+                err = unsafe { *libc::__errno_location() };
+            }
+        }
+
+        if promoted == len {
+            self.collapsed += 1;
+        } else {
+            self.collapse_failed += 1;
+        }
+        if promoted == 0 && err != 0 {
+            return Err(err);
+        }
+
+        Ok(promoted)
+    }
+
+    // let the compaction move [addr, addr + len), which has to be allocated and span whole pool
+    // pages of a single page size since the pages are moved along with it
+    pub fn register_movable(&mut self, addr: usize, len: usize) -> Result<(), i32> {
+        let end = addr + align_up(len, *PAGE_SIZE);
+        let (pagesz, pagesz_end) = self.get_addr_pagesz_range(addr);
+        if len == 0
+            || !is_aligned(addr, pagesz)
+            || !is_aligned(end, pagesz)
+            || end > pagesz_end
+            || !self.is_allocated(addr, end)
+        {
+            return Err(libc::EINVAL);
+        }
+
+        let idx = self.movable.partition_point(|x| x.end <= addr);
+        if self.movable.get(idx).is_some_and(|x| x.start < end) {
+            return Err(libc::EEXIST);
+        }
+        self.movable.insert(idx, addr..end);
+        Ok(())
+    }
+
+    pub fn unregister_movable(&mut self, addr: usize) -> Result<(), i32> {
+        let idx = self
+            .movable
+            .iter()
+            .position(|x| x.start == addr)
+            .ok_or(libc::ENOENT)?;
+        self.movable.remove(idx);
+        Ok(())
+    }
+
+    pub fn set_pin_callback(&mut self, callback: Option<(PinCallback, usize)>) {
+        self.pin_callback = callback;
+    }
+
+    // pin the pages of the part of [start, end) inside the region for owner
+    pub fn pin(&mut self, owner: PinOwner, start: usize, end: usize) {
+        let start = align_down(start.max(self.start), *PAGE_SIZE);
+        let end = align_up(end.min(self.max), *PAGE_SIZE);
+        if start < end {
+            self.pins.push((owner, start..end));
+        }
+    }
+
+    // drop a pin of the application starting at addr
+    pub fn unpin(&mut self, addr: usize) -> Result<(), i32> {
+        let addr = align_down(addr, *PAGE_SIZE);
+        let idx = self
+            .pins
+            .iter()
+            .position(|(owner, x)| *owner == PinOwner::Application && x.start == addr)
+            .ok_or(libc::ENOENT)?;
+        self.pins.remove(idx);
+        Ok(())
+    }
+
+    // drop all the pins of owner
+    pub fn unpin_all(&mut self, owner: PinOwner) {
+        self.pins.retain(|(x, _)| *x != owner);
+    }
+
+    // whether [start, end) overlaps a pinned range, or the application's callback says so;
+    // the callback runs under the region lock
+    fn held(&self, start: usize, end: usize) -> bool {
+        self.pins
+            .iter()
+            .any(|(_, x)| x.start < end && start < x.end)
+            || self.pin_callback.is_some_and(|(callback, arg)| unsafe {
+                callback(
+                    start as *mut libc::c_void,
+                    end - start,
+                    arg as *mut libc::c_void,
+                ) != 0
+            })
+    }
+
+    // the free space below `below` aligned to `align`: (total, largest contiguous)
+    pub fn aligned_free(&self, align: usize, below: usize) -> (usize, usize) {
+        self.free_map
+            .iter()
+            .map(|x| align_down(x.end.min(below), align).saturating_sub(align_up(x.start, align)))
+            .fold((0, 0), |(total, largest), x| (total + x, largest.max(x)))
+    }
+
+    // whether [start, end) has locked or demoted pages, their state doesn't survive a move, or
+    // pinned ones, their holder keeps using the old pages
+    fn pinned(&self, start: usize, end: usize) -> bool {
+        self.locked.iter().any(|x| x.start < end && start < x.end)
+            || self.demoted.iter().any(|&x| start <= x && x < end)
+            || self.held(start, end)
+    }
+
+    // the lowest free space below a movable range the range can be moved to: backed by the same
+    // page size, with the same protection key and outside the disabled intervals
+    fn compaction_target(&self, src: &Range<usize>) -> Option<usize> {
+        let len = src.len();
+        let (pagesz, _) = self.get_addr_pagesz_range(src.start);
+        let pkey = self.get_addr_pkey(src.start);
+
+        for r in self.free_map.iter().take_while(|x| x.start < src.start) {
+            let end = r.end.min(src.start);
+            let mut cur = align_up(r.start, pagesz);
+
+            while cur + len <= end {
+                let (sz, sz_end) = self.get_addr_pagesz_range(cur);
+                if sz != pagesz || sz_end < cur + len {
+                    cur = align_up(sz_end, pagesz);
+                    continue;
+                }
+                if self.in_disabled(cur, cur + len)
+                    || self.get_addr_pkey(cur) != pkey
+                    || self.get_addr_pkey(cur + len - 1) != pkey
+                    || self.pinned(cur, cur + len)
+                {
+                    cur += pagesz;
+                    continue;
+                }
+                return Some(cur);
+            }
+        }
+
+        None
+    }
+
+    // move the allocated [from, from + len) to the free [to, to + len) along with its pages
+    fn move_range(&mut self, from: usize, to: usize, len: usize, dryrun: bool) -> Result<(), i32> {
+        // the free map may not be able to split the target's free range
+        if self.del_range_from_freemap(to, len) == usize::MAX {
+            return Err(libc::ENOMEM);
+        }
+
+        let (pagesz, _) = self.get_addr_pagesz_range(from);
+        // the free pages at the target still mapped are replaced by the moved ones
+        let replaced = (to..to + len)
+            .step_by(pagesz)
+            .filter(|&x| Self::is_mapped(x, pagesz))
+            .count();
+
+        let ret = preload_hooks::libc_mremap(
+            from as *mut libc::c_void,
+            len,
+            len,
+            libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED,
+            to as *mut libc::c_void,
+        );
+        if ret == libc::MAP_FAILED {
+            let err = unsafe { *libc::__errno_location() };
+            self.add_range_to_freemap(to, len);
+            return Err(err);
+        }
+
+        // the pages left behind are mapped again when reallocated, like trimmed ones
+        if pagesz > *PAGE_SIZE && replaced > 0 {
+            if self.backing == PoolBacking::HUGETLB && !dryrun {
+                self.account_htlb(pagesz, -(replaced as isize));
+            }
+            page_limits::release(pagesz, replaced);
+        }
+        if pagesz == *PAGE_SIZE || self.backing != PoolBacking::HUGETLB || dryrun {
+            self.name_backing(to, len);
+        }
+
+        // the protections of the allocations move along
+        let prots = self
+            .prot_map
+            .iter()
+            .filter(|(r, _)| r.start < from + len && from < r.end)
+            .map(|(r, prot)| {
+                (
+                    r.start.max(from) - from,
+                    r.end.min(from + len) - from,
+                    *prot,
+                )
+            })
+            .collect::<Vec<_>>();
+        self.add_range_to_freemap(from, len);
+        self.clear_prot(from, from + len);
+        for (start, end, prot) in prots {
+            self.set_prot(to + start, to + end, prot);
+        }
+
+        Ok(())
+    }
+
+    // move the movable ranges down to the lowest free space that fits them, the highest ones
+    // first, squeezing the free space together towards the high-water mark
+    pub fn compact(&mut self, dryrun: bool) -> Compaction {
+        let below = self.high_water;
+        let mut out = Compaction {
+            before: self.aligned_free(compaction::ALIGN, below),
+            ..Default::default()
+        };
+
+        for i in (0..self.movable.len()).rev() {
+            let src = self.movable[i].clone();
+            if self.pinned(src.start, src.end) {
+                continue;
+            }
+            let to = match self.compaction_target(&src) {
+                Some(x) => x,
+                None => continue,
+            };
+
+            match self.move_range(src.start, to, src.len(), dryrun) {
+                Ok(()) => {
+                    self.movable[i] = to..to + src.len();
+                    out.moves.push(Move {
+                        from: src.start,
+                        to,
+                        len: src.len(),
+                    });
+                }
+                Err(err) => {
+                    println!(
+                        "({}) compaction: failed to move 0x{:x} to 0x{:x}: errno {}",
+                        self.label(),
+                        src.start,
+                        to,
+                        err
+                    );
+                    out.failed += 1;
+                }
+            }
+        }
+        self.movable.sort_by_key(|x| x.start);
+
+        out.after = self.aligned_free(compaction::ALIGN, below);
+        out
+    }
+
+    // live stats, for the control socket, only the hugetlb pages are accounted as hugepage-backed
+    // here and the residency is left out, both come from smaps (see Allocator::stats)
+    pub fn stats(&self) -> RegionStats {
+        let (lock_acquired, lock_contended) = self.lock.stats();
+        let (used, high_water, brk) = self.extent();
+        let (free_hugepages, partial_hugepages) = self.hugepage_fill();
+
+        RegionStats {
+            alloc_type: self.alloc_type,
+            name: self.name.clone(),
+            len: self.len,
+            allocated: self.allocated,
+            peak: self.peak,
+            used,
+            high_water,
+            brk,
+            huge: self
+                .htlb_mapped
+                .iter()
+                .map(|(pagesz, nr)| pagesz * nr)
+                .sum(),
+            allocs: self.allocs + self.cache_hits.load(Ordering::Relaxed),
+            frees: self.frees,
+            bytes_allocated: self.bytes_allocated,
+            lock_acquired,
+            lock_contended,
+            intervals: self.interval_states().join(","),
+            start: self.start,
+            free_hugepages,
+            partial_hugepages,
+            fill_placed: self.fill_placed,
+            freemap_ranges: self.free_map.len(),
+            freemap_bytes: self.free_map.capacity(),
+            freemap_refused: self.free_map.refused(),
+            grown: self.grown,
+            grow_events: self.grow_events,
+            ..Default::default()
+        }
+    }
+
+    // whether the region is backed by THPs rather than hugetlb pages
+    pub fn thp_backed(&self) -> bool {
+        self.alloc_type != AllocType::FILE && self.backing != PoolBacking::HUGETLB
+    }
+
+    pub fn print_stats(&self) {
+        let label = self.label();
+        let (used, high_water, brk) = self.extent();
+        if self.alloc_type == AllocType::BRK {
+            println!(
+                "({}) used: {}, high water: {}, brk: {}",
+                label,
+                size_to_str(used),
+                size_to_str(high_water),
+                size_to_str(brk)
+            );
+        } else {
+            println!(
+                "({}) used: {}, high water: {}",
+                label,
+                size_to_str(used),
+                size_to_str(high_water)
+            );
+        }
+
+        if self.limit.is_set() {
+            println!(
+                "({}) allocated: {}, peak: {}, over soft limit: {}, hard limit hits: {}",
+                label,
+                size_to_str(self.allocated),
+                size_to_str(self.peak),
+                self.soft_exceeded,
+                self.hard_denied
+            );
+        }
+
+        if !self.caches.is_empty() {
+            println!(
+                "({}) cache hits: {}, refills: {}",
+                label,
+                self.cache_hits.load(Ordering::Relaxed),
+                self.cache_refills.load(Ordering::Relaxed)
+            );
+        }
+
+        if self.map_retries > 0 || self.map_fallbacks > 0 {
+            println!(
+                "({}) mapping retries: {}, base page fallbacks: {}",
+                label, self.map_retries, self.map_fallbacks
+            );
+        }
+
+        if self.placement == PlacementPolicy::FILL {
+            let (free, partial) = self.hugepage_fill();
+            println!(
+                "({}) placement ({}): {} allocations into partially used hugepages, {} free and {} partially used hugepages",
+                label,
+                self.placement.as_str(),
+                self.fill_placed,
+                free,
+                partial
+            );
+        }
+
+        let states = self.interval_states();
+        if states.iter().any(|x| *x != "ok") {
+            println!("({}) intervals: {}", label, states.join(", "));
+        }
+
+        if self.zeroed > 0 {
+            println!(
+                "({}) zeroing ({}): {} hugepages in {:?}, {:?} per page",
+                label,
+                self.zero.as_str(),
+                self.zeroed,
+                self.zero_time,
+                self.zero_time / self.zeroed as u32
+            );
+        }
+
+        if self.prot_widened > 0
+            || self.locked_allocs > 0
+            || self.populated > 0
+            || self.flags_dropped > 0
+            || self.hugetlb_absorbed > 0
+        {
+            println!(
+                "({}) flags: {} with a wider protection, {} locked, {} populated, {} with \
+                 dropped flags, {} MAP_HUGETLB absorbed",
+                label,
+                self.prot_widened,
+                self.locked_allocs,
+                self.populated,
+                self.flags_dropped,
+                self.hugetlb_absorbed
+            );
+        }
+        if self.mte_allocs > 0 {
+            println!(
+                "({}) mte: {} tagged allocations, {} page runs left untagged",
+                label, self.mte_allocs, self.mte_failed
+            );
+        }
+        if self.grow_events > 0 || self.grow_refused > 0 {
+            println!(
+                "({}) grown: {} past the pool in {} steps (up to {}), {} refused",
+                label,
+                size_to_str(self.grown),
+                self.grow_events,
+                size_to_str(self.grow_limit.saturating_sub(self.start)),
+                self.grow_refused
+            );
+        }
+        if self.free_map.strained() || self.freemap_leaked > 0 {
+            println!(
+                "({}) free map: {}, {} leaked",
+                label,
+                self.free_map.as_string(),
+                size_to_str(self.freemap_leaked)
+            );
+        }
+
+        if !self.pins.is_empty() || self.pin_kept > 0 {
+            let (io, app): (Vec<_>, Vec<_>) = self
+                .pins
+                .iter()
+                .partition(|(owner, _)| matches!(owner, PinOwner::IoUring(_)));
+            println!(
+                "({}) pinned: {} io_uring fixed buffers ({}), {} application ranges ({}), {} \
+                 reclaims or moves skipped",
+                label,
+                io.len(),
+                size_to_str(io.iter().map(|(_, x)| x.len()).sum()),
+                app.len(),
+                size_to_str(app.iter().map(|(_, x)| x.len()).sum()),
+                self.pin_kept
+            );
+        }
+
+        if self.backing != PoolBacking::HUGETLB {
+            println!(
+                "({}) {} backing, collapsed: {}, collapse failed: {}",
+                label,
+                self.backing.as_str(),
+                self.collapsed,
+                self.collapse_failed
+            );
+        }
+
+        if self.swapped > 0 {
+            println!(
+                "({}) swap: {} cold pages ({}) advised",
+                label,
+                self.swapped,
+                size_to_str(self.swapped_bytes)
+            );
+        }
+    }
+
+    // the pages backing [start, end) across the pool intervals, each interval's pages are aligned
+    // to its own page size and never spill over into the next one, the file region doesn't map
+    // any
+    fn backing_plan(&self, start: usize, end: usize) -> Vec<PlannedPage> {
+        let mut plan = Vec::new();
+        if self.alloc_type == AllocType::FILE {
+            return plan;
+        }
+
+        let mut cur = start;
+        while cur < end {
+            let (pagesz, next) = self.get_addr_pagesz_range(cur);
+            let next = next.min(end);
+
+            let mut addr = align_down(cur, pagesz);
+            while addr < next {
+                plan.push(PlannedPage {
+                    addr,
+                    pagesz,
+                    fresh: pagesz > *PAGE_SIZE && !Self::is_mapped(addr, pagesz),
+                });
+                addr += pagesz;
+            }
+            cur = addr;
+        }
+
+        plan
+    }
+
+    // (page size, nr) of the fresh pool hugepages of a plan
+    fn fresh_pages(plan: &[PlannedPage]) -> Vec<(usize, usize)> {
+        let mut out: Vec<(usize, usize)> = Vec::new();
+        for p in plan.iter().filter(|p| p.fresh) {
+            match out.iter_mut().find(|(sz, _)| *sz == p.pagesz) {
+                Some((_, nr)) => *nr += 1,
+                None => out.push((p.pagesz, 1)),
+            }
+        }
+        out
+    }
+
+    // reserve the pool hugepages a plan maps for the first time against the per page size caps
+    // and the reservation, so that a plan exceeding them fails before anything is mapped; returns
+    // the (page size, nr) of the pages denied
+    fn reserve_pages(plan: &[PlannedPage]) -> Result<(), (usize, usize)> {
+        let needed = Self::fresh_pages(plan);
+        for (i, &(pagesz, nr)) in needed.iter().enumerate() {
+            if !page_limits::reserve(pagesz, nr) {
+                for &(pagesz, nr) in needed[..i].iter() {
+                    page_limits::release(pagesz, nr);
+                }
+                return Err((pagesz, nr));
+            }
+        }
+
+        Ok(())
+    }
+
+    // allocate memory for the [start, end] range
+    pub fn alloc_range(
+        &mut self,
+        addr: usize,
+        len: usize,
+        prot: i32,
+        flags: i32,
+        dryrun: bool,
+    ) -> usize {
+        let len = align_up(len, *PAGE_SIZE);
+        // the hints into the disabled intervals are ignored like the unavailable ones
+        let fixed = flags & (libc::MAP_FIXED | libc::MAP_FIXED_NOREPLACE) != 0;
+        let refused = self.free_map.refused();
+        let mut start = if !fixed
+            && !self.disabled.is_empty()
+            && (addr == 0 || self.in_disabled(addr, addr + len))
+        {
+            usize::MAX
+        } else {
+            self.del_range_from_freemap(addr, len)
+        };
+        if start == usize::MAX {
+            // the free map is at its hard cap and couldn't split the range
+            if self.free_map.refused() != refused {
+                return start;
+            }
+            if (flags & libc::MAP_FIXED_NOREPLACE) != 0 {
+                // this will trigger an EEXIST for FIXED_NORPLACE
+                return start;
+            } else if (flags & libc::MAP_FIXED) != 0 {
+                // for MAP_FIXED, make sure that the whole requested range has been previously allocated
+                assert!(self
+                    .free_map
+                    .iter()
+                    .all(|x| !x.contains(&start) && !x.contains(&(start + len))));
+                self.protect(addr, addr + len, prot);
+                return addr;
+            } else {
+                // ignore the address hint for non FIXED requests, growing the region if it's out
+                // of space
+                let mut placed = self.place(len);
+                if placed.is_none() && self.grow(len) {
+                    placed = self.place(len);
+                }
+                start = match placed {
+                    Some(x) => self.del_range_from_freemap(x, len),
+                    None => usize::MAX,
+                };
+                if start == usize::MAX {
+                    return start;
+                }
+            }
+        }
+        let end = start + len;
+
+        let plan = self.backing_plan(start, end);
+        if let Err((pagesz, nr)) = Self::reserve_pages(&plan) {
+            println!(
+                "({}) mmap of {} at 0x{:x} failed with ENOMEM, it {}",
+                self.label(),
+                size_to_str(len),
+                start,
+                page_limits::describe(pagesz, nr)
+            );
+            self.add_range_to_freemap(start, len);
+            return usize::MAX;
+        }
+
+        self.high_water = self.high_water.max(end);
+
+        self.charge(len);
+        self.allocs += 1;
+        self.set_prot(start, end, prot);
+
+        // for file mapping, we don't need to allocate memory
+        if self.alloc_type == AllocType::FILE {
+            return start;
+        }
+
+        if let Err(e) = self.map_range(&plan, prot, flags, dryrun) {
+            println!("{}", e);
+            self.free_range(start, len);
+            self.disable_failed(&e);
+            return usize::MAX;
+        }
+
+        self.apply_flags(start, end, prot, flags, dryrun);
+        start
+    }
+
+    // place a MAP_HUGETLB request in the pool intervals of its page size (the default hugepage
+    // size if the request doesn't encode one), aligned to it and rounded up to it like the kernel
+    // does; None if the pool has no such interval, usize::MAX if they're out of space
+    pub fn alloc_hugetlb(
+        &mut self,
+        len: usize,
+        prot: i32,
+        flags: i32,
+        default: usize,
+        dryrun: bool,
+    ) -> Option<usize> {
+        let pagesz = match (flags >> libc::MAP_HUGE_SHIFT) & MAP_HUGE_MASK {
+            0 => default,
+            x => 1 << x,
+        };
+        if pagesz == 0 || !self.intervals().any(|x| x.2 == pagesz) {
+            return None;
+        }
+
+        let len = align_up(len, pagesz);
+        let fit = self
+            .intervals()
+            .filter(|x| x.2 == pagesz)
+            .find_map(|(start, end, _)| {
+                let end = end.min(self.max);
+                self.free_map.iter().find_map(|r| {
+                    let addr = align_up(r.start.max(start), pagesz);
+                    (addr + len <= r.end.min(end) && !self.in_disabled(addr, addr + len))
+                        .then_some(addr)
+                })
+            });
+        let Some(addr) = fit else {
+            return Some(usize::MAX);
+        };
+
+        // the pages come from the pool, the page size bits go along with MAP_HUGETLB
+        let flags = flags & !(libc::MAP_HUGETLB | MAP_HUGE_MASK << libc::MAP_HUGE_SHIFT);
+        let ret = self.alloc_range(addr, len, prot, flags, dryrun);
+        if ret != usize::MAX {
+            self.hugetlb_absorbed += 1;
+        }
+        Some(ret)
+    }
+
+    // apply the protection and the flags of an allocation to its freshly mapped range
+    fn apply_flags(&mut self, start: usize, end: usize, prot: i32, flags: i32, dryrun: bool) {
+        if flags & !HONOURED_FLAGS != 0 {
+            self.flags_dropped += 1;
+        }
+
+        // the fresh pages were populated when mapped, the rest of them may not be yet
+        if flags & libc::MAP_POPULATE != 0 && !dryrun {
+            let advice = if prot & libc::PROT_WRITE != 0 {
+                MADV_POPULATE_WRITE
+            } else {
+                MADV_POPULATE_READ
+            };
+            preload_hooks::libc_madvise(start as *mut libc::c_void, end - start, advice);
+            self.populated += 1;
+        }
+
+        if prot & PROT_MTE != 0 {
+            self.mte_allocs += 1;
+        }
+
+        if flags & libc::MAP_LOCKED != 0 {
+            let idx = self.locked.partition_point(|x| x.start < start);
+            self.locked.insert(idx, start..end);
+            self.locked_allocs += 1;
+        }
+
+        self.sync_pages(start, end);
+        if self.alloc_type == AllocType::ANON && self.page_prot(start, end) != prot {
+            self.prot_widened += 1;
+        }
+    }
+
+    // the protection of the backing pages overlapping [start, end), the union of the ones of the
+    // allocations on them (RW if there are none)
+    fn page_prot(&self, start: usize, end: usize) -> i32 {
+        let idx = self.prot_map.partition_point(|(r, _)| r.end <= start);
+        self.prot_map[idx..]
+            .iter()
+            .take_while(|(r, _)| r.start < end)
+            .map(|(_, prot)| *prot)
+            .reduce(|a, b| a | b)
+            .unwrap_or(RW)
+    }
+
+    // whether any allocation on the backing pages overlapping [start, end) is locked
+    fn page_locked(&self, start: usize, end: usize) -> bool {
+        let idx = self.locked.partition_point(|r| r.end <= start);
+        self.locked.get(idx).is_some_and(|r| r.start < end)
+    }
+
+    // apply the protection and the locking of the allocations to the pages backing [start, end),
+    // the pages are shared, so each one gets the union of the protections of the allocations on
+    // it and is locked if any of them is; the heap stays RW and the file mappings are the
+    // application's own
+    fn sync_pages(&mut self, start: usize, end: usize) {
+        if self.alloc_type != AllocType::ANON {
+            return;
+        }
+
+        // nothing to do while every page is RW and unlocked
+        let lower = align_down(start, self.max_pgsz).max(self.start);
+        let upper = align_up(end, self.max_pgsz).min(self.max);
+        if !self.restricted && self.page_prot(lower, upper) == RW && !self.page_locked(lower, upper)
+        {
+            return;
+        }
+
+        // runs of pages in the same state are applied at once
+        let mut run: Option<(Range<usize>, i32, bool)> = None;
+        let mut cur = start;
+        while cur < end {
+            let (pagesz, _) = self.get_addr_pagesz_range(cur);
+            let page = align_down(cur, pagesz)..align_down(cur, pagesz) + pagesz;
+            let prot = self.page_prot(page.start, page.end);
+            let locked = self.page_locked(page.start, page.end);
+            cur = page.end;
+
+            match &mut run {
+                Some((r, p, l)) if r.end == page.start && *p == prot && *l == locked => {
+                    r.end = page.end
+                }
+                _ => {
+                    if let Some(x) = run.replace((page, prot, locked)) {
+                        self.apply_state(x);
+                    }
+                }
+            }
+        }
+        if let Some(x) = run {
+            self.apply_state(x);
+        }
+    }
+
+    fn apply_state(&mut self, (range, prot, locked): (Range<usize>, i32, bool)) {
+        let (addr, len) = (range.start as *mut libc::c_void, range.len());
+
+        if preload_hooks::libc_mprotect(addr, len, prot) != 0 && prot & PROT_MTE != 0 {
+            self.mte_failed += 1;
+            preload_hooks::libc_mprotect(addr, len, prot & !PROT_MTE);
+        }
+        if locked {
+            unsafe { libc::mlock(addr, len) };
+        } else if self.restricted {
+            unsafe { libc::munlock(addr, len) };
+        }
+        self.restricted |= prot != RW || locked;
+    }
+
+    // change the protection of the allocated [start, end), applied to the backing pages
+    pub fn protect(&mut self, start: usize, end: usize, prot: i32) {
+        self.set_prot(start, end, prot);
+        self.sync_pages(start, end);
+    }
+
+    // the protection requested for the allocation at addr
+    pub fn prot_at(&self, addr: usize) -> i32 {
+        let idx = self.prot_map.partition_point(|(r, _)| r.end <= addr);
+        match self.prot_map.get(idx) {
+            Some((r, prot)) if r.start <= addr => *prot,
+            _ => RW,
+        }
+    }
+
+    // map the pages of a reserved plan, all or nothing: if a page can't be mapped the pages
+    // mapped so far are unmapped again, so the application never sees half of a mapping
+    fn map_range(
+        &mut self,
+        plan: &[PlannedPage],
+        prot: i32,
+        flags: i32,
+        dryrun: bool,
+    ) -> Result<(), MapError> {
+        let mut mapped = Vec::new();
+        for (i, p) in plan.iter().enumerate() {
+            match self.alloc(p.addr, p.pagesz, prot, flags, dryrun) {
+                Ok(true) => mapped.push(*p),
+                // reserved, but e.g. partly covered by a foreign mapping
+                Ok(false) if p.fresh => page_limits::release(p.pagesz, 1),
+                Ok(false) => {}
+                Err(e) => {
+                    for (pagesz, nr) in Self::fresh_pages(&plan[i..]) {
+                        page_limits::release(pagesz, nr);
+                    }
+                    self.unmap_pages(&mapped, dryrun);
+                    return Err(e);
+                }
+            }
+        }
+
+        self.flush_collapse();
+        Ok(())
+    }
+
+    // undo the mapping of pages mapped by an unfinished plan
+    fn unmap_pages(&mut self, pages: &[PlannedPage], dryrun: bool) {
+        // the queued ranges are about to go away
+        if let Some(batch) = &mut self.collapse_batch {
+            batch.clear();
+        }
+
+        for p in pages.iter() {
+            preload_hooks::libc_munmap(p.addr as *mut libc::c_void, p.pagesz);
+
+            // base page fallbacks were already unaccounted
+            if let Some(idx) = self.demoted.iter().position(|&x| x == p.addr) {
+                self.demoted.remove(idx);
+            } else if p.pagesz > *PAGE_SIZE {
+                if self.backing == PoolBacking::HUGETLB && !dryrun {
+                    self.account_htlb(p.pagesz, -1);
+                }
+                page_limits::release(p.pagesz, 1);
+            }
+        }
+
+        if !pages.is_empty() {
+            println!(
+                "({}) rolled back {} mapped pages",
+                self.alloc_type.as_str(),
+                pages.len()
+            );
+        }
+    }
+
+    #[inline]
+    fn cache_class(len: usize) -> Option<usize> {
+        if len.is_power_of_two() && len >= *PAGE_SIZE {
+            let class = (len / *PAGE_SIZE).trailing_zeros() as usize;
+            if class < CACHE_CLASSES {
+                return Some(class);
+            }
+        }
+        None
+    }
 
-    // the htlb intervals pool
-    pool: Pool,
+    // allocate a range from the calling thread's cache, refilling the cache in batches from the
+    // free map, so that the region lock is only taken on refills
+    pub fn cache_alloc(&mut self, len: usize, dryrun: bool) -> Option<usize> {
+        if self.caches.is_empty() {
+            return None;
+        }
 
-    pub start: usize,
-    pub end: usize,
-    pub max: usize,
+        let class = Self::cache_class(align_up(len, *PAGE_SIZE))?;
+        let len = *PAGE_SIZE << class;
+        let slot = unsafe { libc::syscall(libc::SYS_gettid) } as usize % self.caches.len();
 
-    pub max_pgsz: usize,
-    pub len: usize,
+        self.caches[slot].lock.lock();
 
-    free_map: Vec<Range<usize>>,
+        if self.caches[slot].ranges[class].is_empty() {
+            let prot = libc::PROT_READ | libc::PROT_WRITE;
+            let flags = libc::MAP_ANONYMOUS | libc::MAP_PRIVATE;
+            let batch = len * self.cache_batch;
 
-    lock: Lock,
-}
+            self.lock();
+            let mut start = match self.place(batch) {
+                Some(x) => self.del_range_from_freemap(x, batch),
+                None => usize::MAX,
+            };
+            let mut plan = Vec::new();
+            if start != usize::MAX {
+                plan = self.backing_plan(start, start + batch);
+                if Self::reserve_pages(&plan).is_err() {
+                    self.add_range_to_freemap(start, batch);
+                    start = usize::MAX;
+                }
+            }
+            if start != usize::MAX {
+                let end = start + batch;
+                self.high_water = self.high_water.max(end);
 
-impl Region {
-    pub fn new(pool: Pool, alloc_type: AllocType, len: usize) -> Self {
-        let free_map = Vec::with_capacity(len);
+                // cached ranges are neither free nor allocated, they're charged when handed out
+                match self.map_range(&plan, prot, flags, dryrun) {
+                    Ok(()) => {
+                        // the pages may still be in the state of the allocations freed on them
+                        self.sync_pages(start, end);
+                        self.caches[slot].ranges[class].extend((start..end).step_by(len).rev());
+                    }
+                    Err(e) => {
+                        println!("{}", e);
+                        self.add_range_to_freemap(start, batch);
+                        self.disable_failed(&e);
+                    }
+                }
+            }
+            self.unlock();
 
-        let (max_pgsz, len) = pool.intervals.iter().fold((0, 0), |(pgsz, end), x| {
-            (x.pagesz.max(pgsz), x.end.max(end))
-        });
+            self.cache_refills.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+        }
 
-        Self {
-            pool,
-            alloc_type,
-            start: 0,
-            end: 0,
-            max: 0,
-            max_pgsz,
-            len,
-            free_map,
-            lock: Lock::new(true),
+        let mut addr = self.caches[slot].ranges[class].pop();
+        if let Some(x) = addr {
+            self.lock();
+            // over the hard limit, the regular path denies the request
+            if self
+                .limit
+                .hard
+                .is_some_and(|hard| self.allocated + len > hard)
+            {
+                self.caches[slot].ranges[class].push(x);
+                addr = None;
+            } else {
+                self.charge(len);
+                self.set_prot(x, x + len, RW);
+                self.sync_pages(x, x + len);
+            }
+            self.unlock();
         }
+        self.caches[slot].lock.unlock();
+
+        addr
     }
 
-    pub fn init(&mut self, start: usize) {
-        self.start = start;
-        self.end = self.start;
-        self.max = self.start + self.len;
+    pub fn free_range(&mut self, start: usize, len: usize) {
+        let len = align_up(len, *PAGE_SIZE);
+        debug_assert!(self.allocated >= len);
+        self.allocated -= len;
+        self.frees += 1;
+        // re-arm the watermarks dropped below
+        while self.watermarks_crossed > 0
+            && !self.over_watermark(self.watermarks[self.watermarks_crossed - 1])
+        {
+            self.watermarks_crossed -= 1;
+        }
+        self.add_range_to_freemap(start, len);
+        self.clear_prot(start, start + len);
 
-        self.free_map.push(self.start..self.max);
+        let end = start + len;
+        self.movable.retain(|x| x.end <= start || x.start >= end);
+
+        // the pages left to the rest of the allocations on them (or free) may be unlocked or get
+        // a narrower protection
+        let mut idx = self.locked.partition_point(|x| x.end <= start);
+        while idx < self.locked.len() && self.locked[idx].start < end {
+            let x = self.locked.remove(idx);
+            if x.start < start {
+                self.locked.insert(idx, x.start..start);
+                idx += 1;
+            }
+            if x.end > end {
+                self.locked.insert(idx, end..x.end);
+                idx += 1;
+            }
+        }
+        self.sync_pages(start, end);
     }
 
-    #[inline]
-    fn get_addr_pagesz(&self, addr: usize) -> usize {
-        let offset = addr - self.start;
+    fn del_range_from_freemap(&mut self, start: usize, len: usize) -> usize {
+        match freemap::take(&mut self.free_map, start, len) {
+            Some(x) => {
+                self.update_fill(x, x + len, true);
+                x
+            }
+            None => usize::MAX,
+        }
+    }
 
-        self.pool
-            .intervals
-            .iter()
-            .find_map(|x| {
-                if x.start <= offset && x.end >= offset {
-                    Some(x.pagesz)
-                } else {
-                    None
+    fn add_range_to_freemap(&mut self, start: usize, len: usize) {
+        if !freemap::give(&mut self.free_map, start, len) {
+            if self.freemap_leaked == 0 {
+                println!(
+                    "({}) free map at its hard cap, the ranges it can't take back are leaked",
+                    self.label()
+                );
+            }
+            self.freemap_leaked += len;
+            return;
+        }
+        self.update_fill(start, start + len, false);
+    }
+
+    // index of the pool hugepage at addr in the fill levels, None for the base pages
+    fn fill_index(&self, addr: usize) -> Option<usize> {
+        let offset = addr.checked_sub(self.start)?;
+        let idx = self.pool.interval_at(offset);
+        let x = self.pool.intervals.get(idx)?;
+
+        (x.start <= offset && offset < x.end && x.pagesz > *PAGE_SIZE)
+            .then(|| self.fill_base[idx] + (offset - x.start) / x.pagesz)
+    }
+
+    // account [start, end) leaving (taken) or going back to the free map on its hugepages
+    fn update_fill(&mut self, start: usize, end: usize, taken: bool) {
+        let mut cur = start;
+        while cur < end {
+            let (pagesz, next) = self.get_addr_pagesz_range(cur);
+            let next = next.min(end);
+
+            if pagesz > *PAGE_SIZE {
+                let mut page = align_down(cur, pagesz);
+                while page < next {
+                    let bytes = next.min(page + pagesz) - cur.max(page);
+                    if let Some(i) = self.fill_index(page) {
+                        self.fill[i] = match taken {
+                            true => self.fill[i] + bytes,
+                            false => self.fill[i].saturating_sub(bytes),
+                        };
+                    }
+                    page += pagesz;
                 }
-            })
-            .unwrap_or(PAGE_SIZE)
+            }
+            cur = next;
+        }
     }
 
-    // allocate memory for the given addr based on the pool config
-    #[inline]
-    fn alloc(&self, addr: usize, pagesz: usize, prot: i32, flags: i32, dryrun: bool) {
-        let mut hflags = flags | libc::MAP_FIXED_NOREPLACE;
-        if pagesz > PAGE_SIZE && !dryrun {
-            hflags |= libc::MAP_HUGETLB | (pagesz.trailing_zeros() as i32) << libc::MAP_HUGE_SHIFT;
+    // bytes not in the free map of the pool hugepage of addr, and its page size
+    fn fill_of(&self, addr: usize) -> Option<(usize, usize)> {
+        let i = self.fill_index(addr)?;
+        Some((self.fill[i], self.get_addr_pagesz_range(addr).0))
+    }
+
+    // extend the region past its end, in GROW_CHUNK steps up to its grow limit, so that len fits
+    // along with its trailing free space; the space has to be free of mappings, once it isn't the
+    // region stops growing
+    fn grow(&mut self, len: usize) -> bool {
+        if self.grow_limit <= self.max {
+            return false;
         }
 
+        let tail = self
+            .free_map
+            .last()
+            .filter(|x| x.end == self.max)
+            .map_or(0, |x| x.len());
+        let need = len.saturating_sub(tail);
+        let chunk = align_up(need, GROW_CHUNK).min(self.grow_limit - self.max);
+        if chunk < need {
+            self.grow_refused += 1;
+            return false;
+        }
+
+        let flags = libc::MAP_PRIVATE
+            | libc::MAP_ANONYMOUS
+            | libc::MAP_NORESERVE
+            | libc::MAP_FIXED_NOREPLACE;
         let ret = preload_hooks::libc_mmap(
-            align_down(addr, pagesz) as *mut libc::c_void,
-            pagesz,
-            prot | libc::PROT_READ | libc::PROT_WRITE,
-            hflags,
+            self.max as *mut libc::c_void,
+            chunk,
+            libc::PROT_NONE,
+            flags,
             -1,
             0,
         );
+        if ret != libc::MAP_FAILED {
+            preload_hooks::libc_munmap(ret, chunk);
+        }
+        // the kernels before 4.17 take MAP_FIXED_NOREPLACE as a hint
+        if ret as usize != self.max {
+            println!(
+                "({}) can't grow past 0x{:x}, the space is taken",
+                self.label(),
+                self.max
+            );
+            self.grow_limit = self.max;
+            self.grow_refused += 1;
+            return false;
+        }
 
-        if ret == libc::MAP_FAILED {
-            // this can happen if we've already mapped this interval
-            unsafe {
-                assert_eq!(*libc::__errno_location(), libc::EEXIST);
+        let start = self.max;
+        self.max += chunk;
+        self.len += chunk;
+        self.grown += chunk;
+        self.grow_events += 1;
+        self.add_range_to_freemap(start, chunk);
+        true
+    }
+
+    // where an allocation of len goes, see PlacementPolicy
+    fn place(&mut self, len: usize) -> Option<usize> {
+        if self.placement == PlacementPolicy::FILL {
+            if let Some(x) = self.fill_fit(len) {
+                self.fill_placed += 1;
+                return Some(x);
             }
         }
+        freemap::first_fit(&self.free_map, len, self.disabled_ranges())
     }
 
-    // allocate memory for the [start, end] range
-    pub fn alloc_range(
-        &mut self,
-        addr: usize,
-        len: usize,
-        prot: i32,
-        flags: i32,
-        dryrun: bool,
-    ) -> usize {
-        let len = align_up(len, PAGE_SIZE);
-        let mut start = self.del_range_from_freemap(addr, len);
-        if start == usize::MAX {
-            if (flags & libc::MAP_FIXED_NOREPLACE) != 0 {
-                // this will trigger an EEXIST for FIXED_NORPLACE
-                return start;
-            } else if (flags & libc::MAP_FIXED) != 0 {
-                // for MAP_FIXED, make sure that the whole requested range has been previously allocated
-                assert!(self
-                    .free_map
-                    .iter()
-                    .all(|x| !x.contains(&start) && !x.contains(&(start + len))));
-                return addr;
-            } else {
-                // ignore the address hint for non FIXED requests
-                start = self.del_range_from_freemap(0, len);
-                if start == usize::MAX {
-                    return start;
+    // the free space of the fullest partially used hugepage that fits len: the free ranges only
+    // border partially used hugepages at their unaligned ends, the lowest address wins a tie
+    fn fill_fit(&self, len: usize) -> Option<usize> {
+        let mut best: Option<(usize, usize)> = None;
+
+        for r in self.free_map.iter().filter(|x| x.len() >= len) {
+            let head = self.fill_of(r.start).and_then(|(fill, pagesz)| {
+                let page_end = align_down(r.start, pagesz) + pagesz;
+                (r.start + len <= page_end.min(r.end)).then_some((fill, r.start))
+            });
+            let tail = self.fill_of(r.end - 1).and_then(|(fill, pagesz)| {
+                let page = align_down(r.end - 1, pagesz);
+                (r.end - len >= page.max(r.start)).then_some((fill, r.end - len))
+            });
+
+            for (fill, addr) in [head, tail].into_iter().flatten() {
+                if fill == 0 || self.in_disabled(addr, addr + len) {
+                    continue;
+                }
+                if best.is_none_or(|(f, a)| fill > f || (fill == f && addr < a)) {
+                    best = Some((fill, addr));
                 }
             }
         }
-        let end = start + len;
 
-        if end > self.end {
-            self.end = end;
+        best.map(|(_, addr)| addr)
+    }
+
+    // (free, partially used) pool hugepages below the region end
+    fn hugepage_fill(&self) -> (usize, usize) {
+        let mut out = (0, 0);
+        for (i, x) in self.pool.intervals.iter().enumerate() {
+            if x.pagesz <= *PAGE_SIZE {
+                continue;
+            }
+            let nr = (x.end.min(self.max - self.start).saturating_sub(x.start)) / x.pagesz;
+            for &fill in self.fill[self.fill_base[i]..self.fill_base[i] + nr].iter() {
+                match fill {
+                    0 => out.0 += 1,
+                    f if f < x.pagesz => out.1 += 1,
+                    _ => {}
+                }
+            }
         }
+        out
+    }
 
-        // for file mapping, we don't need to allocate memory
-        if self.alloc_type == AllocType::FILE {
-            return start;
+    // remove [start, end) from the protections map, splitting partially covered ranges
+    fn clear_prot(&mut self, start: usize, end: usize) {
+        let mut idx = 0;
+        while idx < self.prot_map.len() {
+            let (r, prot) = self.prot_map[idx].clone();
+
+            if r.end <= start || r.start >= end {
+                idx += 1;
+            } else if r.start < start && r.end > end {
+                self.prot_map[idx].0.end = start;
+                self.prot_map.insert(idx + 1, (end..r.end, prot));
+                return;
+            } else if r.start < start {
+                self.prot_map[idx].0.end = start;
+                idx += 1;
+            } else if r.end > end {
+                self.prot_map[idx].0.start = end;
+                idx += 1;
+            } else {
+                self.prot_map.remove(idx);
+            }
         }
+    }
 
-        let mut cur = start;
-        while cur < end {
-            let pagesz = self.get_addr_pagesz(cur);
-            cur = align_down(cur, pagesz);
-            self.alloc(cur, pagesz, prot, flags, dryrun);
-            cur += pagesz;
+    // exclude a mapping mosalloc didn't create from the free map, returns the absorbed bytes; the
+    // heap has to stay contiguous, so it's truncated at the mapping instead
+    pub fn absorb(&mut self, start: usize, end: usize) -> usize {
+        let start = align_down(start.max(self.start), *PAGE_SIZE);
+        let end = align_up(end.min(self.max), *PAGE_SIZE);
+        if start >= end {
+            return 0;
         }
 
-        start
+        if self.alloc_type == AllocType::BRK {
+            let absorbed = self.max - start;
+            self.max = start;
+            self.free_map.retain(|x| x.start < start);
+            if let Some(last) = self.free_map.last_mut() {
+                last.end = last.end.min(start);
+            }
+            return absorbed;
+        }
+
+        let overlapping = self
+            .free_map
+            .iter()
+            .filter(|x| x.start < end && x.end > start)
+            .map(|x| x.start.max(start)..x.end.min(end))
+            .collect::<Vec<Range<usize>>>();
+
+        let mut absorbed = 0;
+        for r in overlapping {
+            self.del_range_from_freemap(r.start, r.len());
+            absorbed += r.len();
+            self.foreign.push(r);
+        }
+        self.foreign.sort_by_key(|x| x.start);
+
+        absorbed
     }
 
-    pub fn free_range(&mut self, start: usize, len: usize) {
-        let len = align_up(len, PAGE_SIZE);
-        self.add_range_to_freemap(start, len);
-        if self.end == start + len {
-            self.end = if let Some(r) = self.free_map.iter().last() {
-                r.start
-            } else {
-                self.start
-            };
+    // exclude a mapping that appeared in the region at runtime from the free map, returns the
+    // absorbed bytes; freed pool pages stay mapped until trimmed, so anonymous memory is only known
+    // to be foreign beyond the pages mosalloc ever mapped, while named mappings (files, [uprobes],
+    // io_uring rings ...) are foreign wherever they're free
+    pub fn absorb_intruder(&mut self, start: usize, end: usize, anonymous: bool) -> usize {
+        let start = if anonymous {
+            start.max(self.mapped_high)
+        } else {
+            start
+        };
+        // the heap can only be truncated above the break
+        let start = start.max(self.brk);
+        if start >= end || self.is_allocated(start, end) {
+            return 0;
         }
+
+        self.absorb(start, end)
     }
 
-    fn del_range_from_freemap(&mut self, start: usize, len: usize) -> usize {
-        println!("{:x} {} {:?}", start, len, self.free_map);
-        let ridx = self
-            .free_map
+    // whether [start, end) overlaps an absorbed foreign mapping
+    pub fn is_foreign(&self, start: usize, end: usize) -> bool {
+        self.foreign.iter().any(|x| x.start < end && x.end > start)
+    }
+
+    // return the parts of [addr, addr + len) within absorbed foreign mappings, unmapped by the
+    // application, to the free map; returns the pieces of the range in order, along with whether
+    // each was foreign
+    pub fn release_foreign(&mut self, addr: usize, len: usize) -> Vec<(Range<usize>, bool)> {
+        let end = addr + align_up(len, *PAGE_SIZE);
+        let pieces = split_at_ranges(&self.foreign, addr, end);
+
+        for (r, _) in pieces.iter().filter(|x| x.1) {
+            remove_range(&mut self.foreign, r);
+            self.add_range_to_freemap(r.start, r.len());
+        }
+
+        pieces
+    }
+
+    // check whether [start, end) is wholly allocated
+    pub fn is_allocated(&self, start: usize, end: usize) -> bool {
+        self.free_map
             .iter()
-            .position(|x| (start == 0 || x.contains(&start)) && (x.len() - start) >= len);
+            .all(|x| x.end <= start || x.start >= end)
+    }
 
-        if ridx.is_none() {
-            return usize::MAX;
+    // set the protection flags for [start, end), merging with same-protection neighbours
+    pub fn set_prot(&mut self, start: usize, end: usize, prot: i32) {
+        self.clear_prot(start, end);
+
+        let idx = self
+            .prot_map
+            .iter()
+            .position(|(r, _)| r.start >= end)
+            .unwrap_or(self.prot_map.len());
+
+        let left =
+            idx > 0 && self.prot_map[idx - 1].0.end == start && self.prot_map[idx - 1].1 == prot;
+        let right = idx < self.prot_map.len()
+            && self.prot_map[idx].0.start == end
+            && self.prot_map[idx].1 == prot;
+
+        if left && right {
+            self.prot_map[idx - 1].0.end = self.prot_map[idx].0.end;
+            self.prot_map.remove(idx);
+        } else if left {
+            self.prot_map[idx - 1].0.end = end;
+        } else if right {
+            self.prot_map[idx].0.start = start;
+        } else {
+            self.prot_map.insert(idx, (start..end, prot));
         }
+    }
 
-        let ridx = ridx.unwrap();
+    // iterate over the allocated ranges, split at page size boundaries
+    pub fn allocations(&self) -> impl Iterator<Item = AllocationInfo> + '_ {
+        self.prot_map.iter().flat_map(move |(r, prot)| {
+            let mut cur = r.start;
+            let end = r.end;
+            let prot = *prot;
 
-        let range_start = self.free_map[ridx].start;
+            iter::from_fn(move || {
+                if cur >= end {
+                    return None;
+                }
+
+                let (pagesz, pagesz_end) = self.get_addr_pagesz_range(cur);
+                let page = align_down(cur, pagesz);
+                let info = AllocationInfo {
+                    alloc_type: self.alloc_type,
+                    start: cur,
+                    end: pagesz_end.min(end),
+                    pagesz,
+                    prot,
+                    applied_prot: match self.alloc_type {
+                        AllocType::ANON => self.page_prot(page, page + pagesz),
+                        AllocType::BRK => RW,
+                        AllocType::FILE => prot,
+                    },
+                    locked: self.page_locked(page, page + pagesz),
+                };
+                cur = info.end;
+
+                Some(info)
+            })
+        })
+    }
 
-        for r in self.free_map.iter() {
-            println!("{:x} - {:x}", r.start, r.end);
+    // dump the bookkeeping state, relative to the region start
+    pub fn snapshot(&self) -> RegionSnapshot {
+        RegionSnapshot {
+            alloc_type: self.alloc_type,
+            name: self.name.clone(),
+            brk: self.brk - self.start,
+            high_water: self.high_water - self.start,
+            len: self.len,
+            free_map: self
+                .free_map
+                .iter()
+                .map(|x| (x.start - self.start)..(x.end - self.start))
+                .collect(),
+            prot_map: self
+                .prot_map
+                .iter()
+                .map(|(x, prot)| ((x.start - self.start)..(x.end - self.start), *prot))
+                .collect(),
         }
-        println!(
-            "del_range: start: {:x} range_start: {:x}",
-            start, range_start
-        );
+    }
 
-        // remove the range if it's wholly allocated
-        if self.free_map[ridx].len() == len {
-            self.free_map.remove(ridx);
-        } else if start == 0 || start == self.free_map[ridx].start {
-            self.free_map[ridx].start += len;
-        } else {
-            let new_range = (start + len)..self.free_map[ridx].end;
-            self.free_map[ridx].end = start;
-            self.free_map.insert(ridx + 1, new_range);
+    // restore the bookkeeping state from a snapshot of a region with the same layout
+    pub fn restore(&mut self, snapshot: &RegionSnapshot) {
+        assert_eq!(self.alloc_type, snapshot.alloc_type);
+        assert_eq!(self.len, snapshot.len, "region layout mismatch");
+
+        self.brk = self.start + snapshot.brk;
+        self.high_water = self.start + snapshot.high_water;
+
+        self.free_map.clear();
+        for x in snapshot.free_map.iter() {
+            assert!(
+                self.free_map
+                    .push((self.start + x.start)..(self.start + x.end)),
+                "free map hard cap too low for the snapshot"
+            );
         }
 
-        for r in self.free_map.iter() {
-            println!("{:x} - {:x}", r.start, r.end);
+        self.allocated = self.len - self.free_map.iter().map(|x| x.len()).sum::<usize>();
+        self.peak = self.allocated;
+
+        // every hugepage is full but for the free ranges on it
+        for (i, x) in self.pool.intervals.iter().enumerate() {
+            if x.pagesz > *PAGE_SIZE {
+                let base = self.fill_base[i];
+                let nr = (x.end - x.start) / x.pagesz;
+                self.fill[base..base + nr].fill(x.pagesz);
+            }
         }
-        println!(
-            "del_range: start: {:x} range_start: {:x}",
-            start, range_start
+        for j in 0..self.free_map.len() {
+            let r = self.free_map[j].clone();
+            self.update_fill(r.start, r.end, false);
+        }
+
+        self.prot_map.clear();
+        self.prot_map.extend(
+            snapshot
+                .prot_map
+                .iter()
+                .map(|(x, prot)| ((self.start + x.start)..(self.start + x.end), *prot)),
         );
-        if start == 0 {
-            range_start
-        } else {
-            start
+    }
+
+    // replace the backing of [addr, addr + len) with a new mapping with the given flags,
+    // preserving its contents, see remap
+    fn remap_backing(&mut self, addr: usize, len: usize, flags: i32) -> Result<(), i32> {
+        // the copy doesn't carry the allocation tags over
+        if self.page_prot(addr, addr + len) & PROT_MTE != 0 {
+            return Err(libc::EPERM);
+        }
+        // nor the pinned pages, their holder keeps the old ones
+        if self.held(addr, addr + len) {
+            self.pin_kept += 1;
+            return Err(libc::EBUSY);
+        }
+
+        remap::replace_backing(addr, len, flags)?;
+
+        // the new backing doesn't inherit the name and the protection key (nor the protection and
+        // the locking of the page, applied by the callers)
+        if flags & libc::MAP_HUGETLB == 0 {
+            self.name_backing(addr, len);
         }
+        self.apply_pkey(addr, len, libc::PROT_READ | libc::PROT_WRITE);
+
+        Ok(())
     }
 
-    fn add_range_to_freemap(&mut self, start: usize, len: usize) {
-        println!("{:x} {} {:?}", start, len, self.free_map);
-        let end = start + len;
+    // whether [addr, addr + len) is wholly mapped, msync fails with ENOMEM otherwise
+    fn is_mapped(addr: usize, len: usize) -> bool {
+        unsafe { libc::msync(addr as *mut libc::c_void, len, libc::MS_ASYNC) == 0 }
+    }
 
-        let mut left = false;
-        let mut right = false;
+    // unmap the fully free pool hugepages, they're mapped again when reallocated, returns the
+    // released bytes
+    pub fn trim(&mut self, dryrun: bool) -> usize {
+        let mut released = 0;
 
-        // just add the range in the free map if empty
-        if self.free_map.is_empty() {
-            self.free_map.push(start..end);
-            return;
+        for i in 0..self.pool.intervals.len() {
+            let pagesz = self.pool.intervals[i].pagesz;
+            if pagesz <= *PAGE_SIZE {
+                continue;
+            }
+
+            let lower = self.start + self.pool.intervals[i].start;
+            let upper = self.start + self.pool.intervals[i].end;
+
+            for j in 0..self.free_map.len() {
+                let start = align_up(self.free_map[j].start.max(lower), pagesz);
+                let end = align_down(self.free_map[j].end.min(upper), pagesz);
+
+                for addr in (start..end).step_by(pagesz) {
+                    if self.held(addr, addr + pagesz) {
+                        self.pin_kept += 1;
+                        continue;
+                    }
+                    if !Self::is_mapped(addr, pagesz)
+                        || preload_hooks::libc_munmap(addr as *mut libc::c_void, pagesz) != 0
+                    {
+                        continue;
+                    }
+
+                    // demoted hugepages were already unaccounted
+                    if let Some(idx) = self.demoted.iter().position(|&x| x == addr) {
+                        self.demoted.remove(idx);
+                    } else {
+                        if self.backing == PoolBacking::HUGETLB && !dryrun {
+                            self.account_htlb(pagesz, -1);
+                        }
+                        page_limits::release(pagesz, 1);
+                    }
+                    released += pagesz;
+                }
+            }
         }
 
-        // find where the range should go in the free map
-        let idx = self
-            .free_map
-            .iter()
-            .position(|x| x.start >= end)
-            .unwrap_or(self.free_map.len());
+        released
+    }
 
-        println!("idx: {} {:x} {:x}", idx, start, end);
+    // drop the backing of the whole pages in [addr, addr + len), they're zero-filled on the next
+    // touch
+    pub fn discard(&mut self, addr: usize, len: usize) -> i32 {
+        let end = addr + len;
+        let mut cur = addr;
+        let mut ret = 0;
 
-        // check if we can merge with a range to our left
-        if idx > 0 && self.free_map[idx - 1].end == start {
-            self.free_map[idx - 1].end = end;
-            left = true;
+        while cur < end {
+            let (pagesz, next) = self.get_addr_pagesz_range(cur);
+            let next = next.min(end);
+
+            // partially covered hugepages are kept intact, and so are the pinned pages
+            let start = align_up(cur, pagesz);
+            let stop = align_down(next, pagesz);
+            if start < stop && self.held(start, stop) {
+                self.pin_kept += 1;
+            } else if start < stop
+                && preload_hooks::libc_madvise(
+                    start as *mut libc::c_void,
+                    stop - start,
+                    libc::MADV_DONTNEED,
+                ) != 0
+            {
+                ret = -1;
+            }
+
+            cur = next;
         }
 
-        // check if we can merge with a range to our left
-        if idx < self.free_map.len() && self.free_map[idx].start == end {
-            self.free_map[idx].start = start;
-            right = true;
+        ret
+    }
+
+    // back the hugepage at addr with base pages
+    pub fn demote_page(&mut self, addr: usize, pagesz: usize) -> Result<(), i32> {
+        assert!(!self.demoted.contains(&addr));
+
+        // already backed by base pages
+        if self.backing == PoolBacking::BASE {
+            return Err(libc::EINVAL);
         }
 
-        // if we merged with both ends, merge those together
-        if left && right {
-            self.free_map[idx - 1].end = self.free_map[idx].end;
-            self.free_map.remove(idx);
+        // released by a trim
+        if !Self::is_mapped(addr, pagesz) {
+            return Err(libc::ENOMEM);
+        }
+
+        let ret = self.remap_backing(addr, pagesz, 0);
+        self.sync_pages(addr, addr + pagesz);
+        ret?;
+        self.demoted.push(addr);
+        self.account_htlb(pagesz, -1);
+        page_limits::release(pagesz, 1);
+
+        Ok(())
+    }
+
+    // back a previously demoted hugepage at addr with a hugepage again (kernel 5.16+)
+    pub fn promote_page(&mut self, addr: usize, pagesz: usize) -> Result<(), i32> {
+        // released by a trim and mapped as a hugepage again
+        let idx = self
+            .demoted
+            .iter()
+            .position(|&x| x == addr)
+            .ok_or(libc::EINVAL)?;
+
+        if !page_limits::reserve(pagesz, 1) {
+            return Err(libc::ENOMEM);
+        }
+        let ret = self.remap_backing(
+            addr,
+            pagesz,
+            libc::MAP_HUGETLB | (pagesz.trailing_zeros() as i32) << libc::MAP_HUGE_SHIFT,
+        );
+        self.sync_pages(addr, addr + pagesz);
+        if let Err(e) = ret {
+            page_limits::release(pagesz, 1);
+            return Err(e);
+        }
+        self.demoted.remove(idx);
+        self.account_htlb(pagesz, 1);
+
+        Ok(())
+    }
+
+    // queue the cold page at addr on the batch pushing it towards swap, unless it's backed by a
+    // hugetlb page
+    pub fn swap_page(
+        &mut self,
+        addr: usize,
+        pagesz: usize,
+        batch: &mut AdviceBatch,
+    ) -> Result<(), i32> {
+        if self.backing == PoolBacking::HUGETLB && !self.demoted.contains(&addr) {
+            return Err(libc::EINVAL);
         }
 
-        if !left && !right {
-            self.free_map.insert(idx, start..end);
+        if self.held(addr, addr + pagesz) {
+            self.pin_kept += 1;
+            return Err(libc::EBUSY);
         }
-        for r in self.free_map.iter() {
-            println!("{:x} - {:x}", r.start, r.end);
+
+        batch.add(addr, pagesz);
+        self.swapped += 1;
+        self.swapped_bytes += pagesz;
+
+        Ok(())
+    }
+
+    // the page size backing addr as planned: the one of its pool interval, unless it's mapped with
+    // base pages (dryrun, base backing, demoted hugepage or one that fell back to base pages)
+    pub fn page_size_of(&self, addr: usize, dryrun: bool) -> usize {
+        let addr = untagged(addr);
+        let (pagesz, _) = self.get_addr_pagesz_range(addr);
+
+        if dryrun || self.backing == PoolBacking::BASE || self.is_demoted(align_down(addr, pagesz))
+        {
+            *PAGE_SIZE
+        } else {
+            pagesz
         }
     }
 
+    #[inline]
+    pub fn is_demoted(&self, addr: usize) -> bool {
+        self.demoted.contains(&addr)
+    }
+
+    // absolute (start, end, page size) of the pool intervals
+    pub fn intervals(&self) -> impl Iterator<Item = (usize, usize, usize)> + '_ {
+        self.pool
+            .intervals
+            .iter()
+            .map(|x| (self.start + x.start, self.start + x.end, x.pagesz))
+    }
+
     #[inline]
     pub fn contains(&self, addr: usize) -> bool {
+        let addr = untagged(addr);
         addr >= self.start && addr < self.max
     }
 
     #[inline]
     pub fn lock(&mut self) {
         self.lock.lock();
+        metadata::unprotect();
     }
 
     #[inline]
     pub fn unlock(&mut self) {
+        metadata::protect();
         self.lock.unlock();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(x: &[Range<usize>]) -> MetaVec<Range<usize>> {
+        let mut v = Vec::new_in(MetaAlloc);
+        v.extend_from_slice(x);
+        v
+    }
+
+    #[test]
+    fn split_at_ranges_pieces() {
+        let foreign = [0x2000..0x4000, 0x6000..0x7000];
+
+        // outside, within, and partially overlapping one range or several
+        assert_eq!(
+            split_at_ranges(&foreign, 0x8000, 0x9000),
+            vec![(0x8000..0x9000, false)]
+        );
+        assert_eq!(
+            split_at_ranges(&foreign, 0x2000, 0x3000),
+            vec![(0x2000..0x3000, true)]
+        );
+        assert_eq!(
+            split_at_ranges(&foreign, 0x1000, 0x3000),
+            vec![(0x1000..0x2000, false), (0x2000..0x3000, true)]
+        );
+        assert_eq!(
+            split_at_ranges(&foreign, 0x3000, 0x6800),
+            vec![
+                (0x3000..0x4000, true),
+                (0x4000..0x6000, false),
+                (0x6000..0x6800, true)
+            ]
+        );
+        assert_eq!(
+            split_at_ranges(&foreign, 0x1000, 0x8000),
+            vec![
+                (0x1000..0x2000, false),
+                (0x2000..0x4000, true),
+                (0x4000..0x6000, false),
+                (0x6000..0x7000, true),
+                (0x7000..0x8000, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn remove_range_splits() {
+        let mut foreign = ranges(&[0x2000..0x6000, 0x8000..0x9000]);
+
+        remove_range(&mut foreign, &(0x3000..0x4000));
+        assert_eq!(
+            &foreign[..],
+            [0x2000..0x3000, 0x4000..0x6000, 0x8000..0x9000]
+        );
+
+        remove_range(&mut foreign, &(0x4000..0x6000));
+        remove_range(&mut foreign, &(0x8000..0x8800));
+        assert_eq!(&foreign[..], [0x2000..0x3000, 0x8800..0x9000]);
+    }
+}
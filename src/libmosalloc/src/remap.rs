@@ -0,0 +1,200 @@
+use std::cell::Cell;
+use std::hint;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use libc;
+
+use crate::preload_hooks;
+
+// the range whose backing is being replaced is write-protected during the copy, the writes to it
+// fault and wait for the new backing in the SIGSEGV handler, then retry; the remaps are
+// serialized, SEQ is odd while one is in progress and [START, END) is the range of the last one
+static LOCK: AtomicBool = AtomicBool::new(true);
+static SEQ: AtomicUsize = AtomicUsize::new(0);
+static START: AtomicUsize = AtomicUsize::new(0);
+static END: AtomicUsize = AtomicUsize::new(0);
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+static mut OLD_SEGV: Option<libc::sigaction> = None;
+
+// the fault of the calling thread already retried once the last remap completed, (addr, seq)
+thread_local! {
+    static RETRIED: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+}
+
+// pass a fault that isn't ours to the previous SIGSEGV handler, or crash with the default action
+pub fn forward(
+    old: Option<libc::sigaction>,
+    sig: i32,
+    info: *mut libc::siginfo_t,
+    ctx: *mut libc::c_void,
+) {
+    unsafe {
+        match old {
+            Some(old) if old.sa_sigaction != libc::SIG_DFL && old.sa_sigaction != libc::SIG_IGN => {
+                if old.sa_flags & libc::SA_SIGINFO != 0 {
+                    let f: extern "C" fn(i32, *mut libc::siginfo_t, *mut libc::c_void) =
+                        std::mem::transmute(old.sa_sigaction);
+                    f(sig, info, ctx);
+                } else {
+                    let f: extern "C" fn(i32) = std::mem::transmute(old.sa_sigaction);
+                    f(sig);
+                }
+            }
+            // an ignored fault re-executes forever, it gets the default action too
+            _ => {
+                libc::signal(sig, libc::SIG_DFL);
+                libc::raise(sig);
+            }
+        }
+    }
+}
+
+extern "C" fn segv_handler(sig: i32, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    unsafe {
+        let addr = (*info).si_addr() as usize;
+        let seq = SEQ.load(Ordering::Acquire);
+
+        if addr >= START.load(Ordering::Relaxed) && addr < END.load(Ordering::Relaxed) {
+            if seq & 1 == 1 {
+                while SEQ.load(Ordering::Acquire) == seq {
+                    hint::spin_loop();
+                }
+                return;
+            }
+            // the remap may have completed after the fault, retry once
+            if RETRIED.with(|x| x.replace((addr, seq))) != (addr, seq) {
+                return;
+            }
+        }
+
+        forward(*ptr::addr_of!(OLD_SEGV), sig, info, ctx);
+    }
+}
+
+fn install() {
+    if INSTALLED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = segv_handler as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO;
+        let mut old: libc::sigaction = std::mem::zeroed();
+        if libc::sigaction(libc::SIGSEGV, &action, &mut old) == 0 {
+            *ptr::addr_of_mut!(OLD_SEGV) = Some(old);
+        }
+    }
+}
+
+// replace the backing of [addr, addr + len) with a new RW mapping with the given flags, preserving
+// its contents; the concurrent writes wait for the new backing, but the ones by the kernel
+// (read(2) into the range ...) fail with EFAULT meanwhile
+pub fn replace_backing(addr: usize, len: usize, flags: i32) -> Result<(), i32> {
+    let prot = libc::PROT_READ | libc::PROT_WRITE;
+    let flags = flags | libc::MAP_ANONYMOUS | libc::MAP_PRIVATE;
+
+    let tmp = preload_hooks::libc_mmap(ptr::null_mut(), len, prot, flags, -1, 0);
+    if tmp == libc::MAP_FAILED {
+        return Err(unsafe { *libc::__errno_location() });
+    }
+
+    install();
+    while LOCK
+        .compare_exchange(true, false, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        hint::spin_loop();
+    }
+    START.store(addr, Ordering::Relaxed);
+    END.store(addr + len, Ordering::Relaxed);
+    SEQ.fetch_add(1, Ordering::Release);
+
+    // the allocations on the page may not be readable either
+    let ret = if preload_hooks::libc_mprotect(addr as *mut libc::c_void, len, libc::PROT_READ) != 0
+    {
+        Err(unsafe { *libc::__errno_location() })
+    } else {
+        unsafe {
+            ptr::copy_nonoverlapping(addr as *const u8, tmp as *mut u8, len);
+        }
+
+        let ret = preload_hooks::libc_mremap(
+            tmp,
+            len,
+            len,
+            libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED,
+            addr as *mut libc::c_void,
+        );
+        if ret == libc::MAP_FAILED {
+            let err = unsafe { *libc::__errno_location() };
+            preload_hooks::libc_mprotect(addr as *mut libc::c_void, len, prot);
+            Err(err)
+        } else {
+            Ok(())
+        }
+    };
+
+    SEQ.fetch_add(1, Ordering::Release);
+    LOCK.store(true, Ordering::Release);
+
+    if ret.is_err() {
+        preload_hooks::libc_munmap(tmp, len);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn replace_backing_keeps_concurrent_writes() {
+        const LEN: usize = 4 << 20;
+        const SLOTS: usize = LEN / 8;
+
+        let addr = preload_hooks::libc_mmap(
+            ptr::null_mut(),
+            LEN,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+            -1,
+            0,
+        );
+        assert_ne!(addr, libc::MAP_FAILED);
+        let addr = addr as usize;
+
+        // the writer stores increasing values over every slot, the last one must survive
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let slots = addr as *mut u64;
+                let mut n = 0;
+                while !stop.load(Ordering::Relaxed) {
+                    n += 1;
+                    for i in (0..SLOTS).step_by(61) {
+                        unsafe { ptr::write_volatile(slots.add(i), n) };
+                    }
+                }
+                n
+            })
+        };
+
+        for _ in 0..16 {
+            replace_backing(addr, LEN, 0).unwrap();
+        }
+        stop.store(true, Ordering::Relaxed);
+        let n = writer.join().unwrap();
+
+        let slots = addr as *const u64;
+        for i in (0..SLOTS).step_by(61) {
+            assert_eq!(unsafe { ptr::read_volatile(slots.add(i)) }, n);
+        }
+        preload_hooks::libc_munmap(addr as *mut libc::c_void, LEN);
+    }
+}
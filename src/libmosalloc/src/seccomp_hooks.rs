@@ -1,142 +1,340 @@
 use epoll;
 use libseccomp::notify::*;
 use libseccomp::*;
+use std::fs::File;
+use std::mem::size_of;
+use std::os::unix::fs::FileExt;
+use std::ptr::addr_of_mut;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::sync_channel;
-use std::thread;
-use syscalls::Sysno;
 
 use crate::allocator::Allocator;
 use crate::internal_allocator::InternalAllocator;
+use crate::io_uring;
+use crate::preload_hooks::{self, preload_alloc};
+use crate::service;
+use crate::threads;
 
 use mosalloc::utils::htlb::MosallocConfig;
+use mosalloc::utils::trace::TraceOp;
 
-const SYSCALLS: [&'static str; 6] = ["brk", "mmap", "munmap", "mprotect", "madvise", "mremap"];
+// hooked syscalls, not all of them exist on every arch (e.g. mmap2 / old_mmap are 32-bit only)
+const SYSCALLS: [&'static str; 9] = [
+    "brk",
+    "mmap",
+    "mmap2",
+    "old_mmap",
+    "munmap",
+    "mprotect",
+    "madvise",
+    "mremap",
+    "io_uring_register",
+];
+
+// mmap2 offsets are in 4KB units, regardless of the base page size
+const MMAP2_SHIFT: u32 = 12;
+
+// (name, nr) of the hooked syscalls available on the native arch, out of the configured ones
+fn native_syscalls(hooks: &[TraceOp]) -> Vec<(&'static str, i32)> {
+    SYSCALLS
+        .iter()
+        .filter(|&&name| {
+            let op = match name {
+                "mmap2" | "old_mmap" => TraceOp::MMAP,
+                // the fixed buffer registrations are always tracked
+                "io_uring_register" => return true,
+                _ => name.parse::<TraceOp>().unwrap(),
+            };
+            hooks.contains(&op)
+        })
+        .filter_map(|&name| {
+            ScmpSyscall::from_name(name)
+                .ok()
+                .map(|sc| (name, sc.to_syscall_nr()))
+        })
+        // libseccomp resolves syscalls missing from the arch to negative pseudo-numbers
+        .filter(|&(_, nr)| nr >= 0)
+        .collect()
+}
+
+// old_mmap passes a pointer to the arguments struct, made of native words
+fn old_mmap_args(req: &ScmpNotifReq) -> [u64; 6] {
+    let mem = File::open(format!("/proc/{}/mem", req.pid)).unwrap();
+    let mut buf = [0u8; 6 * size_of::<usize>()];
+    mem.read_exact_at(&mut buf, req.data.args[0]).unwrap();
+
+    let mut out = [0u64; 6];
+    for (i, x) in buf.chunks_exact(size_of::<usize>()).enumerate() {
+        out[i] = usize::from_ne_bytes(x.try_into().unwrap()) as u64;
+    }
+    out
+}
+
+// read the memory of the notifying thread at addr into buf
+fn read_mem(req: &ScmpNotifReq, addr: usize, buf: &mut [u8]) -> bool {
+    File::open(format!("/proc/{}/mem", req.pid))
+        .and_then(|mem| mem.read_exact_at(buf, addr as u64))
+        .is_ok()
+}
+
+// the (addr, len, prot, flags, fd, offset) arguments of the mmap variants
+fn mmap_args(name: &str, req: &ScmpNotifReq) -> [u64; 6] {
+    let args = req.data.args;
+
+    match name {
+        "mmap2" => [
+            args[0],
+            args[1],
+            args[2],
+            args[3],
+            args[4],
+            args[5] << MMAP2_SHIFT,
+        ],
+        "old_mmap" => old_mmap_args(req),
+        // on i386 the plain mmap syscall is old_mmap
+        "mmap" if cfg!(target_arch = "x86") => old_mmap_args(req),
+        _ => args,
+    }
+}
 
 // mosalloc allocator instance when seccomp hooks are used
 static mut SECCOMP_MOSALLOC: Option<Allocator> = None;
 
+// raw syscalls caught by the hybrid hooks' filter, per hooked syscall
+static CAUGHT: [AtomicUsize; SYSCALLS.len()] = [const { AtomicUsize::new(0) }; SYSCALLS.len()];
+
+// the seccomp allocator instance, if initialized
+pub unsafe fn seccomp_alloc() -> Option<&'static mut Allocator> {
+    (*addr_of_mut!(SECCOMP_MOSALLOC)).as_mut()
+}
+
+// the errno of a failed call, negated as the notification response expects it
+unsafe fn neg_errno() -> i32 {
+    -*libc::__errno_location()
+}
+
+// track the fixed buffers of a notified io_uring_register and let the thread make it, its ring
+// may only take registrations from its submitter; the buffers of a failed registration stay
+// pinned until its ring unregisters them
+fn respond_io_uring(mosalloc: &mut Allocator, req: &ScmpNotifReq) -> ScmpNotifResp {
+    let args = req.data.args;
+    let reg = io_uring::parse(
+        args[1] as u32,
+        args[2] as usize,
+        args[3] as u32,
+        |addr, buf| read_mem(req, addr, buf),
+    );
+    mosalloc.io_uring_register(args[0] as i32, reg);
+
+    ScmpNotifResp::new(req.id, 0, 0, NOTIF_FLAG_CONTINUE)
+}
+
+// the mmaps of the threads left out by the thread filter go straight to the kernel
+fn forwarded(name: &str, req: &ScmpNotifReq) -> bool {
+    matches!(name, "mmap" | "mmap2" | "old_mmap") && !threads::selected_tid(req.pid as i32)
+}
+
+// run a notified syscall through the allocator, returns its (return value, -errno)
+unsafe fn handle(mosalloc: &mut Allocator, name: &str, req: &ScmpNotifReq) -> (i64, i32) {
+    let ret;
+    let err;
+
+    match name {
+        // the raw brk never fails with an errno, it returns the new break, or the current one if
+        // it can't be moved (e.g. brk(0), which queries it)
+        "brk" => {
+            let addr = req.data.args[0] as usize;
+            ret = match mosalloc.do_brk(Some(addr), None) {
+                usize::MAX => mosalloc.current_brk(),
+                _ => addr,
+            } as i64;
+            err = 0;
+        }
+        "mmap" | "mmap2" | "old_mmap" => {
+            let args = mmap_args(name, req);
+            ret = mosalloc.mmap(
+                args[0] as usize,
+                args[1] as usize,
+                args[2] as i32,
+                args[3] as i32,
+                args[4] as i32,
+                args[5] as i64,
+            ) as isize as i64;
+            err = if ret != libc::MAP_FAILED as isize as i64 {
+                0
+            } else {
+                neg_errno()
+            };
+        }
+        "munmap" => {
+            ret = mosalloc.munmap(req.data.args[0] as usize, req.data.args[1] as usize) as i64;
+            err = if ret == 0 as i64 { 0 } else { neg_errno() };
+        }
+        "mprotect" => {
+            ret = mosalloc.mprotect(
+                req.data.args[0] as usize,
+                req.data.args[1] as usize,
+                req.data.args[2] as i32,
+            ) as i64;
+            err = if ret == 0 as i64 { 0 } else { neg_errno() };
+        }
+        "madvise" => {
+            ret = mosalloc.madvise(
+                req.data.args[0] as usize,
+                req.data.args[1] as usize,
+                req.data.args[2] as i32,
+            ) as i64;
+            err = if ret == 0 as i64 { 0 } else { neg_errno() };
+        }
+        "mremap" => {
+            ret = mosalloc.mremap(
+                req.data.args[0] as usize,
+                req.data.args[1] as usize,
+                req.data.args[2] as usize,
+                req.data.args[3] as i32,
+                req.data.args[4] as usize,
+            ) as isize as i64;
+            err = if ret != libc::MAP_FAILED as isize as i64 {
+                0
+            } else {
+                neg_errno()
+            };
+        }
+        _ => {
+            panic!();
+        }
+    }
+
+    (ret, err)
+}
+
+// respond to the notifications on fd forever, `respond` gets the name of the hooked syscall
+unsafe fn serve(
+    fd: i32,
+    handled: &[(&'static str, i32)],
+    mut respond: impl FnMut(&'static str, &ScmpNotifReq) -> ScmpNotifResp,
+) {
+    let pfd = epoll::create(false).unwrap();
+
+    let event = epoll::Event::new(epoll::Events::EPOLLIN, 0);
+    epoll::ctl(pfd, epoll::ControlOptions::EPOLL_CTL_ADD, fd, event).unwrap();
+
+    loop {
+        epoll::wait(pfd, -1, &mut [event]).unwrap();
+        let req = ScmpNotifReq::receive(fd).unwrap();
+        println!("got syscall {}", req.data.syscall);
+
+        let name = handled
+            .iter()
+            .find(|(_, nr)| *nr == req.data.syscall)
+            .map(|(name, _)| *name)
+            .unwrap();
+
+        let resp = respond(name, &req);
+        println!("ret: {:x}, err: {}", resp.val, resp.error);
+        resp.respond(fd).unwrap();
+    }
+}
+
+// load a filter notifying the hooked syscalls of the calling thread and the ones it creates,
+// returns its notify fd
+fn load_filter(syscalls: &[(&'static str, i32)]) -> i32 {
+    let mut filter = ScmpFilterContext::new_filter(ScmpAction::Allow).unwrap();
+
+    // new filters only match the native arch
+    for (_, nr) in syscalls.iter() {
+        // FIXME: add finer grained control for e.g. mmap ranges or fds
+        filter.add_rule(ScmpAction::Notify, *nr).unwrap();
+    }
+
+    filter.load().unwrap();
+    filter.get_notify_fd().unwrap()
+}
+
 pub unsafe fn seccomp_init(config: MosallocConfig) {
     let (fd_tx, fd_rx) = sync_channel::<i32>(0);
     let (stx, srx) = sync_channel::<bool>(0);
 
-    thread::spawn(move || {
+    let syscalls = native_syscalls(&config.hooks);
+    let handled = syscalls.clone();
+    threads::set(&config.threads);
+
+    service::spawn("seccomp", move || {
         let fd = fd_rx.recv().unwrap();
 
         SECCOMP_MOSALLOC = Some(Allocator::new(config, true));
         let mosalloc = SECCOMP_MOSALLOC.as_mut().unwrap();
+        mosalloc.spawn_services();
         stx.send(true).unwrap();
 
-        let pfd = epoll::create(false).unwrap();
-
-        let event = epoll::Event::new(epoll::Events::EPOLLIN, 0);
-        epoll::ctl(pfd, epoll::ControlOptions::EPOLL_CTL_ADD, fd, event).unwrap();
-
-        let mut err;
-        let mut ret;
-
-        loop {
-            epoll::wait(pfd, -1, &mut [event]).unwrap();
-            let req = ScmpNotifReq::receive(fd).unwrap();
-            println!("got syscall {}", req.data.syscall);
-
-            match req.data.syscall {
-                brk if brk == Sysno::brk as i32 => {
-                    let oldbrk = mosalloc.do_brk(Some(req.data.args[0] as usize), None);
-                    ret = if oldbrk != usize::MAX {
-                        req.data.args[0] as i64
-                    } else {
-                        oldbrk as i64
-                    };
-                    err = 0;
-                }
-                mmap if mmap == Sysno::mmap as i32 => {
-                    ret = mosalloc.mmap(
-                        req.data.args[0] as usize,
-                        req.data.args[1] as usize,
-                        req.data.args[2] as i32,
-                        req.data.args[3] as i32,
-                        req.data.args[4] as i32,
-                        req.data.args[5] as i64,
-                    ) as i64;
-                    err = if ret != libc::MAP_FAILED as i64 {
-                        0
-                    } else {
-                        *libc::__errno_location()
-                    };
-                }
-                munmap if munmap == Sysno::munmap as i32 => {
-                    ret = mosalloc.munmap(req.data.args[0] as usize, req.data.args[1] as usize)
-                        as i64;
-                    err = if ret == 0 as i64 {
-                        0
-                    } else {
-                        *libc::__errno_location()
-                    };
-                }
-                mprotect if mprotect == Sysno::mprotect as i32 => {
-                    ret = mosalloc.mprotect(
-                        req.data.args[0] as usize,
-                        req.data.args[1] as usize,
-                        req.data.args[2] as i32,
-                    ) as i64;
-                    err = if ret == 0 as i64 {
-                        0
-                    } else {
-                        *libc::__errno_location()
-                    };
-                }
-                madvise if madvise == Sysno::madvise as i32 => {
-                    ret = mosalloc.madvise(
-                        req.data.args[0] as usize,
-                        req.data.args[1] as usize,
-                        req.data.args[2] as i32,
-                    ) as i64;
-                    err = if ret == 0 as i64 {
-                        0
-                    } else {
-                        *libc::__errno_location()
-                    };
-                }
-                mremap if mremap == Sysno::mremap as i32 => {
-                    ret = mosalloc.mremap(
-                        req.data.args[0] as usize,
-                        req.data.args[1] as usize,
-                        req.data.args[2] as usize,
-                        req.data.args[3] as i32,
-                        req.data.args[4] as usize,
-                    ) as i64;
-                    err = if ret != libc::MAP_FAILED as i64 {
-                        0
-                    } else {
-                        *libc::__errno_location()
-                    };
-                }
-                _ => {
-                    panic!();
-                }
+        serve(fd, &handled, |name, req| {
+            if forwarded(name, req) {
+                return ScmpNotifResp::new(req.id, 0, 0, NOTIF_FLAG_CONTINUE);
+            }
+            if name == "io_uring_register" {
+                return respond_io_uring(mosalloc, req);
             }
 
-            println!("ret: {:x}, err: {}", ret, err);
-            let resp = ScmpNotifResp::new(req.id, ret, err, 0);
-            resp.respond(fd).unwrap();
-        }
+            let (ret, err) = handle(mosalloc, name, req);
+            ScmpNotifResp::new(req.id, ret, err, 0)
+        });
     });
 
-    let mut filter = ScmpFilterContext::new_filter(ScmpAction::Allow).unwrap();
-
-    filter.add_arch(ScmpArch::X8664).unwrap();
-
-    for sc in SYSCALLS.iter() {
-        // FIXME: add finer grained control for e.g. mmap ranges or fds
-        filter
-            .add_rule(ScmpAction::Notify, ScmpSyscall::from_name(sc).unwrap())
-            .unwrap();
-    }
-
-    filter.load().unwrap();
-    fd_tx.send(filter.get_notify_fd().unwrap()).unwrap();
+    fd_tx.send(load_filter(&syscalls)).unwrap();
     srx.recv().unwrap();
 
     // FIXME: do we need to drain?
     InternalAllocator::print_stats();
 }
+
+// the seccomp filter of the hybrid hooks, once the preload allocator is up: the calls through the
+// PLT are handled by the preload hooks, the filter only catches the raw syscalls bypassing them
+// (inline syscalls, glibc internals) and routes them to the same allocator, while the ones of the
+// threads inside mosalloc are let through
+pub unsafe fn hybrid_init(hooks: &[TraceOp]) {
+    let (fd_tx, fd_rx) = sync_channel::<i32>(0);
+
+    let syscalls = native_syscalls(hooks);
+    let handled = syscalls.clone();
+
+    // spawned before the filter is loaded, so that its own syscalls aren't caught
+    service::spawn("hybrid", move || {
+        let fd = fd_rx.recv().unwrap();
+        let mosalloc = preload_alloc().unwrap();
+
+        serve(fd, &handled, |name, req| {
+            if preload_hooks::is_inside(req.pid as i32) || forwarded(name, req) {
+                return ScmpNotifResp::new(req.id, 0, 0, NOTIF_FLAG_CONTINUE);
+            }
+
+            if let Some(idx) = SYSCALLS.iter().position(|x| *x == name) {
+                CAUGHT[idx].fetch_add(1, Ordering::Relaxed);
+            }
+            if name == "io_uring_register" {
+                return respond_io_uring(mosalloc, req);
+            }
+
+            let (ret, err) = handle(mosalloc, name, req);
+            ScmpNotifResp::new(req.id, ret, err, 0)
+        });
+    });
+
+    fd_tx.send(load_filter(&syscalls)).unwrap();
+}
+
+pub fn print_caught_stats() {
+    let caught = SYSCALLS
+        .iter()
+        .zip(CAUGHT.iter())
+        .map(|(name, nr)| (name, nr.load(Ordering::Relaxed)))
+        .filter(|(_, nr)| *nr > 0)
+        .map(|(name, nr)| format!("{}: {}", name, nr))
+        .collect::<Vec<String>>();
+
+    if !caught.is_empty() {
+        println!(
+            "raw syscalls caught past the preload hooks: {}",
+            caught.join(", ")
+        );
+    }
+}
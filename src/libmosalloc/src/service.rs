@@ -0,0 +1,57 @@
+use std::cell::Cell;
+use std::mem;
+use std::sync::Mutex;
+use std::thread;
+
+// CPUs the service threads are pinned to, unpinned if empty
+static HOUSEKEEPING: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+thread_local! {
+    static IS_SERVICE: Cell<bool> = const { Cell::new(false) };
+}
+
+pub fn set_housekeeping(cpus: &[usize]) {
+    *HOUSEKEEPING.lock().unwrap() = cpus.to_vec();
+}
+
+// whether the calling thread is a mosalloc service thread, whose own memory operations are kept
+// out of the pools and the stats
+pub fn is_service() -> bool {
+    IS_SERVICE.get()
+}
+
+// pin the calling thread to cpus
+fn pin(cpus: &[usize]) -> Result<(), i32> {
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    for &cpu in cpus {
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+
+    match unsafe { libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) } {
+        0 => Ok(()),
+        _ => Err(unsafe { *libc::__errno_location() }),
+    }
+}
+
+// spawn a mosalloc service thread named mos-<name> (shown by top, ps -T ...), pinned to the
+// housekeeping CPUs if any
+pub fn spawn(name: &str, f: impl FnOnce() + Send + 'static) {
+    let cpus = HOUSEKEEPING.lock().unwrap().clone();
+    let name = format!("mos-{}", name);
+
+    thread::Builder::new()
+        .name(name.clone())
+        .spawn(move || {
+            IS_SERVICE.set(true);
+            if !cpus.is_empty() {
+                if let Err(errno) = pin(&cpus) {
+                    println!(
+                        "{}: failed to pin to CPUs {:?}: errno {}",
+                        name, cpus, errno
+                    );
+                }
+            }
+            f()
+        })
+        .unwrap();
+}
@@ -0,0 +1,69 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::ops::Range;
+
+// sum the given smaps fields in bytes over the VMAs, into the slot picked by `slot` from their
+// (start, end, name), the VMAs it returns None for are skipped
+fn scan(
+    fields: &[&str],
+    nr_slots: usize,
+    slot: impl Fn(usize, usize, &str) -> Option<usize>,
+) -> Vec<Vec<usize>> {
+    let smaps = BufReader::new(File::open("/proc/self/smaps").unwrap());
+
+    let mut current = None;
+    let mut totals = vec![vec![0; fields.len()]; nr_slots];
+
+    for line in smaps.lines() {
+        let line = line.unwrap();
+        let mut words = line.split_whitespace();
+        let key = words.next().unwrap_or("");
+
+        // VMA header lines start with the address range, e.g. '7f0000000000-7f0000200000'
+        if let Some((vma_start, vma_end)) = key.split_once('-') {
+            if let (Ok(vma_start), Ok(vma_end)) = (
+                usize::from_str_radix(vma_start, 16),
+                usize::from_str_radix(vma_end, 16),
+            ) {
+                // perms, offset, dev and inode come before the name
+                let name = words.nth(4).unwrap_or("");
+                current = slot(vma_start, vma_end, name);
+                continue;
+            }
+        }
+
+        if let Some(i) = current {
+            if let Some(j) = key
+                .strip_suffix(':')
+                .and_then(|x| fields.iter().position(|y| *y == x))
+            {
+                totals[i][j] += words.next().unwrap().parse::<usize>().unwrap() << 10;
+            }
+        }
+    }
+
+    totals
+}
+
+// sum the given smaps field (e.g. AnonHugePages) in bytes over the VMAs overlapping [start, end)
+pub fn smaps_field(start: usize, end: usize, field: &str) -> usize {
+    scan(&[field], 1, |vma_start, vma_end, _| {
+        (vma_start < end && vma_end > start).then_some(0)
+    })[0][0]
+}
+
+// sum the given smaps field in bytes over the VMAs with the given name, e.g.
+// [anon:mosalloc:internal]
+pub fn smaps_named_field(name: &str, field: &str) -> usize {
+    scan(&[field], 1, |_, _, x| (x == name).then_some(0))[0][0]
+}
+
+// sum the given smaps fields in bytes over the VMAs overlapping each of the (disjoint) ranges, in
+// a single pass
+pub fn smaps_ranges(ranges: &[Range<usize>], fields: &[&str]) -> Vec<Vec<usize>> {
+    scan(fields, ranges.len(), |vma_start, vma_end, _| {
+        ranges
+            .iter()
+            .position(|x| vma_start < x.end && vma_end > x.start)
+    })
+}
@@ -0,0 +1,125 @@
+use std::ffi::CStr;
+use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+use libc::c_char;
+
+// the tids registered at once, 0 marks a free slot
+const MAX_TIDS: usize = 1024;
+
+// the threads whose new mappings are handled, the rest are forwarded to libc: the ones named in
+// the config (their comm, as set by pthread_setname_np) and the tids registered, all threads
+// until either is set
+static FILTER: AtomicBool = AtomicBool::new(false);
+static NAMES: OnceLock<Vec<String>> = OnceLock::new();
+static TIDS: [AtomicI32; MAX_TIDS] = [const { AtomicI32::new(0) }; MAX_TIDS];
+
+// mmaps of the other threads forwarded to libc
+static FORWARDED: AtomicUsize = AtomicUsize::new(0);
+
+// the threads of the config, names or tids
+pub fn set(threads: &[String]) {
+    let (tids, names): (Vec<&String>, Vec<&String>) =
+        threads.iter().partition(|x| x.parse::<i32>().is_ok());
+
+    for tid in tids {
+        if !register(tid.parse::<i32>().unwrap()) {
+            println!("threads: can't register {}, too many threads", tid);
+        }
+    }
+    if !names.is_empty() {
+        NAMES.set(names.into_iter().cloned().collect()).unwrap();
+        FILTER.store(true, Ordering::Relaxed);
+    }
+}
+
+// handle the new mappings of the thread tid (the caller's if 0) from now on
+pub fn register(tid: i32) -> bool {
+    let tid = match tid {
+        0 => unsafe { libc::gettid() },
+        _ => tid,
+    };
+    FILTER.store(true, Ordering::Relaxed);
+
+    if TIDS.iter().any(|x| x.load(Ordering::Acquire) == tid) {
+        return true;
+    }
+    TIDS.iter().any(|x| {
+        x.compare_exchange(0, tid, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    })
+}
+
+// forward the new mappings of the thread tid (the caller's if 0) to libc again
+pub fn unregister(tid: i32) -> bool {
+    let tid = match tid {
+        0 => unsafe { libc::gettid() },
+        _ => tid,
+    };
+
+    TIDS.iter().any(|x| {
+        x.compare_exchange(tid, 0, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    })
+}
+
+fn matches(tid: i32, name: Option<&str>) -> bool {
+    let selected = TIDS.iter().any(|x| x.load(Ordering::Acquire) == tid)
+        || name.is_some_and(|name| NAMES.get().is_some_and(|x| x.iter().any(|x| x == name)));
+    if !selected {
+        FORWARDED.fetch_add(1, Ordering::Relaxed);
+    }
+    selected
+}
+
+// whether the new mappings of the calling thread are handled, its name is read with prctl, which
+// doesn't allocate
+pub fn selected() -> bool {
+    if !FILTER.load(Ordering::Relaxed) {
+        return true;
+    }
+
+    let mut buf = [0 as c_char; 16];
+    let name = (NAMES.get().is_some()
+        && unsafe { libc::prctl(libc::PR_GET_NAME, buf.as_mut_ptr()) } == 0)
+        .then(|| unsafe { CStr::from_ptr(buf.as_ptr()) }.to_str().ok())
+        .flatten();
+    matches(unsafe { libc::gettid() }, name)
+}
+
+// whether the new mappings of the thread tid of this process are handled, for the seccomp
+// supervisor
+pub fn selected_tid(tid: i32) -> bool {
+    if !FILTER.load(Ordering::Relaxed) {
+        return true;
+    }
+
+    let name = NAMES
+        .get()
+        .and_then(|_| fs::read_to_string(format!("/proc/self/task/{}/comm", tid)).ok());
+    matches(tid, name.as_deref().map(|x| x.trim_end()))
+}
+
+pub fn print_stats() {
+    if !FILTER.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let tids = TIDS
+        .iter()
+        .map(|x| x.load(Ordering::Relaxed))
+        .filter(|x| *x != 0)
+        .map(|x| x.to_string())
+        .chain(NAMES.get().into_iter().flatten().cloned())
+        .collect::<Vec<String>>();
+    println!(
+        "threads: {} mmaps from threads other than {} forwarded",
+        FORWARDED.load(Ordering::Relaxed),
+        if tids.is_empty() {
+            "none".to_string()
+        } else {
+            tids.join(", ")
+        }
+    );
+}
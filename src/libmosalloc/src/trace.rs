@@ -0,0 +1,160 @@
+use std::io::Write;
+use std::mem::size_of;
+use std::ptr::addr_of_mut;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mosalloc::utils::trace::{as_bytes, TraceHeader, TraceOp, TraceRecord};
+
+use crate::internal_maps;
+use crate::rawio::RawFile;
+use crate::service;
+
+// flusher state, only touched off the syscall path
+#[derive(Debug)]
+struct Flusher {
+    out: RawFile,
+    // sequence number of the next record to drain
+    tail: u64,
+    records: Vec<TraceRecord>,
+}
+
+// fixed-size memory-mapped trace ring, once full the oldest records are overwritten
+#[derive(Debug)]
+pub struct TraceRing {
+    buf: *mut TraceRecord,
+    size: usize,
+    start: Instant,
+    // number of records ever written, the current epoch is head / size
+    head: AtomicU64,
+    // records overwritten before the flusher could drain them
+    lost: AtomicU64,
+    flusher: Mutex<Flusher>,
+}
+
+unsafe impl Send for TraceRing {}
+unsafe impl Sync for TraceRing {}
+
+impl TraceRing {
+    pub fn new(path: &str, size: usize) -> Self {
+        assert!(size > 0);
+
+        let buf = internal_maps::map(size * size_of::<TraceRecord>(), 0);
+        assert!(buf != libc::MAP_FAILED);
+
+        let mut out = RawFile::create(path).unwrap();
+        out.write_all(as_bytes(&[TraceHeader::new(size)])).unwrap();
+
+        Self {
+            buf: buf as *mut TraceRecord,
+            size,
+            start: Instant::now(),
+            head: AtomicU64::new(0),
+            lost: AtomicU64::new(0),
+            flusher: Mutex::new(Flusher {
+                out,
+                tail: 0,
+                records: Vec::with_capacity(size),
+            }),
+        }
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.head.load(Ordering::Relaxed) / self.size as u64
+    }
+
+    #[inline]
+    unsafe fn slot(&self, seq: u64) -> *mut TraceRecord {
+        self.buf.add((seq % self.size as u64) as usize)
+    }
+
+    #[inline]
+    unsafe fn slot_seq(&self, seq: u64) -> &AtomicU64 {
+        AtomicU64::from_ptr(addr_of_mut!((*self.slot(seq)).seq))
+    }
+
+    // append a record, lock-free so it can be called from the hooks
+    pub fn record(
+        &self,
+        op: TraceOp,
+        addr: usize,
+        len: usize,
+        arg: usize,
+        arg2: usize,
+        ret: usize,
+    ) {
+        let seq = self.head.fetch_add(1, Ordering::Relaxed);
+
+        unsafe {
+            let slot = self.slot(seq);
+            // a zero sequence number marks the slot as being written
+            self.slot_seq(seq).store(0, Ordering::Release);
+            (*slot).time = self.start.elapsed().as_nanos() as u64;
+            (*slot).op = op as u32;
+            (*slot).tid = libc::gettid() as u32;
+            (*slot).addr = addr as u64;
+            (*slot).len = len as u64;
+            (*slot).arg = arg as u64;
+            (*slot).arg2 = arg2 as u64;
+            (*slot).ret = ret as u64;
+            self.slot_seq(seq).store(seq + 1, Ordering::Release);
+        }
+    }
+
+    // drain the committed records to disk, stops at the first record still being written
+    pub fn flush(&self) {
+        let mut flusher = self.flusher.lock().unwrap();
+        let head = self.head.load(Ordering::Acquire);
+
+        // the writers lapped us, skip the overwritten records
+        if head - flusher.tail > self.size as u64 {
+            let lost = head - flusher.tail - self.size as u64;
+            self.lost.fetch_add(lost, Ordering::Relaxed);
+            flusher.tail += lost;
+        }
+
+        flusher.records.clear();
+        while flusher.tail < head {
+            let seq = flusher.tail;
+            let committed = unsafe { self.slot_seq(seq).load(Ordering::Acquire) };
+
+            if committed < seq + 1 {
+                // still being written, retry on the next flush
+                break;
+            }
+
+            let record = unsafe { self.slot(seq).read_volatile() };
+            let still = unsafe { self.slot_seq(seq).load(Ordering::Acquire) };
+            if committed == seq + 1 && still == seq + 1 {
+                flusher.records.push(record);
+            } else {
+                // overwritten while we were reading it
+                self.lost.fetch_add(1, Ordering::Relaxed);
+            }
+            flusher.tail += 1;
+        }
+
+        let Flusher { out, records, .. } = &mut *flusher;
+        out.write_all(as_bytes(records)).unwrap();
+        out.flush().unwrap();
+    }
+
+    pub fn print_stats(&self) {
+        println!(
+            "trace: {} records, epoch {}, {} lost",
+            self.head.load(Ordering::Relaxed),
+            self.epoch(),
+            self.lost.load(Ordering::Relaxed)
+        );
+    }
+}
+
+// spawn a thread draining the trace ring every `period` ms
+pub fn spawn(ring: Arc<TraceRing>, period: u64) {
+    service::spawn("trace", move || loop {
+        thread::sleep(Duration::from_millis(period));
+        ring.flush();
+    });
+}
@@ -0,0 +1,154 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mosalloc::utils::control::RegionStats;
+use mosalloc::utils::htlb::WindowTrigger;
+use mosalloc::utils::misc::size_to_str;
+
+use crate::init::mosalloc;
+use crate::service;
+
+#[derive(Debug)]
+enum WindowState {
+    PENDING,
+    // when the window opened, and the region stats it opened with
+    OPEN(Instant, Vec<RegionStats>),
+    // how long the window was open, and the region stats over it
+    CLOSED(Duration, Vec<RegionStats>),
+}
+
+// window of interest the stats and the trace are collected in, opened and closed by its triggers
+#[derive(Debug)]
+pub struct Window {
+    start: Option<WindowTrigger>,
+    stop: Option<WindowTrigger>,
+    period: u64,
+    // checked on the syscall path, to gate the trace
+    active: AtomicBool,
+    state: Mutex<WindowState>,
+}
+
+// stats accumulated between the `from` and `to` snapshots, the cumulative counters as deltas and
+// the rest as of `to`, the regions missing in `from` count from zero
+fn delta(from: &[RegionStats], to: Vec<RegionStats>) -> Vec<RegionStats> {
+    to.into_iter()
+        .map(|x| match from.iter().find(|y| y.label() == x.label()) {
+            Some(y) => RegionStats {
+                allocs: x.allocs - y.allocs,
+                frees: x.frees - y.frees,
+                bytes_allocated: x.bytes_allocated - y.bytes_allocated,
+                lock_acquired: x.lock_acquired - y.lock_acquired,
+                lock_contended: x.lock_contended - y.lock_contended,
+                ..x
+            },
+            None => x,
+        })
+        .collect()
+}
+
+impl Window {
+    // without a start trigger the window is open from startup
+    pub fn new(start: Option<WindowTrigger>, stop: Option<WindowTrigger>, period: u64) -> Self {
+        let state = match start {
+            Some(_) => WindowState::PENDING,
+            None => WindowState::OPEN(Instant::now(), Vec::new()),
+        };
+
+        Self {
+            active: AtomicBool::new(start.is_none()),
+            start,
+            stop,
+            period,
+            state: Mutex::new(state),
+        }
+    }
+
+    #[inline]
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn open(&self, stats: Vec<RegionStats>) {
+        let mut state = self.state.lock().unwrap();
+        if let WindowState::PENDING = *state {
+            *state = WindowState::OPEN(Instant::now(), stats);
+            self.active.store(true, Ordering::Relaxed);
+            println!("window: opened");
+        }
+    }
+
+    pub fn close(&self, stats: Vec<RegionStats>) {
+        let mut state = self.state.lock().unwrap();
+        if let WindowState::OPEN(opened, from) = &*state {
+            self.active.store(false, Ordering::Relaxed);
+            *state = WindowState::CLOSED(opened.elapsed(), delta(from, stats));
+            println!("window: closed");
+        }
+    }
+
+    pub fn print_stats(&self) {
+        match &*self.state.lock().unwrap() {
+            WindowState::PENDING => println!("window: never opened"),
+            WindowState::OPEN(..) => println!("window: still open"),
+            WindowState::CLOSED(elapsed, stats) => {
+                println!("window: open for {:?}", elapsed);
+                for x in stats.iter() {
+                    println!(
+                        "window: ({}) allocs: {}, frees: {}, allocated: {}, used at close: {}, \
+                         lock contended: {}/{}",
+                        x.label(),
+                        x.allocs,
+                        x.frees,
+                        size_to_str(x.bytes_allocated),
+                        size_to_str(x.used),
+                        x.lock_contended,
+                        x.lock_acquired
+                    );
+                }
+            }
+        }
+    }
+}
+
+// whether a trigger fired, `since` and `allocated` being the time and the bytes allocated it
+// counts from
+fn fired(trigger: &WindowTrigger, since: Instant, allocated: usize) -> bool {
+    match trigger {
+        WindowTrigger::FILE(path) => Path::new(path).exists(),
+        WindowTrigger::SECS(secs) => since.elapsed() >= Duration::from_secs(*secs),
+        WindowTrigger::BYTES(bytes) => {
+            let now = unsafe { mosalloc().unwrap().bytes_allocated() };
+            now.saturating_sub(allocated) >= *bytes
+        }
+    }
+}
+
+// block until a trigger fires, polling it every `period` ms
+fn wait(trigger: &WindowTrigger, period: u64) {
+    let since = Instant::now();
+    let allocated = match trigger {
+        WindowTrigger::BYTES(_) => unsafe { mosalloc().unwrap().bytes_allocated() },
+        _ => 0,
+    };
+
+    while !fired(trigger, since, allocated) {
+        thread::sleep(Duration::from_millis(period));
+    }
+}
+
+// spawn a thread opening and closing the window as its triggers fire
+pub fn spawn(window: Arc<Window>) {
+    service::spawn("window", move || {
+        if let Some(start) = &window.start {
+            wait(start, window.period);
+            unsafe { mosalloc().unwrap().open_window() };
+        }
+        if let Some(stop) = &window.stop {
+            wait(stop, window.period);
+            unsafe { mosalloc().unwrap().close_window() };
+        }
+    });
+}
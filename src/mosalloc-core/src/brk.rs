@@ -0,0 +1,36 @@
+use core::ops::Range;
+
+// how a brk / sbrk request moves the emulated program break
+#[derive(Debug, PartialEq, Clone)]
+pub enum BrkMove {
+    // the heap grows or shrinks by the range
+    GROW(Range<usize>),
+    SHRINK(Range<usize>),
+    NONE,
+}
+
+// the new program break of a brk (addr) or sbrk (incr) request on the heap spanning `heap`, along
+// with how it moves from `brk`, None if it falls outside of the heap
+pub fn brk_move(
+    heap: Range<usize>,
+    brk: usize,
+    addr: Option<usize>,
+    incr: Option<isize>,
+) -> Option<(usize, BrkMove)> {
+    let newbrk = match addr {
+        Some(addr) => addr,
+        None => brk.checked_add_signed(incr?)?,
+    };
+    if !heap.contains(&newbrk) {
+        return None;
+    }
+
+    let step = if newbrk > brk {
+        BrkMove::GROW(brk..newbrk)
+    } else if newbrk < brk {
+        BrkMove::SHRINK(newbrk..brk)
+    } else {
+        BrkMove::NONE
+    };
+    Some((newbrk, step))
+}
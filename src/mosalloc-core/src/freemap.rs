@@ -0,0 +1,107 @@
+use core::ops::{DerefMut, Range};
+
+use alloc::vec::Vec;
+
+// storage of a free map: the free ranges of a region, disjoint and sorted by their start, in a
+// Vec of any allocator with the allocator_api feature, or a storage that may refuse to grow
+pub trait Ranges: DerefMut<Target = [Range<usize>]> {
+    // false if the storage couldn't grow to fit the range
+    fn insert(&mut self, idx: usize, range: Range<usize>) -> bool;
+    fn remove(&mut self, idx: usize) -> Range<usize>;
+}
+
+#[cfg(not(feature = "allocator_api"))]
+impl Ranges for Vec<Range<usize>> {
+    fn insert(&mut self, idx: usize, range: Range<usize>) -> bool {
+        Vec::insert(self, idx, range);
+        true
+    }
+
+    fn remove(&mut self, idx: usize) -> Range<usize> {
+        Vec::remove(self, idx)
+    }
+}
+
+#[cfg(feature = "allocator_api")]
+impl<A: core::alloc::Allocator> Ranges for Vec<Range<usize>, A> {
+    fn insert(&mut self, idx: usize, range: Range<usize>) -> bool {
+        Vec::insert(self, idx, range);
+        true
+    }
+
+    fn remove(&mut self, idx: usize) -> Range<usize> {
+        Vec::remove(self, idx)
+    }
+}
+
+// take [start, start + len) out of the free map, or the first fit of len if start is 0, and
+// return its start, None if it isn't free or splitting its free range didn't fit in the map
+pub fn take(map: &mut impl Ranges, start: usize, len: usize) -> Option<usize> {
+    let idx = map.iter().position(|x| {
+        (start == 0 && x.len() >= len) || (x.contains(&start) && x.end - start >= len)
+    })?;
+    let range_start = map[idx].start;
+
+    // remove the range if it's wholly allocated
+    if map[idx].len() == len {
+        map.remove(idx);
+    } else if start == 0 || start == map[idx].start {
+        map[idx].start += len;
+    } else {
+        let new_range = (start + len)..map[idx].end;
+        if !map.insert(idx + 1, new_range) {
+            return None;
+        }
+        map[idx].end = start;
+    }
+
+    Some(if start == 0 { range_start } else { start })
+}
+
+// give [start, start + len) back to the free map, merged with its free neighbours, returns false
+// if it didn't fit in the map and was left out
+pub fn give(map: &mut impl Ranges, start: usize, len: usize) -> bool {
+    let end = start + len;
+
+    // find where the range should go in the free map
+    let idx = map.iter().position(|x| x.start >= end).unwrap_or(map.len());
+
+    // check if we can merge with the ranges to our left and right
+    let left = idx > 0 && map[idx - 1].end == start;
+    let right = idx < map.len() && map[idx].start == end;
+
+    match (left, right) {
+        // if we merged with both ends, merge those together
+        (true, true) => {
+            map[idx - 1].end = map[idx].end;
+            map.remove(idx);
+        }
+        (true, false) => map[idx - 1].end = end,
+        (false, true) => map[idx].start = start,
+        (false, false) => return map.insert(idx, start..end),
+    }
+    true
+}
+
+// lowest free address a range of len fits at, outside of the excluded ranges (e.g. the disabled
+// pool intervals)
+pub fn first_fit(
+    map: &[Range<usize>],
+    len: usize,
+    excluded: impl Iterator<Item = Range<usize>> + Clone,
+) -> Option<usize> {
+    for x in map.iter() {
+        let mut cur = x.start;
+        while cur + len <= x.end {
+            // skip past the excluded range in the way
+            match excluded
+                .clone()
+                .find(|y| y.start < cur + len && cur < y.end)
+            {
+                Some(y) => cur = y.end,
+                None => return Some(cur),
+            }
+        }
+    }
+    None
+}
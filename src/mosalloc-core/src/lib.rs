@@ -0,0 +1,15 @@
+// sans-IO bookkeeping of mosalloc: the hugepage pools and their intervals, the free maps of the
+// regions and the placement of new ranges in them, and the emulated program break; nothing here
+// touches libc, sysfs or the config files, so libmosalloc and the simulation / replay tools (or
+// any other allocator research project) share the same logic
+#![no_std]
+#![cfg_attr(feature = "allocator_api", feature(allocator_api))]
+
+extern crate alloc;
+
+pub mod brk;
+pub mod freemap;
+pub mod misc;
+pub mod pool;
+
+pub use pool::{AllocType, Interval, Pool};
@@ -0,0 +1,59 @@
+use alloc::format;
+use alloc::string::String;
+
+// size alignment helprs
+#[inline]
+pub const fn align(n: usize, m: usize, up: bool) -> usize {
+    assert!(m.is_power_of_two());
+    let mut ret = n & !(m - 1);
+    if up && (ret != n) {
+        ret += m;
+    }
+
+    return ret;
+}
+
+#[inline]
+pub const fn align_up(n: usize, m: usize) -> usize {
+    align(n, m, true)
+}
+
+#[inline]
+pub const fn align_down(n: usize, m: usize) -> usize {
+    align(n, m, false)
+}
+
+#[inline]
+pub const fn is_aligned(n: usize, m: usize) -> bool {
+    assert!(m.is_power_of_two());
+    n & (m - 1) == 0
+}
+
+// index of the range containing addr in a list of disjoint ranges sorted by their start
+#[inline]
+pub fn find_range<T>(
+    items: &[T],
+    addr: usize,
+    range: impl Fn(&T) -> (usize, usize),
+) -> Option<usize> {
+    let idx = items.partition_point(|x| range(x).1 <= addr);
+    items
+        .get(idx)
+        .is_some_and(|x| range(x).0 <= addr)
+        .then_some(idx)
+}
+
+// Human-readable size conversion utils
+pub fn size_to_str(sz: usize) -> String {
+    if sz >> 10 == 0 {
+        format!("{}B", sz)
+    } else if sz >> 20 == 0 {
+        format!("{}KB", sz >> 10)
+    } else if sz >> 30 == 0 {
+        format!("{}MB", sz >> 20)
+    } else if sz >> 40 == 0 {
+        format!("{}GB", sz >> 30)
+    } else {
+        format!("{}TB", sz >> 40)
+    }
+}
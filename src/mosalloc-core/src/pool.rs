@@ -0,0 +1,159 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::misc::{is_aligned, size_to_str};
+
+// allocation types for HTLB pools
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AllocType {
+    #[cfg_attr(feature = "serde", serde(rename = "brk"))]
+    BRK,
+    #[default]
+    #[cfg_attr(feature = "serde", serde(rename = "mmap"))]
+    ANON,
+    #[cfg_attr(feature = "serde", serde(rename = "file"))]
+    FILE,
+}
+
+impl AllocType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AllocType::BRK => "brk",
+            AllocType::ANON => "mmap",
+            AllocType::FILE => "file",
+        }
+    }
+}
+
+// HTLB interval
+#[derive(Debug, Clone)]
+pub struct Interval {
+    pub pagesz: usize,
+    pub start: usize,
+    pub end: usize,
+    // sub-pool the interval belongs to, if named
+    pub name: Option<String>,
+}
+
+impl Interval {
+    // whether the page size is supported by the system is left to the caller
+    pub fn new(
+        pagesz: usize,
+        start: usize,
+        end: usize,
+        base_pagesz: usize,
+    ) -> Result<Self, String> {
+        if !pagesz.is_power_of_two() || pagesz <= base_pagesz || !is_aligned(pagesz, base_pagesz) {
+            return Err(format!(
+                "page size {} isn't a multiple of the {} base page size",
+                size_to_str(pagesz),
+                size_to_str(base_pagesz)
+            ));
+        }
+
+        // alignment checks
+        if !is_aligned(start, pagesz) || !is_aligned(end, pagesz) {
+            return Err(format!(
+                "interval {:#x} - {:#x} isn't aligned to the {} page size",
+                start,
+                end,
+                size_to_str(pagesz)
+            ));
+        }
+        if start >= end {
+            return Err(format!("empty interval {:#x} - {:#x}", start, end));
+        }
+
+        Ok(Interval {
+            pagesz,
+            start,
+            end,
+            name: None,
+        })
+    }
+}
+
+// HTLB intervals pool
+#[derive(Debug, Clone)]
+pub struct Pool {
+    pub alloc_type: AllocType,
+    pub intervals: Vec<Interval>,
+}
+
+impl Pool {
+    // Create a new pseudo-htlb pool for file-mapped regions, page size is fixed at the base page size
+    pub fn new_file_pool(sz: usize, base_pagesz: usize) -> Self {
+        assert!(sz.is_multiple_of(base_pagesz));
+        Pool {
+            alloc_type: AllocType::FILE,
+            intervals: vec![Interval {
+                pagesz: base_pagesz,
+                start: 0,
+                end: sz,
+                name: None,
+            }],
+        }
+    }
+
+    // number of HTLB pages of a given size in the pool
+    pub fn nrpages(&self, sz: usize) -> usize {
+        self.intervals
+            .iter()
+            .filter_map(|x| {
+                if x.pagesz == sz {
+                    Some((x.end - x.start) / x.pagesz)
+                } else {
+                    None
+                }
+            })
+            .sum()
+    }
+
+    // end of the last interval, i.e. the length of the region the pool backs
+    pub fn span(&self) -> usize {
+        self.intervals.iter().map(|x| x.end).max().unwrap_or(0)
+    }
+
+    // index of the interval containing offset, or of the first one past it (the number of
+    // intervals if none), the intervals are sorted and disjoint
+    #[inline]
+    pub fn interval_at(&self, offset: usize) -> usize {
+        self.intervals.partition_point(|x| x.end <= offset)
+    }
+
+    // page size backing offset and the end of the same-page-size range containing it, base pages
+    // in the gaps between the intervals; the index of the last interval hit is kept in `hint`, as
+    // consecutive lookups mostly fall in the same interval
+    #[inline]
+    pub fn pagesz_range(
+        &self,
+        offset: usize,
+        hint: &AtomicUsize,
+        base_pagesz: usize,
+    ) -> (usize, usize) {
+        let last = hint.load(Ordering::Relaxed);
+        let idx = match self.intervals.get(last) {
+            Some(x) if x.start <= offset && offset < x.end => last,
+            _ => {
+                let idx = self.interval_at(offset);
+                hint.store(idx, Ordering::Relaxed);
+                idx
+            }
+        };
+
+        match self.intervals.get(idx) {
+            Some(x) if x.start <= offset => (x.pagesz, x.end),
+            Some(x) => (base_pagesz, x.start),
+            None => (base_pagesz, self.span()),
+        }
+    }
+
+    // largest page size of the pool, the alignment of the region it backs
+    pub fn max_pagesz(&self) -> usize {
+        self.intervals.iter().map(|x| x.pagesz).max().unwrap_or(0)
+    }
+}
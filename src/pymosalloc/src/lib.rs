@@ -0,0 +1,497 @@
+use std::path::Path;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+use mosalloc::utils::autosize::prior_peaks;
+use mosalloc::utils::config::{self, ConfigError};
+use mosalloc::utils::control::{self, parse_region_label, region_label};
+use mosalloc::utils::htlb::{self, supported_htlb_sizes, AllocType};
+use mosalloc::utils::layout::{plan_regions, LayoutSnapshot};
+use mosalloc::utils::misc;
+use mosalloc::utils::schema;
+use mosalloc::utils::trace::{trace_from_path, TraceOp};
+
+// columns of the trace records, in the order of the record tuples
+const TRACE_COLUMNS: [&str; 9] = [
+    "seq", "time", "op", "tid", "addr", "len", "arg", "arg2", "ret",
+];
+
+fn value_err(e: String) -> PyErr {
+    PyValueError::new_err(e)
+}
+
+// <line>: <message> of each config error, one per line
+fn config_err(errors: Vec<ConfigError>) -> PyErr {
+    value_err(
+        errors
+            .iter()
+            .map(|(line, e)| format!("{}: {}", line, e))
+            .collect::<Vec<String>>()
+            .join("\n"),
+    )
+}
+
+// brk, mmap or file, along with the name of a named region (e.g. mmap:low)
+fn alloc_type(label: &str) -> PyResult<(AllocType, Option<String>)> {
+    parse_region_label(label).ok_or_else(|| value_err(format!("unknown region `{}`", label)))
+}
+
+// a pool interval, its type, region and config line are only set for the config intervals
+#[pyclass]
+#[derive(Clone)]
+struct Interval {
+    #[pyo3(get)]
+    page_size: usize,
+    #[pyo3(get)]
+    start: usize,
+    #[pyo3(get)]
+    end: usize,
+    #[pyo3(get)]
+    name: Option<String>,
+    #[pyo3(get)]
+    alloc_type: Option<String>,
+    #[pyo3(get)]
+    region: Option<String>,
+    #[pyo3(get)]
+    line: Option<usize>,
+}
+
+impl From<&htlb::Interval> for Interval {
+    fn from(x: &htlb::Interval) -> Self {
+        Self {
+            page_size: x.pagesz,
+            start: x.start,
+            end: x.end,
+            name: x.name.clone(),
+            alloc_type: None,
+            region: None,
+            line: None,
+        }
+    }
+}
+
+#[pymethods]
+impl Interval {
+    fn __repr__(&self) -> String {
+        format!(
+            "Interval({} pages, {:#x}-{:#x})",
+            misc::size_to_str(self.page_size),
+            self.start,
+            self.end
+        )
+    }
+}
+
+// the hugepage pool backing a region
+#[pyclass]
+#[derive(Clone)]
+struct Pool {
+    inner: htlb::Pool,
+}
+
+#[pymethods]
+impl Pool {
+    #[getter]
+    fn alloc_type(&self) -> &'static str {
+        self.inner.alloc_type.as_str()
+    }
+
+    #[getter]
+    fn intervals(&self) -> Vec<Interval> {
+        self.inner.intervals.iter().map(Interval::from).collect()
+    }
+
+    // pages of the given size in the pool
+    fn nrpages(&self, page_size: usize) -> usize {
+        self.inner.nrpages(page_size)
+    }
+
+    // bytes backed by pages of the given size
+    fn size(&self, page_size: usize) -> usize {
+        self.inner.nrpages(page_size) * page_size
+    }
+
+    fn span(&self) -> usize {
+        self.inner.span()
+    }
+
+    fn max_page_size(&self) -> usize {
+        self.inner.max_pagesz()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Pool({}, {} intervals, {})",
+            self.alloc_type(),
+            self.inner.intervals.len(),
+            misc::size_to_str(self.inner.span())
+        )
+    }
+}
+
+// a brk and anon pool config, versioned or legacy CSV
+#[pyclass]
+struct PoolConfig {
+    inner: config::PoolConfig,
+}
+
+#[pymethods]
+impl PoolConfig {
+    #[staticmethod]
+    fn from_path(path: &str) -> PyResult<Self> {
+        config::PoolConfig::from_path(Path::new(path))
+            .map(|inner| Self { inner })
+            .map_err(value_err)
+    }
+
+    // parse the contents of a config
+    #[staticmethod]
+    fn parse(content: &str) -> PyResult<Self> {
+        config::PoolConfig::errors_from_str(content)
+            .map(|inner| Self { inner })
+            .map_err(config_err)
+    }
+
+    #[getter]
+    fn version(&self) -> u32 {
+        self.inner.version
+    }
+
+    #[getter]
+    fn intervals(&self) -> Vec<Interval> {
+        self.inner
+            .intervals
+            .iter()
+            .map(|x| Interval {
+                alloc_type: Some(x.alloc_type.as_str().to_string()),
+                region: x.region.clone(),
+                line: Some(x.line),
+                ..Interval::from(&x.interval)
+            })
+            .collect()
+    }
+
+    // (name, below) of the named anon regions
+    #[getter]
+    fn regions(&self) -> Vec<(String, Option<usize>)> {
+        self.inner
+            .regions
+            .iter()
+            .map(|x| (x.name.clone(), x.below))
+            .collect()
+    }
+
+    // the pool of a region, brk, mmap or a named one (e.g. mmap:low)
+    fn pool(&self, region: &str) -> PyResult<Pool> {
+        let (alloc_type, name) = alloc_type(region)?;
+        match name {
+            None => Ok(self.inner.pool(alloc_type)),
+            Some(name) => self
+                .inner
+                .named_pools()
+                .into_iter()
+                .find(|(x, _)| x.name == name)
+                .map(|(_, x)| x)
+                .ok_or_else(|| value_err(format!("undefined region `{}`", name))),
+        }
+        .map(|inner| Pool { inner })
+    }
+
+    // (name, below, pool) of the named anon regions
+    fn named_pools(&self) -> Vec<(String, Option<usize>, Pool)> {
+        self.inner
+            .named_pools()
+            .into_iter()
+            .map(|(x, inner)| (x.name, x.below, Pool { inner }))
+            .collect()
+    }
+
+    // (page size, pages) of the hugepages the brk and anon pools (named regions included) need
+    // reserved, for each supported page size
+    fn nrpages(&self) -> Vec<(usize, usize)> {
+        let pools = [
+            self.inner.pool(AllocType::BRK),
+            self.inner.pool(AllocType::ANON),
+        ]
+        .into_iter()
+        .chain(self.inner.named_pools().into_iter().map(|(_, x)| x))
+        .collect::<Vec<htlb::Pool>>();
+
+        supported_htlb_sizes()
+            .into_iter()
+            .map(|sz| (sz, pools.iter().map(|x| x.nrpages(sz)).sum()))
+            .collect()
+    }
+
+    fn check_ffa(&self, anon_ffa_size: usize, file_ffa_size: usize) -> PyResult<()> {
+        self.inner
+            .check_ffa(anon_ffa_size, file_ffa_size)
+            .map_err(|e| config_err(vec![e]))
+    }
+
+    fn to_versioned(&self) -> String {
+        self.inner.to_versioned()
+    }
+
+    // (region, start, end) of the regions placed against the address space of a program stopped
+    // right after exec, or of the current process without one, as run_mosalloc --plan-only does
+    #[args(program = "None", args = "Vec::new()", file_pool_size = "1 << 30")]
+    fn plan(
+        &self,
+        program: Option<&str>,
+        args: Vec<String>,
+        file_pool_size: usize,
+    ) -> PyResult<Vec<(String, usize, usize)>> {
+        let snapshot = match program {
+            Some(program) => LayoutSnapshot::stopped(program, &args),
+            None => LayoutSnapshot::current(),
+        }
+        .map_err(value_err)?;
+
+        let placed = plan_regions(&snapshot, &self.inner, file_pool_size).map_err(value_err)?;
+        Ok(placed
+            .into_iter()
+            .map(|(name, pool, start)| {
+                (
+                    region_label(pool.alloc_type, name.as_deref()),
+                    start,
+                    start + pool.span(),
+                )
+            })
+            .collect())
+    }
+}
+
+// (line, message) of every error of a config, empty if it's valid
+#[pyfunction]
+fn validate(content: &str) -> Vec<(usize, String)> {
+    config::PoolConfig::errors_from_str(content)
+        .err()
+        .unwrap_or_default()
+}
+
+// stats of a region, as served on the control socket
+#[pyclass]
+#[derive(Clone)]
+struct RegionStats {
+    #[pyo3(get)]
+    label: String,
+    #[pyo3(get)]
+    alloc_type: &'static str,
+    #[pyo3(get)]
+    name: Option<String>,
+    #[pyo3(get)]
+    len: usize,
+    #[pyo3(get)]
+    allocated: usize,
+    #[pyo3(get)]
+    peak: usize,
+    #[pyo3(get)]
+    used: usize,
+    #[pyo3(get)]
+    high_water: usize,
+    #[pyo3(get)]
+    brk: usize,
+    #[pyo3(get)]
+    huge: usize,
+    #[pyo3(get)]
+    allocs: usize,
+    #[pyo3(get)]
+    frees: usize,
+    #[pyo3(get)]
+    bytes_allocated: usize,
+    #[pyo3(get)]
+    lock_acquired: usize,
+    #[pyo3(get)]
+    lock_contended: usize,
+    #[pyo3(get)]
+    rss: usize,
+    #[pyo3(get)]
+    private_hugetlb: usize,
+    #[pyo3(get)]
+    swap: usize,
+    // health of each pool interval: ok, degraded or disabled
+    #[pyo3(get)]
+    intervals: Vec<String>,
+    #[pyo3(get)]
+    start: usize,
+    #[pyo3(get)]
+    free_hugepages: usize,
+    #[pyo3(get)]
+    partial_hugepages: usize,
+    #[pyo3(get)]
+    fill_placed: usize,
+    #[pyo3(get)]
+    freemap_ranges: usize,
+    #[pyo3(get)]
+    freemap_bytes: usize,
+    #[pyo3(get)]
+    freemap_refused: usize,
+    #[pyo3(get)]
+    grown: usize,
+    #[pyo3(get)]
+    grow_events: usize,
+}
+
+impl From<&control::RegionStats> for RegionStats {
+    fn from(x: &control::RegionStats) -> Self {
+        Self {
+            label: x.label(),
+            alloc_type: x.alloc_type.as_str(),
+            name: x.name.clone(),
+            len: x.len,
+            allocated: x.allocated,
+            peak: x.peak,
+            used: x.used,
+            high_water: x.high_water,
+            brk: x.brk,
+            huge: x.huge,
+            allocs: x.allocs,
+            frees: x.frees,
+            bytes_allocated: x.bytes_allocated,
+            lock_acquired: x.lock_acquired,
+            lock_contended: x.lock_contended,
+            rss: x.rss,
+            private_hugetlb: x.private_hugetlb,
+            swap: x.swap,
+            intervals: x
+                .intervals
+                .split(',')
+                .filter(|x| !x.is_empty())
+                .map(String::from)
+                .collect(),
+            start: x.start,
+            free_hugepages: x.free_hugepages,
+            partial_hugepages: x.partial_hugepages,
+            fill_placed: x.fill_placed,
+            freemap_ranges: x.freemap_ranges,
+            freemap_bytes: x.freemap_bytes,
+            freemap_refused: x.freemap_refused,
+            grown: x.grown,
+            grow_events: x.grow_events,
+        }
+    }
+}
+
+// stats of a process, a control socket reply or a stats file
+#[pyclass]
+struct ProcessStats {
+    #[pyo3(get)]
+    pid: i32,
+    #[pyo3(get)]
+    host: String,
+    #[pyo3(get)]
+    rank: Option<usize>,
+    #[pyo3(get)]
+    job: Option<String>,
+    #[pyo3(get)]
+    last: bool,
+    #[pyo3(get)]
+    regions: Vec<RegionStats>,
+}
+
+#[pyfunction]
+fn parse_stats(content: &str) -> PyResult<ProcessStats> {
+    let stats = control::ProcessStats::from_text(content).map_err(value_err)?;
+
+    Ok(ProcessStats {
+        pid: stats.pid,
+        host: stats.host,
+        rank: stats.rank,
+        job: stats.job,
+        last: stats.last,
+        regions: stats.regions.iter().map(RegionStats::from).collect(),
+    })
+}
+
+// (region type, peak) of a prior run, from a stats reply or a mosalloc_collector job report, as
+// run_mosalloc --auto-size reads it
+#[pyfunction]
+fn peaks(path: &str) -> PyResult<Vec<(&'static str, usize)>> {
+    prior_peaks(Path::new(path))
+        .map(|x| x.into_iter().map(|(t, peak)| (t.as_str(), peak)).collect())
+        .map_err(value_err)
+}
+
+// a trace written by libmosalloc, its records as tuples of TRACE_COLUMNS, e.g. for
+// pandas.DataFrame(trace.records, columns=trace.columns)
+#[pyclass]
+struct Trace {
+    #[pyo3(get)]
+    ring_size: u64,
+    #[pyo3(get)]
+    records: Vec<(u64, u64, &'static str, u32, u64, u64, u64, u64, u64)>,
+}
+
+#[pymethods]
+impl Trace {
+    #[classattr]
+    fn columns() -> Vec<&'static str> {
+        TRACE_COLUMNS.to_vec()
+    }
+}
+
+#[pyfunction]
+fn read_trace(path: &str) -> PyResult<Trace> {
+    let (header, records) = trace_from_path(Path::new(path)).map_err(value_err)?;
+
+    Ok(Trace {
+        ring_size: header.ring_size,
+        records: records
+            .iter()
+            .map(|x| {
+                (
+                    x.seq,
+                    x.time,
+                    TraceOp::from_u32(x.op).map(|x| x.as_str()).unwrap_or("?"),
+                    x.tid,
+                    x.addr,
+                    x.len,
+                    x.arg,
+                    x.arg2,
+                    x.ret,
+                )
+            })
+            .collect(),
+    })
+}
+
+// JSON schema of the stats and trace records
+#[pyfunction]
+fn json_schema() -> String {
+    schema::json_schema()
+}
+
+#[pyfunction]
+fn size_to_str(sz: usize) -> String {
+    misc::size_to_str(sz)
+}
+
+#[pyfunction]
+fn supported_page_sizes() -> Vec<usize> {
+    supported_htlb_sizes()
+}
+
+#[pymodule]
+fn pymosalloc(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add("CONFIG_VERSION", config::CONFIG_VERSION)?;
+    m.add("SCHEMA_VERSION", schema::SCHEMA_VERSION)?;
+
+    m.add_class::<Interval>()?;
+    m.add_class::<Pool>()?;
+    m.add_class::<PoolConfig>()?;
+    m.add_class::<RegionStats>()?;
+    m.add_class::<ProcessStats>()?;
+    m.add_class::<Trace>()?;
+
+    m.add_function(wrap_pyfunction!(validate, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_stats, m)?)?;
+    m.add_function(wrap_pyfunction!(peaks, m)?)?;
+    m.add_function(wrap_pyfunction!(read_trace, m)?)?;
+    m.add_function(wrap_pyfunction!(json_schema, m)?)?;
+    m.add_function(wrap_pyfunction!(size_to_str, m)?)?;
+    m.add_function(wrap_pyfunction!(supported_page_sizes, m)?)?;
+
+    Ok(())
+}
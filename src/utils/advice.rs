@@ -0,0 +1,174 @@
+use std::sync::OnceLock;
+
+use nix::errno::Errno;
+use nix::libc;
+
+// not exported by the libc crate yet
+pub const MADV_COLD: i32 = 20;
+pub const MADV_PAGEOUT: i32 = 21;
+pub const MADV_POPULATE_READ: i32 = 22;
+pub const MADV_POPULATE_WRITE: i32 = 23;
+pub const MADV_COLLAPSE: i32 = 25;
+
+// max iovecs per process_madvise call (UIO_MAXIOV)
+pub const MAX_IOV: usize = 1024;
+
+// advice process_madvise accepts, the rest is applied range by range with madvise
+const PROCESS_MADVISE_ADVICE: [i32; 4] =
+    [MADV_COLD, MADV_PAGEOUT, libc::MADV_WILLNEED, MADV_COLLAPSE];
+
+// pidfd of the current process, -1 if pidfds aren't supported
+fn self_pidfd() -> i32 {
+    static PIDFD: OnceLock<i32> = OnceLock::new();
+    *PIDFD.get_or_init(|| pidfd_open(std::process::id() as i32).unwrap_or(-1))
+}
+
+// pidfd of a running process
+pub fn pidfd_open(pid: i32) -> Result<i32, Errno> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if fd == -1 {
+        Err(Errno::last())
+    } else {
+        Ok(fd as i32)
+    }
+}
+
+// a batch of ranges to apply the same advice to, with as few syscalls as possible; full batches
+// are applied as ranges are added, so the batch never grows past MAX_IOV
+#[derive(Debug)]
+pub struct AdviceBatch {
+    pidfd: i32,
+    local: bool,
+    advice: i32,
+    iov: Vec<libc::iovec>,
+    // bytes advised and the first error since the last flush
+    advised: usize,
+    err: Option<Errno>,
+}
+
+unsafe impl Send for AdviceBatch {}
+
+impl AdviceBatch {
+    // advice for the current process, falls back to madvise if process_madvise can't be used
+    pub fn local(advice: i32) -> Self {
+        Self::with_pidfd(self_pidfd(), true, advice)
+    }
+
+    // advice for another process
+    pub fn remote(pidfd: i32, advice: i32) -> Self {
+        Self::with_pidfd(pidfd, false, advice)
+    }
+
+    fn with_pidfd(pidfd: i32, local: bool, advice: i32) -> Self {
+        Self {
+            pidfd,
+            local,
+            advice,
+            iov: Vec::with_capacity(MAX_IOV),
+            advised: 0,
+            err: None,
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.iov.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.iov.is_empty()
+    }
+
+    pub fn add(&mut self, addr: usize, len: usize) {
+        // merge with the previous range if contiguous
+        if let Some(last) = self.iov.last_mut() {
+            if last.iov_base as usize + last.iov_len == addr {
+                last.iov_len += len;
+                return;
+            }
+        }
+
+        if self.iov.len() == MAX_IOV {
+            self.apply();
+        }
+
+        self.iov.push(libc::iovec {
+            iov_base: addr as *mut libc::c_void,
+            iov_len: len,
+        });
+    }
+
+    // apply the pending ranges
+    fn apply(&mut self) {
+        let total = self.iov.iter().map(|x| x.iov_len).sum::<usize>();
+
+        let mut ret = if self.pidfd != -1 && PROCESS_MADVISE_ADVICE.contains(&self.advice) {
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_process_madvise,
+                    self.pidfd,
+                    self.iov.as_ptr(),
+                    self.iov.len(),
+                    self.advice,
+                    0,
+                )
+            };
+            if ret == -1 {
+                Err(Errno::last())
+            } else {
+                Ok(ret as usize)
+            }
+        } else {
+            Err(Errno::EINVAL)
+        };
+
+        // the advice can still be applied to the current process range by range
+        if self.local && matches!(ret, Err(Errno::ENOSYS) | Err(Errno::EINVAL)) {
+            ret = self.iov.iter().try_fold(0, |acc, x| {
+                // raw syscall, so that the mosalloc hooks aren't involved
+                let ret =
+                    unsafe { libc::syscall(libc::SYS_madvise, x.iov_base, x.iov_len, self.advice) };
+                if ret == -1 {
+                    Err(Errno::last())
+                } else {
+                    Ok(acc + x.iov_len)
+                }
+            });
+        }
+
+        match ret {
+            Ok(advised) => {
+                self.advised += advised;
+                if advised < total && self.err.is_none() {
+                    self.err = Some(Errno::EAGAIN);
+                }
+            }
+            Err(err) => {
+                self.err.get_or_insert(err);
+            }
+        }
+
+        self.iov.clear();
+    }
+
+    // drop the pending ranges without applying them
+    pub fn clear(&mut self) {
+        self.iov.clear();
+    }
+
+    // apply the pending ranges, returns the bytes advised since the last flush or the first error
+    pub fn flush(&mut self) -> Result<usize, Errno> {
+        if !self.iov.is_empty() {
+            self.apply();
+        }
+
+        let advised = self.advised;
+        self.advised = 0;
+
+        match self.err.take() {
+            Some(err) => Err(err),
+            None => Ok(advised),
+        }
+    }
+}
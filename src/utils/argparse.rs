@@ -1,11 +1,20 @@
 use nix::sched::{sched_getaffinity, CpuSet};
+use nix::sys::signal::Signal;
 use nix::unistd::Pid;
 use std::path::Path;
 
-use super::htlb::{self, HTLBReq, HookType};
+use super::config::PoolConfig;
+use super::htlb::{
+    self, DrainPolicy, EarlyPolicy, HTLBReq, HeapPolicy, HookType, PlacementPolicy, PoolBacking,
+    ReclaimPolicy, ReserveStrategy, SizeLimit, SwapPolicy, WindowTrigger, ZeroPolicy,
+};
 use super::misc::*;
+use super::multirun::Budget;
 use super::rangelist::{Id, RangeList};
 use super::sysfs_path::*;
+use super::tlb::TlbModel;
+use super::trace::TraceOp;
+use super::trace_state::TracePoint;
 
 pub fn parse_file_path(s: &str) -> Result<String, String> {
     if Path::new(s).is_file() {
@@ -15,10 +24,34 @@ pub fn parse_file_path(s: &str) -> Result<String, String> {
     }
 }
 
+// a file path, or a Slurm filename pattern (e.g. pool.%t.csv) checked once expanded
+pub fn parse_config_path(s: &str) -> Result<String, String> {
+    if s.contains('%') {
+        Ok(s.to_owned())
+    } else {
+        parse_file_path(s)
+    }
+}
+
 pub fn parse_size(s: &str) -> Result<usize, String> {
     Ok(size_from_str(s))
 }
 
+// a size that may also be given as a percentage of the RAM (e.g. %10-of-RAM)
+pub fn parse_size_or_ram(s: &str) -> Result<usize, String> {
+    size_or_ram_from_str(s)
+}
+
+// a power of two size
+pub fn parse_align(s: &str) -> Result<usize, String> {
+    let align = size_from_str(s);
+    if align.is_power_of_two() {
+        Ok(align)
+    } else {
+        Err(format!("{} is not a power of two", s))
+    }
+}
+
 pub fn default_node() -> Id {
     let cpu_set = sched_getaffinity(Pid::from_raw(0)).unwrap();
     let cpus = RangeList::from_path(sysfs_path_online_cpus());
@@ -63,10 +96,174 @@ pub fn parse_htlb_req(s: &str) -> Result<HTLBReq, String> {
         Ok(HTLBReq {
             req: req.map(|x| x.unwrap()).collect(),
             node: 0,
+            strategy: ReserveStrategy::STATIC,
         })
     }
 }
 
+// <page size>=<amount>[,...], the amount is either a byte budget with a size suffix, rounded up
+// to whole pages, or a page count without one, e.g. 2MB=20GB,1GB=8
+pub fn parse_htlb_bytes(s: &str) -> Result<HTLBReq, String> {
+    let supported_sizes = htlb::supported_htlb_sizes();
+    let mut req = vec![0; supported_sizes.len()];
+
+    for x in s.trim().split(',') {
+        let err = || format!("Invalid HTLB request {} (expected <page size>=<amount>)", x);
+        let (pagesz, amount) = x
+            .split_once('=')
+            .filter(|(pagesz, amount)| is_size_str(pagesz) && is_size_str(amount))
+            .ok_or_else(err)?;
+
+        let pagesz = size_from_str(pagesz);
+        let idx = supported_sizes
+            .iter()
+            .position(|&x| x == pagesz)
+            .ok_or_else(|| format!("Unsupported page size {}", size_to_str(pagesz)))?;
+        req[idx] += match amount.parse::<usize>() {
+            Ok(nr) => nr,
+            Err(_) => size_from_str(amount).div_ceil(pagesz),
+        };
+    }
+
+    Ok(HTLBReq {
+        req,
+        node: 0,
+        strategy: ReserveStrategy::STATIC,
+    })
+}
+
+// the pages the brk and anon pools of a config need
+pub fn parse_fit_config(s: &str) -> Result<HTLBReq, String> {
+    let path = Path::new(s);
+    PoolConfig::from_path(path)?;
+    Ok(HTLBReq::from_config(path, 0))
+}
+
 pub fn parse_hook_type(s: &str) -> Result<HookType, String> {
     s.parse::<HookType>()
 }
+
+pub fn parse_reserve_strategy(s: &str) -> Result<ReserveStrategy, String> {
+    s.parse::<ReserveStrategy>()
+}
+
+pub fn parse_pool_backing(s: &str) -> Result<PoolBacking, String> {
+    s.parse::<PoolBacking>()
+}
+
+pub fn parse_reclaim_policy(s: &str) -> Result<ReclaimPolicy, String> {
+    s.parse::<ReclaimPolicy>()
+}
+
+pub fn parse_swap_policy(s: &str) -> Result<SwapPolicy, String> {
+    s.parse::<SwapPolicy>()
+}
+
+pub fn parse_drain_policy(s: &str) -> Result<DrainPolicy, String> {
+    s.parse::<DrainPolicy>()
+}
+
+pub fn parse_early_policy(s: &str) -> Result<EarlyPolicy, String> {
+    s.parse::<EarlyPolicy>()
+}
+
+pub fn parse_heap_policy(s: &str) -> Result<HeapPolicy, String> {
+    s.parse::<HeapPolicy>()
+}
+
+pub fn parse_fault_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(x) if (0.0..=1.0).contains(&x) => Ok(x),
+        _ => Err(format!("Invalid fault rate {} (expected a probability)", s)),
+    }
+}
+
+// a cpulist of online CPUs, kept as given
+pub fn parse_cpu_list(s: &str) -> Result<String, String> {
+    let online = RangeList::from_path(sysfs_path_online_cpus());
+    match cpus_from_str(s)?.into_iter().find(|&x| !online.contains(x)) {
+        Some(cpu) => Err(format!("CPU {} isn't online", cpu)),
+        None => Ok(s.to_owned()),
+    }
+}
+
+pub fn parse_watermark(s: &str) -> Result<usize, String> {
+    match s.trim_end_matches('%').parse::<usize>() {
+        Ok(x) if (1..=100).contains(&x) => Ok(x),
+        _ => Err(format!("Invalid watermark {} (expected a percentage)", s)),
+    }
+}
+
+pub fn parse_trace_op(s: &str) -> Result<TraceOp, String> {
+    s.parse::<TraceOp>()
+}
+
+pub fn parse_fault_op(s: &str) -> Result<TraceOp, String> {
+    match s.parse::<TraceOp>()? {
+        op if op.injectable() => Ok(op),
+        _ => Err(format!(
+            "Can't inject failures into {} (only mmap, brk and mremap)",
+            s
+        )),
+    }
+}
+
+pub fn parse_trace_point(s: &str) -> Result<TracePoint, String> {
+    s.parse::<TracePoint>()
+}
+
+pub fn parse_zero_policy(s: &str) -> Result<ZeroPolicy, String> {
+    s.parse::<ZeroPolicy>()
+}
+
+pub fn parse_placement_policy(s: &str) -> Result<PlacementPolicy, String> {
+    s.parse::<PlacementPolicy>()
+}
+
+pub fn parse_budget(s: &str) -> Result<Budget, String> {
+    s.parse::<Budget>()
+}
+
+// session names are stored in the whitespace-separated reservations state file
+pub fn parse_session(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.contains(char::is_whitespace) {
+        Err(format!("Invalid session name: {:?}", s))
+    } else {
+        Ok(s.to_string())
+    }
+}
+
+// a signal to catch by name, with or without the SIG prefix (e.g. USR1), the ones run_mosalloc
+// forwards or uses for the timeout can't be
+pub fn parse_signal(s: &str) -> Result<Signal, String> {
+    let name = if s.starts_with("SIG") {
+        s.to_string()
+    } else {
+        format!("SIG{}", s)
+    };
+    match name.parse::<Signal>() {
+        Ok(
+            Signal::SIGKILL | Signal::SIGSTOP | Signal::SIGINT | Signal::SIGTERM | Signal::SIGALRM,
+        ) => Err(format!("Signal {} can't be used", s)),
+        Ok(sig) => Ok(sig),
+        Err(_) => Err(format!("Unknown signal: {}", s)),
+    }
+}
+
+pub fn parse_size_limit(s: &str) -> Result<SizeLimit, String> {
+    s.parse::<SizeLimit>()
+}
+
+pub fn parse_window_trigger(s: &str) -> Result<WindowTrigger, String> {
+    s.parse::<WindowTrigger>()
+}
+
+pub fn parse_tlb_model(s: &str) -> Result<TlbModel, String> {
+    s.parse::<TlbModel>()
+}
+
+// an address in hex, with or without the 0x prefix
+pub fn parse_addr(s: &str) -> Result<usize, String> {
+    usize::from_str_radix(s.trim_start_matches("0x"), 16)
+        .map_err(|_| format!("Invalid address: {}", s))
+}
@@ -0,0 +1,100 @@
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+use nix::errno::Errno;
+use nix::libc;
+
+use super::htlb::{AllocType, Pool, PoolExt, PAGE_SIZE};
+use super::misc::{align_down, align_up};
+
+// not exported by the libc crate yet
+const MPOL_MF_MOVE: i32 = 1 << 1;
+
+// a mapping of /proc/<pid>/maps
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mapping {
+    pub range: Range<usize>,
+    pub private: bool,
+    pub name: String,
+}
+
+pub fn process_maps(pid: i32) -> Result<Vec<Mapping>, String> {
+    let maps =
+        fs::read_to_string(format!("/proc/{}/maps", pid)).map_err(|e| format!("{}: {}", pid, e))?;
+
+    Ok(maps
+        .lines()
+        .map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<&str>>();
+            let range = fields[0]
+                .split_once('-')
+                .map(|(s, e)| {
+                    usize::from_str_radix(s, 16).unwrap()..usize::from_str_radix(e, 16).unwrap()
+                })
+                .unwrap();
+            Mapping {
+                range,
+                private: fields[1].ends_with('p'),
+                name: fields.get(5).unwrap_or(&"").to_string(),
+            }
+        })
+        .collect())
+}
+
+// (range, page size) to advise for each pool interval, brk intervals are placed relative to the
+// start of the heap and anon intervals relative to the largest anonymous mapping, the ranges are
+// clamped to the mappings
+pub fn attach_targets(maps: &[Mapping], config: &Path) -> Vec<(Range<usize>, usize)> {
+    let heap = maps.iter().find(|x| x.name == "[heap]");
+    let anon = maps
+        .iter()
+        .filter(|x| x.private && x.name.is_empty())
+        .max_by_key(|x| x.range.len());
+
+    [(AllocType::BRK, heap), (AllocType::ANON, anon)]
+        .into_iter()
+        .filter_map(|(alloc_type, mapping)| Some((Pool::from_config(alloc_type, config), mapping?)))
+        .flat_map(|(pool, mapping)| {
+            let base = mapping.range.start;
+            let end = mapping.range.end;
+            pool.intervals
+                .into_iter()
+                .filter_map(move |x| {
+                    let start = align_up(base + x.start, x.pagesz);
+                    let stop = align_down((base + x.end).min(end), x.pagesz);
+                    (start < stop).then_some((start..stop, x.pagesz))
+                })
+                .collect::<Vec<(Range<usize>, usize)>>()
+        })
+        .collect()
+}
+
+// migrate the pages of a range of another process to a NUMA node, returns the pages that
+// couldn't be moved (e.g. not populated yet)
+pub fn move_to_node(pid: i32, range: &Range<usize>, node: usize) -> Result<usize, Errno> {
+    let pages = range
+        .clone()
+        .step_by(*PAGE_SIZE)
+        .map(|x| x as *mut libc::c_void)
+        .collect::<Vec<*mut libc::c_void>>();
+    let nodes = vec![node as i32; pages.len()];
+    let mut status = vec![0i32; pages.len()];
+
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_move_pages,
+            pid,
+            pages.len(),
+            pages.as_ptr(),
+            nodes.as_ptr(),
+            status.as_mut_ptr(),
+            MPOL_MF_MOVE,
+        )
+    };
+    if ret == -1 {
+        return Err(Errno::last());
+    }
+
+    Ok(status.iter().filter(|&&x| x != node as i32).count())
+}
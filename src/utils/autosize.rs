@@ -0,0 +1,156 @@
+use std::fs;
+use std::path::Path;
+
+use super::config::{IntervalEntry, PoolConfig, CONFIG_VERSION};
+use super::control::{ProcessStats, STATS_HEADER};
+use super::elf::ElfInfo;
+use super::htlb::{htlb_interval, supported_htlb_sizes, AllocType, PAGE_SIZE};
+use super::misc::{align_up, size_to_str};
+
+// headroom over the peak usage of a prior run
+const HEADROOM_PCT: usize = 25;
+// brk pool size when there's no prior run, unless the static data calls for more
+const MIN_BRK_POOL: usize = 64 << 20;
+// brk pool size per byte of static data when there's no prior run
+const BRK_DATA_RATIO: usize = 4;
+// anon pool size when there's no prior run
+const DEFAULT_ANON_POOL: usize = 256 << 20;
+
+// a pool size and where it was derived from
+#[derive(Debug, Clone)]
+pub struct PoolEstimate {
+    pub alloc_type: AllocType,
+    pub size: usize,
+    pub source: String,
+}
+
+// (region, peak) of a prior run, the max over its processes, from a mosalloc stats reply or a
+// mosalloc_collector job report
+pub fn prior_peaks(path: &Path) -> Result<Vec<(AllocType, usize)>, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let mut peaks: Vec<(AllocType, usize)> = Vec::new();
+    let mut add = |alloc_type: AllocType, peak: usize| {
+        let max = peaks.iter_mut().find(|(x, _)| *x == alloc_type);
+        match max {
+            Some((_, max)) => *max = (*max).max(peak),
+            None => peaks.push((alloc_type, peak)),
+        }
+    };
+
+    if content.starts_with(STATS_HEADER) {
+        let stats =
+            ProcessStats::from_text(&content).map_err(|e| format!("{}: {}", path.display(), e))?;
+        for r in stats.regions.iter() {
+            add(r.alloc_type, r.peak);
+        }
+        return Ok(peaks);
+    }
+
+    // host,pid,rank,region,size,allocated,peak,...; the per region totals sum the peaks
+    for line in content
+        .lines()
+        .filter(|x| !x.starts_with('#'))
+        .skip(1)
+        .filter(|x| !x.starts_with("total,"))
+    {
+        let fields = line.split(',').collect::<Vec<&str>>();
+        let parse_err = || format!("{}: invalid job report line: {}", path.display(), line);
+
+        let alloc_type = [AllocType::BRK, AllocType::ANON, AllocType::FILE]
+            .into_iter()
+            .find(|x| fields.get(3) == Some(&x.as_str()))
+            .ok_or_else(parse_err)?;
+        let peak = fields
+            .get(6)
+            .and_then(|x| x.parse::<usize>().ok())
+            .ok_or_else(parse_err)?;
+        add(alloc_type, peak);
+    }
+
+    Ok(peaks)
+}
+
+// brk and anon pool sizes for an executable, from the peaks of a prior run if there's one, or
+// its static data otherwise
+pub fn estimate(elf: &ElfInfo, prior: &[(AllocType, usize)]) -> Vec<PoolEstimate> {
+    let peak = |alloc_type: AllocType| {
+        prior
+            .iter()
+            .find(|(x, _)| *x == alloc_type)
+            .map(|(_, peak)| *peak)
+    };
+    let with_headroom = |x: usize| x + x * HEADROOM_PCT / 100;
+
+    let brk = match peak(AllocType::BRK) {
+        Some(x) => PoolEstimate {
+            alloc_type: AllocType::BRK,
+            size: with_headroom(x),
+            source: format!("prior peak {} + {}%", size_to_str(x), HEADROOM_PCT),
+        },
+        None => PoolEstimate {
+            alloc_type: AllocType::BRK,
+            size: MIN_BRK_POOL.max(elf.data_size() * BRK_DATA_RATIO),
+            source: format!(
+                "{}x the {} data + bss",
+                BRK_DATA_RATIO,
+                size_to_str(elf.data_size())
+            ),
+        },
+    };
+
+    let anon = match peak(AllocType::ANON) {
+        Some(x) => PoolEstimate {
+            alloc_type: AllocType::ANON,
+            size: with_headroom(x),
+            source: format!("prior peak {} + {}%", size_to_str(x), HEADROOM_PCT),
+        },
+        None => PoolEstimate {
+            alloc_type: AllocType::ANON,
+            size: DEFAULT_ANON_POOL,
+            source: "default, no prior run".to_string(),
+        },
+    };
+
+    vec![brk, anon]
+}
+
+// intervals covering [0, size), with the largest page sizes first; the size is rounded up to the
+// smallest hugepage size
+fn fill_intervals(alloc_type: AllocType, size: usize) -> Vec<IntervalEntry> {
+    let mut sizes = supported_htlb_sizes()
+        .into_iter()
+        .filter(|x| *x > *PAGE_SIZE)
+        .collect::<Vec<usize>>();
+    sizes.sort();
+    let size = align_up(size, *sizes.first().expect("no hugepage sizes supported"));
+
+    let mut out = Vec::new();
+    let mut start = 0;
+    for pagesz in sizes.iter().rev() {
+        let end = start + (size - start) / pagesz * pagesz;
+        if end > start {
+            out.push(IntervalEntry {
+                alloc_type,
+                interval: htlb_interval(*pagesz, start, end).unwrap(),
+                region: None,
+                line: 0,
+            });
+            start = end;
+        }
+    }
+
+    out
+}
+
+// pool config of the estimated pools
+pub fn auto_config(estimates: &[PoolEstimate]) -> PoolConfig {
+    PoolConfig {
+        version: CONFIG_VERSION,
+        intervals: estimates
+            .iter()
+            .flat_map(|x| fill_intervals(x.alloc_type, x.size))
+            .collect(),
+        regions: Vec::new(),
+    }
+}
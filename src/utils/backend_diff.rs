@@ -0,0 +1,179 @@
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use nix::libc;
+
+use super::htlb::{HookType, MosallocConfig};
+use super::snapshot::AllocatorSnapshot;
+use super::trace::{trace_from_path, TraceOp, TraceRecord};
+
+// the backends compared, the hybrid one being the preload hooks plus a seccomp filter
+pub const DIFF_BACKENDS: [HookType; 2] = [HookType::PRELOAD, HookType::SECCOMP];
+
+// trace and layout of a workload run under a single backend
+#[derive(Debug)]
+pub struct BackendRun {
+    pub hook: HookType,
+    pub status: Option<i32>,
+    pub trace: Vec<TraceRecord>,
+    // hash of the allocator bookkeeping at exit, the offsets of the free and allocated ranges
+    pub layout: u64,
+}
+
+// the calls of a record the backends have to agree on, the sequence number, time and tid differ
+// between runs
+fn key(x: &TraceRecord) -> (u32, u64, u64, u64, u64, u64) {
+    (x.op, x.addr, x.len, x.arg, x.arg2, x.ret)
+}
+
+fn record_str(x: &TraceRecord) -> String {
+    format!(
+        "{} addr 0x{:x} len 0x{:x} arg 0x{:x} arg2 0x{:x} ret 0x{:x}",
+        TraceOp::from_u32(x.op).map_or("?", |op| op.as_str()),
+        x.addr,
+        x.len,
+        x.arg,
+        x.arg2,
+        x.ret
+    )
+}
+
+// hash of the bookkeeping of a snapshot, the ranges are offsets from the region starts so it
+// doesn't depend on where the regions were placed
+pub fn layout_hash(path: &Path) -> Result<u64, String> {
+    let snapshot = AllocatorSnapshot::from_path(path)?;
+
+    let mut hasher = DefaultHasher::new();
+    for r in snapshot.regions.iter() {
+        r.alloc_type.as_str().hash(&mut hasher);
+        r.name.hash(&mut hasher);
+        (r.brk, r.high_water, r.len).hash(&mut hasher);
+        r.free_map.hash(&mut hasher);
+        r.prot_map.hash(&mut hasher);
+    }
+    Ok(hasher.finish())
+}
+
+// run the workload under the given backend, with ASLR disabled so that both backends see the
+// same addresses, and collect its trace and layout
+pub fn run_backend(
+    config: &MosallocConfig,
+    hook: HookType,
+    prefix: &str,
+    lib: &str,
+    cmd: &[String],
+) -> Result<BackendRun, String> {
+    let file = |ext: &str| -> PathBuf {
+        env::temp_dir().join(format!(
+            "mosalloc-diff-{}-{}.{}",
+            std::process::id(),
+            hook.as_str(),
+            ext
+        ))
+    };
+    let (trace, snapshot) = (file("trace"), file("snapshot"));
+
+    let config = MosallocConfig {
+        hook,
+        trace: Some(trace.to_string_lossy().into_owned()),
+        snapshot: Some(snapshot.to_string_lossy().into_owned()),
+        ..config.clone()
+    };
+
+    let status = unsafe {
+        Command::new(&cmd[0])
+            .args(&cmd[1..])
+            .envs(config.env_vars(prefix))
+            .env("LD_PRELOAD", lib)
+            .pre_exec(|| {
+                if libc::personality(libc::ADDR_NO_RANDOMIZE as libc::c_ulong) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            })
+    }
+    .status()
+    .map_err(|e| format!("{}: {}", cmd[0], e))?;
+
+    let run = trace_from_path(&trace).and_then(|(_, records)| {
+        Ok(BackendRun {
+            hook,
+            status: status.code(),
+            trace: records,
+            layout: layout_hash(&snapshot)?,
+        })
+    });
+    let _ = fs::remove_file(&trace);
+    let _ = fs::remove_file(&snapshot);
+
+    run.map_err(|e| format!("{}: {}", hook.as_str(), e))
+}
+
+// the divergences between the runs of two backends, the first `max` differing records along with
+// the exit status and layout mismatches
+pub fn diff_runs(a: &BackendRun, b: &BackendRun, max: usize) -> Vec<String> {
+    let mut diffs = Vec::new();
+    let (x, y) = (a.hook.as_str(), b.hook.as_str());
+
+    if a.status != b.status {
+        diffs.push(format!(
+            "exit status: {} {:?}, {} {:?}",
+            x, a.status, y, b.status
+        ));
+    }
+    if a.layout != b.layout {
+        diffs.push(format!(
+            "layout hash: {} {:016x}, {} {:016x}",
+            x, a.layout, y, b.layout
+        ));
+    }
+
+    let records = a
+        .trace
+        .iter()
+        .zip(b.trace.iter())
+        .enumerate()
+        .filter(|(_, (r, s))| key(r) != key(s))
+        .collect::<Vec<_>>();
+    for (i, (r, s)) in records.iter().take(max) {
+        diffs.push(format!(
+            "record {}: {} {}, {} {}",
+            i,
+            x,
+            record_str(r),
+            y,
+            record_str(s)
+        ));
+    }
+    if records.len() > max {
+        diffs.push(format!(
+            "... {} more differing records",
+            records.len() - max
+        ));
+    }
+
+    if a.trace.len() != b.trace.len() {
+        let (longer, extra) = if a.trace.len() > b.trace.len() {
+            (x, &a.trace[b.trace.len()..])
+        } else {
+            (y, &b.trace[a.trace.len()..])
+        };
+        diffs.push(format!(
+            "records: {} {}, {} {}, {} first extra one: {}",
+            x,
+            a.trace.len(),
+            y,
+            b.trace.len(),
+            longer,
+            record_str(&extra[0])
+        ));
+    }
+
+    diffs
+}
@@ -0,0 +1,223 @@
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+use nix::libc;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{alarm, Pid};
+
+// max children run at once
+pub const MAX_CHILDREN: usize = 64;
+
+// pids of the running children, the targets of the forwarded signals, 0 once reaped
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_CHILD: AtomicI32 = AtomicI32::new(0);
+static CHILDREN: [AtomicI32; MAX_CHILDREN] = [NO_CHILD; MAX_CHILDREN];
+// the children terminated by the timeout
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_TIMED_OUT: AtomicBool = AtomicBool::new(false);
+static TIMED_OUT: [AtomicBool; MAX_CHILDREN] = [NOT_TIMED_OUT; MAX_CHILDREN];
+// whether the timeout already expired, the next alarm is the end of the grace period
+static EXPIRED: AtomicBool = AtomicBool::new(false);
+// whether the checkpoint signal ended the current phase of a phased run
+static CHECKPOINTED: AtomicBool = AtomicBool::new(false);
+
+// signals forwarded to the child
+const FORWARDED: [Signal; 2] = [Signal::SIGINT, Signal::SIGTERM];
+// seconds between the SIGTERM and the SIGKILL of a timed out child
+const KILL_GRACE: u32 = 5;
+// exit code of a timed out child, as timeout(1)
+const TIMEOUT_EXIT_CODE: i32 = 124;
+// si_code of the signals sent by the kernel, e.g. by the terminal on ^C
+const SI_KERNEL: libc::c_int = 0x80;
+
+// how the child terminated
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ChildExit {
+    Exited(i32),
+    Signaled(i32),
+    TimedOut,
+}
+
+impl ChildExit {
+    // exit code to propagate, a signaled child is reported the way the shell does
+    pub fn code(&self) -> i32 {
+        match self {
+            ChildExit::Exited(code) => *code,
+            ChildExit::Signaled(sig) => 128 + sig,
+            ChildExit::TimedOut => TIMEOUT_EXIT_CODE,
+        }
+    }
+
+    pub fn as_string(&self) -> String {
+        match self {
+            ChildExit::Exited(code) => format!("exited with {}", code),
+            ChildExit::Signaled(sig) => format!(
+                "killed by {}",
+                Signal::try_from(*sig)
+                    .map(|x| x.as_str().to_string())
+                    .unwrap_or_else(|_| sig.to_string())
+            ),
+            ChildExit::TimedOut => "timed out".to_string(),
+        }
+    }
+}
+
+extern "C" fn forward_signal(sig: libc::c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    // the terminal signals the whole foreground process group, the child included
+    if unsafe { (*info).si_code } == SI_KERNEL {
+        return;
+    }
+
+    for child in CHILDREN.iter() {
+        let pid = child.load(Ordering::SeqCst);
+        if pid > 0 {
+            unsafe { libc::kill(pid, sig) };
+        }
+    }
+}
+
+// first alarm terminates the children still running, the one after the grace period kills them
+extern "C" fn timeout(_: libc::c_int) {
+    let expired = EXPIRED.swap(true, Ordering::SeqCst);
+
+    for (child, timed_out) in CHILDREN.iter().zip(TIMED_OUT.iter()) {
+        let pid = child.load(Ordering::SeqCst);
+        if pid <= 0 {
+            continue;
+        }
+        if !expired {
+            timed_out.store(true, Ordering::SeqCst);
+            unsafe { libc::kill(pid, libc::SIGTERM) };
+        } else {
+            unsafe { libc::kill(pid, libc::SIGKILL) };
+        }
+    }
+
+    if !expired {
+        alarm::set(KILL_GRACE);
+    }
+}
+
+// the checkpoint signal terminates the children, for the next phase to be started
+extern "C" fn checkpoint(_: libc::c_int) {
+    CHECKPOINTED.store(true, Ordering::SeqCst);
+
+    for child in CHILDREN.iter() {
+        let pid = child.load(Ordering::SeqCst);
+        if pid > 0 {
+            unsafe { libc::kill(pid, libc::SIGTERM) };
+        }
+    }
+}
+
+// runs a phase of a command, until it exits or the given checkpoint signal is received (e.g. sent
+// by the program to its parent), which terminates it (SIGTERM); the exit comes along with whether
+// the phase ended at a checkpoint
+pub fn run_phase(
+    cmd: &mut Command,
+    timeout_secs: Option<u32>,
+    signal: Signal,
+) -> Result<(ChildExit, bool), String> {
+    let action = SigAction::new(
+        SigHandler::Handler(checkpoint),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe { sigaction(signal, &action) }.map_err(|e| format!("sigaction: {}", e))?;
+
+    CHECKPOINTED.store(false, Ordering::SeqCst);
+    let exit = run(cmd, timeout_secs)?;
+    Ok((exit, CHECKPOINTED.swap(false, Ordering::SeqCst)))
+}
+
+// runs a command to completion, forwarding SIGINT / SIGTERM to it and terminating it after the
+// timeout (secs), if any
+pub fn run(cmd: &mut Command, timeout_secs: Option<u32>) -> Result<ChildExit, String> {
+    run_all(&mut [cmd], timeout_secs).map(|x| x[0])
+}
+
+// runs the commands concurrently to completion, forwarding SIGINT / SIGTERM to all of them and
+// terminating them after the timeout (secs), if any; the exits are in the order of the commands
+pub fn run_all(
+    cmds: &mut [&mut Command],
+    timeout_secs: Option<u32>,
+) -> Result<Vec<ChildExit>, String> {
+    if cmds.len() > MAX_CHILDREN {
+        return Err(format!("can't run more than {} children", MAX_CHILDREN));
+    }
+
+    let forward = SigAction::new(
+        SigHandler::SigAction(forward_signal),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    let alarm_action = SigAction::new(
+        SigHandler::Handler(timeout),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe {
+        for sig in FORWARDED {
+            sigaction(sig, &forward).map_err(|e| format!("sigaction: {}", e))?;
+        }
+        sigaction(Signal::SIGALRM, &alarm_action).map_err(|e| format!("sigaction: {}", e))?;
+    }
+
+    for (i, cmd) in cmds.iter_mut().enumerate() {
+        match cmd.spawn() {
+            Ok(child) => CHILDREN[i].store(child.id() as i32, Ordering::SeqCst),
+            Err(e) => {
+                // don't leave the already spawned ones behind
+                kill_spawned(i);
+                return Err(format!("{}: {}", cmd.get_program().to_string_lossy(), e));
+            }
+        }
+    }
+    if let Some(secs) = timeout_secs {
+        alarm::set(secs);
+    }
+
+    let mut exits = vec![None; cmds.len()];
+    while exits.iter().any(|x| x.is_none()) {
+        // reap the children as they exit, so that they aren't signaled after that
+        let (pid, exit) = match waitpid(None, None) {
+            Ok(WaitStatus::Exited(pid, code)) => (pid, ChildExit::Exited(code)),
+            Ok(WaitStatus::Signaled(pid, sig, _)) => (pid, ChildExit::Signaled(sig as i32)),
+            Ok(_) => continue,
+            Err(e) => {
+                alarm::cancel();
+                return Err(format!("wait: {}", e));
+            }
+        };
+        let i = match CHILDREN[..cmds.len()]
+            .iter()
+            .position(|x| x.load(Ordering::SeqCst) == pid.as_raw())
+        {
+            Some(i) => i,
+            None => continue,
+        };
+
+        CHILDREN[i].store(0, Ordering::SeqCst);
+        exits[i] = Some(if TIMED_OUT[i].swap(false, Ordering::SeqCst) {
+            ChildExit::TimedOut
+        } else {
+            exit
+        });
+    }
+    alarm::cancel();
+    EXPIRED.store(false, Ordering::SeqCst);
+
+    Ok(exits.into_iter().flatten().collect())
+}
+
+// kills and reaps the first n children
+fn kill_spawned(n: usize) {
+    for child in CHILDREN[..n].iter() {
+        let pid = child.swap(0, Ordering::SeqCst);
+        if pid > 0 {
+            let _ = nix::sys::signal::kill(Pid::from_raw(pid), Signal::SIGKILL);
+            let _ = waitpid(Pid::from_raw(pid), None);
+        }
+    }
+}
@@ -0,0 +1,655 @@
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use super::htlb::{htlb_interval, AllocType, Interval, Pool};
+use super::misc::{is_aligned, size_from_str};
+
+// the versioned configs use a small TOML-like format, not TOML: one `key = value` per line at
+// the top level (`version`) or in the [[interval]] and [[region]] tables, the values being basic
+// strings without escapes or integers, and # comments; the inline tables, arrays, multi-line and
+// literal strings, dotted keys ... of TOML are rejected
+
+// current pool config schema version, legacy CSV configs are version 0, version 2 adds the
+// named anon regions
+pub const CONFIG_VERSION: u32 = 2;
+
+const INTERVAL_KEYS: [&str; 6] = ["type", "page_size", "start", "end", "name", "region"];
+const REGION_KEYS: [&str; 2] = ["name", "below"];
+
+// the pools can't extend past the 47-bit user address space
+pub const MAX_POOL_END: usize = 1 << 47;
+
+// (line, message) of a config error, line 1 for the errors about the config as a whole
+pub type ConfigError = (usize, String);
+
+// an [[interval]] or [[region]] table, its header line and its (key, value) entries
+type Table = (&'static str, usize, Vec<(String, String)>);
+
+// deserialized legacy CSV interval
+#[derive(Debug, Deserialize)]
+struct CSVRecord {
+    #[serde(rename = "type")]
+    region_type: String,
+    page_size: String,
+    start_offset: String,
+    end_offset: String,
+}
+
+// a pool interval, along with its region and the config line it was defined at
+#[derive(Debug, Clone)]
+pub struct IntervalEntry {
+    pub alloc_type: AllocType,
+    pub interval: Interval,
+    // the named anon region of the interval, None for the default one
+    pub region: Option<String>,
+    pub line: usize,
+}
+
+// an anon region besides the default one, at an address range of its own, e.g. a low one for
+// the MAP_32BIT requests
+#[derive(Debug, Clone)]
+pub struct RegionEntry {
+    pub name: String,
+    // the region has to end below this address
+    pub below: Option<usize>,
+    pub line: usize,
+}
+
+// brk and anon (mmap) pool intervals configuration
+#[derive(Debug)]
+pub struct PoolConfig {
+    pub version: u32,
+    pub intervals: Vec<IntervalEntry>,
+    pub regions: Vec<RegionEntry>,
+}
+
+// sizes are either plain byte counts or have a KB / MB / GB / TB suffix
+fn parse_size_value(s: &str) -> Result<usize, String> {
+    let digits = s.trim_end_matches(|c: char| !c.is_ascii_digit());
+    let valid = !digits.is_empty()
+        && digits.chars().all(|c| c.is_ascii_digit())
+        && ["", "b", "B", "kB", "KB", "mB", "MB", "gB", "GB", "tB", "TB"]
+            .contains(&&s[digits.len()..]);
+
+    if valid {
+        Ok(size_from_str(s))
+    } else {
+        Err(format!("invalid size `{}`", s))
+    }
+}
+
+// the shortest exact representation of a size
+fn size_value_str(sz: usize) -> String {
+    ["TB", "GB", "MB", "KB"]
+        .iter()
+        .zip([40, 30, 20, 10])
+        .find(|(_, shift)| sz != 0 && is_aligned(sz, 1 << shift))
+        .map(|(sfx, shift)| format!("{}{}", sz >> shift, sfx))
+        .unwrap_or_else(|| sz.to_string())
+}
+
+// <path>:<line>: <message>, without the line for the errors about the file itself
+pub fn error_str(path: &Path, (line, e): &ConfigError) -> String {
+    match line {
+        0 => format!("{}: {}", path.display(), e),
+        _ => format!("{}:{}: {}", path.display(), line, e),
+    }
+}
+
+fn parse_alloc_type(s: &str) -> Result<AllocType, String> {
+    [AllocType::BRK, AllocType::ANON]
+        .into_iter()
+        .find(|x| x.as_str() == s)
+        .ok_or_else(|| format!("unknown region type `{}` (expected brk or mmap)", s))
+}
+
+// split [start, end) by a page size spec, either a single page size or a list of
+// <page size>:<length> sub-ranges laid out from the start, the last one may leave out its length
+// to take the remainder, e.g. "1GB:2GB,2MB"
+fn split_page_sizes(
+    spec: &str,
+    start: usize,
+    end: usize,
+) -> Result<Vec<(usize, usize, usize)>, String> {
+    let parts = spec.split(',').map(|x| x.trim()).collect::<Vec<&str>>();
+    if parts.len() == 1 && !spec.contains(':') {
+        return Ok(vec![(parse_size_value(spec)?, start, end)]);
+    }
+
+    let mut ranges = Vec::new();
+    let mut from = start;
+    for (i, part) in parts.iter().enumerate() {
+        let (pagesz, len) = match part.split_once(':') {
+            Some((pagesz, len)) => (
+                parse_size_value(pagesz.trim())?,
+                parse_size_value(len.trim())?,
+            ),
+            None if i == parts.len() - 1 => (parse_size_value(part)?, end.saturating_sub(from)),
+            None => {
+                return Err(format!(
+                    "sub-range `{}` needs a length, only the last one takes the remainder",
+                    part
+                ))
+            }
+        };
+        if len == 0 {
+            return Err(format!("sub-range `{}` is empty", part));
+        }
+        if from.checked_add(len).is_none_or(|x| x > end) {
+            return Err(format!(
+                "sub-range `{}` ends past the end of the interval",
+                part
+            ));
+        }
+        ranges.push((pagesz, from, from + len));
+        from += len;
+    }
+    if from != end {
+        return Err(format!(
+            "the sub-ranges leave {} of the interval uncovered",
+            size_value_str(end - from)
+        ));
+    }
+
+    Ok(ranges)
+}
+
+// the entries of an interval, one per sub-range of its page size spec
+fn interval_entries(
+    line: usize,
+    region_type: &str,
+    pagesz: &str,
+    start: &str,
+    end: &str,
+) -> Result<Vec<IntervalEntry>, ConfigError> {
+    let parse = || -> Result<Vec<IntervalEntry>, String> {
+        let alloc_type = parse_alloc_type(region_type)?;
+        split_page_sizes(pagesz, parse_size_value(start)?, parse_size_value(end)?)?
+            .into_iter()
+            .map(|(pagesz, start, end)| {
+                Ok(IntervalEntry {
+                    alloc_type,
+                    interval: htlb_interval(pagesz, start, end)?,
+                    region: None,
+                    line,
+                })
+            })
+            .collect()
+    };
+
+    parse().map_err(|e| (line, e))
+}
+
+// strip a trailing comment, unless the # is quoted
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+// a basic string or an integer, which is all the schema uses
+fn parse_value(s: &str) -> Result<String, String> {
+    if let Some(inner) = s.strip_prefix('"') {
+        match inner.strip_suffix('"') {
+            Some(x) if !x.contains('"') && !x.contains('\\') => Ok(x.to_string()),
+            _ => Err(format!("invalid string {}", s)),
+        }
+    } else if !s.is_empty() && s.chars().all(|c| c.is_ascii_digit() || c == '_') {
+        Ok(s.replace('_', ""))
+    } else {
+        Err(format!(
+            "invalid value `{}` (expected a string or an integer)",
+            s
+        ))
+    }
+}
+
+impl PoolConfig {
+    // load a versioned config, or a legacy CSV one, reporting all the invalid intervals one per line
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        Self::errors_from_path(path).map_err(|errors| {
+            errors
+                .iter()
+                .map(|x| error_str(path, x))
+                .collect::<Vec<String>>()
+                .join("\n")
+        })
+    }
+
+    // load a config, the errors are sorted by line (0 for the file ones)
+    pub fn errors_from_path(path: &Path) -> Result<Self, Vec<ConfigError>> {
+        let content = fs::read_to_string(path).map_err(|e| vec![(0, e.to_string())])?;
+        Self::errors_from_str(&content)
+    }
+
+    // parse the contents of a config, the errors are sorted by line
+    pub fn errors_from_str(content: &str) -> Result<Self, Vec<ConfigError>> {
+        if Self::is_legacy(content) {
+            Self::from_csv_str(content)
+        } else {
+            Self::from_versioned_str(content)
+        }
+        .map_err(|mut errors| {
+            errors.sort_by_key(|(line, _)| *line);
+            errors
+        })
+    }
+
+    // legacy configs start with the CSV header
+    pub fn is_legacy(content: &str) -> bool {
+        content
+            .lines()
+            .next()
+            .is_some_and(|x| x.starts_with("type,"))
+    }
+
+    fn from_csv_str(content: &str) -> Result<Self, Vec<ConfigError>> {
+        let mut reader = csv::Reader::from_reader(content.as_bytes());
+        let headers = reader
+            .headers()
+            .map_err(|e| vec![(1, e.to_string())])?
+            .clone();
+
+        // invalid rows are skipped, so that the rest are still validated
+        let mut intervals = Vec::new();
+        let mut errors = Vec::new();
+        for rec in reader.records() {
+            let rec = match rec {
+                Ok(x) => x,
+                Err(e) => {
+                    let line = e.position().map_or(0, |x| x.line() as usize);
+                    errors.push((line, e.to_string()));
+                    continue;
+                }
+            };
+            let line = rec.position().map_or(0, |x| x.line() as usize);
+            let entry = rec
+                .deserialize::<CSVRecord>(Some(&headers))
+                .map_err(|e| (line, e.to_string()))
+                .and_then(|rec| {
+                    interval_entries(
+                        line,
+                        &rec.region_type,
+                        &rec.page_size,
+                        &rec.start_offset,
+                        &rec.end_offset,
+                    )
+                });
+
+            match entry {
+                Ok(x) => intervals.extend(x),
+                Err(e) => errors.push(e),
+            }
+        }
+
+        Self::validate(0, intervals, Vec::new(), errors)
+    }
+
+    fn from_versioned_str(content: &str) -> Result<Self, Vec<ConfigError>> {
+        Self::parse_versioned(content).map_err(|e| vec![e])?
+    }
+
+    // the syntax errors stop the parsing, the invalid intervals are collected
+    #[allow(clippy::type_complexity)]
+    fn parse_versioned(content: &str) -> Result<Result<Self, Vec<ConfigError>>, ConfigError> {
+        let mut version = None;
+        let mut tables: Vec<Table> = Vec::new();
+
+        for (i, raw) in content.lines().enumerate() {
+            let line = i + 1;
+            let text = strip_comment(raw).trim();
+
+            if text.is_empty() {
+                continue;
+            } else if text == "[[interval]]" {
+                tables.push(("interval", line, Vec::new()));
+                continue;
+            } else if text == "[[region]]" {
+                tables.push(("region", line, Vec::new()));
+                continue;
+            } else if text.starts_with('[') {
+                return Err((line, format!("unknown table {}", text)));
+            }
+
+            let (key, value) = text
+                .split_once('=')
+                .ok_or_else(|| (line, format!("expected `key = value`, got `{}`", text)))?;
+            let key = key.trim();
+            let value = parse_value(value.trim()).map_err(|e| (line, e))?;
+
+            match tables.last_mut() {
+                None if key == "version" => {
+                    version = Some(
+                        value
+                            .parse::<u32>()
+                            .map_err(|_| (line, format!("invalid version {}", value)))?,
+                    );
+                }
+                None => return Err((line, format!("unknown key `{}`", key))),
+                Some((kind, _, entries)) => {
+                    let keys = if *kind == "region" {
+                        &REGION_KEYS[..]
+                    } else {
+                        &INTERVAL_KEYS[..]
+                    };
+                    if !keys.contains(&key) {
+                        return Err((
+                            line,
+                            format!(
+                                "unknown {} key `{}` (expected one of {})",
+                                kind,
+                                key,
+                                keys.join(", ")
+                            ),
+                        ));
+                    }
+                    if entries.iter().any(|(k, _)| k == key) {
+                        return Err((line, format!("duplicate key `{}`", key)));
+                    }
+                    entries.push((key.to_string(), value));
+                }
+            }
+        }
+
+        let version = match version {
+            None => {
+                return Err((
+                    1,
+                    "missing `version`, legacy CSV configs can be converted with `mosalloc \
+                     config migrate`"
+                        .to_string(),
+                ))
+            }
+            Some(x) if x == 0 || x > CONFIG_VERSION => {
+                return Err((
+                    1,
+                    format!(
+                        "unsupported config version {} (supported: 1 - {})",
+                        x, CONFIG_VERSION
+                    ),
+                ))
+            }
+            Some(x) => x,
+        };
+
+        fn get<'a>((kind, line, entries): &'a Table, key: &str) -> Result<&'a str, ConfigError> {
+            entries
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+                .ok_or_else(|| (*line, format!("{} is missing `{}`", kind, key)))
+        }
+
+        let mut regions = Vec::new();
+        let mut errors = Vec::new();
+        for table in tables.iter().filter(|(kind, _, _)| *kind == "region") {
+            let line = &table.1;
+            if version < 2 {
+                errors.push((*line, "named regions need version 2".to_string()));
+                continue;
+            }
+            let entry = get(table, "name").and_then(|name| {
+                Ok(RegionEntry {
+                    name: name.to_string(),
+                    below: get(table, "below")
+                        .ok()
+                        .map(parse_size_value)
+                        .transpose()
+                        .map_err(|e| (*line, e))?,
+                    line: *line,
+                })
+            });
+            match entry {
+                Ok(x) => regions.push(x),
+                Err(e) => errors.push(e),
+            }
+        }
+
+        let (intervals, interval_errors): (Vec<_>, Vec<_>) = tables
+            .iter()
+            .filter(|(kind, _, _)| *kind == "interval")
+            .map(|table| {
+                let line = &table.1;
+                let get = |key: &str| get(table, key);
+
+                let mut entries = interval_entries(
+                    *line,
+                    get("type")?,
+                    get("page_size")?,
+                    get("start")?,
+                    get("end")?,
+                )?;
+                // optional sub-pool name and named region, shared by the sub-ranges
+                for entry in entries.iter_mut() {
+                    entry.interval.name = get("name").ok().map(|x| x.to_string());
+                    entry.region = get("region").ok().map(|x| x.to_string());
+                }
+                if get("region").is_ok() && version < 2 {
+                    return Err((*line, "named regions need version 2".to_string()));
+                }
+
+                Ok(entries)
+            })
+            .partition(|x: &Result<Vec<IntervalEntry>, ConfigError>| x.is_ok());
+
+        errors.extend(interval_errors.into_iter().filter_map(|x| x.err()));
+        Ok(Self::validate(
+            version,
+            intervals.into_iter().flatten().flatten().collect(),
+            regions,
+            errors,
+        ))
+    }
+
+    // make sure each region has intervals, listed in ascending order without overlaps and within
+    // the address space (and the bound of the region), along with the errors of the intervals
+    // that failed to parse
+    fn validate(
+        version: u32,
+        intervals: Vec<IntervalEntry>,
+        regions: Vec<RegionEntry>,
+        mut errors: Vec<ConfigError>,
+    ) -> Result<Self, Vec<ConfigError>> {
+        for (i, r) in regions.iter().enumerate() {
+            if let Some(x) = regions[..i].iter().find(|x| x.name == r.name) {
+                errors.push((
+                    r.line,
+                    format!("region {} is already defined at line {}", r.name, x.line),
+                ));
+            }
+        }
+        for x in intervals.iter() {
+            match &x.region {
+                Some(_) if x.alloc_type != AllocType::ANON => {
+                    errors.push((x.line, "only mmap intervals can have a region".to_string()))
+                }
+                Some(name) if !regions.iter().any(|r| r.name == *name) => {
+                    errors.push((x.line, format!("undefined region {}", name)))
+                }
+                _ => {}
+            }
+        }
+
+        for x in intervals.iter() {
+            if x.interval.end > MAX_POOL_END {
+                errors.push((
+                    x.line,
+                    format!(
+                        "interval end {:#x} is past the {:#x} end of the user address space",
+                        x.interval.end, MAX_POOL_END
+                    ),
+                ));
+            }
+        }
+
+        // the duplicates are reported above
+        let named = regions
+            .iter()
+            .enumerate()
+            .filter(|(i, x)| !regions[..*i].iter().any(|y| y.name == x.name))
+            .map(|(_, x)| (AllocType::ANON, Some(x.name.clone()), x.line));
+        for (alloc_type, name, line) in [(AllocType::BRK, None, 1), (AllocType::ANON, None, 1)]
+            .into_iter()
+            .chain(named)
+        {
+            let region = intervals
+                .iter()
+                .filter(|x| x.alloc_type == alloc_type && x.region == name)
+                .collect::<Vec<&IntervalEntry>>();
+
+            // the intervals that failed to parse may be the ones of the region
+            if region.is_empty() && errors.is_empty() {
+                errors.push((
+                    line,
+                    match &name {
+                        Some(name) => format!("no intervals in region {}", name),
+                        None => format!("no {} intervals", alloc_type.as_str()),
+                    },
+                ));
+            }
+
+            // the region is placed at an address of its own, it can't be larger than the bound
+            let below = regions
+                .iter()
+                .find(|x| Some(&x.name) == name.as_ref())
+                .and_then(|x| x.below);
+            if let (Some(below), Some(x)) = (below, region.iter().max_by_key(|x| x.interval.end)) {
+                if x.interval.end > below {
+                    errors.push((
+                        x.line,
+                        format!(
+                            "region {} doesn't fit below {:#x}",
+                            name.as_ref().unwrap(),
+                            below
+                        ),
+                    ));
+                }
+            }
+
+            // the row reaching the furthest so far, so that a short interval nested in a longer
+            // one doesn't hide the longer one from the rows after it
+            let mut furthest: Option<&IntervalEntry> = None;
+            for x in region.windows(2) {
+                let (prev, cur) = (x[0], x[1]);
+                if furthest.is_none_or(|f| prev.interval.end > f.interval.end) {
+                    furthest = Some(prev);
+                }
+
+                if cur.interval.start < prev.interval.start {
+                    errors.push((
+                        cur.line,
+                        format!(
+                            "interval isn't in ascending order, it starts before the one at line {}",
+                            prev.line
+                        ),
+                    ));
+                } else if let Some(f) = furthest.filter(|f| f.interval.end > cur.interval.start) {
+                    errors.push((
+                        cur.line,
+                        format!("interval overlaps the one at line {}", f.line),
+                    ));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(Self {
+                version,
+                intervals,
+                regions,
+            })
+        } else {
+            Err(errors)
+        }
+    }
+
+    // make sure the free maps can hold a range per interval of their region, the file region
+    // has a single interval (the file pool)
+    pub fn check_ffa(&self, anon_ffa_size: usize, file_ffa_size: usize) -> Result<(), ConfigError> {
+        if file_ffa_size == 0 {
+            return Err((0, "the file FFA size can't be 0".to_string()));
+        }
+
+        match self
+            .intervals
+            .iter()
+            .filter(|x| x.alloc_type == AllocType::ANON)
+            .nth(anon_ffa_size)
+        {
+            Some(x) => Err((
+                x.line,
+                format!(
+                    "the mmap intervals exceed the anon FFA size ({})",
+                    anon_ffa_size
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // the pool of the default region of a type, with its intervals sorted
+    pub fn pool(&self, alloc_type: AllocType) -> Pool {
+        self.region_pool(alloc_type, None)
+    }
+
+    // the pools of the named anon regions
+    pub fn named_pools(&self) -> Vec<(RegionEntry, Pool)> {
+        self.regions
+            .iter()
+            .map(|x| (x.clone(), self.region_pool(AllocType::ANON, Some(&x.name))))
+            .collect()
+    }
+
+    fn region_pool(&self, alloc_type: AllocType, name: Option<&str>) -> Pool {
+        let mut intervals = self
+            .intervals
+            .iter()
+            .filter(|x| x.alloc_type == alloc_type && x.region.as_deref() == name)
+            .map(|x| x.interval.clone())
+            .collect::<Vec<Interval>>();
+        intervals.sort_by_key(|k| k.start);
+
+        Pool {
+            alloc_type,
+            intervals,
+        }
+    }
+
+    // serialize to the current versioned schema
+    pub fn to_versioned(&self) -> String {
+        let mut out = format!("# mosalloc pool config\nversion = {}\n", CONFIG_VERSION);
+
+        for x in self.regions.iter() {
+            out += &format!("\n[[region]]\nname = \"{}\"\n", x.name);
+            if let Some(below) = x.below {
+                out += &format!("below = \"{}\"\n", size_value_str(below));
+            }
+        }
+
+        for x in self.intervals.iter() {
+            out += &format!(
+                "\n[[interval]]\ntype = \"{}\"\npage_size = \"{}\"\nstart = \"{}\"\nend = \"{}\"\n",
+                x.alloc_type.as_str(),
+                size_value_str(x.interval.pagesz),
+                size_value_str(x.interval.start),
+                size_value_str(x.interval.end)
+            );
+            if let Some(name) = &x.interval.name {
+                out += &format!("name = \"{}\"\n", name);
+            }
+            if let Some(region) = &x.region {
+                out += &format!("region = \"{}\"\n", region);
+            }
+        }
+
+        out
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use nix::errno::Errno;
+use nix::libc;
+
+use super::htlb::{HookType, MosallocConfig};
+
+const PAGE: usize = 4096;
+
+const RW: usize = (libc::PROT_READ | libc::PROT_WRITE) as usize;
+const ANON: usize = (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS) as usize;
+
+// an edge case of a hooked syscall, issued raw so that the seccomp filter sees it as is, returning
+// the outcome to compare: the errno, the return value, or only whether an address was returned
+// since the placement differs
+pub struct Case {
+    pub name: &'static str,
+    run: fn(&Scratch) -> String,
+}
+
+// page-aligned ranges the cases work on: a mapped one and one that was mapped and unmapped again,
+// three pages each
+pub struct Scratch {
+    mapped: usize,
+    unmapped: usize,
+}
+
+unsafe fn sys(nr: libc::c_long, args: [usize; 6]) -> isize {
+    libc::syscall(nr, args[0], args[1], args[2], args[3], args[4], args[5]) as isize
+}
+
+fn outcome(ret: isize) -> String {
+    match ret {
+        -1 => format!("errno:{:?}", Errno::last()),
+        _ => format!("ret:{}", ret),
+    }
+}
+
+fn addr_outcome(ret: isize) -> String {
+    match ret {
+        -1 => format!("errno:{:?}", Errno::last()),
+        _ => "addr".to_string(),
+    }
+}
+
+fn mmap(args: [usize; 6]) -> String {
+    addr_outcome(unsafe { sys(libc::SYS_mmap, args) })
+}
+
+fn munmap(addr: usize, len: usize) -> String {
+    outcome(unsafe { sys(libc::SYS_munmap, [addr, len, 0, 0, 0, 0]) })
+}
+
+fn mprotect(addr: usize, len: usize, prot: usize) -> String {
+    outcome(unsafe { sys(libc::SYS_mprotect, [addr, len, prot, 0, 0, 0]) })
+}
+
+fn madvise(addr: usize, len: usize, advice: usize) -> String {
+    outcome(unsafe { sys(libc::SYS_madvise, [addr, len, advice, 0, 0, 0]) })
+}
+
+fn mremap(addr: usize, old: usize, new: usize, flags: usize, new_addr: usize) -> String {
+    addr_outcome(unsafe { sys(libc::SYS_mremap, [addr, old, new, flags, new_addr, 0]) })
+}
+
+// the raw brk returns the new break, or the current one if it can't be moved, relative to the
+// current one since the heap is placed differently
+fn brk(addr: impl Fn(usize) -> usize) -> String {
+    unsafe {
+        let cur = sys(libc::SYS_brk, [0; 6]) as usize;
+        let ret = sys(libc::SYS_brk, [addr(cur), 0, 0, 0, 0, 0]) as usize;
+        // restore the break moved by the case
+        sys(libc::SYS_brk, [cur, 0, 0, 0, 0, 0]);
+        match ret.wrapping_sub(cur) as isize {
+            0 => "ret:brk".to_string(),
+            x => format!("ret:brk{:+}", x),
+        }
+    }
+}
+
+pub const CASES: &[Case] = &[
+    Case {
+        name: "mmap-ok",
+        run: |_| mmap([0, 3 * PAGE, RW, ANON, usize::MAX, 0]),
+    },
+    Case {
+        name: "mmap-len-0",
+        run: |_| mmap([0, 0, RW, ANON, usize::MAX, 0]),
+    },
+    Case {
+        name: "mmap-no-type",
+        run: |_| mmap([0, PAGE, RW, libc::MAP_ANONYMOUS as usize, usize::MAX, 0]),
+    },
+    Case {
+        name: "mmap-bad-flags",
+        run: |_| {
+            let flags = libc::MAP_SHARED_VALIDATE | libc::MAP_ANONYMOUS | libc::MAP_SYNC;
+            mmap([0, PAGE, RW, flags as usize, usize::MAX, 0])
+        },
+    },
+    Case {
+        name: "mmap-fixed-unaligned",
+        run: |x| {
+            let flags = ANON | libc::MAP_FIXED as usize;
+            mmap([x.unmapped + 1, PAGE, RW, flags, usize::MAX, 0])
+        },
+    },
+    Case {
+        name: "mmap-huge-len",
+        run: |_| mmap([0, usize::MAX & !(PAGE - 1), RW, ANON, usize::MAX, 0]),
+    },
+    Case {
+        name: "mmap-bad-fd",
+        run: |_| mmap([0, PAGE, RW, libc::MAP_PRIVATE as usize, usize::MAX, 0]),
+    },
+    Case {
+        name: "mmap-unaligned-offset",
+        run: |_| mmap([0, PAGE, RW, ANON, usize::MAX, 1]),
+    },
+    Case {
+        name: "mmap-huge-offset",
+        run: |_| mmap([0, 2 * PAGE, RW, ANON, usize::MAX, usize::MAX & !(PAGE - 1)]),
+    },
+    Case {
+        name: "munmap-unaligned",
+        run: |x| munmap(x.mapped + 1, PAGE),
+    },
+    Case {
+        name: "munmap-len-0",
+        run: |x| munmap(x.mapped, 0),
+    },
+    Case {
+        name: "munmap-huge-len",
+        run: |x| munmap(x.mapped, usize::MAX & !(PAGE - 1)),
+    },
+    Case {
+        name: "munmap-unmapped",
+        run: |x| munmap(x.unmapped, PAGE),
+    },
+    Case {
+        name: "mprotect-unaligned",
+        run: |x| mprotect(x.mapped + 1, PAGE, RW),
+    },
+    Case {
+        name: "mprotect-len-0",
+        run: |x| mprotect(x.mapped, 0, RW),
+    },
+    Case {
+        name: "mprotect-bad-prot",
+        run: |x| mprotect(x.mapped, PAGE, 0x100),
+    },
+    Case {
+        name: "mprotect-unmapped",
+        run: |x| mprotect(x.unmapped, PAGE, RW),
+    },
+    Case {
+        name: "madvise-unaligned",
+        run: |x| madvise(x.mapped + 1, PAGE, libc::MADV_DONTNEED as usize),
+    },
+    Case {
+        name: "madvise-len-0",
+        run: |x| madvise(x.mapped, 0, libc::MADV_DONTNEED as usize),
+    },
+    Case {
+        name: "madvise-bad-advice",
+        run: |x| madvise(x.mapped, PAGE, 12345),
+    },
+    Case {
+        name: "madvise-unmapped",
+        run: |x| madvise(x.unmapped, PAGE, libc::MADV_DONTNEED as usize),
+    },
+    Case {
+        name: "mremap-unaligned",
+        run: |x| {
+            mremap(
+                x.mapped + 1,
+                PAGE,
+                2 * PAGE,
+                libc::MREMAP_MAYMOVE as usize,
+                0,
+            )
+        },
+    },
+    Case {
+        name: "mremap-bad-flags",
+        run: |x| mremap(x.mapped, PAGE, 2 * PAGE, 0x80, 0),
+    },
+    Case {
+        name: "mremap-fixed-no-maymove",
+        run: |x| {
+            mremap(
+                x.mapped,
+                PAGE,
+                PAGE,
+                libc::MREMAP_FIXED as usize,
+                x.unmapped,
+            )
+        },
+    },
+    Case {
+        name: "mremap-new-len-0",
+        run: |x| mremap(x.mapped, PAGE, 0, libc::MREMAP_MAYMOVE as usize, 0),
+    },
+    Case {
+        name: "mremap-unmapped",
+        run: |x| mremap(x.unmapped, PAGE, 2 * PAGE, libc::MREMAP_MAYMOVE as usize, 0),
+    },
+    Case {
+        name: "brk-query",
+        run: |_| brk(|_| 0),
+    },
+    Case {
+        name: "brk-grow",
+        run: |_| brk(|cur| cur + PAGE),
+    },
+    Case {
+        name: "brk-below-start",
+        run: |_| brk(|_| PAGE),
+    },
+    Case {
+        name: "brk-huge",
+        run: |_| brk(|_| usize::MAX & !(PAGE - 1)),
+    },
+];
+
+// run the cases in the calling process, returning (name, outcome) in order
+pub fn run_cases() -> Vec<(&'static str, String)> {
+    let map = || unsafe { sys(libc::SYS_mmap, [0, 3 * PAGE, RW, ANON, usize::MAX, 0]) as usize };
+    let scratch = Scratch {
+        mapped: map(),
+        unmapped: map(),
+    };
+    unsafe { sys(libc::SYS_munmap, [scratch.unmapped, 3 * PAGE, 0, 0, 0, 0]) };
+
+    CASES.iter().map(|x| (x.name, (x.run)(&scratch))).collect()
+}
+
+// run the cases in a child (the current executable with `args`), natively or under the given
+// backend, and collect their outcomes
+pub fn run_suite(
+    config: Option<(&MosallocConfig, HookType)>,
+    prefix: &str,
+    lib: &str,
+    args: &[&str],
+) -> Result<HashMap<String, String>, String> {
+    let label = config.map_or("native", |(_, hook)| hook.as_str());
+    let out = env::temp_dir().join(format!(
+        "mosalloc-conformance-{}-{}",
+        std::process::id(),
+        label
+    ));
+
+    let exe = env::current_exe().map_err(|e| e.to_string())?;
+    let mut cmd = Command::new(&exe);
+    cmd.args(args).arg(&out);
+    if let Some((config, hook)) = config {
+        let config = MosallocConfig {
+            hook,
+            ..config.clone()
+        };
+        cmd.envs(config.env_vars(prefix)).env("LD_PRELOAD", lib);
+    }
+    let status = cmd
+        .status()
+        .map_err(|e| format!("{}: {}", exe.display(), e))?;
+
+    let results = fs::read_to_string(&out).map_err(|e| format!("{}: {}", label, e));
+    let _ = fs::remove_file(&out);
+    if !status.success() {
+        println!("{}: cases exited with {}", label, status);
+    }
+
+    Ok(results?
+        .lines()
+        .filter_map(|x| x.split_once(' '))
+        .map(|(name, outcome)| (name.to_string(), outcome.to_string()))
+        .collect())
+}
+
+// write the outcomes of the cases to path, one `name outcome` line each
+pub fn write_cases(path: &Path) -> Result<(), String> {
+    let lines = run_cases()
+        .iter()
+        .map(|(name, outcome)| format!("{} {}\n", name, outcome))
+        .collect::<String>();
+    fs::write(path, lines).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// the cases whose outcome differs from the native one, or that didn't run at all
+pub fn diff_suites(
+    native: &HashMap<String, String>,
+    hooked: &HashMap<String, String>,
+) -> Vec<String> {
+    CASES
+        .iter()
+        .filter_map(|x| {
+            let n = native.get(x.name).map_or("missing", |x| x.as_str());
+            let h = hooked.get(x.name).map_or("missing", |x| x.as_str());
+            (n != h).then(|| format!("{}: native {}, mosalloc {}", x.name, n, h))
+        })
+        .collect()
+}
@@ -0,0 +1,420 @@
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use super::htlb::AllocType;
+use super::schema::{from_fields, to_fields, SCHEMA_VERSION};
+
+// followed by the schema version of the stats
+pub const STATS_HEADER: &str = "# mosalloc stats v";
+pub const POLICIES_HEADER: &str = "# mosalloc policies v1";
+
+// the policies that can be changed at runtime on the control socket
+pub const POLICY_KEYS: [&str; 7] = [
+    "reclaim",
+    "watermarks",
+    "mmap_threshold",
+    "brk_limit",
+    "anon_limit",
+    "file_limit",
+    "disabled_intervals",
+];
+
+// live statistics of a single region, the counters are cumulative since startup
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RegionStats {
+    pub alloc_type: AllocType,
+    // the name of a named anon region, None for the default ones
+    pub name: Option<String>,
+    pub len: usize,
+    pub allocated: usize,
+    pub peak: usize,
+    // allocated bytes handed out to the application (not sitting in the thread caches), the
+    // high-water mark and the program break (heap only, 0 otherwise), as offsets from the start
+    pub used: usize,
+    pub high_water: usize,
+    pub brk: usize,
+    // bytes backed by hugepages (hugetlb pages mapped, or THPs)
+    pub huge: usize,
+    pub allocs: usize,
+    pub frees: usize,
+    pub bytes_allocated: usize,
+    pub lock_acquired: usize,
+    pub lock_contended: usize,
+    // residency according to smaps: the resident base pages and THPs, the hugetlb pages (not in
+    // the rss) and the swapped out bytes
+    pub rss: usize,
+    pub private_hugetlb: usize,
+    pub swap: usize,
+    // health of each pool interval, comma separated: ok, degraded (pages fell back to base pages
+    // or failed to map) or disabled (no new allocations placed in it)
+    pub intervals: String,
+    // start address of the region
+    pub start: usize,
+    // pool hugepages with nothing allocated on them and the ones partially used, and the
+    // allocations the fill placement put into partially used hugepages
+    pub free_hugepages: usize,
+    pub partial_hugepages: usize,
+    pub fill_placed: usize,
+    // free map ranges, the bytes of storage holding them, and the inserts refused at its hard cap
+    pub freemap_ranges: usize,
+    pub freemap_bytes: usize,
+    pub freemap_refused: usize,
+    // bytes the region grew by past its pool, and in how many steps
+    pub grown: usize,
+    pub grow_events: usize,
+}
+
+impl RegionStats {
+    // the region type, along with the name of a named region (e.g. mmap:low)
+    pub fn label(&self) -> String {
+        region_label(self.alloc_type, self.name.as_deref())
+    }
+}
+
+// the label of a region, as found in the stats and snapshot files
+pub fn region_label(alloc_type: AllocType, name: Option<&str>) -> String {
+    match name {
+        Some(name) => format!("{}:{}", alloc_type.as_str(), name),
+        None => alloc_type.as_str().to_string(),
+    }
+}
+
+// the (type, name) of a region label
+pub fn parse_region_label(label: &str) -> Option<(AllocType, Option<String>)> {
+    let (alloc_type, name) = match label.split_once(':') {
+        Some((x, name)) => (x, Some(name.to_string())),
+        None => (label, None),
+    };
+
+    [AllocType::BRK, AllocType::ANON, AllocType::FILE]
+        .into_iter()
+        .find(|x| x.as_str() == alloc_type)
+        .map(|x| (x, name))
+}
+
+// usage of the internal allocator of libmosalloc, its arena and the larger mmap'd allocations
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InternalStats {
+    pub arena_size: usize,
+    pub arena_allocated: usize,
+    pub arena_peak: usize,
+    pub mmap_allocated: usize,
+    pub mmap_overhead: usize,
+    // allocations that failed, because of an exhausted arena or an unsupported alignment
+    pub failed: usize,
+    // address space of all the mosalloc own mappings (arena, metadata, trace buffers ...), and
+    // their rss when the kernel could name them
+    pub mapped: usize,
+    pub rss: Option<usize>,
+}
+
+// rank env vars of the common launchers
+const RANK_VARS: [&str; 4] = [
+    "SLURM_PROCID",
+    "PMI_RANK",
+    "OMPI_COMM_WORLD_RANK",
+    "PMIX_RANK",
+];
+// job id env vars of the common batch systems
+const JOB_VARS: [&str; 3] = ["SLURM_JOB_ID", "PBS_JOBID", "LSB_JOBID"];
+
+// statistics of an instrumented process, as served on its control socket and pushed to the
+// collector
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProcessStats {
+    pub pid: i32,
+    pub host: String,
+    pub rank: Option<usize>,
+    pub job: Option<String>,
+    // the final stats, sent by an exiting process
+    pub last: bool,
+    pub regions: Vec<RegionStats>,
+    pub internal: Option<InternalStats>,
+}
+
+impl ProcessStats {
+    // empty stats of the current process, along with its host, rank and job
+    pub fn current() -> Self {
+        let var = |vars: &[&str]| vars.iter().find_map(|x| env::var(x).ok());
+
+        Self {
+            pid: std::process::id() as i32,
+            host: fs::read_to_string("/proc/sys/kernel/hostname")
+                .map(|x| x.trim().to_string())
+                .unwrap_or_else(|_| "localhost".to_string()),
+            rank: var(&RANK_VARS).and_then(|x| x.parse().ok()),
+            job: var(&JOB_VARS),
+            last: false,
+            regions: Vec::new(),
+            internal: None,
+        }
+    }
+
+    // the region and internal stats lines are the key=value fields of the records, so that the
+    // readers don't depend on their order and count
+    pub fn to_text(&self) -> String {
+        let mut out = format!(
+            "{}{}\npid {}\nhost {}\n",
+            STATS_HEADER, SCHEMA_VERSION, self.pid, self.host
+        );
+        if let Some(rank) = self.rank {
+            out += &format!("rank {}\n", rank);
+        }
+        if let Some(job) = &self.job {
+            out += &format!("job {}\n", job);
+        }
+        if self.last {
+            out += "last\n";
+        }
+
+        let line = |kind: &str, fields: Vec<(String, String)>| {
+            fields.iter().fold(kind.to_string(), |out, (k, v)| {
+                format!("{} {}={}", out, k, v)
+            }) + "\n"
+        };
+        for r in self.regions.iter() {
+            out += &line("region", to_fields(r).unwrap());
+        }
+        if let Some(i) = &self.internal {
+            out += &line("internal", to_fields(i).unwrap());
+        }
+
+        out
+    }
+
+    pub fn from_text(content: &str) -> Result<Self, String> {
+        let mut lines = content.lines();
+
+        let version = lines
+            .next()
+            .and_then(|x| x.strip_prefix(STATS_HEADER))
+            .and_then(|x| x.parse::<u32>().ok())
+            .ok_or_else(|| "not a mosalloc stats reply".to_string())?;
+
+        let mut stats = ProcessStats::default();
+        for line in lines {
+            let fields = line.split_whitespace().collect::<Vec<&str>>();
+            let parse_err = || format!("invalid stats line: {}", line);
+
+            match fields.as_slice() {
+                ["pid", pid] => stats.pid = pid.parse().map_err(|_| parse_err())?,
+                ["host", host] => stats.host = host.to_string(),
+                ["rank", rank] => stats.rank = Some(rank.parse().map_err(|_| parse_err())?),
+                ["job", job] => stats.job = Some(job.to_string()),
+                ["last"] => stats.last = true,
+                ["region", fields @ ..] if version > 1 => stats
+                    .regions
+                    .push(record(fields).map_err(|e| parse_err() + &e)?),
+                ["internal", fields @ ..] if version > 1 => {
+                    stats.internal = Some(record(fields).map_err(|e| parse_err() + &e)?)
+                }
+                // v1 lines are positional, the usage counters are missing in the older ones
+                ["region", label, counters @ ..] if counters.len() == 9 || counters.len() == 12 => {
+                    let (alloc_type, name) = parse_region_label(label).ok_or_else(parse_err)?;
+                    let c = counters
+                        .iter()
+                        .map(|x| x.parse::<usize>().map_err(|_| parse_err()))
+                        .collect::<Result<Vec<usize>, String>>()?;
+
+                    stats.regions.push(RegionStats {
+                        alloc_type,
+                        name,
+                        len: c[0],
+                        allocated: c[1],
+                        peak: c[2],
+                        huge: c[3],
+                        allocs: c[4],
+                        frees: c[5],
+                        bytes_allocated: c[6],
+                        lock_acquired: c[7],
+                        lock_contended: c[8],
+                        used: c.get(9).copied().unwrap_or(c[1]),
+                        high_water: c.get(10).copied().unwrap_or_default(),
+                        brk: c.get(11).copied().unwrap_or_default(),
+                        ..Default::default()
+                    });
+                }
+                ["internal", counters @ ..] if counters.len() == 6 => {
+                    let c = counters
+                        .iter()
+                        .map(|x| x.parse::<usize>().map_err(|_| parse_err()))
+                        .collect::<Result<Vec<usize>, String>>()?;
+
+                    stats.internal = Some(InternalStats {
+                        arena_size: c[0],
+                        arena_allocated: c[1],
+                        arena_peak: c[2],
+                        mmap_allocated: c[3],
+                        mmap_overhead: c[4],
+                        failed: c[5],
+                        ..Default::default()
+                    });
+                }
+                _ => return Err(parse_err()),
+            }
+        }
+
+        Ok(stats)
+    }
+}
+
+// a record from the key=value fields of a stats line
+fn record<T: DeserializeOwned>(fields: &[&str]) -> Result<T, String> {
+    let fields = fields
+        .iter()
+        .map(|x| x.split_once('='))
+        .collect::<Option<Vec<(&str, &str)>>>()
+        .ok_or_else(|| " (expected key=value fields)".to_string())?;
+
+    from_fields(&fields).map_err(|e| format!(" ({})", e))
+}
+
+// the control socket of a process in the given directory
+pub fn socket_path(dir: &Path, pid: i32) -> PathBuf {
+    dir.join(format!("mosalloc-{}.sock", pid))
+}
+
+// (pid, socket path) of the control sockets of the live processes in the given directory
+pub fn control_sockets(dir: &Path) -> Vec<(i32, PathBuf)> {
+    let mut out = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|x| x.ok())
+                .filter_map(|x| {
+                    let name = x.file_name().into_string().ok()?;
+                    let pid = name
+                        .strip_prefix("mosalloc-")?
+                        .strip_suffix(".sock")?
+                        .parse::<i32>()
+                        .ok()?;
+                    // skip the stale sockets of processes that didn't exit cleanly
+                    Path::new(&format!("/proc/{}", pid))
+                        .exists()
+                        .then_some((pid, x.path()))
+                })
+                .collect::<Vec<(i32, PathBuf)>>()
+        })
+        .unwrap_or_default();
+
+    out.sort();
+    out
+}
+
+// send a single line request on the control socket of a process, returns the whole reply
+fn request(path: &Path, req: &str) -> Result<String, String> {
+    let err = |e: std::io::Error| format!("{}: {}", path.display(), e);
+
+    let mut stream = UnixStream::connect(path).map_err(err)?;
+    stream
+        .write_all(format!("{}\n", req).as_bytes())
+        .and_then(|_| stream.shutdown(Shutdown::Write))
+        .map_err(err)?;
+
+    let mut content = String::new();
+    stream.read_to_string(&mut content).map_err(err)?;
+
+    Ok(content)
+}
+
+// fetch the current stats of a process from its control socket
+pub fn query(path: &Path) -> Result<ProcessStats, String> {
+    let content = request(path, "stats")?;
+
+    ProcessStats::from_text(&content).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+// the current (key, value) of the runtime policies of a process
+pub fn policies(path: &Path) -> Result<Vec<(String, String)>, String> {
+    let content = request(path, "policies")?;
+    let mut lines = content.lines();
+
+    if lines.next() != Some(POLICIES_HEADER) {
+        return Err(format!("{}: not a mosalloc policies reply", path.display()));
+    }
+
+    Ok(lines
+        .map(|x| {
+            let (key, value) = x.split_once(' ').unwrap_or((x, ""));
+            (key.to_string(), value.to_string())
+        })
+        .collect())
+}
+
+// change a runtime policy of a process, e.g. (reclaim, release)
+pub fn set_policy(path: &Path, key: &str, value: &str) -> Result<(), String> {
+    let reply = request(path, &format!("set {} {}", key, value))?;
+
+    match reply.trim() {
+        "ok" => Ok(()),
+        x => Err(format!(
+            "{}: {}",
+            path.display(),
+            x.strip_prefix("error ").unwrap_or(x)
+        )),
+    }
+}
+
+// run a compaction pass on a process, returns a line per anon region
+pub fn compact(path: &Path) -> Result<Vec<String>, String> {
+    let reply = request(path, "compact")?;
+    let mut lines = reply.lines();
+
+    match lines.next() {
+        Some("ok") => Ok(lines.map(String::from).collect()),
+        x => {
+            let x = x.unwrap_or_default();
+            Err(format!(
+                "{}: {}",
+                path.display(),
+                x.strip_prefix("error ").unwrap_or(x)
+            ))
+        }
+    }
+}
+
+// the page size backing addr in a process: as planned by its bookkeeping, and as the kernel backs
+// it if that can be told
+pub fn page_size_of(path: &Path, addr: usize) -> Result<(usize, Option<usize>), String> {
+    let reply = request(path, &format!("pagesize 0x{:x}", addr))?;
+    let err = |x: &str| {
+        format!(
+            "{}: {}",
+            path.display(),
+            x.strip_prefix("error ").unwrap_or(x)
+        )
+    };
+
+    match reply.split_whitespace().collect::<Vec<&str>>().as_slice() {
+        ["ok", planned, observed] => Ok((
+            planned.parse::<usize>().map_err(|_| err(reply.trim()))?,
+            observed.parse::<usize>().ok(),
+        )),
+        _ => Err(err(reply.trim())),
+    }
+}
+
+// push stats to a collector, either a unix socket path (unix:<path>) or a TCP address
+// (<host>:<port>)
+pub fn push(collector: &str, stats: &ProcessStats) -> Result<(), String> {
+    let text = stats.to_text();
+    let err = |e: std::io::Error| format!("{}: {}", collector, e);
+
+    match collector.strip_prefix("unix:") {
+        Some(path) => UnixStream::connect(path)
+            .and_then(|mut x| x.write_all(text.as_bytes()))
+            .map_err(err),
+        None => TcpStream::connect(collector)
+            .and_then(|mut x| x.write_all(text.as_bytes()))
+            .map_err(err),
+    }
+}
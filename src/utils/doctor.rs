@@ -0,0 +1,251 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+
+use super::htlb::{get_htlb_surplus_pages, get_htlb_usage, supported_htlb_sizes};
+use super::hugetlbfs::{hugetlbfs_mounts, HugetlbfsMount};
+use super::misc::size_to_str;
+use super::session::{state_file, StateFile};
+
+// the cleanup of a leak, applied once confirmed
+#[derive(Debug, Clone)]
+pub enum Fix {
+    // remove a hugetlbfs file, its pages are freed unless still mapped
+    RemoveFile(PathBuf),
+    // unmount an empty private hugetlbfs mount
+    Unmount(HugetlbfsMount),
+    // restore the node the reservation of a session was taken from, and drop the session
+    Release(String),
+}
+
+impl Fix {
+    pub fn describe(&self) -> String {
+        match self {
+            Fix::RemoveFile(path) => format!("remove {}", path.display()),
+            Fix::Unmount(mnt) => format!("unmount {}", mnt.path.display()),
+            Fix::Release(name) => format!("release the reservation of {}", name),
+        }
+    }
+
+    pub fn apply(&self) -> Result<(), String> {
+        match self {
+            Fix::RemoveFile(path) => fs::remove_file(path).map_err(|e| e.to_string()),
+            Fix::Unmount(mnt) => mnt.unmount().map_err(|e| e.to_string()),
+            Fix::Release(name) => {
+                let mut state = StateFile::open(&state_file())?;
+                let session = state
+                    .remove(name)
+                    .ok_or_else(|| format!("{}: no such session", name))?;
+                session.saved.restore()?;
+                state.save()
+            }
+        }
+    }
+}
+
+// a problem found by the doctor, along with its cleanup if there's a safe one
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub problem: String,
+    pub fix: Option<Fix>,
+}
+
+// whether a process still exists, the ones of other users included
+fn alive(pid: i32) -> bool {
+    !matches!(kill(Pid::from_raw(pid), None), Err(Errno::ESRCH))
+}
+
+// the pid of a mosalloc-<pid>-<n> hugetlbfs temp file, see HugetlbfsMount::tmpfile
+fn tmpfile_pid(name: &str) -> Option<i32> {
+    let (pid, nr) = name.strip_prefix("mosalloc-")?.split_once('-')?;
+    nr.parse::<usize>().ok()?;
+    pid.parse::<i32>().ok()
+}
+
+// the processes with hugetlb mappings (flagged huge in their numa_maps), and the ones that
+// couldn't be inspected
+fn hugetlb_users() -> (Vec<i32>, usize) {
+    let mut users = Vec::new();
+    let mut unreadable = 0;
+
+    for entry in fs::read_dir("/proc").into_iter().flatten().flatten() {
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|x| x.parse::<i32>().ok())
+        else {
+            continue;
+        };
+        match fs::read_to_string(entry.path().join("numa_maps")) {
+            Ok(maps)
+                if maps
+                    .lines()
+                    .any(|x| x.split_whitespace().any(|x| x == "huge")) =>
+            {
+                users.push(pid)
+            }
+            Ok(_) => (),
+            Err(_) => unreadable += 1,
+        }
+    }
+
+    (users, unreadable)
+}
+
+// the reservations of the state file, the ones of crashed run_mosalloc runs are leaked
+fn check_sessions(findings: &mut Vec<Finding>) {
+    let path = state_file();
+    if !path.exists() {
+        return;
+    }
+    let state = match StateFile::open(&path) {
+        Ok(x) => x,
+        Err(e) => {
+            findings.push(Finding {
+                problem: format!("reservations: {}", e),
+                fix: None,
+            });
+            return;
+        }
+    };
+
+    let sizes = supported_htlb_sizes();
+    for s in state.sessions.iter() {
+        let pages = sizes
+            .iter()
+            .zip(s.pages.iter())
+            .filter(|x| *x.1 > 0)
+            .map(|(&sz, nr)| format!("{} {} pages", nr, size_to_str(sz)))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        match s.pid {
+            Some(pid) if !alive(pid) => findings.push(Finding {
+                problem: format!(
+                    "reservations: {} of the crashed run_mosalloc {} still held on node {}",
+                    pages, pid, s.saved.node
+                ),
+                fix: Some(Fix::Release(s.name.clone())),
+            }),
+            Some(pid) => println!("reservations: {} held by run_mosalloc {}", pages, pid),
+            None => println!(
+                "reservations: session {} keeps {} on node {} after {} run(s), released with `reserve_huge_pages release {}`",
+                s.name, pages, s.saved.node, s.runs, s.name
+            ),
+        }
+    }
+}
+
+// the mosalloc temp files left behind by crashed processes, and the empty private mounts;
+// returns the bytes of hugepages held by the files, per page size
+fn check_hugetlbfs(findings: &mut Vec<Finding>) -> Vec<(usize, usize)> {
+    let mut held = Vec::new();
+
+    for mnt in hugetlbfs_mounts() {
+        // see mount_private
+        let private = mnt
+            .path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .is_some_and(|x| x.starts_with("mosalloc-hugetlbfs-"));
+        let Ok(entries) = fs::read_dir(&mnt.path) else {
+            continue;
+        };
+
+        let mut files = 0;
+        for entry in entries.flatten() {
+            files += 1;
+            let bytes = entry.metadata().map_or(0, |x| x.blocks() as usize * 512);
+            held.push((mnt.pagesz, bytes));
+
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some(pid) = tmpfile_pid(&name).filter(|x| !alive(*x)) {
+                findings.push(Finding {
+                    problem: format!(
+                        "hugetlbfs: {} of the exited process {} holds {}",
+                        entry.path().display(),
+                        pid,
+                        size_to_str(bytes)
+                    ),
+                    fix: Some(Fix::RemoveFile(entry.path())),
+                });
+            }
+        }
+
+        if private && files == 0 {
+            findings.push(Finding {
+                problem: format!(
+                    "hugetlbfs: private {} mount {} is empty",
+                    size_to_str(mnt.pagesz),
+                    mnt.path.display()
+                ),
+                fix: Some(Fix::Unmount(mnt.clone())),
+            });
+        }
+    }
+
+    held
+}
+
+// the hugepages in use or reserved while no process maps any and no hugetlbfs file holds them,
+// e.g. the ones of SysV shm segments outliving their processes
+fn check_counters(findings: &mut Vec<Finding>, held: &[(usize, usize)]) {
+    let (users, unreadable) = hugetlb_users();
+
+    let mut used = 0;
+    for &sz in supported_htlb_sizes().iter() {
+        let Ok(usage) = get_htlb_usage(sz) else {
+            continue;
+        };
+        let files = held
+            .iter()
+            .filter(|x| x.0 == sz)
+            .map(|x| x.1)
+            .sum::<usize>();
+        println!(
+            "{} pages: {} total, {} free, {} reserved, {} surplus, {} in hugetlbfs files",
+            size_to_str(sz),
+            usage.total,
+            usage.free,
+            usage.rsvd,
+            get_htlb_surplus_pages(sz).unwrap_or(0),
+            size_to_str(files)
+        );
+        used += usage.used().saturating_sub(files / sz) * sz;
+    }
+    println!(
+        "processes with hugetlb mappings: {:?}{}",
+        users,
+        if unreadable > 0 {
+            format!(" ({} processes not readable, run as root)", unreadable)
+        } else {
+            String::new()
+        }
+    );
+
+    if used > 0 && users.is_empty() && unreadable == 0 {
+        findings.push(Finding {
+            problem: format!(
+                "counters: {} of hugepages in use or reserved with no process mapping them and no hugetlbfs file holding them (e.g. SysV shm segments, see ipcs -m)",
+                size_to_str(used)
+            ),
+            fix: None,
+        });
+    }
+}
+
+// look for the hugepages leaked by crashed runs: the reservations of the state file, the
+// hugetlbfs files and mounts, and the hugepage counters
+pub fn diagnose() -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    check_sessions(&mut findings);
+    let held = check_hugetlbfs(&mut findings);
+    check_counters(&mut findings, &held);
+
+    findings
+}
@@ -0,0 +1,237 @@
+use std::fs;
+use std::path::Path;
+
+const ELFMAG: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2MSB: u8 = 2;
+
+const ET_DYN: u16 = 3;
+
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+const PT_GNU_STACK: u32 = 0x6474e551;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+const SHT_NOBITS: u32 = 8;
+
+// a PT_LOAD segment
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub vaddr: usize,
+    pub filesz: usize,
+    pub memsz: usize,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+// the parts of an executable's ELF metadata relevant to sizing its regions
+#[derive(Debug, Clone)]
+pub struct ElfInfo {
+    pub pie: bool,
+    pub dynamic: bool,
+    pub segments: Vec<Segment>,
+    // size of .bss, or of the zero-filled tails of the writable segments without section headers
+    pub bss: usize,
+    // PT_GNU_STACK (executable, size), None if missing, i.e. a legacy executable stack
+    pub stack: Option<(bool, usize)>,
+}
+
+// field reader honoring the ELF class and byte order
+struct Reader<'a> {
+    data: &'a [u8],
+    be: bool,
+    is64: bool,
+}
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&self, off: usize) -> Result<[u8; N], String> {
+        self.data
+            .get(off..off + N)
+            .and_then(|x| x.try_into().ok())
+            .ok_or_else(|| format!("truncated ELF file (offset {:#x})", off))
+    }
+
+    fn u16(&self, off: usize) -> Result<u16, String> {
+        let b = self.bytes::<2>(off)?;
+        Ok(if self.be {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        })
+    }
+
+    fn u32(&self, off: usize) -> Result<u32, String> {
+        let b = self.bytes::<4>(off)?;
+        Ok(if self.be {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        })
+    }
+
+    fn u64(&self, off: usize) -> Result<u64, String> {
+        let b = self.bytes::<8>(off)?;
+        Ok(if self.be {
+            u64::from_be_bytes(b)
+        } else {
+            u64::from_le_bytes(b)
+        })
+    }
+
+    // an address / offset / size sized field (Elf32_Addr or Elf64_Addr)
+    fn word(&self, off: usize) -> Result<usize, String> {
+        if self.is64 {
+            self.u64(off).map(|x| x as usize)
+        } else {
+            self.u32(off).map(|x| x as usize)
+        }
+    }
+
+    // size of the .bss section, None if the executable is stripped of its section headers
+    fn bss_size(
+        &self,
+        shoff: usize,
+        shentsize: usize,
+        shnum: usize,
+        shstrndx: usize,
+    ) -> Result<Option<usize>, String> {
+        if shoff == 0 || shnum == 0 || shstrndx >= shnum {
+            return Ok(None);
+        }
+
+        // (name, type, offset, size) of a section header
+        let section = |i: usize| -> Result<(usize, u32, usize, usize), String> {
+            let sh = shoff + i * shentsize;
+            if self.is64 {
+                Ok((
+                    self.u32(sh)? as usize,
+                    self.u32(sh + 0x4)?,
+                    self.word(sh + 0x18)?,
+                    self.word(sh + 0x20)?,
+                ))
+            } else {
+                Ok((
+                    self.u32(sh)? as usize,
+                    self.u32(sh + 0x4)?,
+                    self.word(sh + 0x10)?,
+                    self.word(sh + 0x14)?,
+                ))
+            }
+        };
+
+        let (_, _, strtab, _) = section(shstrndx)?;
+        for i in 0..shnum {
+            let (name, sh_type, _, size) = section(i)?;
+            let name = self
+                .data
+                .get(strtab + name..)
+                .and_then(|x| x.split(|c| *c == 0).next())
+                .unwrap_or_default();
+            if sh_type == SHT_NOBITS && name == b".bss" {
+                return Ok(Some(size));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl ElfInfo {
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::from_bytes(&data).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        if data.len() < 16 || &data[..4] != ELFMAG {
+            return Err("not an ELF file".to_string());
+        }
+
+        let r = Reader {
+            data,
+            be: data[5] == ELFDATA2MSB,
+            is64: data[4] == ELFCLASS64,
+        };
+        // (e_phoff, e_shoff, offset of e_ehsize) of the class, the rest of the header follows it
+        let (phoff, shoff, ehsize) = if r.is64 {
+            (r.word(0x20)?, r.word(0x28)?, 0x34)
+        } else {
+            (r.word(0x1c)?, r.word(0x20)?, 0x28)
+        };
+        let phentsize = r.u16(ehsize + 0x2)? as usize;
+        let phnum = r.u16(ehsize + 0x4)? as usize;
+        let shentsize = r.u16(ehsize + 0x6)? as usize;
+        let shnum = r.u16(ehsize + 0x8)? as usize;
+        let shstrndx = r.u16(ehsize + 0xa)? as usize;
+
+        let mut info = ElfInfo {
+            pie: r.u16(0x10)? == ET_DYN,
+            dynamic: false,
+            segments: Vec::new(),
+            bss: 0,
+            stack: None,
+        };
+
+        for i in 0..phnum {
+            let ph = phoff + i * phentsize;
+            let p_type = r.u32(ph)?;
+            // (flags, vaddr, filesz, memsz), p_flags moved after p_type in Elf64_Phdr
+            let (flags, vaddr, filesz, memsz) = if r.is64 {
+                (
+                    r.u32(ph + 0x4)?,
+                    r.word(ph + 0x10)?,
+                    r.word(ph + 0x20)?,
+                    r.word(ph + 0x28)?,
+                )
+            } else {
+                (
+                    r.u32(ph + 0x18)?,
+                    r.word(ph + 0x8)?,
+                    r.word(ph + 0x10)?,
+                    r.word(ph + 0x14)?,
+                )
+            };
+
+            match p_type {
+                PT_LOAD => info.segments.push(Segment {
+                    vaddr,
+                    filesz,
+                    memsz,
+                    writable: flags & PF_W != 0,
+                    executable: flags & PF_X != 0,
+                }),
+                PT_INTERP => info.dynamic = true,
+                PT_GNU_STACK => info.stack = Some((flags & PF_X != 0, memsz)),
+                _ => {}
+            }
+        }
+
+        info.bss = match r.bss_size(shoff, shentsize, shnum, shstrndx)? {
+            Some(x) => x,
+            None => info
+                .segments
+                .iter()
+                .filter(|x| x.writable)
+                .map(|x| x.memsz.saturating_sub(x.filesz))
+                .sum(),
+        };
+
+        Ok(info)
+    }
+
+    // total size of the loaded image
+    pub fn image_size(&self) -> usize {
+        self.segments.iter().map(|x| x.memsz).sum()
+    }
+
+    // size of the writable (data + bss) segments
+    pub fn data_size(&self) -> usize {
+        self.segments
+            .iter()
+            .filter(|x| x.writable)
+            .map(|x| x.memsz)
+            .sum()
+    }
+}
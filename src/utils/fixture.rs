@@ -0,0 +1,378 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::rangelist::Id;
+use super::sysfs_path::*;
+
+// the node and global counters of a hugepage size, as exposed by sysfs
+const NODE_COUNTERS: [&str; 3] = ["nr_hugepages", "free_hugepages", "surplus_hugepages"];
+const GLOBAL_COUNTERS: [&str; 5] = [
+    "nr_hugepages",
+    "free_hugepages",
+    "surplus_hugepages",
+    "resv_hugepages",
+    "nr_overcommit_hugepages",
+];
+
+// a fake sysfs / procfs tree with the hugepage, THP, overcommit and NUMA topology files read and
+// written by the reservation and status logic, for hermetic runs of it through set_sysfs_root or
+// MOSALLOC_SYSFS_ROOT; the counters are plain files, writing nr_hugepages doesn't update the rest
+#[derive(Debug)]
+pub struct SysfsFixture {
+    pub root: PathBuf,
+    pub nodes: usize,
+    // supported hugepage sizes, the first one is the default
+    pub sizes: Vec<usize>,
+}
+
+fn write(path: &Path, content: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, content)
+}
+
+// the paths of a fixture are built with the root it's created at, whatever the active one
+fn under(root: &Path, path: PathBuf) -> PathBuf {
+    root.join(path.strip_prefix(sysfs_root()).unwrap())
+}
+
+impl SysfsFixture {
+    // creates the tree with no hugepages reserved, node n has cpu n
+    pub fn create(root: &Path, nodes: usize, sizes: &[usize]) -> io::Result<Self> {
+        let fixture = SysfsFixture {
+            root: root.to_path_buf(),
+            nodes,
+            sizes: sizes.to_vec(),
+        };
+        let range = format!("0-{}\n", nodes.saturating_sub(1));
+
+        write(&fixture.path(sysfs_path_online_nodes()), &range)?;
+        write(&fixture.path(sysfs_path_online_cpus()), &range)?;
+        for n in 0..nodes as Id {
+            write(&fixture.path(sysfs_path_node_cpus(n)), &format!("{}\n", n))?;
+        }
+
+        for &sz in sizes.iter() {
+            for leaf in GLOBAL_COUNTERS {
+                write(&fixture.path(sysfs_path_htlb_global(sz >> 10, leaf)), "0\n")?;
+            }
+            for n in 0..nodes as Id {
+                for leaf in NODE_COUNTERS {
+                    write(&fixture.path(sysfs_path_htlb(n, sz >> 10, leaf)), "0\n")?;
+                }
+            }
+        }
+
+        write(
+            &fixture.path(sysfs_path_thp_enabled()),
+            "always [madvise] never\n",
+        )?;
+        write(&fixture.path(sysfs_path_overcommit()), "0\n")?;
+        fixture.set_meminfo(0, 0, 0)?;
+
+        Ok(fixture)
+    }
+
+    pub fn path(&self, path: PathBuf) -> PathBuf {
+        under(&self.root, path)
+    }
+
+    // roots the sysfs / procfs paths of the current process at the fixture
+    pub fn activate(&self) {
+        set_sysfs_root(&self.root);
+    }
+
+    // sets the node counters of a size, along with the global ones summing them over the nodes
+    pub fn set_pages_node(&self, node: Id, sz: usize, nr: usize, free: usize) -> io::Result<()> {
+        write(
+            &self.path(sysfs_path_htlb(node, sz >> 10, "nr_hugepages")),
+            &format!("{}\n", nr),
+        )?;
+        write(
+            &self.path(sysfs_path_htlb(node, sz >> 10, "free_hugepages")),
+            &format!("{}\n", free),
+        )?;
+
+        let sum = |leaf: &str| -> io::Result<usize> {
+            (0..self.nodes as Id)
+                .map(|n| {
+                    fs::read_to_string(self.path(sysfs_path_htlb(n, sz >> 10, leaf)))
+                        .map(|x| x.trim().parse::<usize>().unwrap_or_default())
+                })
+                .sum()
+        };
+        for leaf in ["nr_hugepages", "free_hugepages"] {
+            write(
+                &self.path(sysfs_path_htlb_global(sz >> 10, leaf)),
+                &format!("{}\n", sum(leaf)?),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // the HugePages_* lines of /proc/meminfo, for the default size
+    pub fn set_meminfo(&self, total: usize, free: usize, rsvd: usize) -> io::Result<()> {
+        let default = self.sizes.first().copied().unwrap_or_default();
+        write(
+            &self.path(procfs_path_meminfo()),
+            &format!(
+                "HugePages_Total:   {}\nHugePages_Free:    {}\nHugePages_Rsvd:    {}\n\
+                 HugePages_Surp:    0\nHugepagesize:   {} kB\n",
+                total,
+                free,
+                rsvd,
+                default >> 10
+            ),
+        )
+    }
+
+    pub fn read(&self, path: PathBuf) -> io::Result<String> {
+        fs::read_to_string(self.path(path)).map(|x| x.trim().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Mutex, MutexGuard};
+
+    use super::*;
+    use crate::utils::htlb::*;
+
+    const MB2: usize = 2 << 20;
+    const GB1: usize = 1 << 30;
+
+    // the sysfs root is global to the process, the tests using a fixture take turns
+    static ROOT_LOCK: Mutex<()> = Mutex::new(());
+
+    // a fixture activated for the duration of a test, removed along with the root reset when
+    // dropped
+    struct Active {
+        fixture: SysfsFixture,
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    impl Drop for Active {
+        fn drop(&mut self) {
+            set_sysfs_root(Path::new("/"));
+            let _ = fs::remove_dir_all(&self.fixture.root);
+        }
+    }
+
+    // two nodes with 2MB (the default) and 1GB pages
+    fn active(name: &str) -> Active {
+        let lock = ROOT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let root =
+            std::env::temp_dir().join(format!("mosalloc-fixture-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+
+        let fixture = SysfsFixture::create(&root, 2, &[MB2, GB1]).unwrap();
+        fixture.activate();
+        Active {
+            fixture,
+            _lock: lock,
+        }
+    }
+
+    fn pages(f: &SysfsFixture, node: Id, sz: usize) -> String {
+        f.read(sysfs_path_htlb(node, sz >> 10, "nr_hugepages"))
+            .unwrap()
+    }
+
+    fn overcommit(f: &SysfsFixture, sz: usize) -> String {
+        f.read(sysfs_path_htlb_global(sz >> 10, "nr_overcommit_hugepages"))
+            .unwrap()
+    }
+
+    fn req(req: Vec<usize>, node: Id, strategy: ReserveStrategy) -> HTLBReq {
+        HTLBReq {
+            req,
+            node,
+            strategy,
+        }
+    }
+
+    #[test]
+    fn sizes_from_fixture() {
+        let _f = active("sizes");
+        assert_eq!(supported_htlb_sizes(), vec![MB2, GB1]);
+        assert_eq!(get_default_htlb_size(), Some(MB2));
+    }
+
+    #[test]
+    fn static_reserve_grows_and_shrinks() {
+        let a = active("static");
+        let f = &a.fixture;
+
+        req(vec![8, 2], 1, ReserveStrategy::STATIC)
+            .reserve_pages()
+            .unwrap();
+        assert_eq!(pages(f, 1, MB2), "8");
+        assert_eq!(pages(f, 1, GB1), "2");
+        // the other node is left alone
+        assert_eq!(pages(f, 0, MB2), "0");
+        assert_eq!(pages(f, 0, GB1), "0");
+
+        req(vec![0, 1], 1, ReserveStrategy::STATIC)
+            .reserve_pages()
+            .unwrap();
+        assert_eq!(pages(f, 1, MB2), "0");
+        assert_eq!(pages(f, 1, GB1), "1");
+        assert_eq!(overcommit(f, MB2), "0");
+    }
+
+    #[test]
+    fn overcommit_reserve_sets_the_limits() {
+        let a = active("overcommit");
+        let f = &a.fixture;
+
+        req(vec![4, 1], 0, ReserveStrategy::OVERCOMMIT)
+            .reserve_pages()
+            .unwrap();
+        assert_eq!(overcommit(f, MB2), "4");
+        assert_eq!(overcommit(f, GB1), "1");
+        // nothing allocated upfront
+        assert_eq!(pages(f, 0, MB2), "0");
+        assert_eq!(pages(f, 0, GB1), "0");
+    }
+
+    #[test]
+    fn overcommit_reserve_keeps_higher_limits() {
+        let a = active("overcommit-higher");
+        let f = &a.fixture;
+
+        set_htlb_overcommit_pages(MB2, 16).unwrap();
+        let state = HTLBState::save(0).unwrap();
+        f.set_pages_node(0, GB1, 2, 2).unwrap();
+
+        req(vec![4, 1], 0, ReserveStrategy::OVERCOMMIT)
+            .reserve_pages()
+            .unwrap();
+        assert_eq!(overcommit(f, MB2), "16");
+        assert_eq!(overcommit(f, GB1), "1");
+
+        // the reserved pages aren't touched
+        state.restore_overcommit().unwrap();
+        assert_eq!(overcommit(f, MB2), "16");
+        assert_eq!(overcommit(f, GB1), "0");
+        assert_eq!(pages(f, 0, GB1), "2");
+    }
+
+    #[test]
+    fn overcommit_reserve_error() {
+        let a = active("overcommit-error");
+        let f = &a.fixture;
+
+        // a limit that can't be accessed, even by root
+        let path = f.path(sysfs_path_htlb_global(GB1 >> 10, "nr_overcommit_hugepages"));
+        fs::remove_file(&path).unwrap();
+        fs::create_dir(&path).unwrap();
+
+        let e = req(vec![4, 1], 0, ReserveStrategy::OVERCOMMIT)
+            .reserve_pages()
+            .unwrap_err();
+        assert!(
+            e.starts_with("couldn't read nr_overcommit_hugepages"),
+            "{}",
+            e
+        );
+        // the sizes before the failing one are set
+        assert_eq!(overcommit(f, MB2), "4");
+    }
+
+    #[test]
+    fn unsupported_size_errors() {
+        let _f = active("unsupported");
+
+        let e = set_htlb_pages_node(0, 4 << 20, 1).unwrap_err();
+        assert_eq!(e, format!("invalid htlb size {}", 4 << 20));
+        assert!(get_htlb_pages_node(0, 4 << 20).is_err());
+        assert!(get_htlb_free_pages_node(0, 4 << 20).is_err());
+        assert!(set_htlb_overcommit_pages(4 << 20, 1).is_err());
+        assert!(get_htlb_surplus_pages(4 << 20).is_err());
+    }
+
+    #[test]
+    fn state_restore() {
+        let a = active("restore");
+        let f = &a.fixture;
+
+        f.set_pages_node(0, MB2, 4, 4).unwrap();
+        let state = HTLBState::save(0).unwrap();
+        assert_eq!(state.pages, vec![4, 0]);
+
+        req(vec![10, 1], 0, ReserveStrategy::STATIC)
+            .reserve_pages()
+            .unwrap();
+        req(vec![3, 3], 0, ReserveStrategy::OVERCOMMIT)
+            .reserve_pages()
+            .unwrap();
+        state.restore().unwrap();
+
+        assert_eq!(pages(f, 0, MB2), "4");
+        assert_eq!(pages(f, 0, GB1), "0");
+        assert_eq!(overcommit(f, MB2), "0");
+        assert_eq!(overcommit(f, GB1), "0");
+    }
+
+    #[test]
+    fn status_output() {
+        let a = active("status");
+        let f = &a.fixture;
+
+        f.set_pages_node(1, MB2, 16, 12).unwrap();
+        f.set_pages_node(1, GB1, 2, 2).unwrap();
+        assert_eq!(
+            htlb_status_node(1),
+            "HugeTLB status for node 1\n\
+             # of 2MB pages (node 1) == 16\n\
+             # of 1GB pages (node 1) == 2\n"
+        );
+        assert!(htlb_status_node(0).contains("# of 2MB pages (node 0) == 0\n"));
+
+        req(vec![4, 1], 0, ReserveStrategy::OVERCOMMIT)
+            .reserve_pages()
+            .unwrap();
+        let status = htlb_overcommit_status();
+        assert!(status.contains("# of 2MB pages: overcommit == 4, surplus == 0\n"));
+        assert!(status.contains("# of 1GB pages: overcommit == 1, surplus == 0\n"));
+    }
+
+    #[test]
+    fn usage_counters() {
+        let a = active("usage");
+        let f = &a.fixture;
+
+        // the default size is read from meminfo, the rest from sysfs
+        f.set_meminfo(10, 6, 2).unwrap();
+        let usage = get_htlb_usage(MB2).unwrap();
+        assert_eq!((usage.total, usage.free, usage.rsvd), (10, 6, 2));
+        assert_eq!(usage.used(), 6);
+
+        f.set_pages_node(0, GB1, 3, 1).unwrap();
+        f.set_pages_node(1, GB1, 2, 2).unwrap();
+        let usage = get_htlb_usage(GB1).unwrap();
+        assert_eq!((usage.total, usage.free, usage.rsvd), (5, 3, 0));
+    }
+
+    #[test]
+    fn request_from_config() {
+        let a = active("config");
+        let config = a.fixture.root.join("pool.conf");
+        fs::write(
+            &config,
+            "version = 1\n\n\
+             [[interval]]\ntype = \"mmap\"\npage_size = \"2MB\"\nstart = \"0\"\nend = \"64MB\"\n\n\
+             [[interval]]\ntype = \"mmap\"\npage_size = \"1GB\"\nstart = \"1GB\"\nend = \"3GB\"\n\n\
+             [[interval]]\ntype = \"brk\"\npage_size = \"2MB\"\nstart = \"0\"\nend = \"8MB\"\n",
+        )
+        .unwrap();
+
+        let r = HTLBReq::from_config(&config, 1);
+        assert_eq!(r.req, vec![36, 2]);
+        assert_eq!(r.node, 1);
+        assert_eq!(r.strategy, ReserveStrategy::STATIC);
+    }
+}
@@ -0,0 +1,174 @@
+use std::fs;
+use std::path::Path;
+
+use super::misc::size_to_str;
+
+pub const HEATMAP_HEADER: &str = "# mosalloc heatmap v1";
+
+// shades used by the renderer, from cold to hot
+const SHADES: &[u8] = b" .:-=+*#%@";
+
+// per-hugepage touch samples of a single pool interval
+#[derive(Debug)]
+pub struct HeatmapInterval {
+    pub id: usize,
+    pub region: String,
+    pub pagesz: usize,
+    pub start: usize,
+    pub nrpages: usize,
+
+    // (timestamp in ms, touched bitmap) for every sample
+    pub samples: Vec<(u64, Vec<bool>)>,
+}
+
+impl HeatmapInterval {
+    pub fn new(id: usize, region: &str, pagesz: usize, start: usize, nrpages: usize) -> Self {
+        Self {
+            id,
+            region: region.to_string(),
+            pagesz,
+            start,
+            nrpages,
+            samples: Vec::new(),
+        }
+    }
+
+    // interval description line, e.g. 'interval 0 mmap 2097152 7f0000000000 512'
+    pub fn header_line(&self) -> String {
+        format!(
+            "interval {} {} {} {:x} {}",
+            self.id, self.region, self.pagesz, self.start, self.nrpages
+        )
+    }
+
+    // sample line, the touched bitmap is hex-encoded with 4 hugepages per digit
+    pub fn sample_line(id: usize, t: u64, touched: &[bool]) -> String {
+        let bitmap = touched
+            .chunks(4)
+            .map(|c| {
+                let digit = c
+                    .iter()
+                    .enumerate()
+                    .fold(0, |acc, (i, &x)| acc | ((x as u32) << i));
+                char::from_digit(digit, 16).unwrap()
+            })
+            .collect::<String>();
+
+        format!("sample {} {} {}", t, id, bitmap)
+    }
+
+    fn parse_bitmap(s: &str, nrpages: usize) -> Vec<bool> {
+        s.chars()
+            .flat_map(|c| {
+                let digit = c.to_digit(16).unwrap();
+                (0..4).map(move |i| digit & (1 << i) != 0)
+            })
+            .take(nrpages)
+            .collect()
+    }
+
+    // number of samples in which each hugepage was touched
+    pub fn touch_counts(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.nrpages];
+        for (_, touched) in self.samples.iter() {
+            for (c, &t) in counts.iter_mut().zip(touched.iter()) {
+                *c += t as usize;
+            }
+        }
+        counts
+    }
+
+    // render the interval as a (time x hugepages) ascii heatmap
+    pub fn render(&self, width: usize, rows: usize) -> String {
+        let cols = width.min(self.nrpages).max(1);
+        let rows = rows.min(self.samples.len()).max(1);
+
+        let mut out = format!(
+            "{} interval {} @ 0x{:x}: {} x {} pages, {} samples\n",
+            self.region,
+            self.id,
+            self.start,
+            self.nrpages,
+            size_to_str(self.pagesz),
+            self.samples.len()
+        );
+
+        for r in 0..rows {
+            let samples =
+                &self.samples[r * self.samples.len() / rows..((r + 1) * self.samples.len() / rows)];
+
+            let line = (0..cols)
+                .map(|c| {
+                    let pages = (c * self.nrpages / cols)..((c + 1) * self.nrpages / cols);
+                    let total = samples.len() * pages.len();
+                    let hot = samples
+                        .iter()
+                        .map(|(_, touched)| touched[pages.clone()].iter().filter(|&&x| x).count())
+                        .sum::<usize>();
+
+                    SHADES[(hot * (SHADES.len() - 1)).checked_div(total).unwrap_or(0)] as char
+                })
+                .collect::<String>();
+
+            out += &format!("|{}|\n", line);
+        }
+
+        let counts = self.touch_counts();
+        let cold = counts.iter().filter(|&&x| x == 0).count();
+        let hot = counts
+            .iter()
+            .filter(|&&x| x * 2 > self.samples.len())
+            .count();
+        out += &format!(
+            "hot (>50% of samples): {} pages, never touched: {} pages ({})\n",
+            hot,
+            cold,
+            size_to_str(cold * self.pagesz)
+        );
+
+        out
+    }
+}
+
+// parse a heatmap file written by libmosalloc
+pub fn heatmap_from_path(path: &Path) -> Result<Vec<HeatmapInterval>, String> {
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut lines = content.lines();
+
+    if lines.next() != Some(HEATMAP_HEADER) {
+        return Err(format!("{} is not a mosalloc heatmap", path.display()));
+    }
+
+    let mut intervals: Vec<HeatmapInterval> = Vec::new();
+    for line in lines {
+        let fields = line.split_whitespace().collect::<Vec<&str>>();
+        let parse_err = || format!("invalid heatmap line: {}", line);
+
+        match fields.as_slice() {
+            ["interval", id, region, pagesz, start, nrpages] => {
+                intervals.push(HeatmapInterval::new(
+                    id.parse().map_err(|_| parse_err())?,
+                    region,
+                    pagesz.parse().map_err(|_| parse_err())?,
+                    usize::from_str_radix(start, 16).map_err(|_| parse_err())?,
+                    nrpages.parse().map_err(|_| parse_err())?,
+                ));
+            }
+            ["sample", t, id, bitmap] => {
+                let id = id.parse::<usize>().map_err(|_| parse_err())?;
+                let interval = intervals
+                    .iter_mut()
+                    .find(|x| x.id == id)
+                    .ok_or_else(parse_err)?;
+                let touched = HeatmapInterval::parse_bitmap(bitmap, interval.nrpages);
+                interval
+                    .samples
+                    .push((t.parse().map_err(|_| parse_err())?, touched));
+            }
+            [] => {}
+            _ => return Err(parse_err()),
+        }
+    }
+
+    Ok(intervals)
+}
@@ -1,16 +1,21 @@
-use csv;
-use serde::Deserialize;
-use std::convert::From;
+use lazy_static::lazy_static;
+use nix::unistd::{sysconf, SysconfVar};
 use std::env;
 use std::fs;
 use std::path::Path;
 use std::str::FromStr;
 
-use super::misc::{size_from_str, size_to_str};
+use super::config::{PoolConfig, RegionEntry};
+use super::misc::{cpus_from_str, size_from_str, size_or_ram_from_str, size_to_str};
 use super::rangelist::Id;
 use super::sysfs_path::*;
+use super::tlb::{TlbModel, DEFAULT_TLB_MODELS};
+use super::trace::TraceOp;
 
-pub const PAGE_SIZE: usize = 1 << 12;
+lazy_static! {
+    // runtime base page size, e.g. 64KB on ppc64 or 16KB/64KB granule arm64 kernels
+    pub static ref PAGE_SIZE: usize = sysconf(SysconfVar::PAGE_SIZE).unwrap().unwrap() as usize;
+}
 
 // list of the system-supported HTLB sizes
 pub fn supported_htlb_sizes() -> Vec<usize> {
@@ -91,19 +96,245 @@ pub fn get_htlb_pages_node(node: Id, sz: usize) -> Result<usize, String> {
     }
 }
 
-// prints the reserved HTLB pages for a given NUMA node
-pub fn print_htlb_status_node(node: Id) {
-    println!("HugeTLB status for node {}", node);
+// helper to get the free HTLB pages for a given NUMA node
+pub fn get_htlb_free_pages_node(node: Id, sz: usize) -> Result<usize, String> {
+    let sizes = supported_htlb_sizes();
+    if !sizes.contains(&sz) {
+        Err(format!("invalid htlb size {}", sz))
+    } else {
+        Ok(
+            fs::read_to_string(sysfs_path_htlb(node, sz >> 10, "free_hugepages"))
+                .unwrap()
+                .trim()
+                .parse::<usize>()
+                .unwrap(),
+        )
+    }
+}
+
+// helper to get the size HTLB pages of a given size are demoted to (kernel 5.16+), if supported
+pub fn get_htlb_demote_size(sz: usize) -> Option<usize> {
+    fs::read_to_string(sysfs_path_htlb_global(sz >> 10, "demote_size"))
+        .ok()
+        .and_then(|x| x.trim().strip_suffix("kB")?.parse::<usize>().ok())
+        .map(|x| x << 10)
+}
+
+// helper to set the size HTLB pages of a given size are demoted to
+pub fn set_htlb_demote_size(sz: usize, demote_sz: usize) -> Result<(), String> {
+    fs::write(
+        sysfs_path_htlb_global(sz >> 10, "demote_size"),
+        format!("{}kB", demote_sz >> 10),
+    )
+    .map_err(|e| format!("couldn't set demote size: {}", e))
+}
+
+// helper to demote free HTLB pages of a given size for a given NUMA node
+pub fn demote_htlb_pages_node(node: Id, sz: usize, nr: usize) -> Result<(), String> {
+    fs::write(sysfs_path_htlb(node, sz >> 10, "demote"), format!("{}", nr))
+        .map_err(|e| format!("couldn't demote {} pages: {}", size_to_str(sz), e))
+}
+
+// helper to read a system-wide HTLB counter (e.g. surplus_hugepages)
+fn get_htlb_counter(sz: usize, leaf: &str) -> Result<usize, String> {
+    let sizes = supported_htlb_sizes();
+    if !sizes.contains(&sz) {
+        Err(format!("invalid htlb size {}", sz))
+    } else {
+        fs::read_to_string(sysfs_path_htlb_global(sz >> 10, leaf))
+            .map_err(|e| format!("couldn't read {}: {}", leaf, e))?
+            .trim()
+            .parse::<usize>()
+            .map_err(|e| format!("invalid {}: {}", leaf, e))
+    }
+}
+
+// helper to set the system-wide max number of surplus (overcommitted) HTLB pages
+pub fn set_htlb_overcommit_pages(sz: usize, nr: usize) -> Result<(), String> {
+    let sizes = supported_htlb_sizes();
+    if !sizes.contains(&sz) {
+        Err(format!("invalid htlb size {}", sz))
+    } else {
+        fs::write(
+            sysfs_path_htlb_global(sz >> 10, "nr_overcommit_hugepages"),
+            format!("{}", nr),
+        )
+        .map_err(|e| format!("couldn't set overcommit pages: {}", e))
+    }
+}
+
+// helper to get the system-wide max number of surplus (overcommitted) HTLB pages
+pub fn get_htlb_overcommit_pages(sz: usize) -> Result<usize, String> {
+    get_htlb_counter(sz, "nr_overcommit_hugepages")
+}
+
+// helper to get the system-wide number of free HTLB pages
+pub fn get_htlb_free_pages(sz: usize) -> Result<usize, String> {
+    get_htlb_counter(sz, "free_hugepages")
+}
+
+// helper to get the system-wide number of currently allocated surplus HTLB pages
+pub fn get_htlb_surplus_pages(sz: usize) -> Result<usize, String> {
+    get_htlb_counter(sz, "surplus_hugepages")
+}
+
+// helper to read a /proc/meminfo field, kB sizes are converted to bytes
+pub fn meminfo_field(field: &str) -> Option<usize> {
+    fs::read_to_string(procfs_path_meminfo())
+        .ok()?
+        .lines()
+        .find_map(|x| x.strip_prefix(field)?.strip_prefix(':'))
+        .and_then(|x| match x.trim().strip_suffix("kB") {
+            Some(kb) => kb.trim().parse::<usize>().ok().map(|x| x << 10),
+            None => x.trim().parse::<usize>().ok(),
+        })
+}
+
+// the default HTLB size
+pub fn get_default_htlb_size() -> Option<usize> {
+    meminfo_field("Hugepagesize")
+}
+
+// system-wide HTLB page counters of a given size
+#[derive(Debug, Clone, Copy)]
+pub struct HTLBUsage {
+    pub total: usize,
+    pub free: usize,
+    pub rsvd: usize,
+}
+
+impl HTLBUsage {
+    // pages either faulted in or reserved by a mapping
+    pub fn used(&self) -> usize {
+        self.total - self.free + self.rsvd
+    }
+}
+
+// helper to get the system-wide HTLB counters of a given size, /proc/meminfo only reports the
+// default size so sysfs is used for the rest
+pub fn get_htlb_usage(sz: usize) -> Result<HTLBUsage, String> {
+    if get_default_htlb_size() == Some(sz) {
+        let field =
+            |x: &str| meminfo_field(x).ok_or_else(|| format!("missing {} in /proc/meminfo", x));
+        Ok(HTLBUsage {
+            total: field("HugePages_Total")?,
+            free: field("HugePages_Free")?,
+            rsvd: field("HugePages_Rsvd")?,
+        })
+    } else {
+        Ok(HTLBUsage {
+            total: get_htlb_counter(sz, "nr_hugepages")?,
+            free: get_htlb_counter(sz, "free_hugepages")?,
+            rsvd: get_htlb_counter(sz, "resv_hugepages")?,
+        })
+    }
+}
+
+// the reserved HTLB pages for a given NUMA node
+pub fn htlb_status_node(node: Id) -> String {
+    let mut out = format!("HugeTLB status for node {}\n", node);
     let sizes = supported_htlb_sizes();
     for &size in sizes.iter() {
-        println!(
-            "# of {} pages (node {}) == {}",
+        out += &format!(
+            "# of {} pages (node {}) == {}\n",
             size_to_str(size),
             node,
             get_htlb_pages_node(node, size).unwrap()
         );
     }
-    println!();
+    out
+}
+
+// prints the reserved HTLB pages for a given NUMA node
+pub fn print_htlb_status_node(node: Id) {
+    println!("{}", htlb_status_node(node));
+}
+
+// the system-wide surplus HTLB pages limits and usage
+pub fn htlb_overcommit_status() -> String {
+    let mut out = "HugeTLB overcommit status (system-wide)\n".to_string();
+    let sizes = supported_htlb_sizes();
+    for &size in sizes.iter() {
+        out += &format!(
+            "# of {} pages: overcommit == {}, surplus == {}\n",
+            size_to_str(size),
+            get_htlb_overcommit_pages(size).unwrap(),
+            get_htlb_surplus_pages(size).unwrap()
+        );
+    }
+    out + "note: surplus pages are allocated on demand and returned on free, but the allocation \
+           can fail at mmap time if memory is fragmented, and the limit isn't per-NUMA node\n"
+}
+
+// prints the system-wide surplus HTLB pages limits and usage
+pub fn print_htlb_overcommit_status() {
+    println!("{}", htlb_overcommit_status());
+}
+
+// HTLB reservation strategy
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ReserveStrategy {
+    // pages are allocated upfront in the static pool (nr_hugepages)
+    STATIC,
+    // pages are allocated on demand from the surplus pool (nr_overcommit_hugepages); the limits
+    // are system-wide, the kernel has no per-node ones, so the surplus pages come from the node
+    // the memory policy of the faulting task picks, and other jobs share the raised limits
+    OVERCOMMIT,
+}
+
+impl ReserveStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReserveStrategy::STATIC => "static",
+            ReserveStrategy::OVERCOMMIT => "overcommit",
+        }
+    }
+}
+
+impl FromStr for ReserveStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "static" => Ok(ReserveStrategy::STATIC),
+            "overcommit" => Ok(ReserveStrategy::OVERCOMMIT),
+            _ => Err(format!("Unknown reservation strategy: {}", s)),
+        }
+    }
+}
+
+// free memory converted from one page size to another while reserving a request
+#[derive(Debug, Clone, Copy)]
+pub struct Conversion {
+    pub from: usize,
+    pub to: usize,
+    // bytes of `from` pages converted, and the `to` pages they became
+    pub bytes: usize,
+    pub pages: usize,
+    // split in place by the kernel, or reallocated as smaller pages
+    pub demoted: bool,
+}
+
+impl Conversion {
+    pub fn describe(&self) -> String {
+        if self.demoted {
+            format!(
+                "demoted {} {} pages to {} {} pages",
+                self.bytes / self.from,
+                size_to_str(self.from),
+                self.pages,
+                size_to_str(self.to)
+            )
+        } else {
+            format!(
+                "reallocated {} of the missing {} pages as {} {} pages",
+                size_to_str(self.bytes),
+                size_to_str(self.from),
+                self.pages,
+                size_to_str(self.to)
+            )
+        }
+    }
 }
 
 // request to reserve HTLB pages for a given Node
@@ -111,9 +342,32 @@ pub fn print_htlb_status_node(node: Id) {
 pub struct HTLBReq {
     pub req: Vec<usize>,
     pub node: Id,
+    pub strategy: ReserveStrategy,
 }
 
 impl HTLBReq {
+    // create a request covering the brk and anon pools (named regions included) of the config
+    pub fn from_config(config: &Path, node: Id) -> Self {
+        let mmap = Pool::from_config(AllocType::ANON, config);
+        let brk = Pool::from_config(AllocType::BRK, config);
+        let named = Pool::named_from_config(config);
+
+        let req = supported_htlb_sizes()
+            .iter()
+            .map(|&x| {
+                mmap.nrpages(x)
+                    + brk.nrpages(x)
+                    + named.iter().map(|(_, p)| p.nrpages(x)).sum::<usize>()
+            })
+            .collect::<Vec<usize>>();
+
+        HTLBReq {
+            req,
+            node,
+            strategy: ReserveStrategy::STATIC,
+        }
+    }
+
     // returns the format string of the request (e.g. i:j:k:l)
     fn req_fmt_str(sizes: &Vec<usize>) -> String {
         sizes
@@ -143,6 +397,10 @@ impl HTLBReq {
 
     // reserves the pages specified in the request
     pub fn reserve_pages(&self) -> Result<(), String> {
+        if self.strategy == ReserveStrategy::OVERCOMMIT {
+            return self.reserve_overcommit_pages();
+        }
+
         let sizes = supported_htlb_sizes();
 
         sizes
@@ -157,77 +415,256 @@ impl HTLBReq {
             .rev()
             .for_each(|(&sz, &req_sz)| set_htlb_pages_node(self.node, sz, req_sz).unwrap());
 
-        let check = sizes
+        let short = sizes
             .iter()
             .zip(self.req.iter())
-            .any(|(&sz, &req_sz)| req_sz != get_htlb_pages_node(self.node, sz).unwrap());
+            .map(|(&sz, &req_sz)| {
+                let cur = get_htlb_pages_node(self.node, sz)?;
+                Ok((sz, req_sz.saturating_sub(cur)))
+            })
+            .collect::<Result<Vec<(usize, usize)>, String>>()?
+            .into_iter()
+            .filter(|&(_, nr)| nr > 0)
+            .map(|(sz, nr)| format!("{} {} pages short", nr, size_to_str(sz)))
+            .collect::<Vec<String>>();
 
-        if check {
-            return Ok(());
+        if short.is_empty() {
+            Ok(())
         } else {
-            return Err("Couldn't allocate pages".to_string());
+            Err(format!("Couldn't allocate pages: {}", short.join(", ")))
         }
     }
-}
 
-// deserialized CSV interval
-#[derive(Debug, Deserialize)]
-struct CSVRecord {
-    #[serde(rename = "type")]
-    region_type: String,
-    page_size: String,
-    start_offset: String,
-    end_offset: String,
-}
+    // reserves the pages specified in the request, covering what can't be reserved with free
+    // pages of the other sizes: excess free pages of larger sizes are demoted beforehand, and the
+    // larger pages still missing afterwards are reallocated as smaller ones, the request being
+    // updated to the pages actually reserved; returns the conversions made
+    pub fn reserve_converting(&mut self) -> Result<Vec<Conversion>, String> {
+        if self.strategy == ReserveStrategy::OVERCOMMIT {
+            return self.reserve_pages().map(|_| Vec::new());
+        }
 
-// HTLB interval
-#[derive(Debug)]
-pub struct Interval {
-    pub pagesz: usize,
-    pub start: usize,
-    pub end: usize,
-}
+        let mut conversions = self.rebalance()?;
+        if self.reserve_pages().is_ok() {
+            return Ok(conversions);
+        }
 
-impl From<CSVRecord> for Interval {
-    fn from(rec: CSVRecord) -> Self {
-        let pagesz = size_from_str(&rec.page_size);
+        conversions.extend(self.reallocate()?);
+        self.reserve_pages().map(|_| conversions)
+    }
 
-        assert!(supported_htlb_sizes().contains(&pagesz), "invalid size");
+    // covers the larger pages missing after a reservation with smaller pages, moving the bytes
+    // reallocated from the request of the larger sizes to the smaller ones
+    fn reallocate(&mut self) -> Result<Vec<Conversion>, String> {
+        let sizes = supported_htlb_sizes();
+        let mut conversions = Vec::new();
+
+        for i in (0..sizes.len()).rev() {
+            let sz = sizes[i];
+            let short = self.req[i].saturating_sub(get_htlb_pages_node(self.node, sz)?);
+            let mut bytes = short * sz;
+
+            for j in (0..i).rev() {
+                if bytes == 0 {
+                    break;
+                }
 
-        let start = size_from_str(&rec.start_offset);
-        let end = size_from_str(&rec.end_offset);
+                let ssz = sizes[j];
+                // the pages the smaller size itself is short of come first
+                let base = get_htlb_pages_node(self.node, ssz)?.max(self.req[j]);
+                set_htlb_pages_node(self.node, ssz, base + bytes.div_ceil(ssz))?;
+                let nr = get_htlb_pages_node(self.node, ssz)?.saturating_sub(base);
+                if nr == 0 {
+                    continue;
+                }
+
+                self.req[j] += nr;
+                conversions.push(Conversion {
+                    from: sz,
+                    to: ssz,
+                    bytes: (nr * ssz).min(bytes),
+                    pages: nr,
+                    demoted: false,
+                });
+                bytes = bytes.saturating_sub(nr * ssz);
+            }
 
-        // alignment checks
-        assert!(start & (pagesz - 1) == 0);
-        assert!(end & (pagesz - 1) == 0);
-        assert!(start != end);
+            // only the larger pages fully covered leave the request
+            self.req[i] -= short - bytes.div_ceil(sz);
+        }
 
-        Interval { pagesz, start, end }
+        Ok(conversions)
+    }
+
+    // returns the kernel cmdline parameters that statically reserve the request at boot time,
+    // optionally restricted to the request's NUMA node (kernel 5.13+ syntax)
+    pub fn boot_params(&self, per_node: bool) -> Vec<String> {
+        let sizes = supported_htlb_sizes();
+        let reqs = sizes
+            .iter()
+            .zip(self.req.iter())
+            .filter(|(_, &req_sz)| req_sz > 0)
+            .collect::<Vec<(&usize, &usize)>>();
+
+        let cmdline_sz = |sz: usize| size_to_str(sz).trim_end_matches('B').to_string();
+
+        let mut params = Vec::new();
+        if let Some((&sz, _)) = reqs.last() {
+            params.push(format!("default_hugepagesz={}", cmdline_sz(sz)));
+        }
+
+        for (&sz, &req_sz) in reqs.iter() {
+            params.push(format!("hugepagesz={}", cmdline_sz(sz)));
+            if per_node {
+                params.push(format!("hugepages={}:{}", self.node, req_sz));
+            } else {
+                params.push(format!("hugepages={}", req_sz));
+            }
+        }
+
+        params
+    }
+
+    // demotes excess free pages of larger sizes to cover the smaller sizes that can't be
+    // satisfied by the currently free pages, returns the demotions made
+    pub fn rebalance(&self) -> Result<Vec<Conversion>, String> {
+        let sizes = supported_htlb_sizes();
+        let mut conversions = Vec::new();
+
+        for (i, (&sz, &req_sz)) in sizes.iter().zip(self.req.iter()).enumerate() {
+            let cur = get_htlb_pages_node(self.node, sz)?;
+            if req_sz <= cur {
+                continue;
+            }
+            let mut deficit = req_sz - cur;
+
+            for (&lsz, &lreq_sz) in sizes.iter().zip(self.req.iter()).skip(i + 1) {
+                if deficit == 0 {
+                    break;
+                }
+
+                let demote_sz = get_htlb_demote_size(lsz);
+                if demote_sz.is_none() {
+                    continue;
+                }
+                if demote_sz != Some(sz) {
+                    set_htlb_demote_size(lsz, sz)?;
+                }
+
+                // only demote pages which are free and not part of the request
+                let excess = get_htlb_pages_node(self.node, lsz)?.saturating_sub(lreq_sz);
+                let excess = excess.min(get_htlb_free_pages_node(self.node, lsz)?);
+                let nr = deficit.div_ceil(lsz / sz).min(excess);
+                if nr == 0 {
+                    continue;
+                }
+
+                demote_htlb_pages_node(self.node, lsz, nr)?;
+                conversions.push(Conversion {
+                    from: lsz,
+                    to: sz,
+                    bytes: nr * lsz,
+                    pages: nr * (lsz / sz),
+                    demoted: true,
+                });
+
+                deficit = deficit.saturating_sub(nr * (lsz / sz));
+            }
+        }
+
+        Ok(conversions)
+    }
+
+    // raises the (system-wide) overcommit limits so that the requested pages can be allocated on
+    // demand, the higher limits already set for others are kept
+    fn reserve_overcommit_pages(&self) -> Result<(), String> {
+        let sizes = supported_htlb_sizes();
+
+        for (&sz, &req_sz) in sizes.iter().zip(self.req.iter()) {
+            if get_htlb_overcommit_pages(sz)? >= req_sz {
+                continue;
+            }
+            set_htlb_overcommit_pages(sz, req_sz)?;
+            if get_htlb_overcommit_pages(sz)? != req_sz {
+                return Err(format!(
+                    "Couldn't set the overcommit limit for {} pages",
+                    size_to_str(sz)
+                ));
+            }
+        }
+
+        Ok(())
     }
 }
 
-// allocation types for HTLB pools
-#[derive(Debug, PartialEq, Copy, Clone)]
-pub enum AllocType {
-    BRK,
-    ANON,
-    FILE,
+// HTLB pool state of a NUMA node (reserved pages, overcommit limits), to restore it after a run
+#[derive(Debug, Clone)]
+pub struct HTLBState {
+    pub node: Id,
+    pub pages: Vec<usize>,
+    pub overcommit: Vec<usize>,
 }
 
-impl AllocType {
-    pub fn as_str(&self) -> &'static str {
-        match self {
-            AllocType::BRK => "brk",
-            AllocType::ANON => "mmap",
-            AllocType::FILE => "file",
+impl HTLBState {
+    pub fn save(node: Id) -> Result<Self, String> {
+        let sizes = supported_htlb_sizes();
+
+        Ok(HTLBState {
+            node,
+            pages: sizes
+                .iter()
+                .map(|&sz| get_htlb_pages_node(node, sz))
+                .collect::<Result<Vec<usize>, String>>()?,
+            overcommit: sizes
+                .iter()
+                .map(|&sz| get_htlb_overcommit_pages(sz))
+                .collect::<Result<Vec<usize>, String>>()?,
+        })
+    }
+
+    // releases the pages reserved since the state was saved, and resets the overcommit limits
+    pub fn restore(&self) -> Result<(), String> {
+        let sizes = supported_htlb_sizes();
+
+        for (i, &sz) in sizes.iter().enumerate() {
+            if get_htlb_pages_node(self.node, sz)? != self.pages[i] {
+                set_htlb_pages_node(self.node, sz, self.pages[i])?;
+            }
+        }
+
+        self.restore_overcommit()
+    }
+
+    // resets the (system-wide) overcommit limits only, leaving the reserved pages alone
+    pub fn restore_overcommit(&self) -> Result<(), String> {
+        let sizes = supported_htlb_sizes();
+
+        for (i, &sz) in sizes.iter().enumerate() {
+            if get_htlb_overcommit_pages(sz)? != self.overcommit[i] {
+                set_htlb_overcommit_pages(sz, self.overcommit[i])?;
+            }
         }
+
+        Ok(())
+    }
+}
+
+pub use mosalloc_core::{AllocType, Interval, Pool};
+
+// HTLB interval of a page size supported by the system
+pub fn htlb_interval(pagesz: usize, start: usize, end: usize) -> Result<Interval, String> {
+    if !supported_htlb_sizes().contains(&pagesz) {
+        return Err(format!("unsupported page size {}", size_to_str(pagesz)));
     }
+    Interval::new(pagesz, start, end, *PAGE_SIZE)
 }
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum HookType {
     PRELOAD,
     SECCOMP,
+    // preload hooks, with a seccomp filter catching the raw syscalls bypassing them
+    HYBRID,
 }
 
 impl HookType {
@@ -235,6 +672,7 @@ impl HookType {
         match self {
             HookType::PRELOAD => "preload",
             HookType::SECCOMP => "seccomp",
+            HookType::HYBRID => "hybrid",
         }
     }
 }
@@ -246,12 +684,384 @@ impl FromStr for HookType {
         match s {
             "preload" => Ok(HookType::PRELOAD),
             "seccomp" => Ok(HookType::SECCOMP),
+            "hybrid" => Ok(HookType::HYBRID),
             _ => Err(format!("Unknown hook type: {}", s)),
         }
     }
 }
 
+// backing of the pool hugepages
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum PoolBacking {
+    // explicit hugetlb pages, from the reserved pools
+    HUGETLB,
+    // THP, promotion is deferred to khugepaged
+    THP,
+    // THP, synchronously promoted with MADV_COLLAPSE (kernel 6.1+)
+    COLLAPSE,
+    // base pages only, THP disabled, whatever the page sizes of the intervals: the same layout
+    // without the hugepages, as a baseline
+    BASE,
+}
+
+impl PoolBacking {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PoolBacking::HUGETLB => "hugetlb",
+            PoolBacking::THP => "thp",
+            PoolBacking::COLLAPSE => "collapse",
+            PoolBacking::BASE => "base",
+        }
+    }
+}
+
+impl FromStr for PoolBacking {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hugetlb" => Ok(PoolBacking::HUGETLB),
+            "thp" => Ok(PoolBacking::THP),
+            "collapse" => Ok(PoolBacking::COLLAPSE),
+            "base" => Ok(PoolBacking::BASE),
+            _ => Err(format!("Unknown pool backing: {}", s)),
+        }
+    }
+}
+
+// what to do with pool memory the application gives back (malloc_trim, MADV_DONTNEED)
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ReclaimPolicy {
+    // keep everything mapped
+    NONE,
+    // unmap fully free hugepages on malloc_trim and drop the backing of discarded hugepages
+    RELEASE,
+}
+
+impl ReclaimPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReclaimPolicy::NONE => "none",
+            ReclaimPolicy::RELEASE => "release",
+        }
+    }
+}
+
+impl FromStr for ReclaimPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(ReclaimPolicy::NONE),
+            "release" => Ok(ReclaimPolicy::RELEASE),
+            _ => Err(format!("Unknown reclaim policy: {}", s)),
+        }
+    }
+}
+
+// how the aging policy pushes the cold base page (or THP) backed pool pages towards swap, hugetlb
+// pages can't be swapped
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum SwapPolicy {
+    // leave them to the kernel
+    NONE,
+    // deactivate them (MADV_COLD), they go first under memory pressure
+    COLD,
+    // reclaim them right away (MADV_PAGEOUT), to swap or zswap
+    PAGEOUT,
+}
+
+impl SwapPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SwapPolicy::NONE => "none",
+            SwapPolicy::COLD => "cold",
+            SwapPolicy::PAGEOUT => "pageout",
+        }
+    }
+}
+
+impl FromStr for SwapPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(SwapPolicy::NONE),
+            "cold" => Ok(SwapPolicy::COLD),
+            "pageout" => Ok(SwapPolicy::PAGEOUT),
+            _ => Err(format!("Unknown swap policy: {}", s)),
+        }
+    }
+}
+
+// whether the pre-existing glibc heap is exhausted (drained) before mosalloc takes over brk
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum DrainPolicy {
+    // always drain
+    FULL,
+    // skip draining when glibc grows the heap through the __morecore hook (glibc<=2.33)
+    AUTO,
+    // never drain
+    NONE,
+}
+
+impl DrainPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DrainPolicy::FULL => "full",
+            DrainPolicy::AUTO => "auto",
+            DrainPolicy::NONE => "none",
+        }
+    }
+}
+
+impl FromStr for DrainPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(DrainPolicy::FULL),
+            "auto" => Ok(DrainPolicy::AUTO),
+            "none" => Ok(DrainPolicy::NONE),
+            _ => Err(format!("Unknown drain policy: {}", s)),
+        }
+    }
+}
+
+// how the anon mmap and brk requests of the other threads are handled while the glibc heap is
+// being drained, the drain itself relies on them failing
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum EarlyPolicy {
+    // fail with ENOMEM
+    ENOMEM,
+    // forward the mmaps to libc, and absorb the ones that land in the anon region once it's
+    // usable (brk requests wait for the drain, the program break is emulated)
+    FORWARD,
+    // block the requests until the drain completes, then serve them from the regions; the wait
+    // is bounded (a second), the drain may need a glibc arena lock held by the waiting thread, and
+    // the requests still pending then fail with ENOMEM, nothing is queued to be replayed later
+    WAIT,
+}
+
+impl EarlyPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EarlyPolicy::ENOMEM => "enomem",
+            EarlyPolicy::FORWARD => "forward",
+            EarlyPolicy::WAIT => "wait",
+        }
+    }
+}
+
+impl FromStr for EarlyPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "enomem" => Ok(EarlyPolicy::ENOMEM),
+            "forward" => Ok(EarlyPolicy::FORWARD),
+            "wait" => Ok(EarlyPolicy::WAIT),
+            _ => Err(format!("Unknown early allocation policy: {}", s)),
+        }
+    }
+}
+
+// how the glibc heap that exists before mosalloc takes over brk is handled
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum HeapPolicy {
+    // move the program break to a new region, the pre-existing heap stays on base pages
+    RELOCATE,
+    // place the region over the pre-existing heap and copy its live contents into it, so that
+    // glibc keeps growing the same heap (falls back to relocate if the region doesn't fit there)
+    COPY,
+    // place the region in the first gap past the program break that fits it, without moving the
+    // program break, brk and sbrk are served from the region wherever it is (relocate falls back
+    // to it when the region doesn't fit right past the program break)
+    DETACH,
+}
+
+impl HeapPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HeapPolicy::RELOCATE => "relocate",
+            HeapPolicy::COPY => "copy",
+            HeapPolicy::DETACH => "detach",
+        }
+    }
+}
+
+impl FromStr for HeapPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "relocate" => Ok(HeapPolicy::RELOCATE),
+            "copy" => Ok(HeapPolicy::COPY),
+            "detach" => Ok(HeapPolicy::DETACH),
+            _ => Err(format!("Unknown heap policy: {}", s)),
+        }
+    }
+}
+
+// when the kernel zeroing of the pool hugepages happens; MAP_UNINITIALIZED isn't an option, the
+// kernel only honors it on no-MMU configs and the hugetlb faults always clear the page
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ZeroPolicy {
+    // on the first touch of each page, within the measured run
+    FAULT,
+    // at setup, by populating every pool hugepage before the program starts
+    PREFAULT,
+    // on first use, populated at mapping time so that the zeroing is timed and reported apart
+    MEASURE,
+}
+
+impl ZeroPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ZeroPolicy::FAULT => "fault",
+            ZeroPolicy::PREFAULT => "prefault",
+            ZeroPolicy::MEASURE => "measure",
+        }
+    }
+}
+
+impl FromStr for ZeroPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fault" => Ok(ZeroPolicy::FAULT),
+            "prefault" => Ok(ZeroPolicy::PREFAULT),
+            "measure" => Ok(ZeroPolicy::MEASURE),
+            _ => Err(format!("Unknown zeroing policy: {}", s)),
+        }
+    }
+}
+
+// where the anon regions place the allocations that don't ask for an address
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum PlacementPolicy {
+    // the lowest free range that fits
+    FIRSTFIT,
+    // the free range of the fullest partially used hugepage that fits a smaller allocation, the
+    // lowest free range otherwise, keeping the free hugepages whole for the larger ones
+    FILL,
+}
+
+impl PlacementPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PlacementPolicy::FIRSTFIT => "first-fit",
+            PlacementPolicy::FILL => "fill",
+        }
+    }
+}
+
+impl FromStr for PlacementPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first-fit" => Ok(PlacementPolicy::FIRSTFIT),
+            "fill" => Ok(PlacementPolicy::FILL),
+            _ => Err(format!("Unknown placement policy: {}", s)),
+        }
+    }
+}
+
+// soft / hard limits on the bytes allocated from a region, independent of the pool size
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
+pub struct SizeLimit {
+    pub soft: Option<usize>,
+    pub hard: Option<usize>,
+}
+
+impl SizeLimit {
+    #[inline]
+    pub fn is_set(&self) -> bool {
+        self.soft.is_some() || self.hard.is_some()
+    }
+
+    // soft:hard, with an empty field for a missing limit
+    pub fn as_string(&self) -> String {
+        let fmt = |x: Option<usize>| x.map(|x| x.to_string()).unwrap_or_default();
+        format!("{}:{}", fmt(self.soft), fmt(self.hard))
+    }
+}
+
+impl FromStr for SizeLimit {
+    type Err = String;
+
+    // soft[:hard], either can be left empty (e.g. `:4GB` only sets the hard limit)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |x: &str| {
+            if x.is_empty() {
+                Ok(None)
+            } else if x.starts_with(|c: char| c.is_ascii_digit()) {
+                Ok(Some(size_from_str(x)))
+            } else {
+                Err(format!("Invalid size limit: {}", s))
+            }
+        };
+
+        let (soft, hard) = s.split_once(':').unwrap_or((s, ""));
+        let limit = SizeLimit {
+            soft: parse(soft)?,
+            hard: parse(hard)?,
+        };
+
+        match limit {
+            SizeLimit {
+                soft: Some(soft),
+                hard: Some(hard),
+            } if soft > hard => Err(format!("Soft limit exceeds the hard limit: {}", s)),
+            _ => Ok(limit),
+        }
+    }
+}
+
+// boundary of the stats / trace window of interest: a marker file appearing, or seconds elapsed
+// and bytes allocated, counted from startup for the start and from the window start for the stop
+#[derive(Debug, PartialEq, Clone)]
+pub enum WindowTrigger {
+    FILE(String),
+    SECS(u64),
+    BYTES(usize),
+}
+
+impl WindowTrigger {
+    pub fn as_string(&self) -> String {
+        match self {
+            WindowTrigger::FILE(path) => format!("file:{}", path),
+            WindowTrigger::SECS(secs) => format!("time:{}", secs),
+            WindowTrigger::BYTES(bytes) => format!("alloc:{}", bytes),
+        }
+    }
+}
+
+impl FromStr for WindowTrigger {
+    type Err = String;
+
+    // file:<path>, time:<seconds> or alloc:<size>
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Invalid window trigger: {}", s);
+
+        match s.split_once(':').ok_or_else(err)? {
+            ("file", path) if !path.is_empty() => Ok(WindowTrigger::FILE(path.to_string())),
+            ("time", secs) => secs
+                .parse::<u64>()
+                .map(WindowTrigger::SECS)
+                .map_err(|_| err()),
+            ("alloc", size) if size.starts_with(|c: char| c.is_ascii_digit()) => {
+                Ok(WindowTrigger::BYTES(size_from_str(size)))
+            }
+            _ => Err(err()),
+        }
+    }
+}
+
 // libmosalloc config
+#[derive(Clone)]
 pub struct MosallocConfig {
     pub pool_config: String,
 
@@ -263,38 +1073,469 @@ pub struct MosallocConfig {
     pub dryrun: bool,
 
     pub hook: HookType,
+    pub backing: PoolBacking,
+
+    pub heatmap: Option<String>,
+    pub heatmap_period: u64,
+
+    pub snapshot: Option<String>,
+    // file the final stats are written to at exit, in the control socket format
+    pub stats_file: Option<String>,
+    pub restore: Option<String>,
+
+    pub aging_period: Option<u64>,
+    pub aging_cold: usize,
+    pub aging_hot: usize,
+    // swap policy of the aging, for the pages idle for `swap_cold` samples
+    pub swap: SwapPolicy,
+    pub swap_cold: usize,
+
+    pub cpu_caches: usize,
+    pub cache_batch: usize,
+
+    pub meminfo_period: Option<u64>,
+    pub reconcile_period: Option<u64>,
+
+    pub trace: Option<String>,
+    pub trace_size: usize,
+    pub trace_flush_period: u64,
+
+    pub fault_rate: Option<f64>,
+    pub fault_after: Option<u64>,
+    pub fault_seed: u64,
+    pub fault_ops: Vec<TraceOp>,
+
+    // calls intercepted by the hooks, the rest go straight to the kernel
+    pub hooks: Vec<TraceOp>,
+    // objects (substrings of their paths) whose mmaps the preload hooks handle, all if empty
+    pub callers: Vec<String>,
+    // threads (names or tids) whose mmaps the hooks handle, along with the ones registered
+    // through the C API, all if neither is set
+    pub threads: Vec<String>,
+
+    // TLB models whose reach over the final layout is reported at exit, none if empty
+    pub tlb: Vec<TlbModel>,
+
+    pub brk_limit: SizeLimit,
+    pub anon_limit: SizeLimit,
+    pub file_limit: SizeLimit,
+
+    // caps on the 2MB / 1GB pool pages mapped at once
+    pub max_2mb_pages: Option<usize>,
+    pub max_1gb_pages: Option<usize>,
+
+    pub reclaim: ReclaimPolicy,
+
+    pub pkeys: bool,
+
+    // pin the RDMA memory regions and CUDA host registrations made through the preload hooks
+    pub pin_devices: bool,
+    // place the private MAP_HUGETLB requests in the pool intervals of their page size, instead of
+    // forwarding them to libc
+    pub absorb_hugetlb: bool,
+    // track the hugepages reserved for the run against what the pool intervals require, and fail
+    // the mmaps exceeding the reservation with ENOMEM instead of a SIGBUS at their first touch
+    pub strict_reservation: bool,
+    // the (page size, pages) run_mosalloc reserved for the run on its node, the ones tracked by
+    // the strict reservation
+    pub reservation: Vec<(usize, usize)>,
+
+    pub protect_metadata: bool,
+
+    // internal allocator sizing, allocations from mmap_threshold up bypass the arena
+    pub arena_size: usize,
+    pub mmap_threshold: usize,
+    pub max_align: usize,
+    // size of the region reserved for mosalloc's own mappings, away from the pools
+    pub internal_region: Option<usize>,
+    // hard cap on the bytes of each region's free map storage
+    pub freemap_max: Option<usize>,
+    // bytes each anon region may grow by past its pool once out of space, in base pages
+    pub anon_ffa_max: Option<usize>,
+
+    pub drain: DrainPolicy,
+    pub drain_max: Option<usize>,
+    pub early: EarlyPolicy,
+
+    pub heap: HeapPolicy,
+
+    pub zero: ZeroPolicy,
+    pub placement: PlacementPolicy,
+
+    pub watermarks: Vec<usize>,
+
+    // CPUs the service threads are pinned to, unpinned if empty
+    pub housekeeping_cpus: Vec<usize>,
+
+    pub control_dir: Option<String>,
+
+    // socket of run_mosalloc to request the pool hugepages on, as the intervals are first touched,
+    // when they aren't reserved up front
+    pub reserve_socket: Option<String>,
+
+    pub collector: Option<String>,
+    pub collector_period: u64,
+
+    // window of interest the stats and the trace are collected in, the whole run by default, and
+    // how often its triggers are polled (ms)
+    pub window_start: Option<WindowTrigger>,
+    pub window_stop: Option<WindowTrigger>,
+    pub window_period: u64,
+}
+
+// env var holding the prefix of the libmosalloc config vars, when it isn't the default one
+pub const ENV_PREFIX_VAR: &str = "MOSALLOC_ENV_PREFIX";
+pub const DEFAULT_ENV_PREFIX: &str = "MOSALLOC_";
+// deprecated prefix, still read when a var isn't set with the configured one
+const LEGACY_ENV_PREFIX: &str = "HPC_";
+
+// prefix of the libmosalloc config vars
+pub fn env_prefix() -> String {
+    env::var(ENV_PREFIX_VAR).unwrap_or_else(|_| DEFAULT_ENV_PREFIX.to_string())
+}
+
+// a libmosalloc config var, falling back to its deprecated HPC_ alias
+fn config_var(name: &str) -> Result<String, env::VarError> {
+    let prefix = env_prefix();
+    env::var(format!("{}{}", prefix, name)).or_else(|e| {
+        let legacy = env::var(format!("{}{}", LEGACY_ENV_PREFIX, name));
+        if legacy.is_ok() {
+            println!(
+                "{}{} is deprecated, use {}{}",
+                LEGACY_ENV_PREFIX, name, prefix, name
+            );
+        }
+        legacy.map_err(|_| e)
+    })
+}
+
+impl Default for MosallocConfig {
+    fn default() -> Self {
+        Self {
+            pool_config: String::new(),
+            anon_ffa_size: 1 << 20,
+            file_ffa_size: 1 << 10,
+            file_pool_size: 1 << 30,
+            analyze_regions: false,
+            dryrun: false,
+            hook: HookType::PRELOAD,
+            backing: PoolBacking::HUGETLB,
+            heatmap: None,
+            heatmap_period: 1000,
+            snapshot: None,
+            stats_file: None,
+            restore: None,
+            aging_period: None,
+            aging_cold: 10,
+            aging_hot: 3,
+            swap: SwapPolicy::NONE,
+            swap_cold: 20,
+            cpu_caches: 0,
+            cache_batch: 16,
+            meminfo_period: None,
+            reconcile_period: None,
+            trace: None,
+            trace_size: 65536,
+            trace_flush_period: 100,
+            fault_rate: None,
+            fault_after: None,
+            fault_seed: 0,
+            fault_ops: vec![TraceOp::MMAP, TraceOp::BRK, TraceOp::MREMAP],
+            hooks: vec![
+                TraceOp::MMAP,
+                TraceOp::MUNMAP,
+                TraceOp::MPROTECT,
+                TraceOp::MADVISE,
+                TraceOp::MREMAP,
+                TraceOp::BRK,
+            ],
+            callers: Vec::new(),
+            threads: Vec::new(),
+            tlb: DEFAULT_TLB_MODELS
+                .split(',')
+                .map(|x| x.parse::<TlbModel>().unwrap())
+                .collect(),
+            brk_limit: SizeLimit::default(),
+            anon_limit: SizeLimit::default(),
+            file_limit: SizeLimit::default(),
+            max_2mb_pages: None,
+            max_1gb_pages: None,
+            reclaim: ReclaimPolicy::NONE,
+            pkeys: false,
+            pin_devices: false,
+            absorb_hugetlb: false,
+            strict_reservation: false,
+            reservation: Vec::new(),
+            protect_metadata: false,
+            arena_size: 256 << 10,
+            mmap_threshold: 4096,
+            max_align: 4096,
+            internal_region: None,
+            freemap_max: None,
+            anon_ffa_max: None,
+            drain: DrainPolicy::FULL,
+            drain_max: None,
+            early: EarlyPolicy::ENOMEM,
+            heap: HeapPolicy::RELOCATE,
+            zero: ZeroPolicy::FAULT,
+            placement: PlacementPolicy::FIRSTFIT,
+            watermarks: Vec::new(),
+            housekeeping_cpus: Vec::new(),
+            control_dir: None,
+            reserve_socket: None,
+            collector: None,
+            collector_period: 1000,
+            window_start: None,
+            window_stop: None,
+            window_period: 100,
+        }
+    }
 }
 
 impl MosallocConfig {
     // Get the config from env and create a new config
     pub fn load() -> Self {
-        let pool_config = env::var("HPC_CONFIG_FILE").unwrap();
+        let d = Self::default();
 
-        let anon_ffa_size = env::var("HPC_ANON_FFA_SIZE")
-            .unwrap()
-            .parse::<usize>()
-            .unwrap();
-        let file_ffa_size = env::var("HPC_FILE_FFA_SIZE")
-            .unwrap()
-            .parse::<usize>()
-            .unwrap();
-        let file_pool_size = env::var("HPC_FILE_POOL_SIZE")
-            .unwrap()
-            .parse::<usize>()
-            .unwrap();
+        let pool_config = config_var("CONFIG_FILE").unwrap();
 
-        let analyze_regions = env::var("HPC_ANALYZE_HPBRS")
+        let [anon_ffa_size, file_ffa_size, file_pool_size] =
+            ["ANON_FFA_SIZE", "FILE_FFA_SIZE", "FILE_POOL_SIZE"].map(|var| {
+                size_or_ram_from_str(&config_var(var).unwrap())
+                    .unwrap_or_else(|e| panic!("{}{}: {}", env_prefix(), var, e))
+            });
+        assert!(
+            file_pool_size.is_multiple_of(*PAGE_SIZE),
+            "{}FILE_POOL_SIZE: {} isn't a multiple of the page size",
+            env_prefix(),
+            file_pool_size
+        );
+        assert!(
+            file_ffa_size > 0,
+            "{}FILE_FFA_SIZE: the file FFA size can't be 0",
+            env_prefix()
+        );
+
+        let analyze_regions = config_var("ANALYZE_HPBRS")
             .unwrap()
             .parse::<bool>()
             .unwrap();
 
-        let dryrun = env::var("HPC_DRYRUN").unwrap().parse::<bool>().unwrap();
+        let dryrun = config_var("DRYRUN").unwrap().parse::<bool>().unwrap();
 
-        let hook = env::var("HPC_HOOK_TYPE")
+        let hook = config_var("HOOK_TYPE")
             .unwrap()
             .parse::<HookType>()
             .unwrap();
 
+        let backing = config_var("POOL_BACKING")
+            .map(|x| x.parse::<PoolBacking>().unwrap())
+            .unwrap_or(d.backing);
+
+        let heatmap = config_var("HEATMAP_FILE").ok();
+        let heatmap_period = config_var("HEATMAP_PERIOD")
+            .map(|x| x.parse::<u64>().unwrap())
+            .unwrap_or(d.heatmap_period);
+
+        let snapshot = config_var("SNAPSHOT_FILE").ok();
+        let stats_file = config_var("STATS_FILE").ok();
+        let restore = config_var("RESTORE_FILE").ok();
+
+        let aging_period = config_var("AGING_PERIOD")
+            .ok()
+            .map(|x| x.parse::<u64>().unwrap());
+        let aging_cold = config_var("AGING_COLD")
+            .map(|x| x.parse::<usize>().unwrap())
+            .unwrap_or(d.aging_cold);
+        let aging_hot = config_var("AGING_HOT")
+            .map(|x| x.parse::<usize>().unwrap())
+            .unwrap_or(d.aging_hot);
+        let swap = config_var("SWAP_POLICY")
+            .map(|x| x.parse::<SwapPolicy>().unwrap())
+            .unwrap_or(d.swap);
+        let swap_cold = config_var("SWAP_COLD")
+            .map(|x| x.parse::<usize>().unwrap())
+            .unwrap_or(d.swap_cold);
+
+        let cpu_caches = config_var("CPU_CACHES")
+            .map(|x| x.parse::<usize>().unwrap())
+            .unwrap_or(d.cpu_caches);
+        let cache_batch = config_var("CACHE_BATCH")
+            .map(|x| x.parse::<usize>().unwrap())
+            .unwrap_or(d.cache_batch);
+
+        let meminfo_period = config_var("MEMINFO_PERIOD")
+            .ok()
+            .map(|x| x.parse::<u64>().unwrap());
+        let reconcile_period = config_var("RECONCILE_PERIOD")
+            .ok()
+            .map(|x| x.parse::<u64>().unwrap());
+
+        let trace = config_var("TRACE_FILE").ok();
+        let trace_size = config_var("TRACE_SIZE")
+            .map(|x| x.parse::<usize>().unwrap())
+            .unwrap_or(d.trace_size);
+        let trace_flush_period = config_var("TRACE_FLUSH_PERIOD")
+            .map(|x| x.parse::<u64>().unwrap())
+            .unwrap_or(d.trace_flush_period);
+
+        let fault_rate = config_var("FAULT_RATE")
+            .ok()
+            .map(|x| x.parse::<f64>().unwrap());
+        let fault_after = config_var("FAULT_AFTER_N")
+            .ok()
+            .map(|x| x.parse::<u64>().unwrap());
+        let fault_seed = config_var("FAULT_SEED")
+            .map(|x| x.parse::<u64>().unwrap())
+            .unwrap_or(d.fault_seed);
+        let fault_ops = config_var("FAULT_OPS")
+            .map(|x| {
+                x.split(',')
+                    .map(|op| op.parse::<TraceOp>().unwrap())
+                    .inspect(|op| {
+                        assert!(op.injectable(), "no fault injection for {}", op.as_str())
+                    })
+                    .collect()
+            })
+            .unwrap_or(d.fault_ops);
+        let hooks = config_var("HOOKS")
+            .map(|x| {
+                x.split(',')
+                    .map(|op| op.parse::<TraceOp>().unwrap())
+                    .collect()
+            })
+            .unwrap_or(d.hooks);
+        let callers = config_var("CALLERS")
+            .map(|x| x.split(',').map(|x| x.to_string()).collect())
+            .unwrap_or_default();
+        let threads = config_var("THREADS")
+            .map(|x| x.split(',').map(|x| x.to_string()).collect())
+            .unwrap_or_default();
+        let tlb = config_var("TLB_MODELS")
+            .map(|x| match x.as_str() {
+                "none" => Vec::new(),
+                _ => x
+                    .split(',')
+                    .map(|x| x.parse::<TlbModel>().unwrap())
+                    .collect(),
+            })
+            .unwrap_or(d.tlb);
+
+        let [brk_limit, anon_limit, file_limit] =
+            ["BRK_LIMIT", "ANON_LIMIT", "FILE_LIMIT"].map(|var| {
+                config_var(var)
+                    .map(|x| x.parse::<SizeLimit>().unwrap())
+                    .unwrap_or_default()
+            });
+
+        let [max_2mb_pages, max_1gb_pages] = ["MAX_2MB_PAGES", "MAX_1GB_PAGES"]
+            .map(|var| config_var(var).ok().map(|x| x.parse::<usize>().unwrap()));
+
+        let reclaim = config_var("RECLAIM_POLICY")
+            .map(|x| x.parse::<ReclaimPolicy>().unwrap())
+            .unwrap_or(d.reclaim);
+
+        let pkeys = config_var("PKEYS")
+            .map(|x| x.parse::<bool>().unwrap())
+            .unwrap_or(d.pkeys);
+
+        let pin_devices = config_var("PIN_DEVICES")
+            .map(|x| x.parse::<bool>().unwrap())
+            .unwrap_or(d.pin_devices);
+        let absorb_hugetlb = config_var("ABSORB_HUGETLB")
+            .map(|x| x.parse::<bool>().unwrap())
+            .unwrap_or(d.absorb_hugetlb);
+        let strict_reservation = config_var("STRICT_RESERVATION")
+            .map(|x| x.parse::<bool>().unwrap())
+            .unwrap_or(d.strict_reservation);
+        let reservation = config_var("RESERVATION")
+            .map(|x| {
+                x.split(',')
+                    .map(|x| {
+                        let (pagesz, nr) = x.split_once(':').unwrap();
+                        (size_from_str(pagesz), nr.parse::<usize>().unwrap())
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let protect_metadata = config_var("PROTECT_METADATA")
+            .map(|x| x.parse::<bool>().unwrap())
+            .unwrap_or(d.protect_metadata);
+
+        let [arena_size, mmap_threshold, max_align] = [
+            ("ARENA_SIZE", d.arena_size),
+            ("MMAP_THRESHOLD", d.mmap_threshold),
+            ("MAX_ALIGN", d.max_align),
+        ]
+        .map(|(var, default)| {
+            config_var(var)
+                .map(|x| x.parse::<usize>().unwrap())
+                .unwrap_or(default)
+        });
+
+        let internal_region = config_var("INTERNAL_REGION")
+            .ok()
+            .map(|x| x.parse::<usize>().unwrap());
+        let freemap_max = config_var("FREEMAP_MAX")
+            .ok()
+            .map(|x| x.parse::<usize>().unwrap());
+        let anon_ffa_max = config_var("ANON_FFA_MAX")
+            .ok()
+            .map(|x| x.parse::<usize>().unwrap());
+
+        let drain = config_var("DRAIN_POLICY")
+            .map(|x| x.parse::<DrainPolicy>().unwrap())
+            .unwrap_or(d.drain);
+        let drain_max = config_var("DRAIN_MAX")
+            .ok()
+            .map(|x| x.parse::<usize>().unwrap());
+
+        let early = config_var("EARLY_POLICY")
+            .map(|x| x.parse::<EarlyPolicy>().unwrap())
+            .unwrap_or(d.early);
+
+        let heap = config_var("HEAP_POLICY")
+            .map(|x| x.parse::<HeapPolicy>().unwrap())
+            .unwrap_or(d.heap);
+
+        let zero = config_var("ZERO_POLICY")
+            .map(|x| x.parse::<ZeroPolicy>().unwrap())
+            .unwrap_or(d.zero);
+        let placement = config_var("PLACEMENT")
+            .map(|x| x.parse::<PlacementPolicy>().unwrap())
+            .unwrap_or(d.placement);
+
+        let watermarks = config_var("WATERMARKS")
+            .map(|x| {
+                x.split(',')
+                    .filter(|pct| !pct.is_empty())
+                    .map(|pct| pct.parse::<usize>().unwrap())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let housekeeping_cpus = config_var("HOUSEKEEPING_CPUS")
+            .map(|x| cpus_from_str(&x).unwrap())
+            .unwrap_or_default();
+
+        let control_dir = config_var("CONTROL_DIR").ok();
+        let reserve_socket = config_var("RESERVE_SOCKET").ok();
+
+        let collector = config_var("COLLECTOR").ok();
+        let collector_period = config_var("COLLECTOR_PERIOD")
+            .map(|x| x.parse::<u64>().unwrap())
+            .unwrap_or(d.collector_period);
+
+        let [window_start, window_stop] = ["WINDOW_START", "WINDOW_STOP"].map(|var| {
+            config_var(var)
+                .ok()
+                .map(|x| x.parse::<WindowTrigger>().unwrap())
+        });
+        let window_period = config_var("WINDOW_PERIOD")
+            .map(|x| x.parse::<u64>().unwrap())
+            .unwrap_or(d.window_period);
+
         Self {
             pool_config,
             anon_ffa_size,
@@ -303,86 +1544,281 @@ impl MosallocConfig {
             analyze_regions,
             dryrun,
             hook,
+            backing,
+            heatmap,
+            heatmap_period,
+            snapshot,
+            stats_file,
+            restore,
+            aging_period,
+            aging_cold,
+            aging_hot,
+            swap,
+            swap_cold,
+            cpu_caches,
+            cache_batch,
+            meminfo_period,
+            reconcile_period,
+            trace,
+            trace_size,
+            trace_flush_period,
+            fault_rate,
+            fault_after,
+            fault_seed,
+            fault_ops,
+            hooks,
+            callers,
+            threads,
+            tlb,
+            brk_limit,
+            anon_limit,
+            file_limit,
+            max_2mb_pages,
+            max_1gb_pages,
+            reclaim,
+            pkeys,
+            pin_devices,
+            absorb_hugetlb,
+            strict_reservation,
+            reservation,
+            protect_metadata,
+            arena_size,
+            mmap_threshold,
+            max_align,
+            internal_region,
+            freemap_max,
+            anon_ffa_max,
+            drain,
+            drain_max,
+            early,
+            heap,
+            zero,
+            placement,
+            watermarks,
+            housekeeping_cpus,
+            control_dir,
+            reserve_socket,
+            collector,
+            collector_period,
+            window_start,
+            window_stop,
+            window_period,
         }
     }
 
-    // Set the env according to the config
-    pub fn save(&self) {
-        env::set_var("HPC_ANON_FFA_SIZE", self.anon_ffa_size.to_string());
-        env::set_var("HPC_FILE_FFA_SIZE", self.file_ffa_size.to_string());
-        env::set_var("HPC_FILE_POOL_SIZE", self.file_pool_size.to_string());
-        env::set_var("HPC_ANALYZE_HPBRS", self.analyze_regions.to_string());
-        env::set_var("HPC_DRYRUN", self.dryrun.to_string());
-        env::set_var("HPC_HOOK_TYPE", self.hook.as_str());
-        env::set_var("HPC_CONFIG_FILE", &self.pool_config);
-    }
-}
+    // (var name without the prefix, value) of the config, unset options are left out
+    pub fn to_env(&self) -> Vec<(&'static str, String)> {
+        let mut vars = vec![
+            ("ANON_FFA_SIZE", self.anon_ffa_size.to_string()),
+            ("FILE_FFA_SIZE", self.file_ffa_size.to_string()),
+            ("FILE_POOL_SIZE", self.file_pool_size.to_string()),
+            ("ANALYZE_HPBRS", self.analyze_regions.to_string()),
+            ("DRYRUN", self.dryrun.to_string()),
+            ("HOOK_TYPE", self.hook.as_str().to_string()),
+            ("POOL_BACKING", self.backing.as_str().to_string()),
+            ("CONFIG_FILE", self.pool_config.clone()),
+        ];
+        let mut opt = |name: &'static str, val: Option<String>| {
+            if let Some(val) = val {
+                vars.push((name, val));
+            }
+        };
 
-// HTLB intervals pool
-#[derive(Debug)]
-pub struct Pool {
-    pub alloc_type: AllocType,
-    pub intervals: Vec<Interval>,
-}
-
-impl Pool {
-    // Create a new pseudo-htlb pool for file-mapped regions, page size is fixed at 4KB
-    pub fn new_file_pool(sz: usize) -> Self {
-        assert!(sz & PAGE_SIZE == 0);
-        Pool {
-            alloc_type: AllocType::FILE,
-            intervals: vec![Interval {
-                pagesz: PAGE_SIZE,
-                start: 0,
-                end: sz,
-            }],
+        opt("HEATMAP_FILE", self.heatmap.clone());
+        opt("HEATMAP_PERIOD", Some(self.heatmap_period.to_string()));
+        opt("SNAPSHOT_FILE", self.snapshot.clone());
+        opt("STATS_FILE", self.stats_file.clone());
+        opt("RESTORE_FILE", self.restore.clone());
+        opt("AGING_PERIOD", self.aging_period.map(|x| x.to_string()));
+        opt("AGING_COLD", Some(self.aging_cold.to_string()));
+        opt("AGING_HOT", Some(self.aging_hot.to_string()));
+        opt("SWAP_POLICY", Some(self.swap.as_str().to_string()));
+        opt("SWAP_COLD", Some(self.swap_cold.to_string()));
+        opt("CPU_CACHES", Some(self.cpu_caches.to_string()));
+        opt("CACHE_BATCH", Some(self.cache_batch.to_string()));
+        opt("MEMINFO_PERIOD", self.meminfo_period.map(|x| x.to_string()));
+        opt(
+            "RECONCILE_PERIOD",
+            self.reconcile_period.map(|x| x.to_string()),
+        );
+        opt("TRACE_FILE", self.trace.clone());
+        opt("TRACE_SIZE", Some(self.trace_size.to_string()));
+        opt(
+            "TRACE_FLUSH_PERIOD",
+            Some(self.trace_flush_period.to_string()),
+        );
+        opt("FAULT_RATE", self.fault_rate.map(|x| x.to_string()));
+        opt("FAULT_AFTER_N", self.fault_after.map(|x| x.to_string()));
+        opt("FAULT_SEED", Some(self.fault_seed.to_string()));
+        opt(
+            "FAULT_OPS",
+            Some(
+                self.fault_ops
+                    .iter()
+                    .map(|x| x.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(","),
+            ),
+        );
+        opt(
+            "CALLERS",
+            (!self.callers.is_empty()).then(|| self.callers.join(",")),
+        );
+        opt(
+            "THREADS",
+            (!self.threads.is_empty()).then(|| self.threads.join(",")),
+        );
+        opt(
+            "TLB_MODELS",
+            Some(if self.tlb.is_empty() {
+                "none".to_string()
+            } else {
+                self.tlb
+                    .iter()
+                    .map(|x| x.as_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            }),
+        );
+        opt(
+            "HOOKS",
+            Some(
+                self.hooks
+                    .iter()
+                    .map(|x| x.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(","),
+            ),
+        );
+        for (var, limit) in [
+            ("BRK_LIMIT", &self.brk_limit),
+            ("ANON_LIMIT", &self.anon_limit),
+            ("FILE_LIMIT", &self.file_limit),
+        ] {
+            opt(var, limit.is_set().then(|| limit.as_string()));
         }
-    }
-
-    // Create a new htlb pool from the intervals-holding CSV config
-    pub fn from_csv(alloc_type: AllocType, config: &Path) -> Self {
-        let mut intervals = csv::Reader::from_path(config)
-            .unwrap()
-            .deserialize()
-            .filter_map(|x| {
-                let rec: CSVRecord = x.unwrap();
-                if rec.region_type == alloc_type.as_str() {
-                    Some(Interval::from(rec))
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<Interval>>();
+        opt("MAX_2MB_PAGES", self.max_2mb_pages.map(|x| x.to_string()));
+        opt("MAX_1GB_PAGES", self.max_1gb_pages.map(|x| x.to_string()));
+        opt("RECLAIM_POLICY", Some(self.reclaim.as_str().to_string()));
+        opt("PKEYS", Some(self.pkeys.to_string()));
+        opt("PIN_DEVICES", Some(self.pin_devices.to_string()));
+        opt("ABSORB_HUGETLB", Some(self.absorb_hugetlb.to_string()));
+        opt(
+            "STRICT_RESERVATION",
+            Some(self.strict_reservation.to_string()),
+        );
+        opt(
+            "RESERVATION",
+            (!self.reservation.is_empty()).then(|| {
+                self.reservation
+                    .iter()
+                    .map(|(pagesz, nr)| format!("{}:{}", size_to_str(*pagesz), nr))
+                    .collect::<Vec<String>>()
+                    .join(",")
+            }),
+        );
+        opt("PROTECT_METADATA", Some(self.protect_metadata.to_string()));
+        opt("ARENA_SIZE", Some(self.arena_size.to_string()));
+        opt("MMAP_THRESHOLD", Some(self.mmap_threshold.to_string()));
+        opt("MAX_ALIGN", Some(self.max_align.to_string()));
+        opt(
+            "INTERNAL_REGION",
+            self.internal_region.map(|x| x.to_string()),
+        );
+        opt("FREEMAP_MAX", self.freemap_max.map(|x| x.to_string()));
+        opt("ANON_FFA_MAX", self.anon_ffa_max.map(|x| x.to_string()));
+        opt("DRAIN_POLICY", Some(self.drain.as_str().to_string()));
+        opt("DRAIN_MAX", self.drain_max.map(|x| x.to_string()));
+        opt("EARLY_POLICY", Some(self.early.as_str().to_string()));
+        opt("HEAP_POLICY", Some(self.heap.as_str().to_string()));
+        opt("ZERO_POLICY", Some(self.zero.as_str().to_string()));
+        opt("PLACEMENT", Some(self.placement.as_str().to_string()));
+        opt(
+            "WATERMARKS",
+            Some(
+                self.watermarks
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<String>>()
+                    .join(","),
+            ),
+        );
+        opt(
+            "HOUSEKEEPING_CPUS",
+            Some(
+                self.housekeeping_cpus
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<String>>()
+                    .join(","),
+            ),
+        );
+        opt("CONTROL_DIR", self.control_dir.clone());
+        opt("RESERVE_SOCKET", self.reserve_socket.clone());
+        opt("COLLECTOR", self.collector.clone());
+        opt("COLLECTOR_PERIOD", Some(self.collector_period.to_string()));
+        opt(
+            "WINDOW_START",
+            self.window_start.as_ref().map(|x| x.as_string()),
+        );
+        opt(
+            "WINDOW_STOP",
+            self.window_stop.as_ref().map(|x| x.as_string()),
+        );
+        opt("WINDOW_PERIOD", Some(self.window_period.to_string()));
 
-        intervals.sort_by_key(|k| k.start);
+        vars
+    }
 
-        let prev = &intervals[0];
-        for next in intervals.iter().skip(1) {
-            assert!(prev.end <= next.start, "overlapping intervals");
+    // (var, value) of the exported env with the given prefix, along with the prefix var itself
+    // unless it's the default one
+    pub fn env_vars(&self, prefix: &str) -> Vec<(String, String)> {
+        let mut vars = self
+            .to_env()
+            .into_iter()
+            .map(|(name, val)| (format!("{}{}", prefix, name), val))
+            .collect::<Vec<(String, String)>>();
+        if prefix != DEFAULT_ENV_PREFIX {
+            vars.insert(0, (ENV_PREFIX_VAR.to_string(), prefix.to_string()));
         }
+        vars
+    }
 
-        Pool {
-            alloc_type,
-            intervals,
+    // Set the env according to the config
+    pub fn save(&self, prefix: &str) {
+        for (var, val) in self.env_vars(prefix) {
+            env::set_var(var, val);
         }
     }
+}
+
+// the pool constructors and queries that go through the config files or sysfs, the rest of the
+// pool bookkeeping is in mosalloc-core
+pub trait PoolExt {
+    fn from_config(alloc_type: AllocType, config: &Path) -> Self;
+    fn named_from_config(config: &Path) -> Vec<(RegionEntry, Self)>
+    where
+        Self: Sized;
+    fn size(&self) -> usize;
+}
+
+impl PoolExt for Pool {
+    // Create a new htlb pool from the intervals-holding config (versioned or legacy CSV)
+    fn from_config(alloc_type: AllocType, config: &Path) -> Self {
+        PoolConfig::from_path(config)
+            .unwrap_or_else(|e| panic!("{}", e))
+            .pool(alloc_type)
+    }
 
-    // number of HTLB pages of a given size in the pool
-    pub fn nrpages(&self, sz: usize) -> usize {
-        self.intervals
-            .iter()
-            .filter_map(|x| {
-                if x.pagesz == sz {
-                    Some((x.end - x.start) / x.pagesz)
-                } else {
-                    None
-                }
-            })
-            .sum()
+    // the htlb pools of the named anon regions of the config, along with their bounds
+    fn named_from_config(config: &Path) -> Vec<(RegionEntry, Self)> {
+        PoolConfig::from_path(config)
+            .unwrap_or_else(|e| panic!("{}", e))
+            .named_pools()
     }
 
     // total size of the HTLB pages in the pool
-    pub fn size(&self) -> usize {
+    fn size(&self) -> usize {
         supported_htlb_sizes()
             .iter()
             .fold(0, |acc, &x| acc + self.nrpages(x) * x)
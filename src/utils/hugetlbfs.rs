@@ -0,0 +1,132 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+
+use nix::errno::Errno;
+use nix::libc;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::unistd::{access, AccessFlags, Gid, Uid};
+
+use super::htlb::get_default_htlb_size;
+use super::misc::size_to_str;
+
+// mountpoint name prefix of the instances mounted by mount_private
+const PRIVATE_PREFIX: &str = "mosalloc-hugetlbfs-";
+
+fn io_errno(e: io::Error) -> Errno {
+    Errno::from_i32(e.raw_os_error().unwrap_or(libc::EIO))
+}
+
+// a hugetlbfs mount, serving a single page size
+#[derive(Debug, Clone)]
+pub struct HugetlbfsMount {
+    pub path: PathBuf,
+    pub pagesz: usize,
+    // mounted by mount_private
+    pub private: bool,
+}
+
+impl HugetlbfsMount {
+    // whether the current user can create files in the mount
+    pub fn writable(&self) -> bool {
+        access(&self.path, AccessFlags::W_OK | AccessFlags::X_OK).is_ok()
+    }
+
+    // lazily unmount a private mount and remove its mountpoint
+    pub fn unmount(&self) -> Result<(), Errno> {
+        umount2(&self.path, MntFlags::MNT_DETACH)?;
+        fs::remove_dir(&self.path).map_err(io_errno)
+    }
+}
+
+// hugetlbfs pagesize mount option, e.g. 2M or 1024K
+fn parse_mount_pagesize(s: &str) -> Option<usize> {
+    let (num, shift) = match s.chars().last()? {
+        'K' => (&s[..s.len() - 1], 10),
+        'M' => (&s[..s.len() - 1], 20),
+        'G' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    num.parse::<usize>().ok().map(|x| x << shift)
+}
+
+// all the hugetlbfs mounts visible to the current process
+pub fn hugetlbfs_mounts() -> Vec<HugetlbfsMount> {
+    let default = get_default_htlb_size();
+
+    fs::read_to_string("/proc/mounts")
+        .unwrap_or_default()
+        .lines()
+        .map(|x| x.split_whitespace().collect::<Vec<&str>>())
+        .filter(|x| x.len() > 3 && x[2] == "hugetlbfs")
+        .filter_map(|x| {
+            let pagesz = x[3]
+                .split(',')
+                .find_map(|opt| opt.strip_prefix("pagesize="))
+                .map(parse_mount_pagesize)
+                .unwrap_or(default)?;
+
+            let path = PathBuf::from(x[1]);
+            let private = path
+                .file_name()
+                .is_some_and(|x| x.to_string_lossy().starts_with(PRIVATE_PREFIX));
+
+            Some(HugetlbfsMount {
+                path,
+                pagesz,
+                private,
+            })
+        })
+        .collect()
+}
+
+// find a hugetlbfs mount for the given page size, preferring the writable ones
+pub fn hugetlbfs_mount(pagesz: usize) -> Option<HugetlbfsMount> {
+    let mounts = hugetlbfs_mounts()
+        .into_iter()
+        .filter(|x| x.pagesz == pagesz)
+        .collect::<Vec<HugetlbfsMount>>();
+
+    mounts
+        .iter()
+        .find(|x| x.writable())
+        .or_else(|| mounts.first())
+        .cloned()
+}
+
+// mount a new hugetlbfs instance for the given page size under the temp dir, owned by uid:gid
+// (requires CAP_SYS_ADMIN, e.g. through `mosalloc hugetlbfs --mount` run as root)
+pub fn mount_private(pagesz: usize, uid: Uid, gid: Gid) -> Result<HugetlbfsMount, Errno> {
+    let path = env::temp_dir().join(format!(
+        "{}{}-{}",
+        PRIVATE_PREFIX,
+        size_to_str(pagesz),
+        process::id()
+    ));
+    fs::create_dir(&path).map_err(io_errno)?;
+
+    let opts = format!(
+        "pagesize={}K,uid={},gid={},mode=0700",
+        pagesz >> 10,
+        uid,
+        gid
+    );
+    if let Err(e) = mount(
+        Some("none"),
+        &path,
+        Some("hugetlbfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some(opts.as_str()),
+    ) {
+        let _ = fs::remove_dir(&path);
+        return Err(e);
+    }
+
+    Ok(HugetlbfsMount {
+        path,
+        pagesz,
+        private: true,
+    })
+}
@@ -0,0 +1,225 @@
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+use nix::libc;
+use nix::sys::ptrace;
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::Pid;
+
+use super::attach::{process_maps, Mapping};
+use super::config::PoolConfig;
+use super::htlb::{AllocType, Pool, PAGE_SIZE};
+use super::misc::{align_up, size_to_str};
+
+// lowest address a region can be placed at, the default vm.mmap_min_addr
+const MIN_ADDR: usize = 0x10000;
+
+// address space of a process, as the regions are placed against it
+#[derive(Debug)]
+pub struct LayoutSnapshot {
+    pub maps: Vec<Mapping>,
+    // program break the heap region is placed past
+    pub brk: usize,
+}
+
+// start of the heap of a process, field 47 of /proc/<pid>/stat
+fn start_brk(pid: i32) -> Result<usize, String> {
+    let stat =
+        fs::read_to_string(format!("/proc/{}/stat", pid)).map_err(|e| format!("{}: {}", pid, e))?;
+
+    // the command name may contain spaces, the fields after it start at the state (field 3)
+    stat.rsplit_once(')')
+        .and_then(|(_, x)| x.split_whitespace().nth(47 - 3))
+        .and_then(|x| x.parse::<usize>().ok())
+        .ok_or_else(|| format!("{}: invalid stat", pid))
+}
+
+impl LayoutSnapshot {
+    // launch a program stopped right after exec, before the dynamic loader runs, and take its
+    // snapshot; the shared libraries are mapped later, away from the program break
+    pub fn stopped(program: &str, args: &[String]) -> Result<Self, String> {
+        let child = unsafe {
+            Command::new(program)
+                .args(args)
+                .pre_exec(|| ptrace::traceme().map_err(io::Error::from))
+        }
+        .spawn()
+        .map_err(|e| format!("{}: {}", program, e))?;
+        let pid = Pid::from_raw(child.id() as i32);
+
+        let snapshot = match waitpid(pid, None) {
+            Ok(WaitStatus::Stopped(_, Signal::SIGTRAP)) => {
+                process_maps(pid.as_raw()).and_then(|maps| {
+                    Ok(Self {
+                        maps,
+                        brk: start_brk(pid.as_raw())?,
+                    })
+                })
+            }
+            Ok(status) => Err(format!("{}: unexpected status {:?}", program, status)),
+            Err(e) => Err(format!("{}: {}", program, e)),
+        };
+
+        let _ = kill(pid, Signal::SIGKILL);
+        let _ = waitpid(pid, None);
+
+        snapshot
+    }
+
+    // snapshot of the current process
+    pub fn current() -> Result<Self, String> {
+        Ok(Self {
+            maps: process_maps(std::process::id() as i32)?,
+            brk: unsafe { libc::sbrk(0) } as usize,
+        })
+    }
+}
+
+// start of each region, given its (type, len, max page size), placed one after the other in the
+// first gap past the previous one (the first past `from`) that fits it, aligned to its max page
+// size; the heap has to fit in the first gap, brk can't move the program break past a mapping,
+// unless it's detached from the program break, brk and sbrk being served from it wherever it is
+pub fn place_regions(
+    maps: &[Mapping],
+    from: usize,
+    regions: &[(AllocType, usize, usize)],
+    detach_heap: bool,
+) -> Result<Vec<usize>, String> {
+    let mut out = Vec::new();
+    let mut upper = from;
+
+    for &(alloc_type, len, pgsz) in regions {
+        let pgsz = pgsz.max(*PAGE_SIZE);
+        let mut start = align_up(upper, pgsz);
+
+        for m in maps.iter() {
+            if m.range.end <= start {
+                continue;
+            }
+            if start
+                .checked_add(len)
+                .is_some_and(|end| end <= m.range.start)
+            {
+                break;
+            }
+            if alloc_type == AllocType::BRK && !detach_heap && m.range.start > start {
+                return Err(format!(
+                    "no room for the {} brk region at {:x}, before {:x}",
+                    len, start, m.range.start
+                ));
+            }
+            if m.name == "[stack]" {
+                return Err(format!(
+                    "no room for the {} {} region below the stack",
+                    len,
+                    alloc_type.as_str()
+                ));
+            }
+            start = align_up(m.range.end, pgsz);
+        }
+
+        upper = start
+            .checked_add(len)
+            .ok_or_else(|| format!("{} region doesn't fit", alloc_type.as_str()))?;
+        out.push(start);
+    }
+
+    Ok(out)
+}
+
+// start of a region that has to end below `below` (e.g. a low region for the MAP_32BIT
+// requests), placed in the first gap past the lowest mappable address that fits it, aligned to
+// its max page size, avoiding the mappings and the regions already placed
+pub fn place_below(
+    maps: &[Mapping],
+    placed: &[Range<usize>],
+    len: usize,
+    pgsz: usize,
+    below: usize,
+) -> Result<usize, String> {
+    let pgsz = pgsz.max(*PAGE_SIZE);
+    let mut taken = maps
+        .iter()
+        .map(|m| m.range.clone())
+        .chain(placed.iter().cloned())
+        .collect::<Vec<Range<usize>>>();
+    taken.sort_by_key(|x| x.start);
+
+    let mut start = align_up(MIN_ADDR, pgsz);
+    for x in taken.iter() {
+        if x.end <= start {
+            continue;
+        }
+        if start.checked_add(len).is_some_and(|end| end <= x.start) {
+            break;
+        }
+        start = align_up(x.end, pgsz);
+    }
+
+    match start.checked_add(len) {
+        Some(end) if end <= below => Ok(start),
+        _ => Err(format!(
+            "no room for the {} region below {:x}",
+            size_to_str(len),
+            below
+        )),
+    }
+}
+
+// (name of a named region, pool, start) of the regions of a config placed against a snapshot,
+// the named regions without a bound past the default ones and the bounded ones in the first gap
+// that fits them; the heap region is detached from the program break if it doesn't fit past it,
+// as at startup
+pub fn plan_regions(
+    snapshot: &LayoutSnapshot,
+    config: &PoolConfig,
+    file_pool_size: usize,
+) -> Result<Vec<(Option<String>, Pool, usize)>, String> {
+    let named = config.named_pools();
+    let (bounded, unbounded): (Vec<_>, Vec<_>) =
+        named.into_iter().partition(|(x, _)| x.below.is_some());
+
+    let mut placed = [
+        (None, config.pool(AllocType::BRK)),
+        (None, config.pool(AllocType::ANON)),
+    ]
+    .into_iter()
+    .chain(unbounded.into_iter().map(|(x, p)| (Some(x.name), p)))
+    .chain([(None, Pool::new_file_pool(file_pool_size, *PAGE_SIZE))])
+    .collect::<Vec<(Option<String>, Pool)>>();
+
+    let regions = placed
+        .iter()
+        .map(|(_, x)| (x.alloc_type, x.span(), x.max_pagesz()))
+        .collect::<Vec<(AllocType, usize, usize)>>();
+    let mut starts = place_regions(&snapshot.maps, snapshot.brk, &regions, false)
+        .or_else(|e| place_regions(&snapshot.maps, snapshot.brk, &regions, true).map_err(|_| e))?;
+
+    let mut ranges = placed
+        .iter()
+        .zip(starts.iter())
+        .map(|((_, p), &start)| start..start + p.span())
+        .collect::<Vec<Range<usize>>>();
+    for (entry, pool) in bounded {
+        let start = place_below(
+            &snapshot.maps,
+            &ranges,
+            pool.span(),
+            pool.max_pagesz(),
+            entry.below.unwrap(),
+        )?;
+        ranges.push(start..start + pool.span());
+        starts.push(start);
+        placed.push((Some(entry.name), pool));
+    }
+
+    Ok(placed
+        .into_iter()
+        .zip(starts)
+        .map(|((name, pool), start)| (name, pool, start))
+        .collect())
+}
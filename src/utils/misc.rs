@@ -1,48 +1,9 @@
 use lazy_static::lazy_static;
 use regex::Regex;
 
-// size alignment helprs
-#[inline]
-pub const fn align(n: usize, m: usize, up: bool) -> usize {
-    assert!(m.is_power_of_two());
-    let mut ret = n & !(m - 1);
-    if up && (ret != n) {
-        ret += m;
-    }
-
-    return ret;
-}
-
-#[inline]
-pub const fn align_up(n: usize, m: usize) -> usize {
-    align(n, m, true)
-}
-
-#[inline]
-pub const fn align_down(n: usize, m: usize) -> usize {
-    align(n, m, false)
-}
+use super::htlb::meminfo_field;
 
-#[inline]
-pub const fn is_aligned(n: usize, m: usize) -> bool {
-    assert!(m.is_power_of_two());
-    n & (m - 1) == 0
-}
-
-// Human-readable size conversion utils
-pub fn size_to_str(sz: usize) -> String {
-    if sz >> 10 == 0 {
-        format!("{}B", sz)
-    } else if sz >> 20 == 0 {
-        format!("{}KB", sz >> 10)
-    } else if sz >> 30 == 0 {
-        format!("{}MB", sz >> 20)
-    } else if sz >> 40 == 0 {
-        format!("{}GB", sz >> 30)
-    } else {
-        format!("{}TB", sz >> 40)
-    }
-}
+pub use mosalloc_core::misc::{align, align_down, align_up, find_range, is_aligned, size_to_str};
 
 pub fn size_from_str(s: &str) -> usize {
     lazy_static! {
@@ -54,10 +15,64 @@ pub fn size_from_str(s: &str) -> usize {
 
     match sfx {
         "" | "b" | "B" => sz,
-        "kB" | "KB" => sz << 10,
-        "mB" | "MB" => sz << 20,
-        "gB" | "GB" => sz << 30,
-        "tB" | "TB" => sz << 40,
+        "K" | "kB" | "KB" => sz << 10,
+        "M" | "mB" | "MB" => sz << 20,
+        "G" | "gB" | "GB" => sz << 30,
+        "T" | "tB" | "TB" => sz << 40,
         &_ => todo!(),
     }
 }
+
+// total RAM in bytes, from /proc/meminfo
+pub fn mem_total() -> Result<usize, String> {
+    meminfo_field("MemTotal").ok_or_else(|| "/proc/meminfo: no MemTotal".to_string())
+}
+
+// a size in bytes (e.g. 1073741824), with a unit (e.g. 512MB) or as a percentage of the RAM (e.g.
+// %10-of-RAM or 10%-of-RAM)
+pub fn size_or_ram_from_str(s: &str) -> Result<usize, String> {
+    if let Some(pct) = s.strip_suffix("-of-RAM") {
+        let pct = pct
+            .strip_prefix('%')
+            .or_else(|| pct.strip_suffix('%'))
+            .and_then(|x| x.parse::<f64>().ok())
+            .filter(|x| *x > 0.0 && *x <= 100.0)
+            .ok_or_else(|| format!("Invalid size {} (expected e.g. %10-of-RAM)", s))?;
+        return Ok((mem_total()? as f64 * pct / 100.0) as usize);
+    }
+
+    if !is_size_str(s) {
+        return Err(format!("Invalid size {} (expected e.g. 512MB)", s));
+    }
+    Ok(size_from_str(s))
+}
+
+// whether the whole string is a size size_from_str understands
+pub fn is_size_str(s: &str) -> bool {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"^\d+[KMGT]?B?$").unwrap();
+    }
+    RE.is_match(s)
+}
+
+// the CPUs of a cpulist (e.g. 0-3,8), in the sysfs / taskset format
+pub fn cpus_from_str(s: &str) -> Result<Vec<usize>, String> {
+    let mut cpus = Vec::new();
+
+    for x in s.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        let (first, last) = x.split_once('-').unwrap_or((x, x));
+        let parse = |y: &str| {
+            y.parse::<usize>()
+                .map_err(|_| format!("Invalid CPU list {} (expected e.g. 0-3,8)", s))
+        };
+        let (first, last) = (parse(first)?, parse(last)?);
+        if first > last {
+            return Err(format!("Invalid CPU range {}", x));
+        }
+        cpus.extend(first..=last);
+    }
+
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
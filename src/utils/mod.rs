@@ -1,5 +1,31 @@
+pub mod advice;
 pub mod argparse;
+pub mod attach;
+pub mod autosize;
+pub mod backend_diff;
+pub mod child;
+pub mod config;
+pub mod conformance;
+pub mod control;
+pub mod doctor;
+pub mod elf;
+#[cfg(any(test, feature = "test-fixtures"))]
+pub mod fixture;
+pub mod heatmap;
 pub mod htlb;
+pub mod hugetlbfs;
+pub mod layout;
 pub mod misc;
+pub mod multirun;
+pub mod on_demand;
 pub mod rangelist;
+pub mod report;
+pub mod schema;
+pub mod selftest;
+pub mod session;
+pub mod slurm;
+pub mod snapshot;
 pub mod sysfs_path;
+pub mod tlb;
+pub mod trace;
+pub mod trace_state;
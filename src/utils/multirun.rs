@@ -0,0 +1,140 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::htlb::supported_htlb_sizes;
+use super::misc::{size_from_str, size_to_str};
+
+// an instance of a concurrent run
+#[derive(Debug, Clone)]
+pub struct Instance {
+    pub config: String,
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+// replaces %i by the instance index
+pub fn expand(pattern: &str, index: usize) -> String {
+    let mut out = String::new();
+    let mut chars = pattern.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('i') => out += &index.to_string(),
+            Some(x) => {
+                out.push('%');
+                out.push(x);
+            }
+            None => out.push('%'),
+        }
+    }
+
+    out
+}
+
+// instances of a run list, one per line as `<config> <program> [args...]`
+pub fn run_list(path: &Path) -> Result<Vec<Instance>, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let mut instances = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace().map(|x| x.to_string());
+        match (fields.next(), fields.next()) {
+            (Some(config), Some(program)) => instances.push(Instance {
+                config,
+                program,
+                args: fields.collect(),
+            }),
+            _ => {
+                return Err(format!(
+                    "{}:{}: expected `<config> <program> [args...]`",
+                    path.display(),
+                    i + 1
+                ))
+            }
+        }
+    }
+
+    if instances.is_empty() {
+        return Err(format!("{}: no instances", path.display()));
+    }
+    Ok(instances)
+}
+
+// hugepages shared by the instances, per supported size (e.g. 2MB:512,1GB:4), None for the
+// sizes without a budget
+#[derive(Debug, Clone)]
+pub struct Budget {
+    pub pages: Vec<Option<usize>>,
+}
+
+impl FromStr for Budget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let sizes = supported_htlb_sizes();
+        let mut pages = vec![None; sizes.len()];
+
+        for x in s.split(',') {
+            let (sz, nr) = x
+                .split_once(':')
+                .ok_or_else(|| format!("Invalid budget {} (expected <size>:<pages>)", x))?;
+            let sz = size_from_str(sz);
+            let i = sizes
+                .iter()
+                .position(|x| *x == sz)
+                .ok_or_else(|| format!("Unsupported hugepage size {}", size_to_str(sz)))?;
+            pages[i] = Some(
+                nr.parse::<usize>()
+                    .map_err(|_| format!("Invalid number of pages {}", nr))?,
+            );
+        }
+
+        Ok(Budget { pages })
+    }
+}
+
+impl Budget {
+    // pages of each size granted to each instance, given their requests; when the requests of
+    // a size exceed its budget it's split proportionally to them
+    pub fn share(&self, reqs: &[Vec<usize>]) -> Vec<Vec<usize>> {
+        let mut shares = reqs.to_vec();
+
+        for (i, budget) in self.pages.iter().enumerate() {
+            let budget = match budget {
+                Some(x) => *x,
+                None => continue,
+            };
+            let total = reqs.iter().map(|x| x[i]).sum::<usize>();
+            if total <= budget {
+                continue;
+            }
+
+            for (share, req) in shares.iter_mut().zip(reqs.iter()) {
+                share[i] = req[i] * budget / total;
+            }
+            // the pages left over by the rounding go to the first instances
+            let mut left = budget - shares.iter().map(|x| x[i]).sum::<usize>();
+            for (share, req) in shares.iter_mut().zip(reqs.iter()) {
+                if left == 0 {
+                    break;
+                }
+                if share[i] < req[i] {
+                    share[i] += 1;
+                    left -= 1;
+                }
+            }
+        }
+
+        shares
+    }
+}
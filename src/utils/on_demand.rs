@@ -0,0 +1,92 @@
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use super::htlb::{get_htlb_pages_node, set_htlb_pages_node};
+use super::misc::size_to_str;
+use super::rangelist::Id;
+
+// the socket run_mosalloc serves the on-demand reservation requests of its children on
+pub fn socket_path() -> PathBuf {
+    env::temp_dir().join(format!("mosalloc-reserve-{}.sock", std::process::id()))
+}
+
+// ask run_mosalloc to reserve nr more pages of pagesz, blocks until they are (or can't be)
+pub fn request(path: &str, pagesz: usize, nr: usize) -> Result<(), String> {
+    let err = |e: std::io::Error| format!("{}: {}", path, e);
+
+    let mut stream = UnixStream::connect(path).map_err(err)?;
+    stream
+        .write_all(format!("reserve {} {}\n", pagesz, nr).as_bytes())
+        .and_then(|_| stream.shutdown(Shutdown::Write))
+        .map_err(err)?;
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).map_err(err)?;
+
+    match reply.trim() {
+        "ok" => Ok(()),
+        x => Err(x.strip_prefix("error ").unwrap_or(x).to_string()),
+    }
+}
+
+// bump the reserved pages of pagesz on the node by nr
+fn bump(node: Id, pagesz: usize, nr: usize) -> Result<(), String> {
+    let cur = get_htlb_pages_node(node, pagesz)?;
+    set_htlb_pages_node(node, pagesz, cur + nr)?;
+
+    let got = get_htlb_pages_node(node, pagesz)?.saturating_sub(cur);
+    println!(
+        "on-demand: reserved {} of {} {} pages on node {}",
+        got,
+        nr,
+        size_to_str(pagesz),
+        node
+    );
+    if got < nr {
+        return Err(format!("{} {} pages short", nr - got, size_to_str(pagesz)));
+    }
+    Ok(())
+}
+
+fn serve(stream: &mut UnixStream, node: Id) -> String {
+    let mut req = String::new();
+    let _ = BufReader::new(&*stream).read_line(&mut req);
+
+    let fields = req.split_whitespace().collect::<Vec<&str>>();
+    let parsed = match fields.as_slice() {
+        ["reserve", pagesz, nr] => pagesz.parse::<usize>().ok().zip(nr.parse::<usize>().ok()),
+        _ => None,
+    };
+
+    match parsed.map(|(pagesz, nr)| bump(node, pagesz, nr)) {
+        Some(Ok(())) => "ok\n".to_string(),
+        Some(Err(e)) => format!("error {}\n", e),
+        None => format!("error invalid request: {}\n", req.trim()),
+    }
+}
+
+// serve the reservation requests on the socket from a thread, one at a time, for as long as
+// run_mosalloc runs
+pub fn spawn_server(path: &Path, node: Id) -> Result<(), String> {
+    // a leftover of an earlier run with the same pid
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+
+            let reply = serve(&mut stream, node);
+            let _ = stream.write_all(reply.as_bytes());
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,417 @@
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use nix::libc;
+
+use super::control::ProcessStats;
+use super::htlb::{
+    get_htlb_free_pages_node, get_htlb_pages_node, supported_htlb_sizes, HTLBReq, PAGE_SIZE,
+};
+use super::hugetlbfs::hugetlbfs_mounts;
+use super::misc::{size_from_str, size_to_str};
+use super::sysfs_path::sysfs_path_thp_enabled;
+
+pub const REPORT_HEADER: &str = "# mosalloc report v";
+// version of the report, lines are only ever added, the readers skip the kinds they don't know
+pub const REPORT_VERSION: u32 = 1;
+
+// the hugepages of a size requested for a run, and the pages of the node once reserved
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Reservation {
+    pub pagesz: usize,
+    pub requested: usize,
+    pub reserved: usize,
+    pub free: usize,
+}
+
+// everything run_mosalloc learns about a run, as a single file: the reservation, the region
+// layout and stats of the program (from its stats file), the resource usage, the kernel features
+// and the warnings
+#[derive(Debug, Default, Clone)]
+pub struct Report {
+    pub program: Vec<String>,
+    pub config: String,
+    // how the hugepages were reserved, e.g. static, on-demand or session <name>
+    pub mode: String,
+    pub exit: String,
+    pub code: i32,
+    pub reservation: Vec<Reservation>,
+    pub features: Vec<(String, String)>,
+    // resource usage of the program, from getrusage
+    pub perf: Vec<(String, u64)>,
+    pub stats: Option<ProcessStats>,
+    pub warnings: Vec<String>,
+}
+
+// the kernel and hugepage features of the system
+pub fn probe_features() -> Vec<(String, String)> {
+    let read = |path: &Path| {
+        fs::read_to_string(path)
+            .map(|x| x.trim().to_string())
+            .unwrap_or_else(|_| "unknown".to_string())
+    };
+    // the selected mode of e.g. 'always [madvise] never'
+    let thp = read(&sysfs_path_thp_enabled());
+    let thp = thp
+        .split_once('[')
+        .and_then(|(_, x)| x.split_once(']'))
+        .map_or(thp.clone(), |(x, _)| x.to_string());
+
+    let sizes = supported_htlb_sizes()
+        .iter()
+        .map(|x| size_to_str(*x))
+        .collect::<Vec<_>>();
+    let mounts = hugetlbfs_mounts()
+        .iter()
+        .map(|x| size_to_str(x.pagesz))
+        .collect::<Vec<_>>();
+
+    vec![
+        (
+            "kernel".to_string(),
+            read(Path::new("/proc/sys/kernel/osrelease")),
+        ),
+        ("page_size".to_string(), size_to_str(*PAGE_SIZE)),
+        ("htlb_sizes".to_string(), sizes.join(",")),
+        ("thp".to_string(), thp),
+        ("hugetlbfs".to_string(), mounts.join(",")),
+    ]
+}
+
+// the pages requested per size and the ones of the node once reserved
+pub fn reservation(req: &HTLBReq) -> Vec<Reservation> {
+    supported_htlb_sizes()
+        .iter()
+        .zip(req.req.iter())
+        .map(|(&pagesz, &requested)| Reservation {
+            pagesz,
+            requested,
+            reserved: get_htlb_pages_node(req.node, pagesz).unwrap_or_default(),
+            free: get_htlb_free_pages_node(req.node, pagesz).unwrap_or_default(),
+        })
+        .collect()
+}
+
+// resource usage of the waited for children, along with the wall time of the run
+pub fn perf_counters(wall: Duration) -> Vec<(String, u64)> {
+    let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+    unsafe { libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage) };
+
+    let ms = |x: libc::timeval| x.tv_sec as u64 * 1000 + x.tv_usec as u64 / 1000;
+    [
+        ("wall_ms", wall.as_millis() as u64),
+        ("user_ms", ms(usage.ru_utime)),
+        ("sys_ms", ms(usage.ru_stime)),
+        ("max_rss", usage.ru_maxrss as u64 * 1024),
+        ("minflt", usage.ru_minflt as u64),
+        ("majflt", usage.ru_majflt as u64),
+        ("nvcsw", usage.ru_nvcsw as u64),
+        ("nivcsw", usage.ru_nivcsw as u64),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v))
+    .collect()
+}
+
+impl Report {
+    // the warnings the rest of the report implies
+    pub fn derive_warnings(&mut self) {
+        for x in self.reservation.iter().filter(|x| x.reserved < x.requested) {
+            self.warnings.push(format!(
+                "{} of {} {} pages reserved",
+                x.reserved,
+                x.requested,
+                size_to_str(x.pagesz)
+            ));
+        }
+        if self.code != 0 {
+            self.warnings.push(format!("the program {}", self.exit));
+        }
+        match &self.stats {
+            Some(stats) => {
+                for r in stats.regions.iter() {
+                    let bad = r
+                        .intervals
+                        .split(',')
+                        .filter(|x| *x != "ok" && !x.is_empty());
+                    let bad = bad.collect::<Vec<_>>();
+                    if !bad.is_empty() {
+                        self.warnings.push(format!(
+                            "({}) {} pool intervals degraded or disabled",
+                            r.label(),
+                            bad.len()
+                        ));
+                    }
+                }
+                if let Some(i) = stats.internal.as_ref().filter(|x| x.failed > 0) {
+                    self.warnings
+                        .push(format!("{} internal allocations failed", i.failed));
+                }
+            }
+            None => self
+                .warnings
+                .push("no stats, the program didn't exit through mosalloc".to_string()),
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = format!("{}{}\n", REPORT_HEADER, REPORT_VERSION);
+
+        out += &format!("program {}\n", self.program.join(" "));
+        out += &format!("config {}\n", self.config);
+        out += &format!("mode {}\n", self.mode);
+        out += &format!("exit {} {}\n", self.code, self.exit);
+        for (k, v) in self.features.iter() {
+            out += &format!("feature {} {}\n", k, v);
+        }
+        for x in self.reservation.iter() {
+            out += &format!(
+                "reservation {} requested={} reserved={} free={}\n",
+                size_to_str(x.pagesz),
+                x.requested,
+                x.reserved,
+                x.free
+            );
+        }
+        for (k, v) in self.perf.iter() {
+            out += &format!("perf {} {}\n", k, v);
+        }
+        // the stats of the program, in the control socket format
+        if let Some(stats) = &self.stats {
+            for line in stats.to_text().lines() {
+                out += &format!("stats {}\n", line);
+            }
+        }
+        for x in self.warnings.iter() {
+            out += &format!("warning {}\n", x);
+        }
+
+        out
+    }
+
+    pub fn from_text(content: &str) -> Result<Self, String> {
+        let mut lines = content.lines();
+
+        lines
+            .next()
+            .and_then(|x| x.strip_prefix(REPORT_HEADER))
+            .and_then(|x| x.parse::<u32>().ok())
+            .ok_or_else(|| "not a mosalloc report".to_string())?;
+
+        let mut report = Report::default();
+        let mut stats = String::new();
+        for line in lines {
+            let parse_err = || format!("invalid report line: {}", line);
+            let (kind, rest) = line.split_once(' ').unwrap_or((line, ""));
+
+            match kind {
+                "program" => report.program = rest.split_whitespace().map(String::from).collect(),
+                "config" => report.config = rest.to_string(),
+                "mode" => report.mode = rest.to_string(),
+                "exit" => {
+                    let (code, exit) = rest.split_once(' ').ok_or_else(parse_err)?;
+                    report.code = code.parse().map_err(|_| parse_err())?;
+                    report.exit = exit.to_string();
+                }
+                "feature" => {
+                    let (k, v) = rest.split_once(' ').unwrap_or((rest, ""));
+                    report.features.push((k.to_string(), v.to_string()));
+                }
+                "reservation" => {
+                    let fields = rest.split_whitespace().collect::<Vec<&str>>();
+                    let value = |key: &str| {
+                        fields
+                            .iter()
+                            .find_map(|x| x.strip_prefix(key)?.strip_prefix('='))
+                            .and_then(|x| x.parse::<usize>().ok())
+                            .ok_or_else(parse_err)
+                    };
+                    report.reservation.push(Reservation {
+                        pagesz: size_from_str(fields.first().ok_or_else(parse_err)?),
+                        requested: value("requested")?,
+                        reserved: value("reserved")?,
+                        free: value("free")?,
+                    });
+                }
+                "perf" => {
+                    let (k, v) = rest.split_once(' ').ok_or_else(parse_err)?;
+                    report
+                        .perf
+                        .push((k.to_string(), v.parse().map_err(|_| parse_err())?));
+                }
+                "stats" => stats += &format!("{}\n", rest),
+                "warning" => report.warnings.push(rest.to_string()),
+                // lines of newer reports
+                _ => {}
+            }
+        }
+        if !stats.is_empty() {
+            report.stats = Some(ProcessStats::from_text(&stats)?);
+        }
+
+        Ok(report)
+    }
+
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|x| Self::from_text(&x))
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    // human-readable summary of the report
+    pub fn show(&self) -> Vec<String> {
+        let mut out = vec![
+            format!("program: {}", self.program.join(" ")),
+            format!("config: {} ({})", self.config, self.mode),
+            format!("exit: {}", self.exit),
+            format!(
+                "kernel: {}",
+                self.features
+                    .iter()
+                    .map(|(k, v)| format!("{} {}", k, v))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        ];
+        for x in self.reservation.iter().filter(|x| x.requested > 0) {
+            out.push(format!(
+                "reservation: {} {} pages requested, {} reserved ({} free)",
+                x.requested,
+                size_to_str(x.pagesz),
+                x.reserved,
+                x.free
+            ));
+        }
+        out.push(format!(
+            "perf: {}",
+            self.perf
+                .iter()
+                .map(|(k, v)| format!("{} {}", k, v))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+        if let Some(stats) = &self.stats {
+            for r in stats.regions.iter() {
+                out.push(format!(
+                    "({}) {:x}, {}: used {}, high water {}, peak {}, huge {}, rss {}",
+                    r.label(),
+                    r.start,
+                    size_to_str(r.len),
+                    size_to_str(r.used),
+                    size_to_str(r.high_water),
+                    size_to_str(r.peak),
+                    size_to_str(r.huge),
+                    size_to_str(r.rss)
+                ));
+            }
+        }
+        for x in self.warnings.iter() {
+            out.push(format!("warning: {}", x));
+        }
+        out
+    }
+}
+
+fn delta(a: u64, b: u64) -> String {
+    match a {
+        0 => format!("{} -> {}", a, b),
+        _ => format!(
+            "{} -> {} ({:+.1}%)",
+            a,
+            b,
+            (b as f64 - a as f64) * 100.0 / a as f64
+        ),
+    }
+}
+
+// the differences between the reports of two runs
+pub fn diff_reports(a: &Report, b: &Report) -> Vec<String> {
+    let mut out = Vec::new();
+
+    for (key, x, y) in [
+        ("program", a.program.join(" "), b.program.join(" ")),
+        ("config", a.config.clone(), b.config.clone()),
+        ("mode", a.mode.clone(), b.mode.clone()),
+        ("exit", a.exit.clone(), b.exit.clone()),
+    ] {
+        if x != y {
+            out.push(format!("{}: {} -> {}", key, x, y));
+        }
+    }
+    for (k, x) in a.features.iter() {
+        match b.features.iter().find(|(l, _)| l == k) {
+            Some((_, y)) if x != y => out.push(format!("feature {}: {} -> {}", k, x, y)),
+            _ => {}
+        }
+    }
+
+    for x in a.reservation.iter() {
+        if let Some(y) = b.reservation.iter().find(|y| y.pagesz == x.pagesz) {
+            if (x.requested, x.reserved) != (y.requested, y.reserved) {
+                out.push(format!(
+                    "reservation {}: requested {} -> {}, reserved {} -> {}",
+                    size_to_str(x.pagesz),
+                    x.requested,
+                    y.requested,
+                    x.reserved,
+                    y.reserved
+                ));
+            }
+        }
+    }
+
+    for (k, x) in a.perf.iter() {
+        if let Some((_, y)) = b.perf.iter().find(|(l, _)| l == k) {
+            if x != y {
+                out.push(format!("perf {}: {}", k, delta(*x, *y)));
+            }
+        }
+    }
+
+    let regions = |x: &Report| x.stats.as_ref().map_or(Vec::new(), |x| x.regions.clone());
+    let (ra, rb) = (regions(a), regions(b));
+    for x in ra.iter() {
+        let y = match rb.iter().find(|y| y.label() == x.label()) {
+            Some(y) => y,
+            None => {
+                out.push(format!("({}) only in the first run", x.label()));
+                continue;
+            }
+        };
+        for (key, u, v) in [
+            ("len", x.len, y.len),
+            ("used", x.used, y.used),
+            ("high_water", x.high_water, y.high_water),
+            ("peak", x.peak, y.peak),
+            ("huge", x.huge, y.huge),
+            ("rss", x.rss, y.rss),
+            ("allocs", x.allocs, y.allocs),
+        ] {
+            if u != v {
+                out.push(format!(
+                    "({}) {}: {}",
+                    x.label(),
+                    key,
+                    delta(u as u64, v as u64)
+                ));
+            }
+        }
+    }
+    for y in rb
+        .iter()
+        .filter(|y| !ra.iter().any(|x| x.label() == y.label()))
+    {
+        out.push(format!("({}) only in the second run", y.label()));
+    }
+
+    for x in a.warnings.iter().filter(|x| !b.warnings.contains(x)) {
+        out.push(format!("warning gone: {}", x));
+    }
+    for x in b.warnings.iter().filter(|x| !a.warnings.contains(x)) {
+        out.push(format!("warning new: {}", x));
+    }
+
+    out
+}
@@ -0,0 +1,284 @@
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess};
+use serde::de::{Deserializer, Visitor};
+use serde::forward_to_deserialize_any;
+use serde::Serialize;
+
+use super::control::ProcessStats;
+use super::trace::{TraceHeader, TraceRecord};
+
+// version of the stats and trace records, v1 being the positional stats lines; fields are only
+// appended, the readers default the ones missing in older records and ignore the unknown ones
+pub const SCHEMA_VERSION: u32 = 2;
+
+type Error = de::value::Error;
+
+// (field, value) pairs of a flat record, as csv serializes it
+pub fn to_fields<T: Serialize>(x: &T) -> Result<Vec<(String, String)>, String> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.serialize(x).map_err(|e| e.to_string())?;
+    let data = writer.into_inner().map_err(|e| e.to_string())?;
+
+    let mut reader = csv::Reader::from_reader(data.as_slice());
+    let headers = reader.headers().map_err(|e| e.to_string())?.clone();
+    let row = reader
+        .records()
+        .next()
+        .ok_or_else(|| "empty record".to_string())?
+        .map_err(|e| e.to_string())?;
+
+    Ok(headers
+        .iter()
+        .zip(row.iter())
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect())
+}
+
+// a flat record from its (field, value) pairs, in any order
+pub fn from_fields<T: DeserializeOwned>(fields: &[(&str, &str)]) -> Result<T, String> {
+    let headers = fields.iter().map(|x| x.0).collect::<csv::StringRecord>();
+    let row = fields.iter().map(|x| x.1).collect::<csv::StringRecord>();
+
+    row.deserialize(Some(&headers)).map_err(|e| e.to_string())
+}
+
+// JSON type of a record field
+#[derive(Debug)]
+enum Kind {
+    Unknown,
+    Integer,
+    Number,
+    String,
+    Boolean,
+    Enum(&'static [&'static str]),
+    Nullable(Box<Kind>),
+    Array(Box<Kind>),
+    Record(&'static str, Vec<(&'static str, Kind)>),
+}
+
+// deserializer recording the shape of the type driving it, fed with placeholder values, so that
+// the schema follows the serde derives of the records
+struct Probe(Kind);
+
+macro_rules! probe_integer {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                self.0 = Kind::Integer;
+                visitor.visit_u64(0)
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for &mut Probe {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Error> {
+        Err(de::Error::custom("untyped record field"))
+    }
+
+    probe_integer! {
+        deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_f64(visitor)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.0 = Kind::Number;
+        visitor.visit_f64(0.0)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.0 = Kind::Boolean;
+        visitor.visit_bool(false)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.0 = Kind::String;
+        visitor.visit_str("")
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let mut inner = Probe(Kind::Unknown);
+        let value = visitor.visit_some(&mut inner)?;
+        self.0 = Kind::Nullable(Box::new(inner.0));
+        Ok(value)
+    }
+
+    // a single element is enough for the element type
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_tuple(1, visitor)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        let mut elements = Elements {
+            probe: Probe(Kind::Unknown),
+            left: len,
+        };
+        let value = visitor.visit_seq(&mut elements)?;
+        self.0 = Kind::Array(Box::new(elements.probe.0));
+        Ok(value)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let mut map = Fields {
+            fields,
+            kinds: Vec::new(),
+        };
+        let value = visitor.visit_map(&mut map)?;
+        self.0 = Kind::Record(name, map.kinds);
+        Ok(value)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.0 = Kind::Enum(variants);
+        visitor.visit_enum(IntoDeserializer::<Error>::into_deserializer(variants[0]))
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 char bytes byte_buf unit unit_struct newtype_struct tuple_struct map identifier
+        ignored_any
+    }
+}
+
+struct Elements {
+    probe: Probe,
+    left: usize,
+}
+
+impl<'de> SeqAccess<'de> for Elements {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(&mut self.probe).map(Some)
+    }
+}
+
+struct Fields {
+    fields: &'static [&'static str],
+    kinds: Vec<(&'static str, Kind)>,
+}
+
+impl<'de> MapAccess<'de> for Fields {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        match self.fields.get(self.kinds.len()) {
+            Some(&field) => seed
+                .deserialize(IntoDeserializer::<Error>::into_deserializer(field))
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let mut probe = Probe(Kind::Unknown);
+        let value = seed.deserialize(&mut probe)?;
+        self.kinds.push((self.fields[self.kinds.len()], probe.0));
+        Ok(value)
+    }
+}
+
+fn probe<T: DeserializeOwned>() -> Kind {
+    let mut probe = Probe(Kind::Unknown);
+    T::deserialize(&mut probe).unwrap();
+    probe.0
+}
+
+// the JSON schema of a field, the records are added to `defs` and referenced
+fn json_type(kind: &Kind, defs: &mut Vec<(&'static str, String)>) -> String {
+    match kind {
+        Kind::Unknown => "{}".to_string(),
+        Kind::Integer => "{\"type\": \"integer\"}".to_string(),
+        Kind::Number => "{\"type\": \"number\"}".to_string(),
+        Kind::String => "{\"type\": \"string\"}".to_string(),
+        Kind::Boolean => "{\"type\": \"boolean\"}".to_string(),
+        Kind::Enum(variants) => format!(
+            "{{\"enum\": [{}]}}",
+            variants
+                .iter()
+                .map(|x| format!("\"{}\"", x))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+        Kind::Nullable(inner) => format!(
+            "{{\"anyOf\": [{}, {{\"type\": \"null\"}}]}}",
+            json_type(inner, defs)
+        ),
+        Kind::Array(inner) => format!(
+            "{{\"type\": \"array\", \"items\": {}}}",
+            json_type(inner, defs)
+        ),
+        Kind::Record(name, fields) => {
+            if !defs.iter().any(|(x, _)| x == name) {
+                // reserve the slot first, the records are listed parents first
+                defs.push((name, String::new()));
+                let properties = fields
+                    .iter()
+                    .map(|(field, kind)| {
+                        format!("        \"{}\": {}", field, json_type(kind, defs))
+                    })
+                    .collect::<Vec<String>>()
+                    .join(",\n");
+                let def = format!(
+                    "{{\n      \"type\": \"object\",\n      \"properties\": {{\n{}\n      }}\n    }}",
+                    properties
+                );
+                defs.iter_mut().find(|(x, _)| x == name).unwrap().1 = def;
+            }
+            format!("{{\"$ref\": \"#/$defs/{}\"}}", name)
+        }
+    }
+}
+
+// JSON schema of the stats and trace records, every field is optional as the readers default the
+// missing ones
+pub fn json_schema() -> String {
+    let mut defs = Vec::new();
+    for kind in [
+        probe::<ProcessStats>(),
+        probe::<TraceHeader>(),
+        probe::<TraceRecord>(),
+    ] {
+        json_type(&kind, &mut defs);
+    }
+
+    format!(
+        "{{\n  \"$schema\": \"https://json-schema.org/draft/2020-12/schema\",\n  \"$id\": \
+         \"urn:mosalloc:records:v{}\",\n  \"title\": \"mosalloc stats and trace records\",\n  \
+         \"version\": {},\n  \"$defs\": {{\n{}\n  }}\n}}\n",
+        SCHEMA_VERSION,
+        SCHEMA_VERSION,
+        defs.iter()
+            .map(|(name, def)| format!("    \"{}\": {}", name, def))
+            .collect::<Vec<String>>()
+            .join(",\n")
+    )
+}
@@ -0,0 +1,202 @@
+use std::ffi::CString;
+use std::os::unix::io::RawFd;
+use std::ptr::null_mut;
+
+use nix::errno::Errno;
+use nix::libc;
+
+use super::htlb::{get_htlb_free_pages, get_htlb_overcommit_pages};
+use super::hugetlbfs::hugetlbfs_mount;
+use super::misc::size_to_str;
+use super::sysfs_path::sysfs_path_htlb_global;
+
+// hugepage mapping paths exercised by the self test
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ProbeKind {
+    ANON,
+    HUGETLBFS,
+    MEMFD,
+}
+
+impl ProbeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProbeKind::ANON => "anon",
+            ProbeKind::HUGETLBFS => "hugetlbfs",
+            ProbeKind::MEMFD => "memfd",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ProbeResult {
+    pub kind: ProbeKind,
+    pub pagesz: usize,
+    pub result: Result<(), Errno>,
+}
+
+// MAP_HUGE_* / MFD_HUGE_* encoding of a hugepage size
+fn huge_flag(pagesz: usize) -> i32 {
+    (pagesz.trailing_zeros() as i32) << libc::MAP_HUGE_SHIFT
+}
+
+// map a single hugepage and touch it, so that the page is actually faulted in
+fn probe_mapping(pagesz: usize, flags: i32, fd: RawFd) -> Result<(), Errno> {
+    unsafe {
+        let addr = libc::mmap(
+            null_mut(),
+            pagesz,
+            libc::PROT_READ | libc::PROT_WRITE,
+            flags,
+            fd,
+            0,
+        );
+        if addr == libc::MAP_FAILED {
+            return Err(Errno::last());
+        }
+
+        (addr as *mut u8).write_volatile(1);
+        libc::munmap(addr, pagesz);
+    }
+
+    Ok(())
+}
+
+fn probe_anon(pagesz: usize) -> Result<(), Errno> {
+    probe_mapping(
+        pagesz,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB | huge_flag(pagesz),
+        -1,
+    )
+}
+
+fn probe_fd(pagesz: usize, fd: RawFd) -> Result<(), Errno> {
+    let ret = if unsafe { libc::ftruncate(fd, pagesz as libc::off_t) } == -1 {
+        Err(Errno::last())
+    } else {
+        probe_mapping(pagesz, libc::MAP_SHARED, fd)
+    };
+
+    unsafe { libc::close(fd) };
+    ret
+}
+
+fn probe_memfd(pagesz: usize) -> Result<(), Errno> {
+    let name = CString::new("mosalloc-selftest").unwrap();
+    let fd = unsafe {
+        libc::memfd_create(
+            name.as_ptr(),
+            libc::MFD_CLOEXEC | libc::MFD_HUGETLB | huge_flag(pagesz) as u32,
+        )
+    };
+    if fd == -1 {
+        return Err(Errno::last());
+    }
+
+    probe_fd(pagesz, fd)
+}
+
+fn probe_hugetlbfs(pagesz: usize) -> Result<(), Errno> {
+    let mnt = hugetlbfs_mount(pagesz).ok_or(Errno::ENODEV)?;
+    let path = CString::new(
+        mnt.path
+            .join(format!("mosalloc-selftest-{}", std::process::id()))
+            .to_str()
+            .unwrap(),
+    )
+    .unwrap();
+
+    let fd = unsafe {
+        libc::open(
+            path.as_ptr(),
+            libc::O_CREAT | libc::O_RDWR | libc::O_EXCL,
+            0o600,
+        )
+    };
+    if fd == -1 {
+        return Err(Errno::last());
+    }
+    unsafe { libc::unlink(path.as_ptr()) };
+
+    probe_fd(pagesz, fd)
+}
+
+pub fn probe(kind: ProbeKind, pagesz: usize) -> ProbeResult {
+    let result = match kind {
+        ProbeKind::ANON => probe_anon(pagesz),
+        ProbeKind::HUGETLBFS => probe_hugetlbfs(pagesz),
+        ProbeKind::MEMFD => probe_memfd(pagesz),
+    };
+
+    ProbeResult {
+        kind,
+        pagesz,
+        result,
+    }
+}
+
+// suggested fixes for a failed probe
+pub fn suggestions(res: &ProbeResult) -> Vec<String> {
+    let sz = size_to_str(res.pagesz);
+    let nr_hugepages = sysfs_path_htlb_global(res.pagesz >> 10, "nr_hugepages");
+    let mut out = Vec::new();
+
+    match res.result {
+        Ok(()) => {}
+        Err(Errno::ENOMEM) => {
+            if get_htlb_free_pages(res.pagesz).unwrap_or(0) == 0
+                && get_htlb_overcommit_pages(res.pagesz).unwrap_or(0) == 0
+            {
+                out.push(format!(
+                    "no free {} pages, reserve some with `echo N > {}` (or reserve_huge_pages)",
+                    sz,
+                    nr_hugepages.display()
+                ));
+            } else {
+                out.push(format!(
+                    "free {} pages exist but couldn't be used, check the cgroup hugetlb.{}.max \
+                     limit and the NUMA memory policy",
+                    sz, sz
+                ));
+            }
+        }
+        Err(Errno::ENODEV) if res.kind == ProbeKind::HUGETLBFS => {
+            out.push(format!(
+                "no hugetlbfs mount for {} pages, mount one with `sudo mosalloc hugetlbfs --mount \
+                 {}` or `mount -t hugetlbfs -o pagesize={}K none /dev/hugepages-{}`",
+                sz,
+                sz,
+                res.pagesz >> 10,
+                sz
+            ));
+        }
+        Err(Errno::EPERM) | Err(Errno::EACCES) => match res.kind {
+            ProbeKind::HUGETLBFS => {
+                out.push(
+                    "the hugetlbfs mount isn't writable, remount it with uid=/gid=/mode= options \
+                     matching the current user"
+                        .to_string(),
+                );
+            }
+            _ => {
+                out.push(
+                    "hugetlb mappings aren't permitted, add the user to the vm.hugetlb_shm_group \
+                     group (sysctl) or raise the memlock limit (ulimit -l)"
+                        .to_string(),
+                );
+            }
+        },
+        Err(Errno::EINVAL) => {
+            out.push(format!(
+                "{} pages are not supported by this kernel for {} mappings",
+                sz,
+                res.kind.as_str()
+            ));
+        }
+        Err(e) => {
+            out.push(format!("unexpected error: {}", e));
+        }
+    }
+
+    out
+}
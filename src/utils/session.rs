@@ -0,0 +1,150 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use nix::fcntl::{flock, FlockArg};
+
+use super::htlb::HTLBState;
+use super::rangelist::Id;
+
+pub const STATE_HEADER: &str = "# mosalloc reservations v1";
+
+// env var overriding the path of the reservations state file
+pub const STATE_FILE_VAR: &str = "MOSALLOC_STATE_FILE";
+const DEFAULT_STATE_FILE: &str = "/run/mosalloc/reservations";
+
+// a reservation kept across consecutive runs, until it's explicitly released, or the one of a
+// single run_mosalloc --release-pages run, kept until the run releases it
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub name: String,
+    // pages reserved for the session, per supported size
+    pub pages: Vec<usize>,
+    // hugepage pool state of the node before the session, restored on release
+    pub saved: HTLBState,
+    pub runs: usize,
+    // the run_mosalloc process holding a single run reservation, left behind if it crashed
+    pub pid: Option<i32>,
+}
+
+// the reservations state file, locked for as long as it's open
+pub struct StateFile {
+    file: File,
+    pub sessions: Vec<Session>,
+}
+
+pub fn state_file() -> PathBuf {
+    PathBuf::from(env::var(STATE_FILE_VAR).unwrap_or(DEFAULT_STATE_FILE.to_string()))
+}
+
+// the session name of the single run reservation of a run_mosalloc process
+pub fn run_session(pid: i32) -> String {
+    format!("run-{}", pid)
+}
+
+fn pages_to_str(pages: &[usize]) -> String {
+    pages
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+fn pages_from_str(s: &str) -> Option<Vec<usize>> {
+    s.split(':').map(|x| x.parse::<usize>().ok()).collect()
+}
+
+impl StateFile {
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let err = |e: String| format!("{}: {}", path.display(), e);
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| err(e.to_string()))?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| err(e.to_string()))?;
+        flock(file.as_raw_fd(), FlockArg::LockExclusive).map_err(|e| err(e.to_string()))?;
+
+        let mut content = String::new();
+        file.read_to_string(&mut content)
+            .map_err(|e| err(e.to_string()))?;
+
+        let mut lines = content.lines();
+        let mut sessions = Vec::new();
+        match lines.next() {
+            None => {}
+            Some(STATE_HEADER) => {
+                for line in lines {
+                    sessions.push(Self::parse_session(line).map_err(err)?);
+                }
+            }
+            Some(_) => return Err(err("not a mosalloc reservations file".to_string())),
+        }
+
+        Ok(StateFile { file, sessions })
+    }
+
+    // <name> <node> <pages> <saved pages> <saved overcommit> <runs> [<pid>]
+    fn parse_session(line: &str) -> Result<Session, String> {
+        let fields = line.split_whitespace().collect::<Vec<&str>>();
+        let parse_err = || format!("invalid session line: {}", line);
+
+        match fields.as_slice() {
+            [name, node, pages, saved, overcommit, runs, pid @ ..] if pid.len() <= 1 => {
+                Ok(Session {
+                    name: name.to_string(),
+                    pages: pages_from_str(pages).ok_or_else(parse_err)?,
+                    saved: HTLBState {
+                        node: node.parse::<Id>().map_err(|_| parse_err())?,
+                        pages: pages_from_str(saved).ok_or_else(parse_err)?,
+                        overcommit: pages_from_str(overcommit).ok_or_else(parse_err)?,
+                    },
+                    runs: runs.parse::<usize>().map_err(|_| parse_err())?,
+                    pid: match pid {
+                        [pid] => Some(pid.parse::<i32>().map_err(|_| parse_err())?),
+                        _ => None,
+                    },
+                })
+            }
+            _ => Err(parse_err()),
+        }
+    }
+
+    pub fn find(&mut self, name: &str) -> Option<&mut Session> {
+        self.sessions.iter_mut().find(|x| x.name == name)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Session> {
+        let i = self.sessions.iter().position(|x| x.name == name)?;
+        Some(self.sessions.remove(i))
+    }
+
+    pub fn save(&mut self) -> Result<(), String> {
+        let mut out = format!("{}\n", STATE_HEADER);
+        for s in self.sessions.iter() {
+            out += &format!(
+                "{} {} {} {} {} {}{}\n",
+                s.name,
+                s.saved.node,
+                pages_to_str(&s.pages),
+                pages_to_str(&s.saved.pages),
+                pages_to_str(&s.saved.overcommit),
+                s.runs,
+                s.pid.map_or(String::new(), |x| format!(" {}", x))
+            );
+        }
+
+        self.file
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| self.file.set_len(0))
+            .and_then(|_| self.file.write_all(out.as_bytes()))
+            .map_err(|e| e.to_string())
+    }
+}
@@ -0,0 +1,160 @@
+use std::env;
+
+use nix::sched::{sched_getaffinity, CpuSet};
+use nix::unistd::Pid;
+
+use super::rangelist::{Id, RangeList};
+use super::sysfs_path::*;
+
+// the Slurm task environment of the current process, as set by srun
+#[derive(Debug)]
+pub struct SlurmTask {
+    pub job: String,
+    // global and node-local rank
+    pub rank: usize,
+    pub local_rank: usize,
+    // tasks and allocated cpus on the current node
+    pub local_tasks: usize,
+    pub cpus_on_node: usize,
+    // allocated memory on the current node
+    pub mem_per_node: Option<usize>,
+}
+
+fn var(name: &str) -> Result<String, String> {
+    env::var(name).map_err(|_| format!("{} isn't set, not running under srun?", name))
+}
+
+fn var_usize(name: &str) -> Result<usize, String> {
+    var(name)?
+        .parse::<usize>()
+        .map_err(|_| format!("invalid {}", name))
+}
+
+// the task count of the nth node of the job, SLURM_TASKS_PER_NODE is e.g. `2(x3),1`
+fn tasks_per_node(s: &str, nth: usize) -> Option<usize> {
+    s.split(',')
+        .flat_map(|x| {
+            let (nr, reps) = match x.split_once("(x") {
+                Some((nr, reps)) => (nr, reps.strip_suffix(')')?.parse::<usize>().ok()?),
+                None => (x, 1),
+            };
+            Some(std::iter::repeat_n(nr.parse::<usize>().ok()?, reps))
+        })
+        .flatten()
+        .nth(nth)
+}
+
+impl SlurmTask {
+    pub fn from_env() -> Result<Self, String> {
+        let local_tasks = match var_usize("SLURM_NTASKS_PER_NODE") {
+            Ok(x) => x,
+            Err(_) => tasks_per_node(
+                &var("SLURM_TASKS_PER_NODE")?,
+                var_usize("SLURM_NODEID").unwrap_or(0),
+            )
+            .ok_or("invalid SLURM_TASKS_PER_NODE")?,
+        };
+        let cpus_on_node = var_usize("SLURM_CPUS_ON_NODE")?;
+
+        // MB, either per node or per allocated cpu
+        let mem_per_node = var_usize("SLURM_MEM_PER_NODE")
+            .or_else(|_| var_usize("SLURM_MEM_PER_CPU").map(|x| x * cpus_on_node))
+            .ok()
+            .map(|x| x << 20);
+
+        Ok(Self {
+            job: var("SLURM_JOB_ID")?,
+            rank: var_usize("SLURM_PROCID")?,
+            local_rank: var_usize("SLURM_LOCALID")?,
+            local_tasks: local_tasks.max(1),
+            cpus_on_node,
+            mem_per_node,
+        })
+    }
+
+    // NUMA node the task is bound to, the one holding most of the cpus of its affinity mask (as set
+    // by srun --cpu-bind), the first one on a tie
+    pub fn numa_node(&self) -> Id {
+        let cpu_set = match sched_getaffinity(Pid::from_raw(0)) {
+            Ok(x) => x,
+            Err(_) => return 0,
+        };
+        let cpus = (0..CpuSet::count())
+            .filter(|&x| cpu_set.is_set(x).unwrap_or(false))
+            .collect::<Vec<Id>>();
+
+        let mut best = (0, 0);
+        for n in RangeList::from_path(sysfs_path_online_nodes()).iter() {
+            let node_cpus = RangeList::from_path(sysfs_path_node_cpus(n));
+            let nr = cpus.iter().filter(|&&x| node_cpus.contains(x)).count();
+            if nr > best.1 {
+                best = (n, nr);
+            }
+        }
+
+        best.0
+    }
+
+    // memory each local task can use for its hugepage pools
+    pub fn mem_budget(&self) -> Option<usize> {
+        self.mem_per_node.map(|x| x / self.local_tasks)
+    }
+
+    // expand the %t (rank), %j (job id) and %% Slurm filename patterns, so that each rank can
+    // use its own pool config
+    pub fn expand(&self, pattern: &str) -> String {
+        let mut out = String::new();
+        let mut chars = pattern.chars();
+
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('t') => out += &self.rank.to_string(),
+                Some('j') => out += &self.job,
+                Some('%') => out.push('%'),
+                Some(x) => {
+                    out.push('%');
+                    out.push(x);
+                }
+                None => out.push('%'),
+            }
+        }
+
+        out
+    }
+}
+
+// quote an argument for a POSIX shell
+fn shell_quote(s: &str) -> String {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=,%@+".contains(c))
+    {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
+
+// an sbatch script launching every rank of the job through run_mosalloc
+pub fn sbatch_script(run_mosalloc: &str, args: &[String], program: &str) -> String {
+    let name = program.rsplit('/').next().unwrap_or(program);
+
+    format!(
+        "#!/bin/bash\n\
+         #SBATCH --job-name=mosalloc-{}\n\
+         # generated by run_mosalloc, add the resource directives of the job and submit it with\n\
+         # sbatch, %t in the --config path is replaced by the rank of each task\n\
+         \n\
+         srun {} {}\n",
+        name,
+        shell_quote(run_mosalloc),
+        args.iter()
+            .map(|x| shell_quote(x))
+            .collect::<Vec<String>>()
+            .join(" ")
+    )
+}
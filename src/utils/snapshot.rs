@@ -0,0 +1,118 @@
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+use super::control::{parse_region_label, region_label};
+use super::htlb::AllocType;
+
+pub const SNAPSHOT_HEADER: &str = "# mosalloc snapshot v1";
+
+// bookkeeping state of a single region, all addresses are offsets from the region start so that
+// a snapshot can be restored on a region placed at a different address
+#[derive(Debug, Clone)]
+pub struct RegionSnapshot {
+    pub alloc_type: AllocType,
+    // the name of a named anon region, None for the default ones
+    pub name: Option<String>,
+    // program break (heap only) and high-water mark
+    pub brk: usize,
+    pub high_water: usize,
+    pub len: usize,
+    pub free_map: Vec<Range<usize>>,
+    pub prot_map: Vec<(Range<usize>, i32)>,
+}
+
+// bookkeeping state of all the mosalloc regions
+#[derive(Debug, Default)]
+pub struct AllocatorSnapshot {
+    pub regions: Vec<RegionSnapshot>,
+}
+
+impl AllocatorSnapshot {
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let mut out = format!("{}\n", SNAPSHOT_HEADER);
+
+        for r in self.regions.iter() {
+            out += &format!(
+                "region {} {:x} {:x} {:x}\n",
+                region_label(r.alloc_type, r.name.as_deref()),
+                r.brk,
+                r.len,
+                r.high_water
+            );
+            for x in r.free_map.iter() {
+                out += &format!("free {:x} {:x}\n", x.start, x.end);
+            }
+            for (x, prot) in r.prot_map.iter() {
+                out += &format!("alloc {:x} {:x} {}\n", x.start, x.end, prot);
+            }
+        }
+
+        fs::write(path, out).map_err(|e| e.to_string())
+    }
+
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut lines = content.lines();
+
+        if lines.next() != Some(SNAPSHOT_HEADER) {
+            return Err(format!("{} is not a mosalloc snapshot", path.display()));
+        }
+
+        let mut snapshot = AllocatorSnapshot::default();
+        for line in lines {
+            let fields = line.split_whitespace().collect::<Vec<&str>>();
+            let parse_err = || format!("invalid snapshot line: {}", line);
+            let hex = |x: &str| usize::from_str_radix(x, 16).map_err(|_| parse_err());
+
+            match fields.as_slice() {
+                // older snapshots have no high-water mark, their end is the highest one known
+                ["region", label, brk, len, high_water @ ..] if high_water.len() <= 1 => {
+                    let (alloc_type, name) = parse_region_label(label).ok_or_else(parse_err)?;
+                    let brk = hex(brk)?;
+                    snapshot.regions.push(RegionSnapshot {
+                        alloc_type,
+                        name,
+                        brk,
+                        high_water: match high_water {
+                            [x] => hex(x)?,
+                            _ => brk,
+                        },
+                        len: hex(len)?,
+                        free_map: Vec::new(),
+                        prot_map: Vec::new(),
+                    });
+                }
+                ["free", start, end] => {
+                    let range = hex(start)?..hex(end)?;
+                    snapshot
+                        .regions
+                        .last_mut()
+                        .ok_or_else(parse_err)?
+                        .free_map
+                        .push(range);
+                }
+                ["alloc", start, end, prot] => {
+                    let range = hex(start)?..hex(end)?;
+                    let prot = prot.parse::<i32>().map_err(|_| parse_err())?;
+                    snapshot
+                        .regions
+                        .last_mut()
+                        .ok_or_else(parse_err)?
+                        .prot_map
+                        .push((range, prot));
+                }
+                [] => {}
+                _ => return Err(parse_err()),
+            }
+        }
+
+        Ok(snapshot)
+    }
+
+    pub fn region(&self, alloc_type: AllocType, name: Option<&str>) -> Option<&RegionSnapshot> {
+        self.regions
+            .iter()
+            .find(|x| x.alloc_type == alloc_type && x.name.as_deref() == name)
+    }
+}
@@ -1,23 +1,49 @@
+use lazy_static::lazy_static;
+use std::env;
 use std::path::{Path, PathBuf};
+use std::sync::RwLock;
 
 use super::rangelist::Id;
 
-const SYSFS_NODES: &str = "/sys/devices/system/node/";
-const SYSFS_CPUS: &str = "/sys/devices/system/cpu/";
-const SYSFS_HTLB: &str = "/sys/kernel/mm/hugepages/";
-const SYSFS_THP_ENABLED: &str = "/sys/kernel/mm/transparent_hugepage/enabled";
-const SYSFS_OVERCOMMIT: &str = "/proc/sys/vm/overcommit_memory";
+const SYSFS_NODES: &str = "sys/devices/system/node/";
+const SYSFS_CPUS: &str = "sys/devices/system/cpu/";
+const SYSFS_HTLB: &str = "sys/kernel/mm/hugepages/";
+const SYSFS_THP_ENABLED: &str = "sys/kernel/mm/transparent_hugepage/enabled";
+const SYSFS_OVERCOMMIT: &str = "proc/sys/vm/overcommit_memory";
+const PROCFS_MEMINFO: &str = "proc/meminfo";
+
+// env var rooting the sysfs / procfs paths at another directory, e.g. a fixture tree
+pub const SYSFS_ROOT_VAR: &str = "MOSALLOC_SYSFS_ROOT";
+
+lazy_static! {
+    static ref ROOT: RwLock<PathBuf> = RwLock::new(PathBuf::from(
+        env::var(SYSFS_ROOT_VAR).unwrap_or("/".to_string())
+    ));
+}
+
+// root the sysfs / procfs paths at the given directory instead of /
+pub fn set_sysfs_root(root: &Path) {
+    *ROOT.write().unwrap() = root.to_path_buf();
+}
+
+pub fn sysfs_root() -> PathBuf {
+    ROOT.read().unwrap().clone()
+}
+
+fn rooted(path: &str) -> PathBuf {
+    ROOT.read().unwrap().join(path)
+}
 
 pub fn sysfs_path_online_cpus() -> PathBuf {
-    Path::new(SYSFS_CPUS).join("online")
+    rooted(SYSFS_CPUS).join("online")
 }
 
 pub fn sysfs_path_online_nodes() -> PathBuf {
-    Path::new(SYSFS_NODES).join("online")
+    rooted(SYSFS_NODES).join("online")
 }
 
 pub fn sysfs_path_node(n: Id) -> PathBuf {
-    Path::new(SYSFS_NODES).join(format!("node{}", n))
+    rooted(SYSFS_NODES).join(format!("node{}", n))
 }
 
 pub fn sysfs_path_node_cpus(n: Id) -> PathBuf {
@@ -25,7 +51,7 @@ pub fn sysfs_path_node_cpus(n: Id) -> PathBuf {
 }
 
 pub fn sysfs_path_htlb_base() -> PathBuf {
-    PathBuf::from(SYSFS_HTLB)
+    rooted(SYSFS_HTLB)
 }
 
 pub fn sysfs_path_htlb(n: Id, sz: usize, leaf: &str) -> PathBuf {
@@ -35,10 +61,20 @@ pub fn sysfs_path_htlb(n: Id, sz: usize, leaf: &str) -> PathBuf {
         .join(leaf)
 }
 
+pub fn sysfs_path_htlb_global(sz: usize, leaf: &str) -> PathBuf {
+    sysfs_path_htlb_base()
+        .join(format!("hugepages-{}kB", sz))
+        .join(leaf)
+}
+
 pub fn sysfs_path_thp_enabled() -> PathBuf {
-    PathBuf::from(SYSFS_THP_ENABLED)
+    rooted(SYSFS_THP_ENABLED)
 }
 
 pub fn sysfs_path_overcommit() -> PathBuf {
-    PathBuf::from(SYSFS_OVERCOMMIT)
+    rooted(SYSFS_OVERCOMMIT)
+}
+
+pub fn procfs_path_meminfo() -> PathBuf {
+    rooted(PROCFS_MEMINFO)
 }
@@ -0,0 +1,167 @@
+use std::str::FromStr;
+
+use super::misc::{size_from_str, size_to_str};
+
+// the baseline layout is backed by 4KB pages only
+const BASE_PAGESZ: usize = 4 << 10;
+
+// the default models reported at exit
+pub const DEFAULT_TLB_MODELS: &str = "skylake,icelake,zen3";
+
+// second level (unified) TLB entries per page size of some common CPUs, the entries shared
+// between page sizes are counted for each of them, so reach is an upper bound
+const PRESETS: [(&str, [(usize, usize); 3]); 3] = [
+    ("skylake", [(4 << 10, 1536), (2 << 20, 1536), (1 << 30, 16)]),
+    (
+        "icelake",
+        [(4 << 10, 2048), (2 << 20, 2048), (1 << 30, 1024)],
+    ),
+    ("zen3", [(4 << 10, 2048), (2 << 20, 2048), (1 << 30, 64)]),
+];
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TlbModel {
+    pub name: String,
+    // (page size, nr of entries), sorted by page size
+    pub entries: Vec<(usize, usize)>,
+}
+
+// reach of a TLB model over a layout, and of the same footprint backed only by 4KB pages
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct TlbReach {
+    pub backed: usize,
+    pub reach: usize,
+    pub base_reach: usize,
+}
+
+impl TlbReach {
+    pub fn coverage(&self) -> f64 {
+        self.reach as f64 / self.backed.max(1) as f64
+    }
+
+    pub fn base_coverage(&self) -> f64 {
+        self.base_reach as f64 / self.backed.max(1) as f64
+    }
+
+    // the footprint beyond the reach, relative to the one of the 4KB only layout, i.e. the
+    // fraction of the accesses that can miss left, assuming uniform ones
+    pub fn uncovered_ratio(&self) -> f64 {
+        let uncovered = self.backed.saturating_sub(self.reach);
+        let base_uncovered = self.backed.saturating_sub(self.base_reach);
+        match base_uncovered {
+            0 => 1.0,
+            _ => uncovered as f64 / base_uncovered as f64,
+        }
+    }
+}
+
+impl TlbModel {
+    fn entries_for(&self, pagesz: usize) -> usize {
+        self.entries
+            .iter()
+            .find(|(sz, _)| *sz == pagesz)
+            .map_or(0, |(_, nr)| *nr)
+    }
+
+    // the bytes of the (page size, bytes) backed layout the TLB entries cover
+    pub fn reach(&self, backed: &[(usize, usize)]) -> TlbReach {
+        let reach = backed
+            .iter()
+            .map(|&(pagesz, bytes)| bytes.min(self.entries_for(pagesz) * pagesz))
+            .sum();
+        let total = backed.iter().map(|(_, bytes)| bytes).sum::<usize>();
+
+        TlbReach {
+            backed: total,
+            reach,
+            base_reach: total.min(self.entries_for(BASE_PAGESZ) * BASE_PAGESZ),
+        }
+    }
+
+    pub fn as_string(&self) -> String {
+        let entries = self
+            .entries
+            .iter()
+            .map(|(pagesz, nr)| format!("{}={}", size_to_str(*pagesz), nr))
+            .collect::<Vec<_>>();
+        format!("{}:{}", self.name, entries.join(":"))
+    }
+}
+
+impl FromStr for TlbModel {
+    type Err = String;
+
+    // a preset (skylake, icelake or zen3) or <name>:<page size>=<entries>[:...], e.g.
+    // mycpu:4KB=1536:2MB=1536:1GB=16
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Invalid TLB model: {}", s);
+
+        let (name, entries) = match s.split_once(':') {
+            Some((name, entries)) => (name, entries),
+            None => {
+                return PRESETS
+                    .iter()
+                    .find(|(name, _)| *name == s)
+                    .map(|(name, entries)| TlbModel {
+                        name: name.to_string(),
+                        entries: entries.to_vec(),
+                    })
+                    .ok_or_else(|| format!("Unknown TLB model: {}", s));
+            }
+        };
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(err());
+        }
+
+        let mut entries = entries
+            .split(':')
+            .map(|x| {
+                let (pagesz, nr) = x.split_once('=').ok_or_else(err)?;
+                if !pagesz.starts_with(|c: char| c.is_ascii_digit()) {
+                    return Err(err());
+                }
+                Ok((
+                    size_from_str(pagesz),
+                    nr.parse::<usize>().map_err(|_| err())?,
+                ))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        entries.sort();
+        if entries.windows(2).any(|x| x[0].0 == x[1].0) {
+            return Err(err());
+        }
+
+        Ok(TlbModel {
+            name: name.to_string(),
+            entries,
+        })
+    }
+}
+
+// the report lines for the given models, one per model
+pub fn reach_report(models: &[TlbModel], backed: &[(usize, usize)]) -> Vec<String> {
+    let pages = backed
+        .iter()
+        .filter(|(_, bytes)| *bytes > 0)
+        .map(|(pagesz, bytes)| format!("{} {}", size_to_str(*bytes), size_to_str(*pagesz)))
+        .collect::<Vec<_>>();
+
+    models
+        .iter()
+        .map(|model| {
+            let x = model.reach(backed);
+            format!(
+                "tlb ({}): reach {} ({:.1}% of {} backed: {}), 4KB only {} ({:.1}%), {:.1}x the reach, {:.1}% of the uncovered footprint left",
+                model.name,
+                size_to_str(x.reach),
+                x.coverage() * 100.0,
+                size_to_str(x.backed),
+                pages.join(", "),
+                size_to_str(x.base_reach),
+                x.base_coverage() * 100.0,
+                x.reach as f64 / x.base_reach.max(1) as f64,
+                x.uncovered_ratio() * 100.0
+            )
+        })
+        .collect()
+}
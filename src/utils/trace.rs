@@ -0,0 +1,174 @@
+use std::fs;
+use std::mem::{size_of, size_of_val};
+use std::path::Path;
+use std::ptr;
+use std::slice;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+pub const TRACE_MAGIC: &[u8; 8] = b"MOSTRACE";
+pub const TRACE_VERSION: u32 = 1;
+// size of the v1 records, the fields are only appended to it
+const TRACE_V1_RECORD_SIZE: usize = 64;
+
+// traced operations
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum TraceOp {
+    MMAP,
+    MUNMAP,
+    MPROTECT,
+    MADVISE,
+    MREMAP,
+    BRK,
+}
+
+impl TraceOp {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TraceOp::MMAP => "mmap",
+            TraceOp::MUNMAP => "munmap",
+            TraceOp::MPROTECT => "mprotect",
+            TraceOp::MADVISE => "madvise",
+            TraceOp::MREMAP => "mremap",
+            TraceOp::BRK => "brk",
+        }
+    }
+
+    pub fn from_u32(op: u32) -> Option<Self> {
+        [
+            TraceOp::MMAP,
+            TraceOp::MUNMAP,
+            TraceOp::MPROTECT,
+            TraceOp::MADVISE,
+            TraceOp::MREMAP,
+            TraceOp::BRK,
+        ]
+        .into_iter()
+        .find(|&x| x as u32 == op)
+    }
+
+    // whether failures can be injected into the op, only the calls that allocate
+    pub fn injectable(&self) -> bool {
+        matches!(self, TraceOp::MMAP | TraceOp::MREMAP | TraceOp::BRK)
+    }
+}
+
+impl FromStr for TraceOp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mmap" => Ok(TraceOp::MMAP),
+            "munmap" => Ok(TraceOp::MUNMAP),
+            "mprotect" => Ok(TraceOp::MPROTECT),
+            "madvise" => Ok(TraceOp::MADVISE),
+            "mremap" => Ok(TraceOp::MREMAP),
+            "brk" => Ok(TraceOp::BRK),
+            _ => Err(format!("Unknown operation: {}", s)),
+        }
+    }
+}
+
+// a single traced operation, the arguments are op-specific:
+// mmap: addr, len, arg = prot, arg2 = flags
+// munmap: addr, len
+// mprotect / madvise: addr, len, arg = prot / advice
+// mremap: addr = old address, len = old size, arg = new size, arg2 = new address
+// brk: addr = requested break (or the increment for sbrk), arg = 1 for sbrk
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TraceRecord {
+    // 1-based sequence number, the ring epoch is (seq - 1) / ring size
+    pub seq: u64,
+    // ns since the start of the trace
+    pub time: u64,
+    pub op: u32,
+    pub tid: u32,
+    pub addr: u64,
+    pub len: u64,
+    pub arg: u64,
+    pub arg2: u64,
+    pub ret: u64,
+}
+
+// trace file header, followed by the raw records
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TraceHeader {
+    pub magic: [u8; 8],
+    pub version: u32,
+    pub record_size: u32,
+    pub ring_size: u64,
+}
+
+impl TraceHeader {
+    pub fn new(ring_size: usize) -> Self {
+        Self {
+            magic: *TRACE_MAGIC,
+            version: TRACE_VERSION,
+            record_size: size_of::<TraceRecord>() as u32,
+            ring_size: ring_size as u64,
+        }
+    }
+}
+
+/// The `#[repr(C)]` records without any padding, whose bytes are all initialized.
+///
+/// # Safety
+///
+/// Only to be implemented for `#[repr(C)]` types without padding bytes.
+pub unsafe trait Pod: Copy {}
+
+// 8 + 8 + 4 + 4 + 5 * 8 and 8 + 4 + 4 + 8 bytes, no padding
+const _: () = assert!(size_of::<TraceRecord>() == 64 && size_of::<TraceHeader>() == 24);
+unsafe impl Pod for TraceRecord {}
+unsafe impl Pod for TraceHeader {}
+
+// reinterpret plain-old-data records as bytes, for writing them out
+pub fn as_bytes<T: Pod>(x: &[T]) -> &[u8] {
+    // SAFETY: T has no padding, so every byte of the slice is initialized
+    unsafe { slice::from_raw_parts(x.as_ptr() as *const u8, size_of_val(x)) }
+}
+
+// read a trace written by libmosalloc
+pub fn trace_from_path(path: &Path) -> Result<(TraceHeader, Vec<TraceRecord>), String> {
+    let data = fs::read(path).map_err(|e| e.to_string())?;
+
+    if data.len() < size_of::<TraceHeader>() {
+        return Err(format!("{} is not a mosalloc trace", path.display()));
+    }
+
+    let header = unsafe { (data.as_ptr() as *const TraceHeader).read_unaligned() };
+    if &header.magic != TRACE_MAGIC {
+        return Err(format!("{} is not a mosalloc trace", path.display()));
+    }
+    // the fields of older records missing in this version are zeroed, and the ones of newer
+    // records unknown to it are skipped
+    let record_size = header.record_size as usize;
+    if record_size < TRACE_V1_RECORD_SIZE {
+        return Err(format!(
+            "unsupported trace version {} (record size {})",
+            header.version, header.record_size
+        ));
+    }
+
+    let known = record_size.min(size_of::<TraceRecord>());
+    let records = data[size_of::<TraceHeader>()..]
+        .chunks_exact(record_size)
+        .map(|x| {
+            let mut record = TraceRecord::default();
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    x.as_ptr(),
+                    &mut record as *mut TraceRecord as *mut u8,
+                    known,
+                )
+            };
+            record
+        })
+        .collect();
+
+    Ok((header, records))
+}
@@ -0,0 +1,256 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::str::FromStr;
+
+use nix::libc;
+
+use super::control::RegionStats;
+use super::misc::size_to_str;
+use super::trace::{TraceOp, TraceRecord};
+
+// the return value of the failed calls, MAP_FAILED or -1
+const FAILED: u64 = u64::MAX;
+
+// a point of a trace: after the record with the given sequence number, or after the last record
+// up to the given time (ns since the start of the trace)
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum TracePoint {
+    SEQ(u64),
+    TIME(u64),
+}
+
+impl TracePoint {
+    pub fn as_string(&self) -> String {
+        match self {
+            TracePoint::SEQ(seq) => format!("seq:{}", seq),
+            TracePoint::TIME(ns) => format!("time:{}", *ns as f64 / 1e9),
+        }
+    }
+
+    // whether r is past the point
+    fn past(&self, r: &TraceRecord) -> bool {
+        match self {
+            TracePoint::SEQ(seq) => r.seq > *seq,
+            TracePoint::TIME(ns) => r.time > *ns,
+        }
+    }
+}
+
+impl FromStr for TracePoint {
+    type Err = String;
+
+    // seq:<sequence number> or time:<seconds> (e.g. time:720.5)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Invalid trace point: {}", s);
+
+        match s.split_once(':').ok_or_else(err)? {
+            ("seq", seq) => seq.parse::<u64>().map(TracePoint::SEQ).map_err(|_| err()),
+            ("time", secs) => secs
+                .parse::<f64>()
+                .ok()
+                .filter(|x| *x >= 0.0)
+                .map(|x| TracePoint::TIME((x * 1e9) as u64))
+                .ok_or_else(err),
+            _ => Err(err()),
+        }
+    }
+}
+
+// a region of the traced process, as found in its stats file, and its high-water mark so far
+#[derive(Debug, Clone)]
+struct TracedRegion {
+    label: String,
+    range: Range<usize>,
+    high_water: usize,
+}
+
+// the mappings and the program break of a traced process, rebuilt from its trace records, along
+// with the free maps of its regions when their layout is known
+#[derive(Debug, Clone, Default)]
+pub struct TraceState {
+    // the last record applied
+    pub seq: u64,
+    pub time: u64,
+    pub applied: usize,
+    // live mappings, start -> (end, prot)
+    pub maps: BTreeMap<usize, (usize, i32)>,
+    pub brk: Option<usize>,
+    regions: Vec<TracedRegion>,
+}
+
+impl TraceState {
+    // the regions are taken from the stats file of the traced run, if any
+    pub fn new(regions: &[RegionStats]) -> Self {
+        Self {
+            regions: regions
+                .iter()
+                .map(|x| TracedRegion {
+                    label: x.label(),
+                    range: x.start..x.start + x.len,
+                    high_water: 0,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    // the state after the records up to point, the records are sorted by their sequence number
+    pub fn at(records: &[TraceRecord], regions: &[RegionStats], point: TracePoint) -> Self {
+        let mut state = Self::new(regions);
+        state.advance(records, point);
+        state
+    }
+
+    // apply the records past the current state up to point, returns how many were applied
+    pub fn advance(&mut self, records: &[TraceRecord], point: TracePoint) -> usize {
+        let from = records.partition_point(|x| x.seq <= self.seq);
+        let count = records[from..]
+            .iter()
+            .take_while(|x| !point.past(x))
+            .count();
+        for r in records[from..from + count].iter() {
+            self.apply(r);
+        }
+        count
+    }
+
+    // take [range) out of the live mappings, returning the pieces removed
+    fn cut(&mut self, range: Range<usize>) -> Vec<(Range<usize>, i32)> {
+        let overlapping = self
+            .maps
+            .range(..range.end)
+            .rev()
+            .take_while(|(_, (end, _))| *end > range.start)
+            .map(|(start, (end, prot))| (*start..*end, *prot))
+            .collect::<Vec<_>>();
+
+        let mut removed = Vec::new();
+        for (x, prot) in overlapping.into_iter().rev() {
+            self.maps.remove(&x.start);
+            if x.start < range.start {
+                self.maps.insert(x.start, (range.start, prot));
+            }
+            if x.end > range.end {
+                self.maps.insert(range.end, (x.end, prot));
+            }
+            removed.push((x.start.max(range.start)..x.end.min(range.end), prot));
+        }
+        removed
+    }
+
+    fn map(&mut self, range: Range<usize>, prot: i32) {
+        self.cut(range.clone());
+        for region in self.regions.iter_mut() {
+            if region.range.contains(&range.start) {
+                region.high_water = region.high_water.max(range.end - region.range.start);
+            }
+        }
+        self.maps.insert(range.start, (range.end, prot));
+    }
+
+    pub fn apply(&mut self, r: &TraceRecord) {
+        self.seq = r.seq;
+        self.time = r.time;
+        self.applied += 1;
+
+        let (addr, len) = (r.addr as usize, r.len as usize);
+        match TraceOp::from_u32(r.op) {
+            Some(TraceOp::MMAP) if r.ret != FAILED => {
+                self.map(r.ret as usize..r.ret as usize + len, r.arg as i32);
+            }
+            Some(TraceOp::MUNMAP) if r.ret == 0 => {
+                self.cut(addr..addr + len);
+            }
+            Some(TraceOp::MPROTECT) if r.ret == 0 => {
+                for (x, _) in self.cut(addr..addr + len) {
+                    self.maps.insert(x.start, (x.end, r.arg as i32));
+                }
+            }
+            Some(TraceOp::MREMAP) if r.ret != FAILED => {
+                let prot = self
+                    .cut(addr..addr + len)
+                    .first()
+                    .map_or(libc::PROT_READ | libc::PROT_WRITE, |x| x.1);
+                self.map(r.ret as usize..r.ret as usize + r.arg as usize, prot);
+            }
+            Some(TraceOp::BRK) if r.ret != FAILED => self.brk = Some(r.ret as usize),
+            _ => (),
+        }
+    }
+
+    // the free ranges of each region: the ones not covered by a live mapping
+    pub fn free_maps(&self) -> Vec<(String, Vec<Range<usize>>)> {
+        self.regions
+            .iter()
+            .map(|region| {
+                let mut free = Vec::new();
+                let mut cur = region.range.start;
+                for (start, (end, _)) in self.maps.range(region.range.clone()) {
+                    if *start > cur {
+                        free.push(cur..*start);
+                    }
+                    cur = cur.max(*end);
+                }
+                if cur < region.range.end {
+                    free.push(cur..region.range.end);
+                }
+                (region.label.clone(), free)
+            })
+            .collect()
+    }
+
+    pub fn as_string(&self, verbose: bool) -> String {
+        let mapped = self.maps.iter().map(|(start, (end, _))| end - start).sum();
+        let mut out = format!(
+            "seq {} ({:.3}s, {} records applied): {} live mappings ({}), brk {}\n",
+            self.seq,
+            self.time as f64 / 1e9,
+            self.applied,
+            self.maps.len(),
+            size_to_str(mapped),
+            self.brk.map_or("-".to_string(), |x| format!("0x{:x}", x))
+        );
+
+        for ((label, free), region) in self.free_maps().iter().zip(self.regions.iter()) {
+            // the free space below the high-water mark is the fragmentation
+            let hw = region.range.start + region.high_water;
+            let below = free
+                .iter()
+                .filter(|x| x.start < hw)
+                .map(|x| x.end.min(hw) - x.start)
+                .collect::<Vec<_>>();
+            out += &format!(
+                "{} 0x{:x}: {} free ranges, high-water {}, {} free below it in {} ranges (largest {})\n",
+                label,
+                region.range.start,
+                free.len(),
+                size_to_str(region.high_water),
+                size_to_str(below.iter().sum()),
+                below.len(),
+                size_to_str(below.iter().max().copied().unwrap_or(0))
+            );
+            for x in free.iter() {
+                out += &format!(
+                    "  free 0x{:x}-0x{:x} ({})\n",
+                    x.start,
+                    x.end,
+                    size_to_str(x.len())
+                );
+            }
+        }
+
+        if verbose || self.regions.is_empty() {
+            for (start, (end, prot)) in self.maps.iter() {
+                out += &format!(
+                    "  map 0x{:x}-0x{:x} ({}) prot {}\n",
+                    start,
+                    end,
+                    size_to_str(end - start),
+                    prot
+                );
+            }
+        }
+
+        out
+    }
+}